@@ -1,25 +1,157 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::process::ExitCode;
 
-use tokio::sync::{mpsc, Semaphore};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use tokio_util::sync::CancellationToken;
 
+use app::logs::log_options;
 use app::supervisor::run_app;
-use score::job::JobRequest;
+use config::{Config, ConfigSource, LogFormat, DEFAULT_PATH};
+use scheduler::handler::HandlerRegistry;
 use utils::logs::init_logs;
 
-#[tokio::main(flavor="multi_thread")]
-async fn main() {
-    init_logs();
+/// Stratum proxy between the miners and the pools
+#[derive(Debug, Parser)]
+#[command(name = "proxy-gates", version, args_conflicts_with_subcommands = true)]
+struct Cli {
+    // `proxy-gates --config ...` is the same as `proxy-gates run --config ...`
+    #[command(flatten)]
+    run: RunArgs,
 
-    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5555);
+    #[command(subcommand)]
+    command: Option<Command>
+}
 
-    let semaphore = Arc::new(Semaphore::new(100));
-    let (tx_cpu_queue_high, rx_cpu_queue_high) = mpsc::channel::<JobRequest>(256);
-    let (tx_cpu_queue_norm, rx_cpu_queue_norm) = mpsc::channel::<JobRequest>(256);
-    let cancel = CancellationToken::new();
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Runs the proxy
+    Run(RunArgs),
+    /// Validates the config and prints the effective one, secrets are hidden
+    CheckConfig(ConfigArgs),
+    /// Version and build info
+    Version
+}
+
+#[derive(Debug, Args)]
+struct RunArgs {
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    /// Format of the log lines, overrides `logs.format`
+    #[arg(long, value_enum, env = "PROXY_GATES_LOG_FORMAT")]
+    log_format: Option<Format>
+}
+
+/// Where the config comes from, the flags override the file and the environment
+#[derive(Debug, Args)]
+struct ConfigArgs {
+    /// Config file, JSON or TOML
+    #[arg(short, long, env = "PROXY_GATES_CONFIG", default_value = DEFAULT_PATH)]
+    config: PathBuf,
+
+    /// Address the miners connect to, overrides `listen`
+    #[arg(long)]
+    listen: Option<String>,
+
+    /// Log filter, e.g. `info` or `info,network=debug`, overrides `log_filter`
+    #[arg(long)]
+    log_level: Option<String>
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    Pretty,
+    Json
+}
+
+impl ConfigArgs {
+    fn source(&self) -> ConfigSource {
+        let mut source = ConfigSource::new(&self.config);
+        if let Some(listen) = &self.listen {
+            source = source.set("listen", listen);
+        }
+        if let Some(log_level) = &self.log_level {
+            source = source.set("log_filter", log_level);
+        }
+
+        source
+    }
+}
 
-    if let Err(e) = run_app(addr, semaphore, tx_cpu_queue_high, tx_cpu_queue_norm, rx_cpu_queue_high, rx_cpu_queue_norm, cancel).await {
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match cli.command {
+        None => run(cli.run),
+        Some(Command::Run(args)) => run(args),
+        Some(Command::CheckConfig(args)) => check_config(&args),
+        Some(Command::Version) => {
+            println!(
+                "proxy-gates {} (commit {}, {} build, {})",
+                env!("CARGO_PKG_VERSION"),
+                env!("PROXY_GATES_COMMIT"),
+                env!("PROXY_GATES_PROFILE"),
+                env!("PROXY_GATES_TARGET")
+            );
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+fn run(args: RunArgs) -> ExitCode {
+    let source = args.config.source();
+    // The logs are set up before the runtime, the proxy reads the config again when it starts
+    let config = match Config::load(&source) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let format = args.log_format.map(|format| match format {
+        Format::Pretty => LogFormat::Pretty,
+        Format::Json => LogFormat::Json
+    });
+    let _logs = match init_logs(&log_options(&config, format)) {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("Couldn't set up the logs: {e:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let runtime = match tokio::runtime::Builder::new_multi_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Application error: {e:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let cancel = CancellationToken::new();
+    if let Err(e) = runtime.block_on(run_app(source, HandlerRegistry::new(), cancel)) {
         eprintln!("Application error: {e:?}");
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
+
+fn check_config(args: &ConfigArgs) -> ExitCode {
+    match Config::load(&args.source()) {
+        Ok(config) => match serde_json::to_string_pretty(&config.redacted()) {
+            Ok(json) => {
+                println!("{json}");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                ExitCode::FAILURE
+            }
+        },
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,261 @@
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde_json::Value;
+
+use control_protocol::{ConnFilter, ControlRequest, ControlResponse};
+
+use crate::table::{cell, Table};
+
+mod table;
+
+/// Operator's tool of the proxy, it talks to the proxy over its local control socket
+#[derive(Debug, Parser)]
+#[command(name = "proxy-gatesctl", version)]
+struct Cli {
+    /// Control socket of the proxy
+    #[arg(long, env = "PROXY_GATES_SOCKET", default_value = "/tmp/proxy-gates.sock")]
+    socket: PathBuf,
+
+    #[arg(short, long, value_enum, default_value_t = Output::Table)]
+    output: Output,
+
+    #[command(subcommand)]
+    command: Command
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Output {
+    Table,
+    Json
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Summary of the running proxy
+    Status,
+    /// Miner connections
+    #[command(subcommand)]
+    Conns(ConnsCommand),
+    /// Upstream pools
+    #[command(subcommand)]
+    Pools(PoolsCommand),
+    /// Banned IPs
+    #[command(subcommand)]
+    Bans(BansCommand),
+    /// Configuration of the proxy
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Hands the listeners to a new process of the proxy binary, this one drains and exits
+    Upgrade,
+    /// Shows a message to the miners (client.show_message)
+    Broadcast {
+        message: String,
+        #[command(flatten)]
+        filter: FilterArgs
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum ConnsCommand {
+    List {
+        #[command(flatten)]
+        filter: FilterArgs
+    },
+    Show {
+        conn_id: u64
+    },
+    Kick {
+        conn_id: u64,
+        #[arg(long)]
+        reason: Option<String>
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum PoolsCommand {
+    List,
+    /// Stops sending workers to the pool and reconnects the miners which work on it
+    Drain {
+        pool: String,
+        /// Pool the workers are sent to instead, without it they aren't authorized
+        #[arg(long = "to")]
+        redirect_to: Option<String>
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum BansCommand {
+    /// Bans the IP and closes its connections
+    Add {
+        ip: IpAddr,
+        #[arg(long)]
+        reason: Option<String>,
+        /// The ban is permanent without it
+        #[arg(long)]
+        ttl_secs: Option<u64>
+    },
+    Remove {
+        ip: IpAddr
+    },
+    List
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    Reload
+}
+
+/// Which connections a command is applied to, the ones matching every filter given, all of them
+/// without a filter
+#[derive(Debug, Args)]
+struct FilterArgs {
+    #[arg(long)]
+    worker: Option<String>,
+    #[arg(long)]
+    subaccount: Option<String>,
+    #[arg(long)]
+    ip: Option<IpAddr>,
+    #[arg(long)]
+    pool: Option<String>
+}
+
+impl From<FilterArgs> for ConnFilter {
+    fn from(args: FilterArgs) -> Self {
+        ConnFilter {
+            worker: args.worker,
+            subaccount: args.subaccount,
+            ip: args.ip,
+            pool: args.pool
+        }
+    }
+}
+
+impl From<Command> for ControlRequest {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::Status => ControlRequest::Status,
+            Command::Conns(ConnsCommand::List { filter }) => ControlRequest::ConnsList { filter: filter.into() },
+            Command::Conns(ConnsCommand::Show { conn_id }) => ControlRequest::ConnsShow { conn_id },
+            Command::Conns(ConnsCommand::Kick { conn_id, reason }) => ControlRequest::ConnsKick { conn_id, reason },
+            Command::Pools(PoolsCommand::List) => ControlRequest::PoolsList,
+            Command::Pools(PoolsCommand::Drain { pool, redirect_to }) => ControlRequest::PoolsDrain { pool, redirect_to },
+            Command::Bans(BansCommand::Add { ip, reason, ttl_secs }) => ControlRequest::BansAdd { ip, reason, ttl_secs },
+            Command::Bans(BansCommand::Remove { ip }) => ControlRequest::BansRemove { ip },
+            Command::Bans(BansCommand::List) => ControlRequest::BansList,
+            Command::Config(ConfigCommand::Reload) => ControlRequest::ConfigReload,
+            Command::Upgrade => ControlRequest::Upgrade,
+            Command::Broadcast { message, filter } => ControlRequest::Broadcast { message, filter: filter.into() }
+        }
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let request: ControlRequest = cli.command.into();
+
+    let response = match control_protocol::request(&cli.socket, &request).await {
+        Ok(response) => response,
+        Err(err) => {
+            eprintln!("Couldn't reach the proxy at {}: {err}", cli.socket.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match response {
+        ControlResponse::Ok(value) => {
+            let output = match cli.output {
+                Output::Json => format!("{}\n", serde_json::to_string_pretty(&value).unwrap_or_default()),
+                Output::Table => render(&request, &value)
+            };
+            // The output may be piped to a tool which doesn't read all of it
+            let _ = std::io::stdout().write_all(output.as_bytes());
+            ExitCode::SUCCESS
+        }
+        ControlResponse::Error(err) => {
+            eprintln!("Error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Table view of the response, the columns depend on the command
+fn render(request: &ControlRequest, value: &Value) -> String {
+    let items = value.as_array().map(Vec::as_slice).unwrap_or_default();
+
+    match request {
+        ControlRequest::ConnsList { .. } => {
+            let mut table = Table::new(vec!["ID", "REMOTE", "STATE", "WORKERS", "UPTIME(S)"]);
+            for conn in items {
+                let workers: Vec<Value> = conn["session"]["workers"].as_array()
+                    .map(|workers| workers.iter().map(|worker| worker["worker_name"].clone()).collect())
+                    .unwrap_or_default();
+                table.row(vec![
+                    cell(&conn["conn_id"]),
+                    cell(&conn["remote"]),
+                    cell(&conn["session"]["state"]),
+                    cell(&Value::Array(workers)),
+                    cell(&conn["duration_secs"])
+                ]);
+            }
+            table.to_string()
+        }
+        ControlRequest::ConnsShow { .. } => {
+            let mut summary = Table::new(vec!["FIELD", "VALUE"]);
+            for (field, value) in [
+                ("conn_id", &value["conn_id"]),
+                ("remote", &value["remote"]),
+                ("miner_id", &value["session"]["miner_id"]),
+                ("state", &value["session"]["state"]),
+                ("primary_worker", &value["session"]["primary_worker"]),
+                ("uptime_secs", &value["duration_secs"])
+            ] {
+                summary.row(vec![field.to_string(), cell(value)]);
+            }
+
+            let mut workers = Table::new(vec!["WORKER", "SUBACCOUNT", "POOL", "SHARES", "LAST SHARE"]);
+            for worker in value["session"]["workers"].as_array().map(Vec::as_slice).unwrap_or_default() {
+                workers.row(vec![
+                    cell(&worker["worker_name"]),
+                    cell(&worker["sub_account_name"]),
+                    cell(&worker["pool_addr"]),
+                    cell(&worker["share_count"]),
+                    cell(&worker["last_share_time"])
+                ]);
+            }
+
+            let mut upstreams = Table::new(vec!["POOL", "WORKERS", "CONNECTED"]);
+            for upstream in value["upstreams"].as_array().map(Vec::as_slice).unwrap_or_default() {
+                upstreams.row(vec![cell(&upstream["pool_addr"]), cell(&upstream["workers"]), cell(&upstream["is_connected"])]);
+            }
+
+            format!("{summary}\n{workers}\n{upstreams}")
+        }
+        ControlRequest::PoolsList => {
+            let mut table = Table::new(vec!["POOL", "SESSIONS", "CONNECTED", "WORKERS", "DRAINED", "REDIRECT"]);
+            for pool in items {
+                table.row(vec![
+                    cell(&pool["pool"]),
+                    cell(&pool["sessions"]),
+                    cell(&pool["connected_sessions"]),
+                    cell(&pool["workers"]),
+                    cell(&pool["drained"]),
+                    cell(&pool["redirect_to"])
+                ]);
+            }
+            table.to_string()
+        }
+        ControlRequest::BansList => {
+            let mut table = Table::new(vec!["IP", "REASON", "CREATED", "EXPIRES"]);
+            for ban in items {
+                table.row(vec![cell(&ban["ip"]), cell(&ban["reason"]), cell(&ban["created_at"]), cell(&ban["expires_at"])]);
+            }
+            table.to_string()
+        }
+        _ => Table::key_value(value).to_string()
+    }
+}
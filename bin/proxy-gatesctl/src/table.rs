@@ -0,0 +1,61 @@
+use std::fmt;
+
+use serde_json::Value;
+
+/// Plain text table, columns are padded to the widest cell
+pub struct Table {
+    headers: Vec<&'static str>,
+    rows: Vec<Vec<String>>
+}
+
+impl Table {
+    pub fn new(headers: Vec<&'static str>) -> Self {
+        Self { headers, rows: Vec::new() }
+    }
+
+    pub fn row(&mut self, row: Vec<String>) -> &mut Self {
+        self.rows.push(row);
+        self
+    }
+
+    /// Two columns table of the object's fields
+    pub fn key_value(value: &Value) -> Self {
+        let mut table = Table::new(vec!["FIELD", "VALUE"]);
+        if let Value::Object(fields) = value {
+            for (key, field) in fields {
+                table.row(vec![key.clone(), cell(field)]);
+            }
+        }
+
+        table
+    }
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut widths: Vec<usize> = self.headers.iter().map(|header| header.len()).collect();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        let headers: Vec<String> = self.headers.iter().map(|header| header.to_string()).collect();
+        for row in std::iter::once(&headers).chain(&self.rows) {
+            let line: Vec<String> = row.iter().zip(&widths).map(|(cell, width)| format!("{cell:<width$}")).collect();
+            writeln!(f, "{}", line.join("  ").trim_end())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Text of a JSON value in a table cell
+pub fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items.iter().map(cell).collect::<Vec<_>>().join(","),
+        other => other.to_string()
+    }
+}
@@ -1 +1,5 @@
-pub mod supervisor;
\ No newline at end of file
+pub mod supervisor;
+pub mod signals;
+pub mod logs;
+pub mod sinks;
+pub mod systemd;
@@ -0,0 +1,85 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::select;
+use tokio::sync::watch;
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+
+use tracing::{info, warn};
+
+use config::{Config, LogFormat, LogRotation};
+use utils::logs::rate_limit::RATE_LIMIT;
+use utils::logs::{LogFile, LogOptions, Rotation};
+
+// The lines dropped by the rate limit are reported this often
+const DROPPED_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Options of the logs from the config, `format` of the command line overrides the config's
+pub fn log_options(config: &Config, format: Option<LogFormat>) -> LogOptions {
+    let format = match format.unwrap_or(config.logs.format) {
+        LogFormat::Pretty => utils::logs::LogFormat::Pretty,
+        LogFormat::Json => utils::logs::LogFormat::Json
+    };
+    let file = config.logs.file.as_ref().map(|file| LogFile {
+        directory: PathBuf::from(&file.directory),
+        prefix: file.prefix.clone(),
+        rotation: match file.rotation {
+            LogRotation::Hourly => Rotation::Hourly,
+            LogRotation::Daily => Rotation::Daily,
+            LogRotation::Never => Rotation::Never
+        },
+        max_files: file.max_files
+    });
+
+    LogOptions {
+        format,
+        filter: config.full_log_filter(),
+        stdout: config.logs.stdout,
+        file,
+        rate_limit: config.logs.rate_limit,
+        rate_limit_interval: Duration::from_secs(config.logs.rate_limit_interval_secs)
+    }
+}
+
+/// Swaps the filter and the rate limit of the logs whenever the reloaded config has new ones, and
+/// reports the lines the rate limit has dropped
+pub async fn watch_logs(mut config: watch::Receiver<Arc<Config>>, shutdown: CancellationToken) -> anyhow::Result<()> {
+    let mut current = config.borrow_and_update().full_log_filter();
+    let mut report_tick = interval(DROPPED_REPORT_INTERVAL);
+
+    loop {
+        select! {
+            _ = shutdown.cancelled() => break,
+            _ = report_tick.tick() => {
+                for dropped in RATE_LIMIT.take_dropped() {
+                    warn!(module = dropped.target, location = %dropped.location, dropped = dropped.count, "Log lines were dropped by the rate limit");
+                }
+            }
+            changed = config.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+
+                let (filter, rate_limit, interval_secs) = {
+                    let config = config.borrow_and_update();
+                    (config.full_log_filter(), config.logs.rate_limit, config.logs.rate_limit_interval_secs)
+                };
+                RATE_LIMIT.set(rate_limit, Duration::from_secs(interval_secs));
+
+                // A filter set through the admin API stays until the config has a new one
+                if filter == current {
+                    continue;
+                }
+                match utils::logs::set_filter(&filter) {
+                    Ok(()) => info!(%filter, "The log filter is changed"),
+                    Err(err) => warn!(%filter, "Couldn't change the log filter: {}", err)
+                }
+                current = filter;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use std::sync::Arc;
+
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+
+use tracing::{info, warn};
+
+use config::reload::ConfigReloader;
+use network::drain::DrainController;
+use network::upgrade::UpgradeController;
+
+/// SIGTERM starts the drain, the second one or SIGINT shuts the proxy down at once. SIGUSR2 upgrades
+/// the proxy to the binary at the same path, SIGHUP reloads the config
+pub async fn handle_signals(
+    drain: Arc<DrainController>, upgrade: Arc<UpgradeController>, reloader: Arc<ConfigReloader>,
+    shutdown: CancellationToken
+) -> anyhow::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigusr2 = signal(SignalKind::user_defined2())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    loop {
+        select! {
+            _ = shutdown.cancelled() => break,
+            _ = sigterm.recv() => {
+                if drain.start() {
+                    info!("SIGTERM received, draining");
+                } else {
+                    warn!("SIGTERM received during the drain, shutting down now");
+                    shutdown.cancel();
+                }
+            }
+            _ = sigusr2.recv() => {
+                info!("SIGUSR2 received, upgrading");
+                let upgrade = Arc::clone(&upgrade);
+                tokio::spawn(async move {
+                    if let Err(err) = upgrade.upgrade().await {
+                        warn!("Upgrade failed, the proxy keeps running: {:#}", err);
+                    }
+                });
+            }
+            _ = sighup.recv() => match reloader.reload() {
+                Ok(summary) => {
+                    info!(changes = ?summary.changes, "SIGHUP received, the config is reloaded");
+                    if !summary.restart_required.is_empty() {
+                        warn!(fields = ?summary.restart_required, "These changes take effect after a restart");
+                    }
+                }
+                Err(err) => warn!("SIGHUP received, the config isn't reloaded: {}", err)
+            },
+            _ = tokio::signal::ctrl_c() => {
+                info!("SIGINT received, shutting down");
+                shutdown.cancel();
+            }
+        }
+    }
+
+    Ok(())
+}
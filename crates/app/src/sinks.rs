@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use config::{CommandSourceKind, Config, EventSinkKind, SinkKind};
+use network::commands::memory_source::MemoryCommandSource;
+use network::commands::CommandSource;
+use network::events::memory_sink::MemorySink;
+use network::events::EventSink;
+use telemetry::sink::log_sink::LogSink;
+use telemetry::sink::prometheus_sink::PrometheusSink;
+use telemetry::sink::statsd_sink::StatsdSink;
+use telemetry::sink::TelemetrySink;
+
+// The sink of the events and the same one as the memory sink, if it's that
+type EventSinks = (Arc<dyn EventSink>, Option<Arc<MemorySink>>);
+// The same for the source of the commands
+type CommandSources = (Arc<dyn CommandSource>, Option<Arc<MemoryCommandSource>>);
+
+/// Sinks of the telemetry the config selects, each one once
+pub async fn telemetry_sinks(config: &Config) -> anyhow::Result<Vec<Arc<dyn TelemetrySink>>> {
+    let mut kinds: Vec<SinkKind> = Vec::new();
+    for kind in &config.telemetry.sinks {
+        if !kinds.contains(kind) {
+            kinds.push(*kind);
+        }
+    }
+
+    let mut sinks: Vec<Arc<dyn TelemetrySink>> = Vec::with_capacity(kinds.len());
+    for kind in kinds {
+        let sink: Arc<dyn TelemetrySink> = match kind {
+            SinkKind::Log => Arc::new(LogSink),
+            SinkKind::Prometheus => Arc::new(PrometheusSink),
+            SinkKind::Statsd => Arc::new(StatsdSink::connect(&config.telemetry.statsd_address).await?),
+            SinkKind::Kafka => kafka_sink(config)?
+        };
+        sinks.push(sink);
+    }
+
+    Ok(sinks)
+}
+
+/// Sink of the events the config selects, the memory one is returned as well for the admin API
+pub fn event_sink(config: &Config) -> anyhow::Result<EventSinks> {
+    match config.events.sink {
+        EventSinkKind::Memory => {
+            let sink = Arc::new(MemorySink::new(config.events.memory_capacity));
+            Ok((Arc::clone(&sink) as Arc<dyn EventSink>, Some(sink)))
+        }
+        EventSinkKind::Kafka => Ok((kafka_event_sink(config)?, None))
+    }
+}
+
+/// Source of the commands the config selects, the memory one is returned as well for the admin API
+pub fn command_source(config: &Config) -> anyhow::Result<CommandSources> {
+    match config.commands.source {
+        CommandSourceKind::Memory => {
+            let source = Arc::new(MemoryCommandSource::new());
+            Ok((Arc::clone(&source) as Arc<dyn CommandSource>, Some(source)))
+        }
+        CommandSourceKind::Kafka => Ok((kafka_command_source(config)?, None))
+    }
+}
+
+#[cfg(feature = "kafka")]
+fn kafka_sink(config: &Config) -> anyhow::Result<Arc<dyn TelemetrySink>> {
+    Ok(Arc::new(kafka::telemetry_sink::KafkaTelemetrySink::new(&config.kafka, &config.telemetry.kafka_topic)?))
+}
+
+#[cfg(not(feature = "kafka"))]
+fn kafka_sink(_config: &Config) -> anyhow::Result<Arc<dyn TelemetrySink>> {
+    anyhow::bail!("The kafka telemetry sink needs the proxy built with the kafka feature")
+}
+
+#[cfg(feature = "kafka")]
+fn kafka_event_sink(config: &Config) -> anyhow::Result<Arc<dyn EventSink>> {
+    Ok(Arc::new(kafka::event_sink::KafkaEventSink::new(&config.kafka, &config.events.topic)?))
+}
+
+#[cfg(not(feature = "kafka"))]
+fn kafka_event_sink(_config: &Config) -> anyhow::Result<Arc<dyn EventSink>> {
+    anyhow::bail!("The kafka event sink needs the proxy built with the kafka feature")
+}
+
+#[cfg(feature = "kafka")]
+fn kafka_command_source(config: &Config) -> anyhow::Result<Arc<dyn CommandSource>> {
+    Ok(Arc::new(kafka::command_source::KafkaCommandSource::new(&config.kafka, &config.commands)?))
+}
+
+#[cfg(not(feature = "kafka"))]
+fn kafka_command_source(_config: &Config) -> anyhow::Result<Arc<dyn CommandSource>> {
+    anyhow::bail!("The kafka command source needs the proxy built with the kafka feature")
+}
@@ -1,31 +1,53 @@
-use std::net::SocketAddr;
+use std::os::fd::AsRawFd;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 
-use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::Semaphore;
+use tokio::sync::{mpsc, Semaphore};
 use tokio::task::JoinSet;
 
 use tokio_util::sync::CancellationToken;
 
 use tracing::{warn, Instrument};
 
+use network::admin::{AdminServer, AdminState};
+use network::commands::CommandConsumer;
+use network::control::{ControlServer, ControlState};
+use network::drain::DrainController;
+use network::events::spool::EventSpool;
+use network::events::{EventEmitter, EventPublisher};
+use network::health::Health;
 use network::server::Server;
+use network::systemd;
+use network::upgrade::{
+    extra_listener_name, notify_ready, InheritedListeners, UpgradeController, ADMIN_LISTENER, CONTROL_LISTENER, STRATUM_LISTENER
+};
+use network::upstream::overrides::PoolOverrides;
+use scheduler::handler::HandlerRegistry;
+use scheduler::handlers::builtin_handlers;
 use scheduler::scheduler::Scheduler;
 use score::job::JobRequest;
 use telemetry::ActivityTelemetry;
-use config::Config;
+use config::reload::ConfigReloader;
+use config::{Config, ConfigSource};
+use crate::logs::watch_logs;
+use crate::sinks::{command_source, event_sink, telemetry_sinks};
+use crate::signals::handle_signals;
+use crate::systemd::notify_systemd;
 use network::api::client::ApiClient;
 
-pub async fn run_app(
-    socket_addr: SocketAddr, semaphore: Arc<Semaphore>,
-    tx_cpu_queue_high: Sender<JobRequest>, tx_cpu_queue_norm: Sender<JobRequest>,
-    rx_cpu_queue_high: Receiver<JobRequest>, rx_cpu_queue_norm: Receiver<JobRequest>,
-    token_shutdown: CancellationToken
-) -> anyhow::Result<()> {
-    let config = Arc::new(Config::new());
-    let config_srv = config.clone();
-    let config_sdr = config.clone();
+/// Runs the proxy with the config from `source` until the shutdown
+pub async fn run_app(source: ConfigSource, handlers: HandlerRegistry, token_shutdown: CancellationToken) -> anyhow::Result<()> {
+    let config = Config::load(&source)?;
+    utils::logs::set_filter(&config.full_log_filter())?;
+
+    // Reloads read the same file and keep the overrides of the command line
+    let reloader = Arc::new(ConfigReloader::new(source, config));
+    let config = reloader.current();
+
+    let semaphore = Arc::new(Semaphore::new(config.scheduler.cpu_permits));
+    let (tx_cpu_queue_high, rx_cpu_queue_high) = mpsc::channel::<JobRequest>(config.scheduler.high_queue_size);
+    let (tx_cpu_queue_norm, rx_cpu_queue_norm) = mpsc::channel::<JobRequest>(config.scheduler.normal_queue_size);
 
     let api_client = Arc::new(ApiClient::new(
         config.api_url.clone(),
@@ -33,30 +55,161 @@ pub async fn run_app(
         10
     ));
 
-    let api_client_scheduler = Arc::clone(&api_client);
+    let overrides = Arc::new(PoolOverrides::new());
+
+    // Custom handlers take precedence over the builtin ones
+    let mut registry = builtin_handlers(Arc::clone(&api_client), Arc::clone(&overrides));
+    registry.extend(handlers);
+    let registry = Arc::new(registry);
 
+    // The connections hand their events to the publisher, without it they're dropped at once
+    let (events, publisher, spool, memory_events) = if config.events.enabled {
+        let (mut sink, memory_events) = event_sink(&config)?;
+        // With the spool the publisher writes to disk, the spool sends to the sink from there
+        let spool = match &config.events.spool {
+            Some(spool_config) => {
+                let spool = Arc::new(EventSpool::open(sink, spool_config)?);
+                sink = Arc::clone(&spool) as _;
+                Some(spool)
+            }
+            None => None
+        };
+        let (publisher, events) = EventPublisher::new(sink, &config.events, &config.kafka.client_id, token_shutdown.clone());
+        (events, Some(publisher), spool, memory_events)
+    } else {
+        (EventEmitter::default(), None, None, None)
+    };
+
+    // After an upgrade the listeners come from the previous process instead of being bound
+    let mut inherited = InheritedListeners::from_env()?;
     let server = Server::new(
-        socket_addr,
+        inherited.tcp_or_bind(STRATUM_LISTENER, config.listen.parse()?).await?,
         tx_cpu_queue_high,
         tx_cpu_queue_norm,
         token_shutdown.clone(),
-        config_srv
+        Arc::new(registry.routes()),
+        reloader.subscribe(),
+        events
     ).await?;
+    let drain = Arc::new(DrainController::new(
+        server.registry(),
+        reloader.subscribe(),
+        server.stop_accepting_token(),
+        token_shutdown.clone()
+    ));
+    let admin_listener = if config.admin.enabled {
+        Some(inherited.tcp_or_bind(ADMIN_LISTENER, config.admin.listen.parse()?).await?)
+    } else {
+        None
+    };
+    let control_listener = if config.control.enabled {
+        match inherited.take_unix(CONTROL_LISTENER)? {
+            Some(listener) => Some(listener),
+            None => Some(ControlServer::bind(Path::new(&config.control.socket_path))?)
+        }
+    } else {
+        None
+    };
+
+    // Sockets nothing has taken yet are for the extra listeners
+    let activated = inherited.take_activated();
+
+    let mut listener_fds = vec![(STRATUM_LISTENER.to_string(), server.listener_fd())];
+    listener_fds.extend(admin_listener.as_ref().map(|listener| (ADMIN_LISTENER.to_string(), listener.as_raw_fd())));
+    listener_fds.extend(control_listener.as_ref().map(|listener| (CONTROL_LISTENER.to_string(), listener.as_raw_fd())));
+    listener_fds.extend(activated.iter().map(|(addr, listener)| (extra_listener_name(addr), listener.as_raw_fd())));
+    let server = server.set_activated(activated);
+    let upgrade = Arc::new(UpgradeController::new(listener_fds, Arc::clone(&drain)));
+
     let scheduler = Scheduler::new(
         rx_cpu_queue_high,
         rx_cpu_queue_norm,
         token_shutdown.clone(),
-        semaphore,
-        config_sdr,
-        api_client_scheduler
+        Arc::clone(&semaphore),
+        Arc::clone(&config),
+        registry
     );
-    let mut telemetry = ActivityTelemetry::new(token_shutdown.clone(), Duration::from_secs(10))
-        .set_jobs_telemetry(true);
+    let health = Arc::new(Health::new(
+        server.registry(), Arc::clone(&drain), Arc::clone(&api_client), scheduler.heartbeat(), server.listening()
+    ));
+    let systemd_notify = (config.systemd.notify && systemd::is_notify_enabled()).then(|| {
+        notify_systemd(
+            Arc::clone(&config), server.listening(), server.registry(), Arc::clone(&drain), scheduler.heartbeat(),
+            token_shutdown.clone()
+        )
+    });
+
+    let (commands, memory_commands) = if config.commands.enabled {
+        let (source, memory_commands) = command_source(&config)?;
+        let consumer = CommandConsumer::new(
+            source, server.registry(), Arc::clone(&overrides), server.bans(), &config.commands, &config.kafka.client_id
+        );
+        (Some(consumer), memory_commands)
+    } else {
+        (None, None)
+    };
+
+    let admin = match admin_listener {
+        Some(listener) => {
+            let mut state = AdminState::new(
+                server.registry(), Arc::clone(&overrides), Arc::clone(&drain), Arc::clone(&upgrade), Arc::clone(&reloader),
+                server.stats(), health
+            );
+            if let Some(memory_events) = memory_events {
+                state = state.set_memory_events(memory_events);
+            }
+            if let Some(memory_commands) = memory_commands {
+                state = state.set_memory_commands(memory_commands);
+            }
+            Some(AdminServer::new(listener, state, token_shutdown.clone())?)
+        }
+        None => None
+    };
+    let control = control_listener.map(|listener| {
+        let state = ControlState::new(
+            server.registry(), overrides, server.bans(), Arc::clone(&upgrade), Arc::clone(&reloader)
+        );
+        ControlServer::new(listener, &config.control.socket_path, state, token_shutdown.clone())
+    });
+    let mut telemetry = ActivityTelemetry::new(
+        token_shutdown.clone(), reloader.subscribe(), Arc::clone(&semaphore), server.registry()
+    ).set_cpu_telemetry(config.telemetry.resources);
+    for sink in telemetry_sinks(&config).await? {
+        telemetry = telemetry.add_sink(sink);
+    }
     let mut set = JoinSet::new();
 
     set.spawn(async move { server.server_run().await }.instrument(tracing::info_span!("server")));
     set.spawn(async move { scheduler.run().await }.instrument(tracing::info_span!("scheduler")));
     set.spawn(async move { telemetry.run_telemetry().await }.instrument(tracing::info_span!("telemetry")));
+    let token_signals = token_shutdown.clone();
+    let log_config = reloader.subscribe();
+    let token_logs = token_shutdown.clone();
+    set.spawn(async move { watch_logs(log_config, token_logs).await }.instrument(tracing::info_span!("logs")));
+    set.spawn(async move { handle_signals(drain, upgrade, reloader, token_signals).await }.instrument(tracing::info_span!("signals")));
+    if let Some(admin) = admin {
+        set.spawn(async move { admin.run().await }.instrument(tracing::info_span!("admin")));
+    }
+    if let Some(control) = control {
+        set.spawn(async move { control.run().await }.instrument(tracing::info_span!("control")));
+    }
+    if let Some(publisher) = publisher {
+        set.spawn(async move { publisher.run().await }.instrument(tracing::info_span!("events")));
+    }
+    if let Some(commands) = commands {
+        let token_commands = token_shutdown.clone();
+        set.spawn(async move { commands.run(token_commands).await }.instrument(tracing::info_span!("commands")));
+    }
+    if let Some(spool) = spool {
+        let token_spool = token_shutdown.clone();
+        set.spawn(async move { spool.run(token_spool).await }.instrument(tracing::info_span!("spool")));
+    }
+    if let Some(systemd_notify) = systemd_notify {
+        set.spawn(systemd_notify.instrument(tracing::info_span!("systemd")));
+    }
+
+    // Every listener is taken, the previous process may drain now
+    notify_ready()?;
 
     tokio::select! {
         _ = token_shutdown.cancelled() => {}
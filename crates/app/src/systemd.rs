@@ -0,0 +1,149 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::select;
+use tokio::sync::watch;
+use tokio::time::{interval, interval_at, Instant};
+use tokio_util::sync::CancellationToken;
+
+use tracing::{info, warn};
+
+use config::Config;
+use network::drain::DrainController;
+use network::health::Heartbeat;
+use network::registry::ConnRegistry;
+use network::systemd;
+use network::upgrade::{is_handed_off, READY_FD_ENV};
+
+// The interval of a branch which never fires, the watchdog is off
+const NO_WATCHDOG: Duration = Duration::from_secs(3600);
+
+/// Keeps systemd informed: READY once every listener is bound, STATUS with the connections, the
+/// watchdog pings while the scheduler loop turns and STOPPING on the shutdown
+pub async fn notify_systemd(
+    config: Arc<Config>, mut listening: watch::Receiver<bool>, registry: Arc<ConnRegistry>,
+    drain: Arc<DrainController>, scheduler: Arc<Heartbeat>, shutdown: CancellationToken
+) -> anyhow::Result<()> {
+    select! {
+        _ = shutdown.cancelled() => return Ok(()),
+        bound = listening.wait_for(|listening| *listening) => { bound?; }
+    }
+
+    // The successor of an upgrade takes the place of the main process, it needs NotifyAccess=all
+    let main_pid = match std::env::var_os(READY_FD_ENV) {
+        Some(_) => format!("MAINPID={}\n", std::process::id()),
+        None => String::new()
+    };
+    send(&format!("{main_pid}READY=1\nSTATUS={}", status(&registry, &drain)));
+    info!("systemd is told the proxy is ready");
+
+    let watchdog = systemd::watchdog_timeout();
+    let status_interval = Duration::from_secs(config.systemd.status_interval_secs);
+    let mut status_tick = interval_at(Instant::now() + status_interval, status_interval);
+    // Twice per timeout, a late ping doesn't get the proxy killed
+    let mut watchdog_tick = interval(watchdog.map_or(NO_WATCHDOG, |timeout| timeout / 2));
+
+    loop {
+        select! {
+            _ = shutdown.cancelled() => break,
+            _ = status_tick.tick() => send(&format!("STATUS={}", status(&registry, &drain))),
+            _ = watchdog_tick.tick(), if watchdog.is_some() => {
+                let age = scheduler.age();
+                if watchdog.is_some_and(|timeout| age < timeout) {
+                    send("WATCHDOG=1");
+                } else {
+                    warn!(age = ?age, "The scheduler loop has stalled, the watchdog isn't pinged");
+                }
+            }
+        }
+    }
+
+    // After an upgrade the successor is the main process, it's not the service which stops
+    if !is_handed_off() {
+        send("STOPPING=1");
+    }
+    Ok(())
+}
+
+fn status(registry: &ConnRegistry, drain: &DrainController) -> String {
+    let state = if drain.is_draining() { "Draining" } else { "Accepting" };
+    format!("{state}, {} connections, {} submits waiting for the pools", registry.len(), registry.pending_submits())
+}
+
+fn send(state: &str) {
+    if let Err(err) = systemd::notify(state) {
+        warn!("Couldn't notify systemd: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UnixDatagram;
+    use tokio::time::timeout;
+
+    use network::events::EventEmitter;
+
+    use super::*;
+
+    async fn received(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 256];
+        let len = timeout(Duration::from_secs(5), socket.recv(&mut buf)).await
+            .expect("systemd is notified")
+            .unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    #[tokio::test]
+    async fn systemd_is_told_ready_pinged_and_stopping() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        // Safety: it's the only test of the crate which changes the environment
+        unsafe {
+            std::env::set_var("NOTIFY_SOCKET", &path);
+            std::env::set_var("WATCHDOG_USEC", "100000");
+            std::env::remove_var("WATCHDOG_PID");
+        }
+
+        let config = Arc::new(Config::default());
+        let (_config_tx, config_rx) = watch::channel(Arc::clone(&config));
+        let registry = Arc::new(ConnRegistry::new(EventEmitter::default()));
+        let drain = Arc::new(DrainController::new(
+            Arc::clone(&registry), config_rx, CancellationToken::new(), CancellationToken::new()
+        ));
+        let scheduler = Arc::new(Heartbeat::new());
+        let (listening_tx, listening) = watch::channel(false);
+        let shutdown = CancellationToken::new();
+        let notifier = tokio::spawn(notify_systemd(
+            config, listening, registry, drain, Arc::clone(&scheduler), shutdown.clone()
+        ));
+
+        // Nothing is sent before the listeners are bound
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        listening_tx.send_replace(true);
+        assert_eq!(received(&systemd).await, "READY=1\nSTATUS=Accepting, 0 connections, 0 submits waiting for the pools");
+
+        // The watchdog is pinged while the scheduler loop turns
+        let turning = tokio::spawn(async move {
+            loop {
+                scheduler.beat();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        assert_eq!(received(&systemd).await, "WATCHDOG=1");
+        turning.abort();
+
+        shutdown.cancel();
+        notifier.await.unwrap().unwrap();
+        let mut last = received(&systemd).await;
+        while last == "WATCHDOG=1" {
+            last = received(&systemd).await;
+        }
+        assert_eq!(last, "STOPPING=1");
+
+        unsafe {
+            std::env::remove_var("NOTIFY_SOCKET");
+            std::env::remove_var("WATCHDOG_USEC");
+        }
+    }
+}
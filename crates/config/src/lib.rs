@@ -1,19 +1,83 @@
 use std::env;
-use std::fs::File;
-use serde_json::from_reader;
-use serde::Deserialize;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 
-#[derive(Debug, Deserialize)]
+use serde::{de, Deserialize, Deserializer, Serialize};
+use thiserror::Error;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+pub mod reload;
+mod sources;
+
+pub use sources::ENV_PREFIX;
+
+pub const DEFAULT_PATH: &str = "./config/config.json";
+
+// Fields which may be read from a file, `<field>_file` next to the field is the path of the file
+const SECRET_FIELDS: [&str; 4] = ["api_key", "database.password", "admin.token", "commands.secret"];
+
+/// Config of the proxy. Every field has a default, a file (JSON, or TOML if it ends with `.toml`)
+/// overrides them, `PROXY_GATES__*` environment variables override the file
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
+    // Main stratum listener
+    pub listen: String,
+    // Address the miners know the proxy by, client.reconnect sends them there
     pub stratum_host: String,
     pub stratum_port: u16,
     pub database: DatabaseConfig,
     pub api_key: String,
-    pub api_url: String
+    pub api_url: String,
+    pub passthrough: PassthroughConfig,
+    // A miner has to subscribe and authorize within this time, otherwise it's disconnected
+    pub handshake_timeout_secs: u64,
+    pub scheduler: SchedulerConfig,
+    pub admin: AdminConfig,
+    pub control: ControlConfig,
+    pub drain: DrainConfig,
+    pub telemetry: TelemetryConfig,
+    pub stats: StatsConfig,
+    pub systemd: SystemdConfig,
+    pub events: EventsConfig,
+    pub commands: CommandsConfig,
+    pub kafka: KafkaConfig,
+    // Filter of the logs in the RUST_LOG syntax, e.g. `info,network=debug`
+    pub log_filter: String,
+    pub logs: LogsConfig,
+    // Stratum is accepted on these addresses too, besides the main one
+    pub listeners: Vec<String>,
+    // IPs the proxy never accepts connections from
+    pub bans: Vec<IpAddr>
+}
+
+/// Where the config comes from: the file, and the values given on the command line, which override
+/// everything else, by the path of the field, e.g. `("listen", "0.0.0.0:3333")`
+#[derive(Debug, Clone)]
+pub struct ConfigSource {
+    pub path: PathBuf,
+    pub overrides: Vec<(String, String)>
+}
+
+/// Something wrong in a config, `key` is the path of the field, e.g. `admin.listen`
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    pub key: String,
+    pub message: String
 }
 
-#[derive(Debug, Deserialize)]
-struct DatabaseConfig {
+/// Every problem of a config, it's reported at once
+#[derive(Debug, Error)]
+pub struct ConfigError {
+    pub path: PathBuf,
+    pub problems: Vec<Problem>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DatabaseConfig {
     pub host: String,
     pub port: u16,
     pub db_name: String,
@@ -21,22 +85,715 @@ struct DatabaseConfig {
     pub connections_limit: u16
 }
 
+/// Forwarding of the methods the proxy has no handler for to the miner's pool
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PassthroughConfig {
+    pub enabled: bool,
+    // Methods which are allowed to be forwarded, all of them if empty. `mining.*` matches by prefix
+    pub allow: Vec<String>,
+    // Methods which are never forwarded, it takes precedence over `allow`
+    pub deny: Vec<String>
+}
+
+/// How the CPU work of the handlers is queued and limited
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SchedulerConfig {
+    // Handlers which run at once
+    pub cpu_permits: usize,
+    pub high_queue_size: usize,
+    pub normal_queue_size: usize,
+    // High priority jobs taken in a row before a normal one gets its turn
+    pub high_budget: u16
+}
+
+/// HTTP API to look at and control the live sessions
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub enabled: bool,
+    pub listen: String,
+    // Bearer token of the API, `api_key` is used if it isn't set
+    pub token: Option<String>
+}
+
+/// Local Unix socket `proxy-gatesctl` talks to
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ControlConfig {
+    pub enabled: bool,
+    pub socket_path: String
+}
+
+/// How the proxy lets the miners go before it exits
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DrainConfig {
+    // client.reconnect is spread over this time, so the miners don't come back all at once
+    pub window_secs: u64,
+    // Longest wait for the pool's answers on the submits sent already
+    pub pending_timeout_secs: u64,
+    // Sibling proxy the miners are sent to, they reconnect to this proxy's address if it isn't set
+    pub reconnect_host: Option<String>,
+    #[serde(deserialize_with = "optional_port")]
+    pub reconnect_port: Option<u16>
+}
+
+/// Where the telemetry goes and the usage it warns about. A warning is logged once the usage crosses
+/// its threshold, 0 turns a threshold off
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    pub interval_secs: u64,
+    // Every sink gets the same series, e.g. `["log", "statsd"]`
+    pub sinks: Vec<SinkKind>,
+    // CPU, memory, fds and threads of the process, tasks of the runtime, CPU permits of the scheduler
+    pub resources: bool,
+    // Of all the cores
+    pub cpu_warn_percent: u64,
+    pub rss_warn_mb: u64,
+    // Of the open files limit
+    pub fds_warn_percent: u64,
+    // Of the CPU permits of the scheduler
+    pub permits_warn_percent: u64,
+    // host:port of the StatsD server, for the statsd sink
+    pub statsd_address: String,
+    // For the kafka sink, the brokers are in the kafka section
+    pub kafka_topic: String
+}
+
+/// Telemetry sinks, `prometheus` serves `/metrics` on the admin API, the others get the series on
+/// every tick of the telemetry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkKind {
+    Log,
+    Prometheus,
+    Statsd,
+    Kafka
+}
+
+/// Hashrate and shares of the workers and subaccounts, kept in memory
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatsConfig {
+    // Workers tracked at most, the same number of subaccounts. A new one is skipped while it's full
+    // and nobody has been idle for a day
+    pub max_workers: usize
+}
+
+/// sd_notify of a Type=notify service: READY once the listeners are bound, STATUS with the
+/// connections and the watchdog pings while the scheduler is turning
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SystemdConfig {
+    // Nothing is sent without NOTIFY_SOCKET anyway
+    pub notify: bool,
+    pub status_interval_secs: u64
+}
+
+/// Where the logs go. The levels of `modules` are added to `log_filter`, both may change on a reload
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogsConfig {
+    // pretty or json, --log-format overrides it
+    pub format: LogFormat,
+    // Level by module, e.g. `["network::connection=warn", "scheduler=debug"]`
+    pub modules: Vec<String>,
+    pub stdout: bool,
+    // Rotated files, besides stdout
+    pub file: Option<LogFileConfig>,
+    // Lines of one log statement per interval, the rest are dropped and counted. Errors are never
+    // dropped, 0 turns it off
+    pub rate_limit: u64,
+    pub rate_limit_interval_secs: u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogFileConfig {
+    pub directory: String,
+    // Files are named `<prefix>.<date>`
+    pub prefix: String,
+    pub rotation: LogRotation,
+    // The oldest files beyond it are deleted, 0 keeps every file
+    pub max_files: usize
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never
+}
+
+/// Events of the shares and the workers for the backend, e.g. the billing. They're buffered and sent
+/// to the sink in batches
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EventsConfig {
+    pub enabled: bool,
+    pub sink: EventSinkKind,
+    // For the kafka sink, the brokers are in the kafka section
+    pub topic: String,
+    // Events waiting for the sink at most, new ones are dropped while it's full
+    pub buffer_size: usize,
+    pub batch_size: usize,
+    // Longest wait for a batch to fill up
+    pub linger_ms: u64,
+    // Last events the memory sink keeps, `/api/v1/events` of the admin API shows them
+    pub memory_capacity: usize,
+    // Hashes every share to find the ones which meet the network's target
+    pub block_candidates: bool,
+    // The batches are written to disk before the sink gets them, they outlive an outage of the sink
+    // and a restart of the proxy
+    pub spool: Option<EventSpoolConfig>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EventSpoolConfig {
+    pub directory: String,
+    // Size of the spool's files at most, `drop_policy` makes room beyond it
+    pub max_bytes: u64,
+    // A new file is started once one is this big, the files the sink has taken are deleted
+    pub segment_bytes: u64,
+    pub drop_policy: SpoolDropPolicy
+}
+
+/// Which events go when the spool is full: the oldest ones, or the new ones aren't spooled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpoolDropPolicy {
+    Oldest,
+    Newest
+}
+
+/// `memory` keeps the events in the proxy, to try the events out without a broker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventSinkKind {
+    Kafka,
+    Memory
+}
+
+/// Commands of the backend to every proxy: kicks, bans, pool targets, messages and difficulties.
+/// Each one is signed with the shared secret, the outcome is sent back on the reply topic
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CommandsConfig {
+    pub enabled: bool,
+    pub source: CommandSourceKind,
+    pub topic: String,
+    pub reply_topic: String,
+    // Key of the HMAC-SHA256 signatures of the commands
+    pub secret: String,
+    // Older commands are rejected, and a command is taken once within it. The ids taken aren't
+    // stored, a restarted proxy takes a command again if it's sent again within max_age
+    pub max_age_secs: u64
+}
+
+/// `memory` takes the commands from `/api/v1/commands` of the admin API, to try them without a broker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandSourceKind {
+    Kafka,
+    Memory
+}
+
+/// Kafka cluster the proxy publishes to
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KafkaConfig {
+    // Comma separated host:port list
+    pub brokers: String,
+    pub client_id: String
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: "127.0.0.1:5555".to_string(),
+            stratum_host: "localhost".to_string(),
+            stratum_port: 5555,
+            database: DatabaseConfig::default(),
+            api_key: String::new(),
+            api_url: "http://127.0.0.1:8080".to_string(),
+            passthrough: PassthroughConfig::default(),
+            handshake_timeout_secs: 30,
+            scheduler: SchedulerConfig::default(),
+            admin: AdminConfig::default(),
+            control: ControlConfig::default(),
+            drain: DrainConfig::default(),
+            telemetry: TelemetryConfig::default(),
+            stats: StatsConfig::default(),
+            systemd: SystemdConfig::default(),
+            events: EventsConfig::default(),
+            commands: CommandsConfig::default(),
+            kafka: KafkaConfig::default(),
+            log_filter: "info".to_string(),
+            logs: LogsConfig::default(),
+            listeners: Vec::new(),
+            bans: Vec::new()
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 5432,
+            db_name: "proxy_gates".to_string(),
+            password: String::new(),
+            connections_limit: 10
+        }
+    }
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            cpu_permits: 100,
+            high_queue_size: 256,
+            normal_queue_size: 256,
+            high_budget: 32
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "127.0.0.1:8081".to_string(),
+            token: None
+        }
+    }
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            socket_path: "/tmp/proxy-gates.sock".to_string()
+        }
+    }
+}
+
+impl Default for DrainConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 30,
+            pending_timeout_secs: 10,
+            reconnect_host: None,
+            reconnect_port: None
+        }
+    }
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 10,
+            sinks: vec![SinkKind::Log, SinkKind::Prometheus],
+            resources: true,
+            cpu_warn_percent: 90,
+            rss_warn_mb: 0,
+            fds_warn_percent: 80,
+            permits_warn_percent: 90,
+            statsd_address: "127.0.0.1:8125".to_string(),
+            kafka_topic: "proxy-gates.telemetry".to_string()
+        }
+    }
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            max_workers: 100_000
+        }
+    }
+}
+
+impl Default for SystemdConfig {
+    fn default() -> Self {
+        Self {
+            notify: true,
+            status_interval_secs: 10
+        }
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sink: EventSinkKind::Kafka,
+            topic: "proxy-gates.events".to_string(),
+            buffer_size: 100_000,
+            batch_size: 500,
+            linger_ms: 100,
+            memory_capacity: 10_000,
+            block_candidates: false,
+            spool: None
+        }
+    }
+}
+
+impl Default for EventSpoolConfig {
+    fn default() -> Self {
+        Self {
+            directory: "/var/lib/proxy-gates/spool".to_string(),
+            max_bytes: 1024 * 1024 * 1024,
+            segment_bytes: 64 * 1024 * 1024,
+            drop_policy: SpoolDropPolicy::Oldest
+        }
+    }
+}
+
+impl Default for LogsConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Pretty,
+            modules: Vec::new(),
+            stdout: true,
+            file: None,
+            rate_limit: 100,
+            rate_limit_interval_secs: 1
+        }
+    }
+}
+
+impl Default for LogFileConfig {
+    fn default() -> Self {
+        Self {
+            directory: "/var/log/proxy-gates".to_string(),
+            prefix: "proxy-gates.log".to_string(),
+            rotation: LogRotation::Daily,
+            max_files: 7
+        }
+    }
+}
+
+impl Default for CommandsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            source: CommandSourceKind::Kafka,
+            topic: "proxy-gates.commands".to_string(),
+            reply_topic: "proxy-gates.command-replies".to_string(),
+            secret: String::new(),
+            max_age_secs: 300
+        }
+    }
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        Self {
+            brokers: String::new(),
+            client_id: "proxy-gates".to_string()
+        }
+    }
+}
+
+impl TelemetryConfig {
+    pub fn has_sink(&self, sink: SinkKind) -> bool {
+        self.sinks.contains(&sink)
+    }
+}
+
+impl AdminConfig {
+    /// Token the requests to the API have to carry
+    pub fn token<'a>(&'a self, api_key: &'a str) -> &'a str {
+        self.token.as_deref().unwrap_or(api_key)
+    }
+}
+
+impl ConfigSource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), overrides: Vec::new() }
+    }
+
+    pub fn set(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.overrides.push((key.into(), value.into()));
+        self
+    }
+}
+
 impl Config {
-    pub fn new() -> Config {
-        let default_path = "./config/config.json";
-        let args: Vec<String> = env::args().collect();
+    /// The config with every layer applied and validated
+    pub fn load(source: &ConfigSource) -> Result<Config, ConfigError> {
+        let config = Config::read(source)?;
+
+        let problems = config.validate();
+        if !problems.is_empty() {
+            return Err(ConfigError { path: source.path.clone(), problems });
+        }
+
+        Ok(config)
+    }
+
+    /// The defaults, the file, the environment, the overrides and the secret files on top of each
+    /// other, not validated
+    pub fn read(source: &ConfigSource) -> Result<Config, ConfigError> {
+        sources::read(source, env::vars()).map_err(|problems| ConfigError { path: source.path.clone(), problems })
+    }
+
+    /// The config as JSON with the values of the secret fields hidden
+    pub fn redacted(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        redact(&mut value);
+        value
+    }
 
-        let config_path = if args.len() < 2 {
-            default_path
-        } else {
-            args[1].as_str()
-        };
+    /// Everything wrong in the config, it's good to use if there is nothing
+    pub fn validate(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
 
-        let file = File::open(config_path).expect("The file couldn't be opened");
+        if self.listen.parse::<SocketAddr>().is_err() {
+            problems.push(Problem::new("listen", "has to be an ip:port address"));
+        }
+        if self.stratum_host.is_empty() {
+            problems.push(Problem::new("stratum_host", "can't be empty"));
+        }
+        if self.stratum_port == 0 {
+            problems.push(Problem::new("stratum_port", "has to be a port, not 0"));
+        }
+        if self.api_key.is_empty() {
+            problems.push(Problem::new("api_key", "is required, set it or api_key_file"));
+        }
+        if !self.api_url.starts_with("http://") && !self.api_url.starts_with("https://") {
+            problems.push(Problem::new("api_url", "has to be an http:// or https:// URL"));
+        }
+        if self.database.connections_limit == 0 {
+            problems.push(Problem::new("database.connections_limit", "has to be more than 0"));
+        }
+        if self.handshake_timeout_secs == 0 {
+            problems.push(Problem::new("handshake_timeout_secs", "has to be more than 0"));
+        }
+        if self.scheduler.cpu_permits == 0 {
+            problems.push(Problem::new("scheduler.cpu_permits", "has to be more than 0"));
+        }
+        if self.scheduler.high_queue_size == 0 {
+            problems.push(Problem::new("scheduler.high_queue_size", "has to be more than 0"));
+        }
+        if self.scheduler.normal_queue_size == 0 {
+            problems.push(Problem::new("scheduler.normal_queue_size", "has to be more than 0"));
+        }
+        if self.scheduler.high_budget == 0 {
+            problems.push(Problem::new("scheduler.high_budget", "has to be more than 0"));
+        }
+        if self.telemetry.interval_secs == 0 {
+            problems.push(Problem::new("telemetry.interval_secs", "has to be more than 0"));
+        }
+        for (key, percent) in [
+            ("telemetry.cpu_warn_percent", self.telemetry.cpu_warn_percent),
+            ("telemetry.fds_warn_percent", self.telemetry.fds_warn_percent),
+            ("telemetry.permits_warn_percent", self.telemetry.permits_warn_percent)
+        ] {
+            if percent > 100 {
+                problems.push(Problem::new(key, "is a percent, it can't be more than 100"));
+            }
+        }
+        if self.telemetry.has_sink(SinkKind::Statsd) && self.telemetry.statsd_address.is_empty() {
+            problems.push(Problem::new("telemetry.statsd_address", "the statsd sink needs the address of the server"));
+        }
+        if self.telemetry.has_sink(SinkKind::Kafka) {
+            if self.kafka.brokers.is_empty() {
+                problems.push(Problem::new("kafka.brokers", "the kafka sink needs the brokers"));
+            }
+            if self.telemetry.kafka_topic.is_empty() {
+                problems.push(Problem::new("telemetry.kafka_topic", "the kafka sink needs a topic"));
+            }
+        }
+        if self.stats.max_workers == 0 {
+            problems.push(Problem::new("stats.max_workers", "has to be more than 0"));
+        }
+        if self.events.enabled {
+            for (key, value) in [
+                ("events.buffer_size", self.events.buffer_size),
+                ("events.batch_size", self.events.batch_size),
+                ("events.memory_capacity", self.events.memory_capacity)
+            ] {
+                if value == 0 {
+                    problems.push(Problem::new(key, "has to be more than 0"));
+                }
+            }
+            if self.events.sink == EventSinkKind::Kafka {
+                if self.kafka.brokers.is_empty() {
+                    problems.push(Problem::new("kafka.brokers", "the kafka event sink needs the brokers"));
+                }
+                if self.events.topic.is_empty() {
+                    problems.push(Problem::new("events.topic", "the kafka event sink needs a topic"));
+                }
+            }
+            if let Some(spool) = &self.events.spool {
+                if spool.directory.is_empty() {
+                    problems.push(Problem::new("events.spool.directory", "can't be empty"));
+                }
+                if spool.segment_bytes == 0 {
+                    problems.push(Problem::new("events.spool.segment_bytes", "has to be more than 0"));
+                }
+                if spool.max_bytes < spool.segment_bytes {
+                    problems.push(Problem::new("events.spool.max_bytes", "can't be less than segment_bytes"));
+                }
+            }
+        }
+        if self.commands.enabled {
+            if self.commands.secret.is_empty() {
+                problems.push(Problem::new("commands.secret", "the commands are checked with it, it can't be empty"));
+            }
+            if self.commands.max_age_secs == 0 {
+                problems.push(Problem::new("commands.max_age_secs", "has to be more than 0"));
+            }
+            if self.commands.source == CommandSourceKind::Kafka {
+                if self.kafka.brokers.is_empty() {
+                    problems.push(Problem::new("kafka.brokers", "the kafka command source needs the brokers"));
+                }
+                for (key, topic) in [("commands.topic", &self.commands.topic), ("commands.reply_topic", &self.commands.reply_topic)] {
+                    if topic.is_empty() {
+                        problems.push(Problem::new(key, "the kafka command source needs it"));
+                    }
+                }
+            }
+        }
+        if self.systemd.status_interval_secs == 0 {
+            problems.push(Problem::new("systemd.status_interval_secs", "has to be more than 0"));
+        }
+        if let Err(err) = EnvFilter::try_new(&self.log_filter) {
+            problems.push(Problem::new("log_filter", format!("isn't a filter: {err}")));
+        }
+        for (i, directive) in self.logs.modules.iter().enumerate() {
+            let is_directive = directive.split_once('=').is_some_and(|(module, level)| {
+                !module.is_empty() && !module.contains(',') && level.parse::<LevelFilter>().is_ok()
+            });
+            if !is_directive {
+                problems.push(Problem::new(format!("logs.modules[{i}]"), "has to be module=level, e.g. network=debug"));
+            }
+        }
+        if !self.logs.stdout && self.logs.file.is_none() {
+            problems.push(Problem::new("logs.stdout", "the logs have to go somewhere, set logs.file or logs.stdout"));
+        }
+        if self.logs.rate_limit > 0 && self.logs.rate_limit_interval_secs == 0 {
+            problems.push(Problem::new("logs.rate_limit_interval_secs", "has to be more than 0"));
+        }
+        if let Some(file) = &self.logs.file {
+            if file.directory.is_empty() {
+                problems.push(Problem::new("logs.file.directory", "can't be empty"));
+            }
+            if file.prefix.is_empty() {
+                problems.push(Problem::new("logs.file.prefix", "can't be empty"));
+            }
+        }
+        if self.admin.enabled {
+            if self.admin.listen.parse::<SocketAddr>().is_err() {
+                problems.push(Problem::new("admin.listen", "has to be an ip:port address"));
+            }
+            if self.admin.token(&self.api_key).is_empty() {
+                problems.push(Problem::new("admin.token", "the admin API needs a token, set it or api_key"));
+            }
+        }
+        if self.control.enabled && self.control.socket_path.is_empty() {
+            problems.push(Problem::new("control.socket_path", "can't be empty"));
+        }
 
-        let reader = std::io::BufReader::new(file);
-        let config: Config = from_reader(reader).expect("Couldn't read JSON");
+        let mut listeners = Vec::new();
+        for (i, listener) in self.listeners.iter().enumerate() {
+            match listener.parse::<SocketAddr>() {
+                Ok(addr) if listeners.contains(&addr) || self.listen.parse::<SocketAddr>().ok() == Some(addr) => {
+                    problems.push(Problem::new(format!("listeners[{i}]"), format!("{addr} is listened on already")));
+                }
+                Ok(addr) => listeners.push(addr),
+                Err(_) => problems.push(Problem::new(format!("listeners[{i}]"), "has to be an ip:port address"))
+            }
+        }
 
-        config
+        problems
     }
-}
\ No newline at end of file
+
+    /// Addresses of `listeners`, the ones which aren't addresses are skipped
+    pub fn listener_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners.iter().filter_map(|listener| listener.parse().ok()).collect()
+    }
+
+    /// `log_filter` with the levels of `logs.modules` after it, they take precedence
+    pub fn full_log_filter(&self) -> String {
+        let mut filter = self.log_filter.clone();
+        for directive in &self.logs.modules {
+            filter.push(',');
+            filter.push_str(directive);
+        }
+
+        filter
+    }
+}
+
+/// Whether the field at `key` holds a secret, e.g. `admin.token`
+pub fn is_secret(key: &str) -> bool {
+    let field = key.rsplit('.').next().unwrap_or(key);
+    SECRET_FIELDS.iter().any(|secret| secret.rsplit('.').next() == Some(field))
+}
+
+fn redact(value: &mut serde_json::Value) {
+    if let serde_json::Value::Object(fields) = value {
+        for (key, field) in fields.iter_mut() {
+            match field {
+                serde_json::Value::Object(_) => redact(field),
+                serde_json::Value::String(secret) if is_secret(key) && !secret.is_empty() => {
+                    *field = serde_json::Value::String("<redacted>".to_string());
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// A port from the environment comes as a string
+fn optional_port<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u16>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Port {
+        Number(u16),
+        Text(String)
+    }
+
+    match Option::<Port>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Port::Number(port)) => Ok(Some(port)),
+        Some(Port::Text(text)) => text.parse().map(Some).map_err(|_| de::Error::custom(format!("{text:?} isn't a port")))
+    }
+}
+
+impl Problem {
+    pub fn new(key: impl Into<String>, message: impl Into<String>) -> Self {
+        Self { key: key.into(), message: message.into() }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is invalid:", self.path.display())?;
+        for problem in &self.problems {
+            write!(f, "\n  {problem}")?;
+        }
+
+        Ok(())
+    }
+}
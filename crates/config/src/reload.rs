@@ -0,0 +1,140 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::Serialize;
+use serde_json::Value;
+use thiserror::Error;
+use tokio::sync::watch;
+
+use crate::{is_secret, Config, ConfigError, ConfigSource, Problem};
+
+// Fields which are read once at the start, a change of them takes effect after a restart
+const RESTART_KEYS: [&str; 22] = [
+    "listen", "stratum_host", "stratum_port", "database", "api_key", "api_url", "scheduler", "admin", "control", "telemetry.interval_secs", "telemetry.resources", "telemetry.sinks",
+    "telemetry.statsd_address", "telemetry.kafka_topic", "stats", "systemd", "events", "commands", "kafka",
+    "logs.format", "logs.stdout", "logs.file"
+];
+
+/// Keeps the running config and publishes a new one to the components when the file is reloaded
+pub struct ConfigReloader {
+    source: ConfigSource,
+    tx: watch::Sender<Arc<Config>>
+}
+
+/// What a reload has changed
+#[derive(Debug, Clone, Serialize)]
+pub struct ReloadSummary {
+    pub path: PathBuf,
+    pub changes: Vec<String>,
+    // Changed fields which take effect after a restart only
+    pub restart_required: Vec<String>
+}
+
+#[derive(Debug, Error)]
+pub enum ReloadError {
+    #[error("{0}")]
+    Read(ConfigError),
+    #[error("{path} is rejected, the running config is kept\n{diff}")]
+    Invalid {
+        path: PathBuf,
+        problems: Vec<Problem>,
+        diff: String
+    }
+}
+
+impl ConfigReloader {
+    pub fn new(source: ConfigSource, config: Config) -> Self {
+        let (tx, _) = watch::channel(Arc::new(config));
+        Self { source, tx }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Config>> {
+        self.tx.subscribe()
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.tx.borrow().clone()
+    }
+
+    /// Reads the file again and publishes it if it's valid, otherwise the running config stays
+    pub fn reload(&self) -> Result<ReloadSummary, ReloadError> {
+        let new = Config::read(&self.source).map_err(ReloadError::Read)?;
+        let current = self.current();
+
+        let old_fields = flatten(&current);
+        let new_fields = flatten(&new);
+        let changed: BTreeSet<&String> = old_fields.keys()
+            .chain(new_fields.keys())
+            .filter(|key| old_fields.get(*key) != new_fields.get(*key))
+            .collect();
+
+        let problems = new.validate();
+        if !problems.is_empty() {
+            let mut diff = String::new();
+            for key in &changed {
+                if let Some(old) = old_fields.get(*key) {
+                    let _ = writeln!(diff, "- {key} = {}", shown(key, old));
+                }
+                if let Some(new) = new_fields.get(*key) {
+                    let _ = writeln!(diff, "+ {key} = {}", shown(key, new));
+                }
+            }
+            for problem in &problems {
+                let _ = writeln!(diff, "! {problem}");
+            }
+
+            return Err(ReloadError::Invalid { path: self.source.path.clone(), problems, diff });
+        }
+
+        let changes = changed.iter()
+            .map(|key| match (old_fields.get(*key), new_fields.get(*key)) {
+                (Some(old), Some(new)) => format!("{key}: {} -> {}", shown(key, old), shown(key, new)),
+                (None, Some(new)) => format!("{key}: {}", shown(key, new)),
+                (Some(old), None) => format!("{key}: {} -> unset", shown(key, old)),
+                (None, None) => key.to_string()
+            })
+            .collect();
+        let restart_required = changed.iter()
+            .filter(|key| RESTART_KEYS.iter().any(|restart| **key == restart || key.starts_with(&format!("{restart}."))))
+            .map(|key| key.to_string())
+            .collect();
+
+        self.tx.send_replace(Arc::new(new));
+
+        Ok(ReloadSummary { path: self.source.path.clone(), changes, restart_required })
+    }
+}
+
+/// Fields of the config by their paths, e.g. `drain.window_secs`. Arrays are single values
+fn flatten(config: &Config) -> BTreeMap<String, Value> {
+    let mut fields = BTreeMap::new();
+    if let Ok(value) = serde_json::to_value(config) {
+        flatten_into(String::new(), value, &mut fields);
+    }
+
+    fields
+}
+
+fn flatten_into(prefix: String, value: Value, fields: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                let path = if prefix.is_empty() { key } else { format!("{prefix}.{key}") };
+                flatten_into(path, value, fields);
+            }
+        }
+        value => {
+            fields.insert(prefix, value);
+        }
+    }
+}
+
+fn shown(key: &str, value: &Value) -> String {
+    if is_secret(key) && !value.is_null() {
+        return "<redacted>".to_string();
+    }
+
+    value.to_string()
+}
@@ -0,0 +1,319 @@
+use std::path::Path;
+
+use serde_json::{Map, Value};
+
+use crate::{Config, ConfigSource, EventSpoolConfig, LogFileConfig, Problem, SECRET_FIELDS};
+
+/// Prefix of the environment variables which override the config, `__` separates the sections,
+/// e.g. `PROXY_GATES__ADMIN__TOKEN`
+pub const ENV_PREFIX: &str = "PROXY_GATES__";
+
+/// Puts the layers on top of each other and collects every problem on the way
+pub(crate) fn read(source: &ConfigSource, vars: impl Iterator<Item = (String, String)>) -> Result<Config, Vec<Problem>> {
+    let mut problems = Vec::new();
+
+    let defaults = serde_json::to_value(Config::default()).unwrap_or_else(|_| Value::Object(Map::new()));
+    let mut merged = defaults.clone();
+    let fields = with_optional_sections(defaults.clone());
+
+    match read_file(&source.path) {
+        Ok(file) => merge(&mut merged, file),
+        Err(problem) => problems.push(problem)
+    }
+
+    let mut vars: Vec<(String, String)> = vars.filter(|(name, _)| name.starts_with(ENV_PREFIX)).collect();
+    vars.sort();
+    for (name, value) in vars {
+        let keys: Vec<String> = name[ENV_PREFIX.len()..].split("__").map(|key| key.to_lowercase()).collect();
+        if let Err(problem) = set(&mut merged, &fields, &keys, &name, value) {
+            problems.push(problem);
+        }
+    }
+
+    // The command line wins over the environment
+    for (key, value) in &source.overrides {
+        let keys: Vec<String> = key.split('.').map(str::to_string).collect();
+        if let Err(problem) = set(&mut merged, &fields, &keys, key, value.clone()) {
+            problems.push(problem);
+        }
+    }
+
+    read_secrets(&mut merged, &mut problems);
+    check(&merged, &defaults, "", &mut problems);
+
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+
+    serde_json::from_value(merged).map_err(|err| vec![Problem::new("config", err.to_string())])
+}
+
+/// JSON, or TOML if the file ends with `.toml`
+fn read_file(path: &Path) -> Result<Value, Problem> {
+    let file = path.display().to_string();
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| Problem::new(&file, format!("couldn't be read: {err}")))?;
+
+    let value = if path.extension().is_some_and(|ext| ext == "toml") {
+        toml::from_str::<Value>(&text).map_err(|err| Problem::new(&file, format!("isn't TOML: {}", err.message())))?
+    } else {
+        serde_json::from_str::<Value>(&text).map_err(|err| Problem::new(&file, format!("isn't JSON: {err}")))?
+    };
+
+    match value {
+        Value::Object(_) => Ok(value),
+        _ => Err(Problem::new(file, "has to be an object of the fields"))
+    }
+}
+
+/// The defaults with the sections which are off (null) filled with the defaults of their fields.
+/// Setting a field of such a section from the environment or the command line turns it on
+fn with_optional_sections(mut defaults: Value) -> Value {
+    defaults["events"]["spool"] = serde_json::to_value(EventSpoolConfig::default()).unwrap_or_default();
+    defaults["logs"]["file"] = serde_json::to_value(LogFileConfig::default()).unwrap_or_default();
+    defaults
+}
+
+fn merge(base: &mut Value, layer: Value) {
+    match (base, layer) {
+        (Value::Object(base), Value::Object(layer)) => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    Some(existing) if existing.is_object() && value.is_object() => merge(existing, value),
+                    _ => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, layer) => *base = layer
+    }
+}
+
+/// Sets the field at `keys` from a raw value, e.g. `PROXY_GATES__DRAIN__WINDOW_SECS=10` sets
+/// `drain.window_secs`. Values of the number, bool and list fields are JSON, a list may be comma
+/// separated as well. Everything else is taken as a string. `name` is where the value comes from
+fn set(merged: &mut Value, defaults: &Value, keys: &[String], name: &str, raw: String) -> Result<(), Problem> {
+    let (field, parents) = keys.split_last().ok_or_else(|| Problem::new(name, "names no field"))?;
+
+    let default = parents.iter().try_fold(defaults, |value, key| value.get(key));
+    let is_secret_file = field.strip_suffix("_file")
+        .is_some_and(|secret| SECRET_FIELDS.contains(&[parents, &[secret.to_string()]].concat().join(".").as_str()));
+    let default_field = default.and_then(|default| default.get(field));
+    if default_field.is_none() && !is_secret_file {
+        return Err(Problem::new(name, "isn't a config field"));
+    }
+
+    let value = match default_field {
+        Some(Value::String(_) | Value::Null) | None => Value::String(raw),
+        Some(Value::Array(_)) => match serde_json::from_str::<Value>(&raw) {
+            Ok(value @ Value::Array(_)) => value,
+            _ => raw.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| Value::String(item.to_string()))
+                .collect()
+        },
+        Some(_) => serde_json::from_str(&raw).unwrap_or(Value::String(raw))
+    };
+
+    let mut target = merged;
+    for (depth, key) in parents.iter().enumerate() {
+        let section = target.as_object_mut()
+            .map(|object| object.entry(key.clone()).or_insert(Value::Null))
+            .ok_or_else(|| Problem::new(name, format!("{key} isn't a section")))?;
+
+        // A section which is off starts from its defaults
+        if section.is_null() {
+            *section = parents[..=depth].iter()
+                .try_fold(defaults, |value, key| value.get(key))
+                .filter(|value| value.is_object())
+                .cloned()
+                .unwrap_or_else(|| Value::Object(Map::new()));
+        }
+        target = section;
+    }
+    target.as_object_mut()
+        .ok_or_else(|| Problem::new(name, "the section isn't an object"))?
+        .insert(field.clone(), value);
+
+    Ok(())
+}
+
+/// `api_key_file` replaces `api_key` with the content of the file, the same for every secret field
+fn read_secrets(merged: &mut Value, problems: &mut Vec<Problem>) {
+    for secret in SECRET_FIELDS {
+        let mut keys: Vec<&str> = secret.split('.').collect();
+        let Some(field) = keys.pop() else { continue };
+
+        let Some(section) = keys.iter().try_fold(&mut *merged, |value, key| value.get_mut(*key)).and_then(Value::as_object_mut) else {
+            continue;
+        };
+
+        let file_key = format!("{field}_file");
+        match section.remove(&file_key) {
+            None | Some(Value::Null) => {}
+            Some(Value::String(path)) => match std::fs::read_to_string(&path) {
+                Ok(content) => {
+                    section.insert(field.to_string(), Value::String(content.trim_end_matches(['\r', '\n']).to_string()));
+                }
+                Err(err) => problems.push(Problem::new(format!("{secret}_file"), format!("{path} couldn't be read: {err}")))
+            },
+            Some(_) => problems.push(Problem::new(format!("{secret}_file"), "has to be a path"))
+        }
+    }
+}
+
+/// Unknown fields and values of a wrong kind, all of them
+fn check(value: &Value, default: &Value, prefix: &str, problems: &mut Vec<Problem>) {
+    let (Value::Object(object), Value::Object(defaults)) = (value, default) else {
+        return;
+    };
+
+    for (key, value) in object {
+        let path = if prefix.is_empty() { key.clone() } else { format!("{prefix}.{key}") };
+        let Some(default) = defaults.get(key) else {
+            problems.push(Problem::new(path, "isn't a config field"));
+            continue;
+        };
+
+        match (default, value) {
+            (Value::Object(_), Value::Object(_)) => check(value, default, &path, problems),
+            // Optional fields, their kind is checked when the config is built
+            (Value::Null, _) => {}
+            (Value::Object(_), _) => problems.push(Problem::new(path, "has to be a section")),
+            (Value::String(_), Value::String(_))
+            | (Value::Number(_), Value::Number(_))
+            | (Value::Bool(_), Value::Bool(_))
+            | (Value::Array(_), Value::Array(_)) => {}
+            (default, _) => problems.push(Problem::new(path, format!("has to be {}", kind(default))))
+        }
+    }
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => "a string",
+        Value::Number(_) => "a number",
+        Value::Bool(_) => "true or false",
+        Value::Array(_) => "a list",
+        Value::Object(_) => "a section",
+        Value::Null => "null"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    fn file(extension: &str, text: &str) -> NamedTempFile {
+        let mut file = tempfile::Builder::new().suffix(extension).tempfile().unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+        file
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect::<Vec<_>>().into_iter()
+    }
+
+    fn keys(problems: &[Problem]) -> Vec<&str> {
+        problems.iter().map(|problem| problem.key.as_str()).collect()
+    }
+
+    #[test]
+    fn layers_override_each_other_in_order() {
+        let config = file(".json", r#"{"api_key": "file", "api_url": "http://file", "stratum_port": 4000, "listen": "0.0.0.0:4000"}"#);
+        let secret = file("", "secret\n");
+        let secret_path = secret.path().display().to_string();
+
+        let source = ConfigSource::new(config.path()).set("listen", "0.0.0.0:5000");
+        let config = read(&source, vars(&[
+            ("PROXY_GATES__API_KEY", "env"),
+            ("PROXY_GATES__API_KEY_FILE", &secret_path),
+            ("PROXY_GATES__API_URL", "http://env"),
+            ("PROXY_GATES__LISTEN", "0.0.0.0:6000"),
+            ("OTHER__API_URL", "http://other")
+        ])).unwrap();
+
+        let defaults = Config::default();
+        assert_eq!(config.stratum_host, defaults.stratum_host);
+        assert_eq!(config.stratum_port, 4000);
+        assert_eq!(config.api_url, "http://env");
+        assert_eq!(config.listen, "0.0.0.0:5000");
+        assert_eq!(config.api_key, "secret");
+    }
+
+    #[test]
+    fn toml_file_is_read() {
+        let config = file(".toml", "stratum_port = 4000\n[drain]\nwindow_secs = 7\n");
+        let config = read(&ConfigSource::new(config.path()), vars(&[])).unwrap();
+
+        assert_eq!(config.stratum_port, 4000);
+        assert_eq!(config.drain.window_secs, 7);
+    }
+
+    #[test]
+    fn env_turns_an_optional_section_on() {
+        let config = file(".json", "{}");
+        let config = read(&ConfigSource::new(config.path()), vars(&[
+            ("PROXY_GATES__EVENTS__SPOOL__DIRECTORY", "/tmp/spool"),
+            ("PROXY_GATES__LOGS__FILE__DIRECTORY", "/tmp/logs")
+        ])).unwrap();
+
+        let spool = config.events.spool.unwrap();
+        assert_eq!(spool.directory, "/tmp/spool");
+        assert_eq!(spool.segment_bytes, EventSpoolConfig::default().segment_bytes);
+        let logs = config.logs.file.unwrap();
+        assert_eq!(logs.directory, "/tmp/logs");
+        assert_eq!(logs.prefix, LogFileConfig::default().prefix);
+    }
+
+    #[test]
+    fn env_adds_to_a_section_of_the_file() {
+        let config = file(".json", r#"{"events": {"spool": {"directory": "/tmp/spool"}}}"#);
+        let config = read(&ConfigSource::new(config.path()), vars(&[("PROXY_GATES__EVENTS__SPOOL__MAX_BYTES", "100")])).unwrap();
+
+        let spool = config.events.spool.unwrap();
+        assert_eq!((spool.directory.as_str(), spool.max_bytes), ("/tmp/spool", 100));
+    }
+
+    #[test]
+    fn every_problem_of_the_sources_is_reported() {
+        let config = file(".json", r#"{"unknown": 1, "stratum_port": "port", "drain": 5, "admin": {"enabled": "yes"}}"#);
+        let problems = read(&ConfigSource::new(config.path()), vars(&[
+            ("PROXY_GATES__NOT_A_FIELD", "1"),
+            ("PROXY_GATES__EVENTS__NOPE__DIRECTORY", "x"),
+            ("PROXY_GATES__DATABASE__PASSWORD_FILE", "/nonexistent/secret")
+        ])).unwrap_err();
+
+        let keys = keys(&problems);
+        for key in [
+            "unknown", "stratum_port", "drain", "admin.enabled", "PROXY_GATES__NOT_A_FIELD",
+            "PROXY_GATES__EVENTS__NOPE__DIRECTORY", "database.password_file"
+        ] {
+            assert!(keys.contains(&key), "{key} isn't in {keys:?}");
+        }
+    }
+
+    #[test]
+    fn missing_file_is_a_problem() {
+        let problems = read(&ConfigSource::new("/nonexistent/config.json"), vars(&[])).unwrap_err();
+        assert_eq!(keys(&problems), ["/nonexistent/config.json"]);
+    }
+
+    #[test]
+    fn validation_reports_every_problem() {
+        let config = file(".json", r#"{"listen": "nowhere", "stratum_port": 0, "api_url": "ftp://api", "scheduler": {"cpu_permits": 0}}"#);
+        let source = ConfigSource::new(config.path());
+        let err = Config::load(&source.set("logs.stdout", "false")).unwrap_err();
+
+        let keys = keys(&err.problems);
+        for key in ["listen", "stratum_port", "api_key", "api_url", "scheduler.cpu_permits", "logs.stdout"] {
+            assert!(keys.contains(&key), "{key} isn't in {keys:?}");
+        }
+    }
+}
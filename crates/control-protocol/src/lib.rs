@@ -0,0 +1,112 @@
+use std::net::IpAddr;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+/// Id of a miner connection, unique within the process
+pub type ConnId = u64;
+
+/// Filters of a connection list as the admin tools get them, a connection has to match every one which is set
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConnFilter {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subaccount: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool: Option<String>
+}
+
+/// A command of the control socket. A client writes one request as a JSON line and reads one response line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Status,
+    ConnsList {
+        #[serde(default)]
+        filter: ConnFilter
+    },
+    ConnsShow {
+        conn_id: ConnId
+    },
+    ConnsKick {
+        conn_id: ConnId,
+        #[serde(default)]
+        reason: Option<String>
+    },
+    PoolsList,
+    PoolsDrain {
+        pool: String,
+        // Workers of the pool are sent there, without it they aren't authorized
+        #[serde(default)]
+        redirect_to: Option<String>
+    },
+    BansAdd {
+        ip: IpAddr,
+        #[serde(default)]
+        reason: Option<String>,
+        #[serde(default)]
+        ttl_secs: Option<u64>
+    },
+    BansRemove {
+        ip: IpAddr
+    },
+    BansList,
+    ConfigReload,
+    // Hands the listeners to a new process of the binary and drains this one
+    Upgrade,
+    Broadcast {
+        message: String,
+        #[serde(default)]
+        filter: ConnFilter
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlResponse {
+    Ok(Value),
+    Error(String)
+}
+
+/// Summary the `status` command answers with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub version: String,
+    pub uptime_secs: u64,
+    pub connections: usize,
+    pub workers: usize,
+    pub pools: usize,
+    pub bans: usize
+}
+
+/// A row of `pools list`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolRow {
+    pub pool: String,
+    pub sessions: usize,
+    pub connected_sessions: usize,
+    pub workers: usize,
+    pub drained: bool,
+    pub redirect_to: Option<String>
+}
+
+/// Sends a single request to the control socket at `path` and waits for the response
+pub async fn request(path: &Path, request: &ControlRequest) -> anyhow::Result<ControlResponse> {
+    let stream = UnixStream::connect(path).await?;
+    let (read_half, mut write_half) = stream.into_split();
+
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    write_half.write_all(line.as_bytes()).await?;
+
+    let mut response = String::new();
+    BufReader::new(read_half).read_line(&mut response).await?;
+
+    Ok(serde_json::from_str(&response)?)
+}
@@ -0,0 +1,67 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::{ClientConfig, Message};
+
+use config::{CommandsConfig, KafkaConfig};
+use network::commands::{CommandAck, CommandSource};
+
+use crate::producer::producer;
+
+// A full local queue of the producer is waited for this long, the reply is dropped then
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Commands of the topic and replies on the reply topic. Every proxy has a consumer group of its own,
+/// named after its client id, so each one gets every command. It starts at the end of the topic, the
+/// older commands are stale anyway
+pub struct KafkaCommandSource {
+    consumer: StreamConsumer,
+    producer: FutureProducer,
+    reply_topic: String
+}
+
+impl KafkaCommandSource {
+    pub fn new(kafka: &KafkaConfig, config: &CommandsConfig) -> anyhow::Result<Self> {
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", &kafka.brokers)
+            .set("client.id", &kafka.client_id)
+            .set("group.id", format!("{}.commands", kafka.client_id))
+            .set("auto.offset.reset", "latest")
+            .create()?;
+        consumer.subscribe(&[config.topic.as_str()])?;
+
+        Ok(Self {
+            consumer,
+            producer: producer(kafka)?,
+            reply_topic: config.reply_topic.clone()
+        })
+    }
+}
+
+#[async_trait]
+impl CommandSource for KafkaCommandSource {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    async fn recv(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        loop {
+            let message = self.consumer.recv().await?;
+            // A message without a value isn't a command
+            if let Some(payload) = message.payload() {
+                return Ok(Some(payload.to_vec()));
+            }
+        }
+    }
+
+    async fn reply(&self, ack: &CommandAck) -> anyhow::Result<()> {
+        let payload = serde_json::to_vec(ack)?;
+        let record = FutureRecord::to(&self.reply_topic).key(&ack.id).payload(&payload);
+
+        self.producer.send(record, QUEUE_TIMEOUT).await.map_err(|(err, _)| err)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+
+use config::KafkaConfig;
+use network::events::{Event, EventSink};
+
+use crate::producer::producer;
+
+// A full local queue of the producer is waited for this long, the batch fails then
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Publishes every event as one JSON message, keyed by its subaccount so the events of a subaccount
+/// land in one partition and stay in order
+pub struct KafkaEventSink {
+    producer: FutureProducer,
+    topic: String
+}
+
+impl KafkaEventSink {
+    pub fn new(config: &KafkaConfig, topic: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            producer: producer(config)?,
+            topic: topic.to_string()
+        })
+    }
+}
+
+#[async_trait]
+impl EventSink for KafkaEventSink {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    async fn publish(&self, events: &[Event]) -> anyhow::Result<()> {
+        let payloads = events.iter().map(serde_json::to_vec).collect::<Result<Vec<_>, _>>()?;
+
+        // The batch is queued at once, the producer batches it on its own
+        let deliveries = events.iter().zip(&payloads).map(|(event, payload)| {
+            let record = FutureRecord::to(&self.topic).key(event.key()).payload(payload);
+            self.producer.send(record, QUEUE_TIMEOUT)
+        });
+        for delivery in join_all(deliveries).await {
+            delivery.map_err(|(err, _)| err)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,4 @@
+pub mod command_source;
+pub mod event_sink;
+pub mod producer;
+pub mod telemetry_sink;
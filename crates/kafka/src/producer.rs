@@ -0,0 +1,22 @@
+use rdkafka::producer::FutureProducer;
+use rdkafka::ClientConfig;
+
+use config::KafkaConfig;
+
+// How long librdkafka keeps trying to deliver a message
+const MESSAGE_TIMEOUT_MS: &str = "5000";
+
+/// Producer to the brokers of the config, the proxy's publishers share one
+pub fn producer(config: &KafkaConfig) -> anyhow::Result<FutureProducer> {
+    if config.brokers.is_empty() {
+        anyhow::bail!("There are no Kafka brokers in the config");
+    }
+
+    let producer = ClientConfig::new()
+        .set("bootstrap.servers", &config.brokers)
+        .set("client.id", &config.client_id)
+        .set("message.timeout.ms", MESSAGE_TIMEOUT_MS)
+        .create()?;
+
+    Ok(producer)
+}
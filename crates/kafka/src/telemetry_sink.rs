@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+
+use config::KafkaConfig;
+use telemetry::sink::{Snapshot, TelemetrySink};
+
+use crate::producer::producer;
+
+// A full local queue of the producer is waited for this long, the snapshot is dropped then
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Publishes every snapshot of the series as one JSON message, keyed by the client id so the
+/// snapshots of a proxy stay in order
+pub struct KafkaTelemetrySink {
+    producer: FutureProducer,
+    topic: String,
+    key: String
+}
+
+impl KafkaTelemetrySink {
+    pub fn new(config: &KafkaConfig, topic: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            producer: producer(config)?,
+            topic: topic.to_string(),
+            key: config.client_id.clone()
+        })
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for KafkaTelemetrySink {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    async fn publish(&self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        let payload = serde_json::to_vec(snapshot)?;
+        let record = FutureRecord::to(&self.topic).key(&self.key).payload(&payload);
+
+        self.producer.send(record, QUEUE_TIMEOUT).await.map_err(|(err, _)| err)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::Arc;
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use tracing::info;
+
+use config::reload::ConfigReloader;
+use config::Config;
+
+use crate::commands::memory_source::MemoryCommandSource;
+use crate::drain::DrainController;
+use crate::events::memory_sink::MemorySink;
+use crate::health::Health;
+use crate::registry::ConnRegistry;
+use crate::stats::ShareStats;
+use crate::upgrade::UpgradeController;
+use crate::upstream::overrides::PoolOverrides;
+
+mod auth;
+mod routes;
+
+/// Everything the admin endpoints work with
+#[derive(Clone)]
+pub struct AdminState {
+    pub registry: Arc<ConnRegistry>,
+    pub overrides: Arc<PoolOverrides>,
+    pub drain: Arc<DrainController>,
+    pub upgrade: Arc<UpgradeController>,
+    pub reloader: Arc<ConfigReloader>,
+    pub stats: Arc<ShareStats>,
+    pub health: Arc<Health>,
+    // The events published to the memory sink, it's there when the events go to it
+    pub memory_events: Option<Arc<MemorySink>>,
+    // Where the commands are pushed, it's there when the commands come from memory
+    pub memory_commands: Option<Arc<MemoryCommandSource>>,
+    config: watch::Receiver<Arc<Config>>,
+    // The token of the config the proxy started with, a reload doesn't change it
+    token: Arc<str>
+}
+
+#[derive(Debug, Error)]
+pub enum AdminError {
+    #[error("unauthorized")]
+    Unauthorized,
+    #[error("{0} is not found")]
+    NotFound(String),
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Failed(String),
+    #[error("{0}")]
+    Rejected(String)
+}
+
+/// HTTP API to look at and control the live sessions. Every request but the probes of the
+/// orchestrator has to carry the admin token as `Authorization: Bearer <token>`
+pub struct AdminServer {
+    listener: TcpListener,
+    state: AdminState,
+    shutdown: CancellationToken
+}
+
+impl AdminState {
+    pub fn new(
+        registry: Arc<ConnRegistry>, overrides: Arc<PoolOverrides>, drain: Arc<DrainController>,
+        upgrade: Arc<UpgradeController>, reloader: Arc<ConfigReloader>, stats: Arc<ShareStats>,
+        health: Arc<Health>
+    ) -> Self {
+        let config = reloader.current();
+        let token = Arc::from(config.admin.token(&config.api_key));
+
+        Self {
+            registry,
+            overrides,
+            drain,
+            upgrade,
+            config: reloader.subscribe(),
+            reloader,
+            stats,
+            health,
+            memory_events: None,
+            memory_commands: None,
+            token
+        }
+    }
+
+    pub fn set_memory_events(mut self, sink: Arc<MemorySink>) -> Self {
+        self.memory_events = Some(sink);
+        self
+    }
+
+    pub fn set_memory_commands(mut self, source: Arc<MemoryCommandSource>) -> Self {
+        self.memory_commands = Some(source);
+        self
+    }
+
+    /// Whether `token` is the admin token
+    pub(crate) fn is_token(&self, token: &str) -> bool {
+        !self.token.is_empty() && auth::constant_time_eq(token.as_bytes(), self.token.as_bytes())
+    }
+}
+
+impl AdminServer {
+    pub fn new(listener: TcpListener, state: AdminState, shutdown: CancellationToken) -> anyhow::Result<Self> {
+        if state.token.is_empty() {
+            anyhow::bail!("The admin API needs a token, set admin.token or api_key");
+        }
+
+        Ok(Self { listener, state, shutdown })
+    }
+
+    /// Listening socket, it's handed to the new process on an upgrade
+    pub fn listener_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        info!(addr = ?self.listener.local_addr()?, "Admin API started!");
+
+        let shutdown = self.shutdown.clone();
+        axum::serve(self.listener, routes::router(self.state))
+            .with_graceful_shutdown(async move { shutdown.cancelled().await })
+            .await?;
+
+        Ok(())
+    }
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            AdminError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AdminError::NotFound(_) => StatusCode::NOT_FOUND,
+            AdminError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AdminError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AdminError::Rejected(_) => StatusCode::UNPROCESSABLE_ENTITY
+        };
+
+        (status, Json(json!({ "error": self.to_string() }))).into_response()
+    }
+}
@@ -0,0 +1,28 @@
+use axum::extract::{Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::middleware::Next;
+use axum::response::Response;
+
+use crate::admin::{AdminError, AdminState};
+
+/// Lets the request through only if it carries the admin token
+pub(super) async fn require_token(State(state): State<AdminState>, request: Request, next: Next) -> Result<Response, AdminError> {
+    let token = request.headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match token {
+        Some(token) if state.is_token(token) => Ok(next.run(request).await),
+        _ => Err(AdminError::Unauthorized)
+    }
+}
+
+// The comparison takes the same time wherever the first mismatch is
+pub(super) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+
+use axum::body::Bytes;
+use axum::http::{header, StatusCode};
+use axum::extract::{Path, Query, State};
+use axum::routing::{get, post};
+use axum::{middleware, Json, Router};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::info;
+
+use config::reload::{ReloadError, ReloadSummary};
+use config::SinkKind;
+use control_protocol::ConnFilter;
+
+use crate::admin::auth::require_token;
+use crate::admin::{AdminError, AdminState};
+use crate::commands::memory_source::MemoryCommandSource;
+use crate::commands::{CommandAck, SignedCommand};
+use crate::events::Event;
+use crate::health::{Probe, Status};
+use crate::metrics::METRICS;
+use crate::registry::{ClosedConn, ConnInfo, PoolSummary, Selector, SessionDetails};
+use crate::server::ConnId;
+use crate::stats::{ShareSummary, StatsFilter};
+
+pub(super) fn router(state: AdminState) -> Router {
+    Router::new()
+        .route("/api/v1/connections", get(list_connections))
+        .route("/api/v1/connections/closed", get(closed_connections))
+        .route("/api/v1/connections/{conn_id}", get(get_connection))
+        .route("/api/v1/connections/{conn_id}/kick", post(kick_connection))
+        .route("/api/v1/workers/{worker}/reconnect", post(reconnect_worker))
+        .route("/api/v1/upstreams", get(list_upstreams))
+        .route("/api/v1/stats/workers", get(worker_stats))
+        .route("/api/v1/stats/workers/{worker}", get(get_worker_stats))
+        .route("/api/v1/stats/subaccounts", get(sub_account_stats))
+        .route("/api/v1/stats/subaccounts/{sub_account}", get(get_sub_account_stats))
+        .route("/api/v1/drain", post(start_drain))
+        .route("/api/v1/upgrade", post(start_upgrade))
+        .route("/api/v1/config/reload", post(reload_config))
+        .route("/api/v1/logs/filter", get(get_log_filter).put(set_log_filter))
+        .route("/api/v1/events", get(list_events))
+        .route("/api/v1/commands", post(push_command))
+        .route("/api/v1/commands/replies", get(command_replies))
+        .route("/metrics", get(metrics))
+        .route("/status", get(status))
+        .layer(middleware::from_fn_with_state(state.clone(), require_token))
+        // The orchestrator probes without the token
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(state)
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct KickRequest {
+    reason: Option<String>
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct LimitQuery {
+    limit: Option<usize>
+}
+
+#[derive(Debug, Deserialize)]
+struct ReconnectRequest {
+    pool: String // host:port of the pool the worker is moved to
+}
+
+#[derive(Debug, Deserialize)]
+struct LogFilterRequest {
+    filter: String // e.g. `info,network::connection=debug`
+}
+
+async fn list_connections(State(state): State<AdminState>, Query(filter): Query<ConnFilter>) -> Json<Vec<ConnInfo>> {
+    Json(state.registry.list(&Selector::from(filter)))
+}
+
+async fn closed_connections(State(state): State<AdminState>) -> Json<Vec<ClosedConn>> {
+    Json(state.registry.closed())
+}
+
+async fn get_connection(State(state): State<AdminState>, Path(conn_id): Path<ConnId>) -> Result<Json<SessionDetails>, AdminError> {
+    state.registry.inspect(conn_id).await
+        .map(Json)
+        .ok_or_else(|| AdminError::NotFound(format!("connection {conn_id}")))
+}
+
+async fn kick_connection(
+    State(state): State<AdminState>, Path(conn_id): Path<ConnId>, body: Bytes
+) -> Result<Json<Value>, AdminError> {
+    // The body is optional
+    let request: KickRequest = if body.is_empty() {
+        KickRequest::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|err| AdminError::BadRequest(err.to_string()))?
+    };
+    let reason = request.reason.unwrap_or_else(|| "admin".to_string());
+
+    if state.registry.kick(&Selector::Conn(conn_id), &reason) == 0 {
+        return Err(AdminError::NotFound(format!("connection {conn_id}")));
+    }
+
+    Ok(Json(json!({ "kicked": conn_id })))
+}
+
+async fn reconnect_worker(
+    State(state): State<AdminState>, Path(worker): Path<String>, Json(request): Json<ReconnectRequest>
+) -> Result<Json<Value>, AdminError> {
+    if request.pool.rsplit_once(':').is_none_or(|(host, port)| host.is_empty() || port.parse::<u16>().is_err()) {
+        return Err(AdminError::BadRequest(format!("pool has to be host:port, got {}", request.pool)));
+    }
+
+    // The worker is sent to the pool when it authorizes again after the reconnect
+    state.overrides.set(worker.clone(), request.pool.clone());
+    let notified = state.registry.reconnect(&Selector::Worker(worker.clone()), None).await;
+
+    Ok(Json(json!({ "worker": worker, "pool": request.pool, "notified": notified })))
+}
+
+async fn list_upstreams(State(state): State<AdminState>) -> Json<BTreeMap<String, PoolSummary>> {
+    Json(state.registry.upstreams().await)
+}
+
+/// Workers by their hashrate over 5 minutes, `?subaccount=` and `?limit=` narrow the list
+async fn worker_stats(State(state): State<AdminState>, Query(filter): Query<StatsFilter>) -> Json<Vec<ShareSummary>> {
+    Json(state.stats.workers(&filter))
+}
+
+async fn get_worker_stats(State(state): State<AdminState>, Path(worker): Path<String>) -> Result<Json<ShareSummary>, AdminError> {
+    state.stats.worker(&worker)
+        .map(Json)
+        .ok_or_else(|| AdminError::NotFound(format!("worker {worker}")))
+}
+
+async fn sub_account_stats(State(state): State<AdminState>, Query(query): Query<LimitQuery>) -> Json<Vec<ShareSummary>> {
+    Json(state.stats.sub_accounts(query.limit))
+}
+
+async fn get_sub_account_stats(
+    State(state): State<AdminState>, Path(sub_account): Path<String>
+) -> Result<Json<ShareSummary>, AdminError> {
+    state.stats.sub_account(&sub_account)
+        .map(Json)
+        .ok_or_else(|| AdminError::NotFound(format!("subaccount {sub_account}")))
+}
+
+/// Starts the drain, the proxy exits once it's done
+async fn start_drain(State(state): State<AdminState>) -> Json<Value> {
+    let started = state.drain.start();
+    Json(json!({ "draining": true, "started": started }))
+}
+
+/// Hands the listeners to a new process of the binary and drains this one
+async fn start_upgrade(State(state): State<AdminState>) -> Result<Json<Value>, AdminError> {
+    let pid = state.upgrade.upgrade().await.map_err(|err| AdminError::Failed(err.to_string()))?;
+    Ok(Json(json!({ "upgraded": true, "pid": pid })))
+}
+
+/// Reads the config file again, an invalid one is rejected with what's wrong in it
+async fn reload_config(State(state): State<AdminState>) -> Result<Json<ReloadSummary>, AdminError> {
+    match state.reloader.reload() {
+        Ok(summary) => Ok(Json(summary)),
+        Err(err @ ReloadError::Invalid { .. }) => Err(AdminError::Rejected(err.to_string())),
+        Err(err) => Err(AdminError::Failed(err.to_string()))
+    }
+}
+
+/// The filter the logs have now, it's the config's unless it has been changed through the API
+async fn get_log_filter() -> Result<Json<Value>, AdminError> {
+    let filter = utils::logs::current_filter().ok_or_else(|| AdminError::Failed("the logs aren't initialized".to_string()))?;
+    Ok(Json(json!({ "filter": filter })))
+}
+
+/// Changes the log levels at once. It stays until a reloaded config has another filter
+async fn set_log_filter(Json(request): Json<LogFilterRequest>) -> Result<Json<Value>, AdminError> {
+    utils::logs::set_filter(&request.filter).map_err(|err| AdminError::BadRequest(err.to_string()))?;
+    info!(filter = %request.filter, "The log filter is changed through the admin API");
+
+    Ok(Json(json!({ "filter": request.filter })))
+}
+
+/// Last events of the memory sink, the oldest first
+async fn list_events(State(state): State<AdminState>, Query(query): Query<LimitQuery>) -> Result<Json<Vec<Event>>, AdminError> {
+    let sink = state.memory_events.as_ref().ok_or_else(|| AdminError::NotFound("/api/v1/events, the memory sink".to_string()))?;
+
+    Ok(Json(sink.events(query.limit)))
+}
+
+/// Queues a signed command as if it came from the topic, the consumer checks and applies it
+async fn push_command(State(state): State<AdminState>, body: Bytes) -> Result<(StatusCode, Json<Value>), AdminError> {
+    let source = memory_commands(&state)?;
+    // Only the shape is checked here, the signature is the consumer's
+    serde_json::from_slice::<SignedCommand>(&body).map_err(|err| AdminError::BadRequest(err.to_string()))?;
+    source.push(body.to_vec()).map_err(|err| AdminError::Rejected(err.to_string()))?;
+
+    Ok((StatusCode::ACCEPTED, Json(json!({ "queued": true }))))
+}
+
+/// Replies of the consumer to the commands, the oldest first
+async fn command_replies(State(state): State<AdminState>) -> Result<Json<Vec<CommandAck>>, AdminError> {
+    Ok(Json(memory_commands(&state)?.replies()))
+}
+
+fn memory_commands(state: &AdminState) -> Result<&MemoryCommandSource, AdminError> {
+    state.memory_commands.as_deref().ok_or_else(|| AdminError::NotFound("/api/v1/commands, the memory command source".to_string()))
+}
+
+/// Prometheus scrape, the scraper sends the admin token like any other client. It's there while the
+/// prometheus sink is in the telemetry sinks
+async fn metrics(State(state): State<AdminState>) -> Result<([(header::HeaderName, &'static str); 1], String), AdminError> {
+    if !state.config.borrow().telemetry.has_sink(SinkKind::Prometheus) {
+        return Err(AdminError::NotFound("/metrics, the prometheus sink".to_string()));
+    }
+
+    let text = METRICS.render(&state.registry).await.map_err(|err| AdminError::Failed(err.to_string()))?;
+
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], text))
+}
+
+/// Liveness, 503 once the scheduler loop has stalled
+async fn healthz(State(state): State<AdminState>) -> (StatusCode, Json<Probe>) {
+    probe_response(state.health.liveness())
+}
+
+/// Readiness, 503 while the proxy shouldn't get new miners, e.g. during a drain
+async fn readyz(State(state): State<AdminState>) -> (StatusCode, Json<Probe>) {
+    probe_response(state.health.readiness().await)
+}
+
+async fn status(State(state): State<AdminState>) -> Json<Status> {
+    Json(state.health.status().await)
+}
+
+fn probe_response(probe: Probe) -> (StatusCode, Json<Probe>) {
+    let code = if probe.ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (code, Json(probe))
+}
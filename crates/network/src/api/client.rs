@@ -55,6 +55,12 @@ impl ApiClient {
     }
 
 
+    /// Whether the API answers at all, any HTTP status counts
+    pub async fn ping(&self) -> anyhow::Result<()> {
+        self.inner.get(&self.base_url).send().await.context("the API isn't reachable")?;
+        Ok(())
+    }
+
     pub async fn get_subaccount_info(&self, worker_full_name: String) -> anyhow::Result<ApiResponse> {
         let url = format!("{}/users/get-subAccount-info?workerName={}", self.base_url, worker_full_name);
 
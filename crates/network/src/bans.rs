@@ -0,0 +1,118 @@
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+/// Longest ban with a ttl, a longer ttl is cut to it. Use a ban without a ttl for a permanent one
+pub const MAX_BAN_TTL_SECS: u64 = 10 * 365 * 24 * 60 * 60;
+
+/// An IP the proxy doesn't accept connections from
+#[derive(Debug, Clone, Serialize)]
+pub struct Ban {
+    pub ip: IpAddr,
+    pub reason: String,
+    pub created_at: u64, // unix time, seconds
+    pub expires_at: Option<u64>, // the ban is permanent if it's None
+    pub from_config: bool // it's in the `bans` of the config, a reload removes it if it's gone from there
+}
+
+#[derive(Debug, Default)]
+pub struct BanList {
+    bans: DashMap<IpAddr, Ban>
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bans the IP for `ttl_secs` (at most `MAX_BAN_TTL_SECS`), or until it's removed if there is no ttl.
+    /// A ban of the same IP is replaced
+    pub fn add(&self, ip: IpAddr, reason: impl Into<String>, ttl_secs: Option<u64>) -> Ban {
+        let now = now_secs();
+        let ban = Ban {
+            ip,
+            reason: reason.into(),
+            created_at: now,
+            expires_at: ttl_secs.map(|ttl| now.saturating_add(ttl.min(MAX_BAN_TTL_SECS))),
+            from_config: false
+        };
+
+        self.bans.insert(ip, ban.clone());
+        ban
+    }
+
+    /// Makes the bans of the config exactly `ips`, returns the IPs banned just now. Bans added by the
+    /// operators are kept
+    pub fn sync_config(&self, ips: &[IpAddr]) -> Vec<IpAddr> {
+        self.bans.retain(|ip, ban| !ban.from_config || ips.contains(ip));
+
+        let mut added = Vec::new();
+        for ip in ips {
+            if self.bans.get(ip).is_some_and(|ban| ban.from_config) {
+                continue;
+            }
+
+            self.bans.insert(*ip, Ban {
+                ip: *ip,
+                reason: "config".to_string(),
+                created_at: now_secs(),
+                expires_at: None,
+                from_config: true
+            });
+            added.push(*ip);
+        }
+
+        added
+    }
+
+    pub fn remove(&self, ip: &IpAddr) -> Option<Ban> {
+        self.bans.remove(ip).map(|(_, ban)| ban)
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        let now = now_secs();
+
+        // An expired ban is dropped when it's seen
+        self.bans.remove_if(ip, |_, ban| ban.expires_at.is_some_and(|expires_at| expires_at <= now));
+        self.bans.contains_key(ip)
+    }
+
+    /// Bans which haven't expired yet
+    pub fn list(&self) -> Vec<Ban> {
+        let now = now_secs();
+        self.bans.retain(|_, ban| ban.expires_at.is_none_or(|expires_at| expires_at > now));
+
+        self.bans.iter().map(|entry| entry.value().clone()).collect()
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn huge_ttl_is_capped() {
+        let bans = BanList::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let ban = bans.add(ip, "test", Some(u64::MAX));
+        assert_eq!(ban.expires_at, Some(ban.created_at + MAX_BAN_TTL_SECS));
+        assert!(bans.is_banned(&ip));
+    }
+
+    #[test]
+    fn expired_ban_is_dropped() {
+        let bans = BanList::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        bans.add(ip, "test", Some(0));
+        assert!(!bans.is_banned(&ip));
+        assert!(bans.list().is_empty());
+    }
+}
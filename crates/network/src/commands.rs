@@ -0,0 +1,485 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::select;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use config::CommandsConfig;
+use control_protocol::ConnFilter;
+
+use crate::bans::BanList;
+use crate::registry::{ConnRegistry, Selector};
+use crate::upstream::overrides::PoolOverrides;
+
+pub mod memory_source;
+
+// A source which fails is read again after this, the wait doubles up to the max
+const RETRY_MIN: Duration = Duration::from_millis(100);
+const RETRY_MAX: Duration = Duration::from_secs(5);
+// Reason of the kicks and the bans which don't give one
+const DEFAULT_REASON: &str = "control plane";
+
+/// A command as it's sent: the payload, a JSON `CommandPayload`, and its HMAC-SHA256 with the
+/// shared secret in hex. The payload is signed as it's sent, it isn't parsed before it's checked
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedCommand {
+    pub payload: String,
+    pub signature: String
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandPayload {
+    // Taken once by a running proxy, the reply carries it
+    pub id: String,
+    pub issued_at: u64, // unix time in seconds
+    // Client id of the proxy the command is for, every proxy takes it if there's none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    pub command: Command
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    KickWorker {
+        worker: String,
+        #[serde(default)]
+        reason: Option<String>
+    },
+    // Kicks the IP's connections too
+    BanIp {
+        ip: IpAddr,
+        #[serde(default)]
+        reason: Option<String>,
+        #[serde(default)]
+        ttl_secs: Option<u64>
+    },
+    // The workers of the subaccount reconnect and go to the pool, none goes back to the API's target
+    SetPoolTarget {
+        sub_account: String,
+        pool: Option<String>
+    },
+    ShowMessage {
+        message: String,
+        #[serde(default)]
+        filter: ConnFilter
+    },
+    // The pools are asked for it with mining.suggest_difficulty
+    SetDifficulty {
+        difficulty: f64,
+        #[serde(default)]
+        filter: ConnFilter
+    }
+}
+
+/// Outcome of a command, sent on the reply topic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandAck {
+    pub id: String, // empty if the payload couldn't be read
+    pub proxy: String,
+    pub at_ms: u64,
+    pub ok: bool,
+    // Connections, pool sessions or bans the command has changed
+    pub applied: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>
+}
+
+/// Where the commands come from and where the replies go
+#[async_trait]
+pub trait CommandSource: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// The next command as it's sent, none once the source is closed
+    async fn recv(&self) -> anyhow::Result<Option<Vec<u8>>>;
+
+    async fn reply(&self, ack: &CommandAck) -> anyhow::Result<()>;
+}
+
+/// Takes the commands of the source, checks their signatures and applies them to the sessions of
+/// the registry
+pub struct CommandConsumer {
+    source: Arc<dyn CommandSource>,
+    registry: Arc<ConnRegistry>,
+    overrides: Arc<PoolOverrides>,
+    bans: Arc<BanList>,
+    secret: Vec<u8>,
+    max_age: u64,
+    proxy: String,
+    // Ids of the commands taken within max_age, with their issued_at. They're in memory only: after
+    // a restart a command is taken again if it's sent again within its max_age
+    seen: HashMap<String, u64>
+}
+
+impl SignedCommand {
+    /// Signs the payload with the shared secret, it's what the backend does
+    pub fn sign(secret: &[u8], payload: &CommandPayload) -> anyhow::Result<Self> {
+        let payload = serde_json::to_string(payload)?;
+        let signature = hex::encode(mac(secret, &payload).finalize().into_bytes());
+
+        Ok(Self { payload, signature })
+    }
+
+    fn verify(&self, secret: &[u8]) -> anyhow::Result<CommandPayload> {
+        let signature = hex::decode(&self.signature).map_err(|_| anyhow::anyhow!("The signature isn't hex"))?;
+        // The comparison takes the same time wherever the first mismatch is
+        mac(secret, &self.payload).verify_slice(&signature).map_err(|_| anyhow::anyhow!("The signature doesn't match"))?;
+
+        Ok(serde_json::from_str(&self.payload)?)
+    }
+}
+
+impl CommandConsumer {
+    /// `proxy` is the name of the proxy in the commands and the replies, e.g. its Kafka client id
+    pub fn new(
+        source: Arc<dyn CommandSource>, registry: Arc<ConnRegistry>, overrides: Arc<PoolOverrides>, bans: Arc<BanList>,
+        config: &CommandsConfig, proxy: &str
+    ) -> Self {
+        Self {
+            source,
+            registry,
+            overrides,
+            bans,
+            secret: config.secret.as_bytes().to_vec(),
+            max_age: config.max_age_secs,
+            proxy: proxy.to_string(),
+            seen: HashMap::new()
+        }
+    }
+
+    pub async fn run(mut self, shutdown: CancellationToken) -> anyhow::Result<()> {
+        info!(source = self.source.name(), "Command consumer started!");
+        let mut retry = RETRY_MIN;
+
+        loop {
+            let received = select! {
+                _ = shutdown.cancelled() => break,
+                received = self.source.recv() => received
+            };
+
+            let command = match received {
+                Ok(Some(command)) => command,
+                Ok(None) => {
+                    warn!(source = self.source.name(), "The command source is closed");
+                    shutdown.cancelled().await;
+                    break;
+                }
+                Err(err) => {
+                    warn!(source = self.source.name(), "Couldn't receive a command, retrying in {:?}: {:#}", retry, err);
+                    select! {
+                        _ = shutdown.cancelled() => break,
+                        _ = sleep(retry) => {}
+                    }
+                    retry = (retry * 2).min(RETRY_MAX);
+                    continue;
+                }
+            };
+            retry = RETRY_MIN;
+
+            let Some(ack) = self.handle(&command).await else {
+                continue;
+            };
+            if let Err(err) = self.source.reply(&ack).await {
+                warn!(id = %ack.id, "Couldn't send the reply of the command: {:#}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    // The reply of the command, none if it's for another proxy
+    async fn handle(&mut self, command: &[u8]) -> Option<CommandAck> {
+        let payload = match serde_json::from_slice::<SignedCommand>(command).map_err(anyhow::Error::from)
+            .and_then(|command| command.verify(&self.secret))
+        {
+            Ok(payload) => payload,
+            Err(err) => {
+                warn!("A command is rejected: {:#}", err);
+                return Some(self.ack(String::new(), Err(err.to_string())));
+            }
+        };
+        if payload.proxy.as_ref().is_some_and(|proxy| *proxy != self.proxy) {
+            return None;
+        }
+
+        if let Err(err) = self.check_fresh(&payload) {
+            warn!(id = %payload.id, "A command is rejected: {}", err);
+            return Some(self.ack(payload.id, Err(err)));
+        }
+
+        info!(id = %payload.id, command = ?payload.command, "Command received");
+        let outcome = self.apply(payload.command).await;
+        Some(self.ack(payload.id, outcome))
+    }
+
+    // A command is taken once, within max_age of when it's issued
+    fn check_fresh(&mut self, payload: &CommandPayload) -> Result<(), String> {
+        let now = now_ms() / 1000;
+        let max_age = self.max_age;
+        self.seen.retain(|_, issued_at| now.saturating_sub(*issued_at) <= max_age);
+
+        if now.saturating_sub(payload.issued_at) > max_age {
+            return Err(format!("the command is older than {max_age}s"));
+        }
+        if payload.issued_at.saturating_sub(now) > max_age {
+            return Err("the command is issued in the future".to_string());
+        }
+        if self.seen.insert(payload.id.clone(), payload.issued_at).is_some() {
+            return Err("the command is taken already".to_string());
+        }
+
+        Ok(())
+    }
+
+    // How many connections, pool sessions or bans the command has changed
+    async fn apply(&self, command: Command) -> Result<usize, String> {
+        match command {
+            Command::KickWorker { worker, reason } => {
+                Ok(self.registry.kick(&Selector::Worker(worker), reason.as_deref().unwrap_or(DEFAULT_REASON)))
+            }
+            Command::BanIp { ip, reason, ttl_secs } => {
+                self.bans.add(ip, reason.unwrap_or_else(|| DEFAULT_REASON.to_string()), ttl_secs);
+                Ok(1 + self.registry.kick(&Selector::RemoteIp(ip), "banned"))
+            }
+            Command::SetPoolTarget { sub_account, pool } => {
+                match pool {
+                    Some(pool) => self.overrides.set_sub_account(sub_account.clone(), pool),
+                    None => {
+                        self.overrides.remove_sub_account(&sub_account);
+                    }
+                }
+                // The workers authorize again and get to the pool they are sent to now
+                Ok(self.registry.reconnect(&Selector::SubAccount(sub_account), None).await)
+            }
+            Command::ShowMessage { message, filter } => Ok(self.registry.show_message(&Selector::from(filter), &message).await),
+            Command::SetDifficulty { difficulty, filter } => {
+                if !difficulty.is_finite() || difficulty <= 0.0 {
+                    return Err(format!("difficulty {difficulty} has to be more than 0"));
+                }
+                Ok(self.registry.suggest_difficulty(&Selector::from(filter), difficulty).await)
+            }
+        }
+    }
+
+    fn ack(&self, id: String, outcome: Result<usize, String>) -> CommandAck {
+        let (ok, applied, error) = match outcome {
+            Ok(applied) => (true, applied, None),
+            Err(error) => (false, 0, Some(error))
+        };
+
+        CommandAck { id, proxy: self.proxy.clone(), at_ms: now_ms(), ok, applied, error }
+    }
+}
+
+fn mac(secret: &[u8], payload: &str) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC takes a key of any length");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use score::miner::Miner;
+    use score::session::{SessionHandle, SessionState};
+    use score::worker::Worker;
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::commands::memory_source::MemoryCommandSource;
+    use crate::connection::pending::PendingSubmits;
+    use crate::events::EventEmitter;
+    use crate::stats::ShareStats;
+
+    const SECRET: &[u8] = b"secret";
+    const POOL: &str = "pool.example:3333";
+
+    // A proxy with one connection, worker acc.rig1 of 10.0.0.1 on POOL. The channels of the miner and
+    // of the pool session are kept so the session can write to them
+    struct Proxy {
+        source: Arc<MemoryCommandSource>,
+        registry: Arc<ConnRegistry>,
+        overrides: Arc<PoolOverrides>,
+        bans: Arc<BanList>,
+        _miner_rx: mpsc::Receiver<String>,
+        pool_rx: mpsc::Receiver<String>
+    }
+
+    impl Proxy {
+        fn new() -> Self {
+            let remote: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+            let (miner_tx, miner_rx) = mpsc::channel(16);
+            let (pool_messages, _) = mpsc::channel(16);
+            let (pool_tx, pool_rx) = mpsc::channel(16);
+
+            let mut miner = Miner::new(remote, miner_tx, pool_messages);
+            miner.transition(SessionState::Subscribed).unwrap();
+            miner.add_pool_session(POOL, pool_tx.clone());
+            miner.add_worker(Worker::new("acc.rig1", "acc", POOL, pool_tx, 0));
+            let snapshot = miner.snapshot();
+
+            let registry = Arc::new(ConnRegistry::new(EventEmitter::default()));
+            registry.register(1, remote, "0.0.0.0:3333".parse().unwrap(), CancellationToken::new());
+            let pending = Arc::new(PendingSubmits::new(Arc::new(ShareStats::new(16)), EventEmitter::default(), 1));
+            registry.attach_session(1, SessionHandle::spawn(miner), pending);
+            registry.update_session(1, &snapshot);
+
+            Self {
+                source: Arc::new(MemoryCommandSource::new()),
+                registry,
+                overrides: Arc::new(PoolOverrides::new()),
+                bans: Arc::new(BanList::new()),
+                _miner_rx: miner_rx,
+                pool_rx
+            }
+        }
+
+        // Sends the commands to the consumer of proxy-1 and waits for `replies` of them
+        async fn consume(&self, commands: &[Vec<u8>], replies: usize) -> Vec<CommandAck> {
+            let config = CommandsConfig { secret: "secret".to_string(), max_age_secs: 60, ..CommandsConfig::default() };
+            let consumer = CommandConsumer::new(
+                self.source.clone(), Arc::clone(&self.registry), Arc::clone(&self.overrides), Arc::clone(&self.bans),
+                &config, "proxy-1"
+            );
+            let shutdown = CancellationToken::new();
+            let running = tokio::spawn(consumer.run(shutdown.clone()));
+
+            for command in commands {
+                self.source.push(command.clone()).unwrap();
+            }
+            timeout(Duration::from_secs(5), async {
+                while self.source.replies().len() < replies {
+                    sleep(Duration::from_millis(5)).await;
+                }
+            }).await.expect("the commands are replied to");
+
+            shutdown.cancel();
+            running.await.unwrap().unwrap();
+            self.source.replies()
+        }
+    }
+
+    fn payload(id: &str, command: Command) -> CommandPayload {
+        CommandPayload { id: id.to_string(), issued_at: now_ms() / 1000, proxy: None, command }
+    }
+
+    fn signed(secret: &[u8], payload: &CommandPayload) -> Vec<u8> {
+        serde_json::to_vec(&SignedCommand::sign(secret, payload).unwrap()).unwrap()
+    }
+
+    fn kick(id: &str) -> CommandPayload {
+        payload(id, Command::KickWorker { worker: "acc.rig1".to_string(), reason: None })
+    }
+
+    #[tokio::test]
+    async fn only_signed_commands_are_taken() {
+        let proxy = Proxy::new();
+
+        let mut tampered = SignedCommand::sign(SECRET, &kick("2")).unwrap();
+        tampered.payload = tampered.payload.replace("acc.rig1", "acc.rig2");
+        let replies = proxy.consume(&[
+            signed(b"other secret", &kick("1")),
+            serde_json::to_vec(&tampered).unwrap(),
+            b"{}".to_vec(),
+            signed(SECRET, &kick("3"))
+        ], 4).await;
+
+        for reply in &replies[..3] {
+            assert!(!reply.ok && reply.id.is_empty() && reply.applied == 0, "{reply:?} is taken");
+        }
+        assert!(replies[3].ok && replies[3].id == "3" && replies[3].applied == 1);
+        assert_eq!(replies[3].proxy, "proxy-1");
+    }
+
+    #[tokio::test]
+    async fn stale_future_and_replayed_commands_are_rejected() {
+        let proxy = Proxy::new();
+        let now = now_ms() / 1000;
+
+        let stale = CommandPayload { issued_at: now - 61, ..kick("stale") };
+        let future = CommandPayload { issued_at: now + 61, ..kick("future") };
+        // Within max_age either way
+        let late = CommandPayload { issued_at: now - 50, ..kick("late") };
+        let early = CommandPayload { issued_at: now + 50, ..kick("early") };
+        let replies = proxy.consume(&[
+            signed(SECRET, &stale),
+            signed(SECRET, &future),
+            signed(SECRET, &late),
+            signed(SECRET, &early),
+            signed(SECRET, &late)
+        ], 5).await;
+
+        let outcomes: Vec<(&str, bool)> = replies.iter().map(|reply| (reply.id.as_str(), reply.ok)).collect();
+        assert_eq!(outcomes, [("stale", false), ("future", false), ("late", true), ("early", true), ("late", false)]);
+        assert_eq!(replies[4].error.as_deref(), Some("the command is taken already"));
+    }
+
+    #[tokio::test]
+    async fn commands_of_another_proxy_are_left_alone() {
+        let proxy = Proxy::new();
+
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other = CommandPayload {
+            proxy: Some("proxy-2".to_string()),
+            ..payload("other", Command::BanIp { ip, reason: None, ttl_secs: None })
+        };
+        let ours = CommandPayload {
+            proxy: Some("proxy-1".to_string()),
+            ..payload("ours", Command::ShowMessage { message: "hi".to_string(), filter: ConnFilter::default() })
+        };
+        let replies = proxy.consume(&[signed(SECRET, &other), signed(SECRET, &ours)], 1).await;
+
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].id, "ours");
+        assert!(!proxy.bans.is_banned(&ip));
+    }
+
+    #[tokio::test]
+    async fn commands_tell_what_they_have_changed() {
+        let mut proxy = Proxy::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let filter = ConnFilter { subaccount: Some("acc".to_string()), ..ConnFilter::default() };
+
+        let replies = proxy.consume(&[
+            signed(SECRET, &payload("message", Command::ShowMessage { message: "hi".to_string(), filter: filter.clone() })),
+            signed(SECRET, &payload("difficulty", Command::SetDifficulty { difficulty: 65536.0, filter: filter.clone() })),
+            signed(SECRET, &payload("bad difficulty", Command::SetDifficulty { difficulty: -1.0, filter })),
+            signed(SECRET, &payload("target", Command::SetPoolTarget {
+                sub_account: "acc".to_string(),
+                pool: Some("other.example:3333".to_string())
+            })),
+            signed(SECRET, &payload("nobody", Command::KickWorker { worker: "acc.rig9".to_string(), reason: None })),
+            signed(SECRET, &payload("ban", Command::BanIp { ip, reason: None, ttl_secs: None }))
+        ], 6).await;
+
+        let applied: Vec<(&str, bool, usize)> = replies.iter().map(|reply| (reply.id.as_str(), reply.ok, reply.applied)).collect();
+        assert_eq!(applied, [
+            ("message", true, 1),
+            ("difficulty", true, 1),
+            ("bad difficulty", false, 0),
+            ("target", true, 1),
+            ("nobody", true, 0),
+            // The ban and the connection of the IP
+            ("ban", true, 2)
+        ]);
+
+        let suggested = proxy.pool_rx.try_recv().unwrap();
+        assert!(suggested.contains("mining.suggest_difficulty") && suggested.contains("65536"));
+        assert_eq!(proxy.overrides.resolve("acc.rig1", "acc", POOL.to_string()).unwrap(), "other.example:3333");
+        assert!(proxy.bans.is_banned(&ip));
+    }
+}
@@ -0,0 +1,63 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+
+use crate::commands::{CommandAck, CommandSource};
+
+// Commands waiting for the consumer, and the last replies kept
+const QUEUE_SIZE: usize = 1024;
+const MAX_REPLIES: usize = 1000;
+
+/// Commands pushed in the proxy instead of a topic, to try them out and to test the consumer
+pub struct MemoryCommandSource {
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: tokio::sync::Mutex<mpsc::Receiver<Vec<u8>>>,
+    replies: Mutex<VecDeque<CommandAck>>
+}
+
+impl Default for MemoryCommandSource {
+    fn default() -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        Self { tx, rx: tokio::sync::Mutex::new(rx), replies: Mutex::new(VecDeque::new()) }
+    }
+}
+
+impl MemoryCommandSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues the command as it would come from the topic, a signed JSON command
+    pub fn push(&self, command: Vec<u8>) -> anyhow::Result<()> {
+        self.tx.try_send(command).map_err(|_| anyhow::anyhow!("The command queue is full"))
+    }
+
+    /// The last replies of the consumer, the oldest first
+    pub fn replies(&self) -> Vec<CommandAck> {
+        self.replies.lock().unwrap_or_else(|err| err.into_inner()).iter().cloned().collect()
+    }
+}
+
+#[async_trait]
+impl CommandSource for MemoryCommandSource {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    // The source holds a sender itself, it's never closed
+    async fn recv(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.rx.lock().await.recv().await)
+    }
+
+    async fn reply(&self, ack: &CommandAck) -> anyhow::Result<()> {
+        let mut replies = self.replies.lock().unwrap_or_else(|err| err.into_inner());
+        if replies.len() >= MAX_REPLIES {
+            replies.pop_front();
+        }
+        replies.push_back(ack.clone());
+
+        Ok(())
+    }
+}
@@ -1,73 +1,152 @@
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU64};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use bytes::BytesMut;
 use serde_json::Value;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::io::{AsyncReadExt, BufReader};
 use tokio::net::TcpStream;
 use tokio::select;
-use tokio::sync::{mpsc, mpsc::Sender, Mutex};
+use tokio::sync::{mpsc, mpsc::Sender};
 use tokio::sync::oneshot;
+use tokio::time::timeout;
 
 use tokio_util::sync::CancellationToken;
 
-use tracing::{debug, info, warn};
+use tracing::{debug, info, trace, warn, Instrument, Span};
 
-use score::job::{Job, JobRequest, ProxyMessage};
-use score::miner::Miner;
+use score::job::{Job, JobRequest, MethodRoutes, PoolMessage, Priority, ProxyMessage};
+use score::miner::{Miner, MinerSnapshot};
+use score::session::SessionHandle;
+use crate::connection::blocks::BlockWatch;
+use crate::connection::pending::{PendingSubmits, ShareOwner};
+use crate::connection::upstreams::UpstreamFilter;
+use crate::connection::writer::{OutboundWriter, ResponseSlot};
+use crate::events::{EventEmitter, EventKind};
 use crate::message::{parse_message::parse_message, Command};
+use crate::metrics::{METRICS, QUEUE_HIGH, QUEUE_NORMAL};
+use crate::passthrough::{Correlator, PassthroughPolicy};
+use crate::registry::{CloseReason, ConnRegistry};
 use crate::server::ConnId;
+use crate::stats::ShareStats;
 use crate::utils::metrics_record_job_outcome;
-use crate::utils::await_and_replay;
+use crate::utils::{await_and_replay, Outcome};
 
-pub static TOTAL_JOBS: AtomicU64 = AtomicU64::new(0);
-pub static TOTAL_JOBS_SUCCEEDED: AtomicU64 = AtomicU64::new(0);
-pub static TOTAL_JOBS_FAILED: AtomicU64 = AtomicU64::new(0);
+pub mod blocks;
+pub mod pending;
+pub mod upstreams;
+pub mod writer;
 
-pub async fn handle_connection(
-    mut socket: TcpStream, token: CancellationToken,
-    conn_id: ConnId, tx_queue_high: Sender<JobRequest>,
-    tx_queue_norm: Sender<JobRequest>, api_url: String
+/// State which every connection shares with the server
+#[derive(Clone)]
+pub(crate) struct ConnShared {
+    pub registry: Arc<ConnRegistry>,
+    pub tx_queue_high: Sender<JobRequest>,
+    pub tx_queue_norm: Sender<JobRequest>,
+    pub routes: Arc<MethodRoutes>,
+    pub passthrough: Arc<PassthroughPolicy>,
+    pub stats: Arc<ShareStats>,
+    pub handshake_timeout: Duration,
+    // How long the pool's answers on the submits are waited for after the miner is gone
+    pub pending_timeout: Duration,
+    pub events: EventEmitter,
+    // The shares are hashed to find the blocks, it costs a bit of CPU per share
+    pub block_candidates: bool
+}
+
+/// What the messages of the pools go through on their way to the miner
+struct PoolRelay {
+    session: SessionHandle,
+    filter: Arc<UpstreamFilter>,
+    writer: OutboundWriter,
+    correlator: Arc<Correlator>,
+    pending: Arc<PendingSubmits>,
+    blocks: Option<Arc<Mutex<BlockWatch>>>
+}
+
+pub(crate) async fn handle_connection(
+    socket: TcpStream, token: CancellationToken,
+    conn_id: ConnId, shared: ConnShared
 ) -> anyhow::Result<()> {
+    let ConnShared {
+        registry, tx_queue_high, tx_queue_norm, routes, passthrough, stats, handshake_timeout, pending_timeout, events,
+        block_candidates
+    } = shared;
     let socket_addr = socket.peer_addr()?;
 
-    let (reader, writer) = socket.into_split();
+    // Whatever way the connection ends, its tasks are stopped
+    let _cancel_on_exit = token.clone().drop_guard();
+
+    let (reader, write_half) = socket.into_split();
     let mut buf = BytesMut::with_capacity(1024);
 
     let mut reader = BufReader::new(reader);
-    let mut writer = Arc::new(Mutex::new(BufWriter::new(writer)));
+    let (writer, writer_join) = OutboundWriter::spawn(write_half, token.clone(), conn_id);
 
     let (miner_tx, miner_rx) = mpsc::channel(12);
+    let (pool_tx, pool_rx) = mpsc::channel(12);
+
+    let session = SessionHandle::spawn(Miner::new(socket_addr, miner_tx, pool_tx));
+    // Submits which wait for the pool's answer, a drain waits for them
+    let pending = Arc::new(PendingSubmits::new(stats, events.clone(), conn_id));
+    registry.attach_session(conn_id, session.clone(), Arc::clone(&pending));
+    // The registry indexes the connection by its workers, it follows the session's changes
+    let mut session_changes = session.clone();
 
-    let miner = Arc::new(Mutex::new(Miner::new(socket_addr, miner_tx)));
+    let correlator = Arc::new(Correlator::new());
+    let blocks = (block_candidates && events.is_enabled()).then(|| Arc::new(Mutex::new(BlockWatch::default())));
 
-    let token_pool_messages = token.clone();
-    process_pool_messages(miner_rx, token_pool_messages, conn_id).await;
+    // The pools' messages are relayed until the submits sent to them are answered, the miner may be gone by then
+    let relay_token = CancellationToken::new();
+    let _stop_relay = relay_token.clone().drop_guard();
+    let filter = Arc::new(UpstreamFilter::default());
+    let relay = PoolRelay {
+        session: session.clone(),
+        filter: Arc::clone(&filter),
+        writer: writer.clone(),
+        correlator: Arc::clone(&correlator),
+        pending: Arc::clone(&pending),
+        blocks: blocks.clone()
+    };
+    process_pool_messages(miner_rx, pool_rx, relay, relay_token, conn_id).await;
+
+    let handshake_deadline = tokio::time::sleep(handshake_timeout);
+    tokio::pin!(handshake_deadline);
+    let mut is_handshake_checked = false;
 
     loop {
-        let miner = Arc::clone(&miner);
         let child_token = token.clone();
         let mut tmp = [0u8;256];
 
         select! {
             _ = child_token.cancelled() => {
-                info!(conn_id, "conn cancelled");
+                debug!(conn_id, "conn cancelled");
                 break;
             }
+            _ = &mut handshake_deadline, if !is_handshake_checked => {
+                let state = session.state();
+                if !state.is_handshake_complete() {
+                    warn!(conn_id, ?state, "The handshake isn't completed in time, closing the connection");
+                    registry.close(conn_id, CloseReason::HandshakeTimeout);
+                    break;
+                }
+                is_handshake_checked = true;
+            }
+            Ok(snapshot) = session_changes.changed() => {
+                registry.update_session(conn_id, &snapshot);
+                record_workers(&snapshot);
+            }
             n = reader.read(&mut tmp) => {
                 let n = n?;
 
                 if n == 0 {
-                    token.cancel();
+                    registry.close(conn_id, CloseReason::ClientClosed);
                     break;
                 }
                 buf.extend_from_slice(&tmp[..n]);
 
-                info!(conn_id, "read {} bytes, buf len = {}", n, buf.len());
-                info!(conn_id, "buf = {:?}", std::str::from_utf8(&buf));
+                trace!(conn_id, "read {} bytes, buf len = {}", n, buf.len());
+                trace!(conn_id, "buf = {:?}", std::str::from_utf8(&buf));
 
                 while let Some(pos) = buf.iter().position(|&b| b == b'\n') {
-                    let miner = Arc::clone(&miner);
-
                     let line = buf.split_to(pos + 1);
                     let line = std::str::from_utf8(&line)?.trim();
 
@@ -75,72 +154,59 @@ pub async fn handle_connection(
 
                     match parse_message(line)? {
                         Command::Ping => {
-                            let (once_tx, mut once_rx) = oneshot::channel::<ProxyMessage>();
-                            let job_request = JobRequest {
-                                job: Job::Ping,
-                                respond_to: once_tx,
-                            };
-
-                            let child_token_clone = child_token.clone();
-                            let writer_clone = Arc::clone(&writer);
-                            tokio::spawn(async move {
-                                let outcome = await_and_replay(writer_clone, once_rx, child_token_clone).await;
-                                metrics_record_job_outcome(outcome);
-                            });
+                            let job_request = new_job_request(Job::Ping, writer.reserve(), &child_token, None);
 
                             tx_queue_norm.send(job_request).await?;
+                            METRICS.set_queue_depth(QUEUE_NORMAL, queue_depth(&tx_queue_norm));
                         },
-                        Command::CSubmit(submit) => {
-                            let (once_tx, mut once_rx) = oneshot::channel::<ProxyMessage>();
-                            let job_request = JobRequest {
-                                job: Job::MiningSubmit((submit, miner)),
-                                respond_to: once_tx,
+                        Command::Call(call) => {
+                            let Some(priority) = routes.priority(&call.method) else {
+                                if passthrough.allows(&call.method) {
+                                    let request = correlator.outgoing(call.message);
+                                    forward_to_pool(&session, None, request, Some((writer.reserve(), call.id))).await?;
+                                } else {
+                                    info!(conn_id, method = %call.method, "no handler for the method");
+                                    writer.reserve().send("BAD COMMAND\n".to_string())?;
+                                }
+                                continue;
                             };
 
-                            let child_token_clone = child_token.clone();
-                            let writer_clone = Arc::clone(&writer);
-                            tokio::spawn(async move {
-                                let outcome = await_and_replay(writer_clone, once_rx, child_token_clone.clone()).await;
-                                metrics_record_job_outcome(outcome);
+                            if call.method == "mining.configure" || call.method == "mining.subscribe" {
+                                filter.handshake_request(&call.id);
+                            }
+                            let submit = (call.method == "mining.submit").then(|| {
+                                let (owner, primary_pool) = share_owner(&session, &call.message);
+                                let job_id = call.message["params"][1].as_str().unwrap_or_default();
+                                pending.insert(&call.id, owner.clone(), job_id);
+                                if let Some(blocks) = &blocks && primary_pool.is_some_and(|pool| pool == owner.pool) {
+                                    report_block(blocks, &call.message, &owner, &events, conn_id);
+                                }
+                                (Arc::clone(&pending), call.id.clone())
                             });
+                            let job_request = new_job_request(Job::Method((call, session.clone())), writer.reserve(), &child_token, submit);
 
-                            tx_queue_high.send(job_request).await?;
+                            match priority {
+                                Priority::High => {
+                                    tx_queue_high.send(job_request).await?;
+                                    METRICS.set_queue_depth(QUEUE_HIGH, queue_depth(&tx_queue_high));
+                                }
+                                Priority::Norm => {
+                                    tx_queue_norm.send(job_request).await?;
+                                    METRICS.set_queue_depth(QUEUE_NORMAL, queue_depth(&tx_queue_norm));
+                                }
+                            }
                         },
-                        Command::CSubscribe(subscribe) => {
-                            let (once_tx, mut once_rx) = oneshot::channel::<ProxyMessage>();
-                            let job_request = JobRequest {
-                                job: Job::MiningSubscribe((subscribe, miner)),
-                                respond_to: once_tx
-                            };
-
-                            let child_token_clone = child_token.clone();
-                            let writer_clone = Arc::clone(&writer);
-                            tokio::spawn(async move {
-                                let outcome = await_and_replay(writer_clone, once_rx, child_token_clone).await;
-                                metrics_record_job_outcome(outcome);
-                            });
-
-                            tx_queue_high.send(job_request).await?;
-                        },
-                        Command::CAuthorize(authorize) => {
-                            let (once_tx, mut once_rx) = oneshot::channel::<ProxyMessage>();
-                            let job_request = JobRequest {
-                                job: Job::MiningAuthorize((authorize, miner)),
-                                respond_to: once_tx
-                            };
-
-                            let child_token_clone = child_token.clone();
-                            let writer_clone = Arc::clone(&writer);
-                            tokio::spawn(async move {
-                                let outcome = await_and_replay(writer_clone, once_rx, child_token_clone).await;
-                                metrics_record_job_outcome(outcome);
-                            });
-
-                            tx_queue_high.send(job_request).await?;
+                        Command::Reply(reply) => {
+                            if passthrough.is_enabled() {
+                                let pool = correlator.reply_pool(&reply);
+                                forward_to_pool(&session, pool.as_deref(), reply.to_string(), None).await?;
+                            } else {
+                                debug!(conn_id, "reply from the miner is dropped: {}", reply);
+                            }
                         }
                         Command::Unknown => {
-                            info!("line: {:?}", line);
-                            writer.lock().await.write_all(b"BAD COMMAND\n").await?;
+                            debug!("line: {:?}", line);
+                            writer.reserve().send("BAD COMMAND\n".to_string())?;
                         }
                     }
                 }
@@ -148,30 +214,169 @@ pub async fn handle_connection(
         }
     }
 
+    // Let the writer flush what is queued already
+    token.cancel();
+    let _ = writer_join.await;
+
+    // The pool's answers on the submits sent already are counted, e.g. of a miner told to reconnect by a drain
+    if !pending.is_empty() && timeout(pending_timeout, pending.answered()).await.is_err() {
+        warn!(conn_id, pending = pending.len(), "The pool hasn't answered every submit of the closed connection");
+    }
+
+    Ok(())
+}
+
+/// Builds a job request and spawns the task which writes the job's answer back to the miner. A submit
+/// which the proxy answers itself isn't waiting for the pool anymore
+fn new_job_request(
+    job: Job, slot: ResponseSlot, token: &CancellationToken, submit: Option<(Arc<PendingSubmits>, Value)>
+) -> JobRequest {
+    let (once_tx, once_rx) = oneshot::channel::<ProxyMessage>();
+
+    let token = token.clone();
+    tokio::spawn(async move {
+        let outcome = await_and_replay(slot, once_rx, token).await;
+        if let Some((pending, id)) = submit && !matches!(outcome, Outcome::NoReply) {
+            pending.remove(&id);
+        }
+        metrics_record_job_outcome(outcome);
+    }.in_current_span());
+
+    JobRequest {
+        job,
+        respond_to: once_tx,
+        span: Span::current()
+    }
+}
+
+/// Puts the workers of the connection and their subaccounts on its span, every line the connection
+/// logs carries them
+fn record_workers(snapshot: &MinerSnapshot) {
+    let workers: Vec<&str> = snapshot.workers.iter().map(|worker| worker.worker_name.as_str()).collect();
+    let mut sub_accounts: Vec<&str> = snapshot.workers.iter().map(|worker| worker.sub_account_name.as_str()).collect();
+    sub_accounts.sort_unstable();
+    sub_accounts.dedup();
+
+    let span = Span::current();
+    span.record("worker", workers.join(","));
+    span.record("subaccount", sub_accounts.join(","));
+}
+
+/// Worker the submit is from, its subaccount and pool, the pool's answer is counted for them. And the
+/// session's primary pool, the miner works on its jobs
+fn share_owner(session: &SessionHandle, message: &Value) -> (ShareOwner, Option<String>) {
+    let worker_name = message["params"][0].as_str().unwrap_or_default();
+
+    let snapshot = session.snapshot();
+    let (sub_account, pool) = snapshot.workers.iter()
+        .find(|worker| worker.worker_name == worker_name)
+        .map(|worker| (worker.sub_account_name.clone(), worker.pool_addr.clone()))
+        .unwrap_or_default();
+
+    (ShareOwner { worker: worker_name.to_string(), sub_account, pool }, snapshot.primary_pool.clone())
+}
+
+/// Publishes the share if it's a block
+fn report_block(blocks: &Mutex<BlockWatch>, submit: &Value, owner: &ShareOwner, events: &EventEmitter, conn_id: ConnId) {
+    let candidate = blocks.lock().unwrap_or_else(|err| err.into_inner()).check(submit);
+
+    if let Some(candidate) = candidate {
+        info!(conn_id, worker = %owner.worker, job_id = %candidate.job_id, hash = %candidate.hash, "The share is a block candidate!");
+        events.emit(conn_id, &owner.worker, &owner.sub_account, EventKind::BlockCandidate {
+            job_id: candidate.job_id,
+            hash: candidate.hash,
+            pool: owner.pool.clone()
+        });
+    }
+}
+
+fn queue_depth<T>(tx: &Sender<T>) -> usize {
+    tx.max_capacity() - tx.capacity()
+}
+
+/// Sends the message as is to the miner's session with the pool, or with the primary worker's pool if
+/// there is no pool. If the miner has no such session and the message is a request, it's answered with
+/// an error
+async fn forward_to_pool(
+    session: &SessionHandle, pool: Option<&str>, message: String, request: Option<(ResponseSlot, Value)>
+) -> anyhow::Result<()> {
+    let pool_tx = match pool {
+        Some(pool) => session.pool_tx_for(pool).await?,
+        None => session.routing().await?.pool_tx
+    };
+
+    match (pool_tx, request) {
+        (Some(pool_tx), _) => pool_tx.send(message).await?,
+        (None, None) => {
+            warn!("There is no pool session to forward to, message is dropped: {}", message);
+        }
+        (None, Some((slot, request_id))) => {
+            if let ProxyMessage::Response(error) = ProxyMessage::error_response(&request_id, 24, "Unauthorized worker") {
+                slot.send(error.into_owned())?;
+            }
+        }
+    }
+
     Ok(())
 }
 
-async fn process_pool_messages(mut miner_rx: mpsc::Receiver<String>, token: CancellationToken, conn_id: ConnId) {
-    let notify_handle =  tokio::spawn(async move {
+/// Relays the messages of the pools and everything sent to the miner's outbound channel (notifications
+/// of the proxy) to the socket
+async fn process_pool_messages(
+    mut miner_rx: mpsc::Receiver<String>, mut pool_rx: mpsc::Receiver<PoolMessage>, relay: PoolRelay,
+    token: CancellationToken, conn_id: ConnId
+) {
+    let PoolRelay { session, filter, writer, correlator, pending, blocks } = relay;
+    tokio::spawn(async move {
+        // The answers on the submits are still counted once the miner can't be written to
+        let mut is_miner_gone = false;
         loop {
             select! {
                 _ = token.cancelled() => {
-                    info!("process miner notify is closed for connId: {}", conn_id);
+                    debug!("process miner notify is closed for connId: {}", conn_id);
                     break;
                 }
                 msg = miner_rx.recv() => {
+                    let Some(msg) = msg else {
+                        warn!("msg to miner is none");
+                        break;
+                    };
+                    trace!("msg to miner -> {}", msg);
+                    if !is_miner_gone && let Err(err) = writer.notify(msg) {
+                        debug!(conn_id, "Couldn't write to miner: {}", err);
+                        is_miner_gone = true;
+                    }
+                }
+                msg = pool_rx.recv() => {
                     match msg {
                         None => {
                             warn!("msg from pool is none");
+                            break;
                         }
-                        Some(msg) => {
-                            let json = serde_json::from_str::<Value>(msg.as_str());
-                            info!("json from pool -> {:?}", json);
+                        Some(PoolMessage { pool, line: msg }) => {
+                            let (msg, is_passthrough_answer) = correlator.incoming(&pool, msg);
+                            pending.resolve(&pool, &msg);
+                            if is_miner_gone {
+                                continue;
+                            }
+                            // The miner asked for it, whatever its id is
+                            if !is_passthrough_answer && !filter.admits(&pool, session.snapshot().primary_pool.as_deref(), &msg) {
+                                trace!(%pool, "msg from pool is dropped -> {}", msg);
+                                continue;
+                            }
+                            if let Some(blocks) = &blocks {
+                                blocks.lock().unwrap_or_else(|err| err.into_inner()).observe(&msg);
+                            }
+                            trace!("msg to miner -> {}", msg);
+                            if let Err(err) = writer.notify(msg) {
+                                debug!(conn_id, "Couldn't write to miner: {}", err);
+                                is_miner_gone = true;
+                            }
                         }
                     };
 
                 }
             }
         }
-    });
-}
\ No newline at end of file
+    }.in_current_span());
+}
@@ -0,0 +1,156 @@
+use std::collections::VecDeque;
+
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+// Jobs a share may still be submitted for
+const MAX_JOBS: usize = 8;
+
+/// Follows the jobs the pool sends to the miner and hashes the miner's shares against them, a share
+/// whose hash meets the network's target is a block. Only the jobs of the connection's primary pool
+/// reach the miner, every worker of the connection is on it
+#[derive(Debug, Default)]
+pub(crate) struct BlockWatch {
+    extranonce1: Vec<u8>,
+    version_mask: u32,
+    jobs: VecDeque<Template>
+}
+
+#[derive(Debug)]
+struct Template {
+    job_id: String,
+    extranonce1: Vec<u8>,
+    prevhash: [u8; 32], // in the order of the header
+    coinb1: Vec<u8>,
+    coinb2: Vec<u8>,
+    branches: Vec<[u8; 32]>,
+    version: u32,
+    nbits: u32
+}
+
+/// A share which is a block
+#[derive(Debug, Clone)]
+pub(crate) struct Candidate {
+    pub job_id: String,
+    pub hash: String // as the explorers show it
+}
+
+impl BlockWatch {
+    /// Takes the extranonce, the version rolling mask and the jobs out of a message of the pool
+    pub fn observe(&mut self, message: &str) {
+        let is_relevant = ["notify", "extranonce", "version-rolling", "[["].iter().any(|needle| message.contains(needle));
+        if !is_relevant {
+            return;
+        }
+        let Ok(json) = serde_json::from_str::<Value>(message) else {
+            return;
+        };
+
+        match json["method"].as_str() {
+            Some("mining.notify") => self.add_job(&json["params"]),
+            Some("mining.set_extranonce") => {
+                if let Some(extranonce1) = json["params"][0].as_str().and_then(|hex| hex::decode(hex).ok()) {
+                    self.extranonce1 = extranonce1;
+                }
+            }
+            Some(_) => {}
+            None => {
+                let result = &json["result"];
+                // The subscribe's answer: [subscriptions, extranonce1, extranonce2 size]
+                if result[0].is_array() && let Some(extranonce1) = result[1].as_str().and_then(|hex| hex::decode(hex).ok()) {
+                    self.extranonce1 = extranonce1;
+                }
+                // The configure's answer
+                if let Some(mask) = result["version-rolling.mask"].as_str().and_then(|mask| u32::from_str_radix(mask, 16).ok()) {
+                    self.version_mask = mask;
+                }
+            }
+        }
+    }
+
+    /// The block of a mining.submit, if its hash meets the network's target
+    pub fn check(&self, submit: &Value) -> Option<Candidate> {
+        let params = &submit["params"];
+        let job_id = params[1].as_str()?;
+        let job = self.jobs.iter().find(|job| job.job_id == job_id)?;
+
+        let extranonce2 = hex::decode(params[2].as_str()?).ok()?;
+        let ntime = u32::from_str_radix(params[3].as_str()?, 16).ok()?;
+        let nonce = u32::from_str_radix(params[4].as_str()?, 16).ok()?;
+        let version = match params[5].as_str().and_then(|bits| u32::from_str_radix(bits, 16).ok()) {
+            Some(bits) => (job.version & !self.version_mask) | (bits & self.version_mask),
+            None => job.version
+        };
+
+        let coinbase = [&job.coinb1[..], &job.extranonce1, &extranonce2, &job.coinb2].concat();
+        let root = job.branches.iter().fold(sha256d(&coinbase), |root, branch| sha256d(&[root, *branch].concat()));
+
+        let mut header = Vec::with_capacity(80);
+        header.extend_from_slice(&version.to_le_bytes());
+        header.extend_from_slice(&job.prevhash);
+        header.extend_from_slice(&root);
+        header.extend_from_slice(&ntime.to_le_bytes());
+        header.extend_from_slice(&job.nbits.to_le_bytes());
+        header.extend_from_slice(&nonce.to_le_bytes());
+
+        let mut hash = sha256d(&header);
+        hash.reverse();
+        (hash <= target(job.nbits)).then(|| Candidate { job_id: job_id.to_string(), hash: hex::encode(hash) })
+    }
+
+    // [job_id, prevhash, coinb1, coinb2, merkle branches, version, nbits, ntime, clean jobs]
+    fn add_job(&mut self, params: &Value) {
+        let Some(job) = self.template(params) else {
+            return;
+        };
+
+        if params[8].as_bool() == Some(true) {
+            self.jobs.clear();
+        }
+        if self.jobs.len() >= MAX_JOBS {
+            self.jobs.pop_front();
+        }
+        self.jobs.push_back(job);
+    }
+
+    fn template(&self, params: &Value) -> Option<Template> {
+        // The pools send the previous hash with the bytes of every 4 byte word swapped
+        let mut prevhash: [u8; 32] = hex::decode(params[1].as_str()?).ok()?.try_into().ok()?;
+        prevhash.chunks_exact_mut(4).for_each(<[u8]>::reverse);
+
+        let branches = params[4].as_array()?.iter()
+            .map(|branch| hex::decode(branch.as_str()?).ok()?.try_into().ok())
+            .collect::<Option<Vec<[u8; 32]>>>()?;
+
+        Some(Template {
+            job_id: params[0].as_str()?.to_string(),
+            extranonce1: self.extranonce1.clone(),
+            prevhash,
+            coinb1: hex::decode(params[2].as_str()?).ok()?,
+            coinb2: hex::decode(params[3].as_str()?).ok()?,
+            branches,
+            version: u32::from_str_radix(params[5].as_str()?, 16).ok()?,
+            nbits: u32::from_str_radix(params[6].as_str()?, 16).ok()?
+        })
+    }
+}
+
+fn sha256d(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(Sha256::digest(data)).into()
+}
+
+// The target of the compact nbits, big endian
+fn target(nbits: u32) -> [u8; 32] {
+    let exponent = (nbits >> 24) as usize;
+    let mantissa = (nbits & 0x007f_ffff).to_be_bytes();
+    let mut target = [0u8; 32];
+
+    // The mantissa is the 3 bytes which end `exponent` bytes from the end
+    for (i, byte) in mantissa[1..].iter().enumerate() {
+        if let Some(position) = (32 + i).checked_sub(exponent) && position < 32 {
+            target[position] = *byte;
+        }
+    }
+
+    target
+}
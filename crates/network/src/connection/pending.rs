@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+use tokio::sync::Notify;
+
+use crate::events::{EventEmitter, EventKind};
+use crate::metrics::{reject_reason, METRICS};
+use crate::server::ConnId;
+use crate::stats::{ShareStats, Verdict};
+
+// A pool which hasn't answered on a submit in this time isn't going to answer
+const PENDING_SUBMIT_TTL: Duration = Duration::from_secs(60);
+const MAX_PENDING_SUBMITS: usize = 1024;
+
+/// Submits of a connection which wait for the pool's answer, by the miner's request id
+#[derive(Debug)]
+pub struct PendingSubmits {
+    pending: Mutex<HashMap<String, PendingSubmit>>,
+    stats: Arc<ShareStats>,
+    events: EventEmitter,
+    conn_id: ConnId,
+    // Last mining.set_difficulty of every pool the workers are on. Stratum starts with 1
+    difficulties: Mutex<HashMap<String, f64>>,
+    // Woken when the last submit is taken out
+    answered: Notify
+}
+
+#[derive(Debug)]
+struct PendingSubmit {
+    sent: Instant,
+    worker: String,
+    sub_account: String, // the answer is counted for it
+    difficulty: f64
+}
+
+/// Worker a share is from, its subaccount and the pool it goes to
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ShareOwner {
+    pub worker: String,
+    pub sub_account: String,
+    pub pool: String
+}
+
+impl PendingSubmits {
+    pub fn new(stats: Arc<ShareStats>, events: EventEmitter, conn_id: ConnId) -> Self {
+        Self {
+            pending: Mutex::new(HashMap::new()),
+            stats,
+            events,
+            conn_id,
+            difficulties: Mutex::new(HashMap::new()),
+            answered: Notify::new()
+        }
+    }
+
+    /// Difficulty of the shares the miner submits to the pool now
+    pub fn difficulty(&self, pool: &str) -> f64 {
+        let difficulties = self.difficulties.lock().unwrap_or_else(|err| err.into_inner());
+        difficulties.get(pool).copied().unwrap_or(1.0)
+    }
+
+    pub(crate) fn insert(&self, id: &Value, owner: ShareOwner, job_id: &str) {
+        let difficulty = self.difficulty(&owner.pool);
+        let ShareOwner { worker, sub_account, pool } = owner;
+        self.events.emit(self.conn_id, &worker, &sub_account, EventKind::ShareSubmitted {
+            job_id: job_id.to_string(),
+            difficulty,
+            pool
+        });
+
+        let mut pending = self.pending.lock().unwrap_or_else(|err| err.into_inner());
+
+        if pending.len() >= MAX_PENDING_SUBMITS {
+            pending.retain(|_, submit| submit.sent.elapsed() < PENDING_SUBMIT_TTL);
+        }
+        if pending.len() < MAX_PENDING_SUBMITS {
+            pending.insert(id.to_string(), PendingSubmit { sent: Instant::now(), worker, sub_account, difficulty });
+        }
+    }
+
+    pub fn remove(&self, id: &Value) {
+        self.take(id);
+    }
+
+    fn take(&self, id: &Value) -> Option<PendingSubmit> {
+        let mut pending = self.pending.lock().unwrap_or_else(|err| err.into_inner());
+        if pending.is_empty() {
+            return None;
+        }
+
+        let submit = pending.remove(&id.to_string());
+        if pending.is_empty() {
+            self.answered.notify_waiters();
+        }
+
+        submit
+    }
+
+    /// Waits until the pool has answered every submit
+    pub async fn answered(&self) {
+        loop {
+            let answered = self.answered.notified();
+            if self.is_empty() {
+                return;
+            }
+            answered.await;
+        }
+    }
+
+    /// Takes the submit out if the message is the pool's answer on it and counts the answer. The
+    /// difficulty the pool sets is kept for the next submits to it
+    pub fn resolve(&self, pool: &str, message: &str) {
+        let is_difficulty = message.contains("mining.set_difficulty");
+        if self.is_empty() && !is_difficulty {
+            return;
+        }
+
+        let Ok(json) = serde_json::from_str::<Value>(message) else {
+            return;
+        };
+        if is_difficulty && json["method"] == "mining.set_difficulty" {
+            if let Some(difficulty) = json["params"][0].as_f64().filter(|difficulty| *difficulty > 0.0) {
+                let mut difficulties = self.difficulties.lock().unwrap_or_else(|err| err.into_inner());
+                difficulties.insert(pool.to_string(), difficulty);
+            }
+            return;
+        }
+
+        if json.get("method").is_none() && let Some(id) = json.get("id") && let Some(submit) = self.take(id) {
+            let reason = reject_reason(&json);
+            let latency = submit.sent.elapsed();
+            METRICS.share_answered(&submit.sub_account, reason);
+            self.stats.record(
+                &submit.worker, &submit.sub_account, submit.difficulty, Verdict::from_reject_reason(reason), latency
+            );
+
+            let (difficulty, latency_ms) = (submit.difficulty, latency.as_millis() as u64);
+            let kind = match reason {
+                None => EventKind::ShareAccepted { difficulty, latency_ms },
+                Some(reason) => EventKind::ShareRejected { difficulty, latency_ms, reason: reason.to_string() }
+            };
+            self.events.emit(self.conn_id, &submit.worker, &submit.sub_account, kind);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        let pending = self.pending.lock().unwrap_or_else(|err| err.into_inner());
+        pending.values().filter(|submit| submit.sent.elapsed() < PENDING_SUBMIT_TTL).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
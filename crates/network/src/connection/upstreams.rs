@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde_json::Value;
+
+/// Picks the messages of the pools which reach the miner. The miner works on the jobs of one pool, the
+/// session's primary pool, so the notifications of any other pool session (jobs, difficulty,
+/// extranonce) aren't relayed. Every new pool session is sent the miner's handshake again, the miner
+/// gets only the first answer on each request. Answers on the forwarded passthrough requests don't
+/// come here, their ids may be the handshake's ones
+#[derive(Debug, Default)]
+pub(crate) struct UpstreamFilter {
+    // Ids of the miner's configure and subscribe, and whether a pool has answered them already
+    handshake: Mutex<HashMap<String, bool>>
+}
+
+impl UpstreamFilter {
+    /// Notes the id of the miner's mining.configure or mining.subscribe
+    pub fn handshake_request(&self, id: &Value) {
+        self.handshake.lock().unwrap_or_else(|err| err.into_inner()).entry(id.to_string()).or_insert(false);
+    }
+
+    /// Whether the message of `pool` is relayed to the miner
+    pub fn admits(&self, pool: &str, primary_pool: Option<&str>, message: &str) -> bool {
+        let Ok(json) = serde_json::from_str::<Value>(message) else {
+            return true;
+        };
+
+        if json.get("method").is_some() {
+            // The requests of every pool are relayed, the miner's replies go back to the pool which asked
+            return !json["id"].is_null() || primary_pool.is_none_or(|primary| primary == pool);
+        }
+
+        // The answers on the shares and the authorizes are bool, a miner may use the handshake's ids for them again
+        if json["result"].is_boolean() {
+            return true;
+        }
+        let mut handshake = self.handshake.lock().unwrap_or_else(|err| err.into_inner());
+        match handshake.get_mut(&json["id"].to_string()) {
+            Some(is_answered) => !std::mem::replace(is_answered, true),
+            None => true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn only_the_first_handshake_answer_is_relayed() {
+        let filter = UpstreamFilter::default();
+        filter.handshake_request(&json!(1));
+
+        let answer = json!({"id": 1, "result": [[], "08000002", 4], "error": null}).to_string();
+        assert!(filter.admits("a:3333", Some("a:3333"), &answer));
+        assert!(!filter.admits("b:3333", Some("a:3333"), &answer));
+
+        // The shares and the authorizes may use the id again
+        assert!(filter.admits("a:3333", Some("a:3333"), r#"{"id": 1, "result": true, "error": null}"#));
+        assert!(filter.admits("a:3333", Some("a:3333"), r#"{"id": 2, "result": [1], "error": null}"#));
+    }
+
+    #[test]
+    fn notifications_of_other_pools_are_dropped() {
+        let filter = UpstreamFilter::default();
+        let notify = r#"{"id": null, "method": "mining.set_difficulty", "params": [1024]}"#;
+
+        assert!(filter.admits("a:3333", Some("a:3333"), notify));
+        assert!(!filter.admits("b:3333", Some("a:3333"), notify));
+        assert!(filter.admits("b:3333", Some("a:3333"), r#"{"id": 5, "method": "client.get_version", "params": []}"#));
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use thiserror::Error;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use tracing::{debug, warn, Instrument};
+
+use crate::server::ConnId;
+
+// Messages waiting to be written, if a queue is full the miner doesn't read fast enough
+const NOTIFY_QUEUE_SIZE: usize = 64;
+const RESPONSE_QUEUE_SIZE: usize = 256;
+// Responses which are ready but wait for the responses on the earlier requests
+const MAX_REORDER_BUFFER: usize = 256;
+// How many messages are written before the buffer is flushed
+const MAX_BATCH: usize = 32;
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+// When the connection is closed, messages queued already get this long to be written
+const CLOSE_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Error)]
+pub enum WriterError {
+    #[error("outbound queue is full, the miner is too slow")]
+    Overflow,
+    #[error("outbound writer is closed")]
+    Closed
+}
+
+/// The single writer of a connection's socket. Notifications are written before responses, responses
+/// are written in the order of the requests
+#[derive(Debug, Clone)]
+pub struct OutboundWriter {
+    tx_notify: mpsc::Sender<String>,
+    tx_response: mpsc::Sender<(u64, Option<String>)>,
+    next_seq: Arc<AtomicU64>,
+    token: CancellationToken
+}
+
+/// A place of a request in the order of responses. If the slot is dropped without an answer,
+/// the next responses aren't held by it
+#[derive(Debug)]
+pub struct ResponseSlot {
+    seq: u64,
+    tx_response: Option<mpsc::Sender<(u64, Option<String>)>>,
+    token: CancellationToken
+}
+
+impl OutboundWriter {
+    pub fn spawn<W>(write_half: W, token: CancellationToken, conn_id: ConnId) -> (Self, JoinHandle<()>)
+    where
+        W: AsyncWrite + Unpin + Send + 'static
+    {
+        let (tx_notify, rx_notify) = mpsc::channel(NOTIFY_QUEUE_SIZE);
+        let (tx_response, rx_response) = mpsc::channel(RESPONSE_QUEUE_SIZE);
+
+        let join = tokio::spawn(run_writer(BufWriter::new(write_half), rx_notify, rx_response, token.clone(), conn_id).in_current_span());
+
+        let writer = Self {
+            tx_notify,
+            tx_response,
+            next_seq: Arc::new(AtomicU64::new(0)),
+            token
+        };
+
+        (writer, join)
+    }
+
+    /// Takes the next place in the order of responses. It has to be called in the order the requests are read
+    pub fn reserve(&self) -> ResponseSlot {
+        ResponseSlot {
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+            tx_response: Some(self.tx_response.clone()),
+            token: self.token.clone()
+        }
+    }
+
+    /// Queues a message which isn't an answer on a request (mining.notify, pool responses, etc.)
+    pub fn notify(&self, message: String) -> Result<(), WriterError> {
+        push(&self.tx_notify, message, &self.token)
+    }
+}
+
+impl ResponseSlot {
+    pub fn send(mut self, response: String) -> Result<(), WriterError> {
+        let tx_response = self.tx_response.take().ok_or(WriterError::Closed)?;
+        push(&tx_response, (self.seq, Some(response)), &self.token)
+    }
+}
+
+impl Drop for ResponseSlot {
+    fn drop(&mut self) {
+        if let Some(tx_response) = self.tx_response.take() {
+            let _ = push(&tx_response, (self.seq, None), &self.token);
+        }
+    }
+}
+
+fn push<T>(tx: &mpsc::Sender<T>, message: T, token: &CancellationToken) -> Result<(), WriterError> {
+    match tx.try_send(message) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(_)) => {
+            // The miner doesn't read, there is no sense to keep the connection
+            token.cancel();
+            Err(WriterError::Overflow)
+        }
+        Err(TrySendError::Closed(_)) => Err(WriterError::Closed)
+    }
+}
+
+async fn run_writer<W: AsyncWrite + Unpin>(
+    mut writer: BufWriter<W>, mut rx_notify: mpsc::Receiver<String>,
+    mut rx_response: mpsc::Receiver<(u64, Option<String>)>, token: CancellationToken, conn_id: ConnId
+) {
+    let mut next_seq: u64 = 0;
+    let mut reorder: BTreeMap<u64, Option<String>> = BTreeMap::new();
+    let mut batch: Vec<String> = Vec::with_capacity(MAX_BATCH);
+
+    loop {
+        select! {
+            biased;
+            _ = token.cancelled() => {
+                while let Ok(message) = rx_notify.try_recv() {
+                    batch.push(message);
+                }
+                while let Ok((seq, response)) = rx_response.try_recv() {
+                    reorder.insert(seq, response);
+                }
+                batch.extend(reorder.into_values().flatten());
+
+                let _ = tokio::time::timeout(CLOSE_FLUSH_TIMEOUT, write_batch(&mut writer, &mut batch)).await;
+                break;
+            }
+            Some(message) = rx_notify.recv() => batch.push(message),
+            Some((seq, response)) = rx_response.recv() => {
+                reorder.insert(seq, response);
+            }
+            else => break
+        }
+
+        // Take whatever else is ready, so it's written with a single flush
+        while batch.len() < MAX_BATCH {
+            match rx_notify.try_recv() {
+                Ok(message) => batch.push(message),
+                Err(_) => break
+            }
+        }
+        while let Ok((seq, response)) = rx_response.try_recv() {
+            reorder.insert(seq, response);
+        }
+
+        while let Some(response) = reorder.remove(&next_seq) {
+            next_seq += 1;
+            if let Some(response) = response {
+                batch.push(response);
+            }
+        }
+
+        if reorder.len() > MAX_REORDER_BUFFER {
+            warn!(conn_id, waiting_for = next_seq, "Too many responses wait for an earlier one, closing the connection");
+            token.cancel();
+            break;
+        }
+
+        if batch.is_empty() {
+            continue;
+        }
+
+        match tokio::time::timeout(WRITE_TIMEOUT, write_batch(&mut writer, &mut batch)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                warn!(conn_id, "Couldn't write to miner: {:?}", err);
+                token.cancel();
+                break;
+            }
+            Err(_) => {
+                warn!(conn_id, "Write to miner timed out, closing the connection");
+                token.cancel();
+                break;
+            }
+        }
+    }
+
+    let _ = tokio::time::timeout(CLOSE_FLUSH_TIMEOUT, writer.shutdown()).await;
+    debug!(conn_id, "outbound writer is closed");
+}
+
+async fn write_batch<W: AsyncWrite + Unpin>(writer: &mut BufWriter<W>, batch: &mut Vec<String>) -> std::io::Result<()> {
+    for mut message in batch.drain(..) {
+        if !message.ends_with('\n') {
+            message.push('\n');
+        }
+        writer.write_all(message.as_bytes()).await?;
+    }
+
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, BufReader, DuplexStream, Lines};
+
+    use super::*;
+
+    fn writer(buffer: usize) -> (OutboundWriter, JoinHandle<()>, Lines<BufReader<DuplexStream>>, CancellationToken) {
+        let (ours, theirs) = tokio::io::duplex(buffer);
+        let token = CancellationToken::new();
+        let (writer, join) = OutboundWriter::spawn(ours, token.clone(), 1);
+
+        (writer, join, BufReader::new(theirs).lines(), token)
+    }
+
+    #[tokio::test]
+    async fn responses_are_written_in_the_order_of_requests() {
+        let (writer, _join, mut lines, _token) = writer(4096);
+
+        let (first, second, third) = (writer.reserve(), writer.reserve(), writer.reserve());
+        third.send("3".to_string()).unwrap();
+        second.send("2".to_string()).unwrap();
+        tokio::task::yield_now().await;
+        first.send("1".to_string()).unwrap();
+
+        for expected in ["1", "2", "3"] {
+            assert_eq!(lines.next_line().await.unwrap().unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn dropped_slot_does_not_hold_the_next_responses() {
+        let (writer, _join, mut lines, _token) = writer(4096);
+
+        let (first, second) = (writer.reserve(), writer.reserve());
+        second.send("2".to_string()).unwrap();
+        drop(first);
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "2");
+
+        let third = writer.reserve();
+        third.send("3".to_string()).unwrap();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "3");
+    }
+
+    #[tokio::test]
+    async fn full_queue_closes_the_connection() {
+        let (writer, join, _lines, token) = writer(4096);
+
+        // The writer doesn't run until the test yields, so nothing is taken from the queue
+        for _ in 0..NOTIFY_QUEUE_SIZE {
+            writer.notify("notify".to_string()).unwrap();
+        }
+        assert!(matches!(writer.notify("notify".to_string()), Err(WriterError::Overflow)));
+        assert!(token.is_cancelled());
+
+        join.await.unwrap();
+        assert!(matches!(writer.notify("notify".to_string()), Err(WriterError::Closed)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_write_closes_the_connection() {
+        // The miner doesn't read, a line doesn't fit into the pipe
+        let (writer, join, _lines, token) = writer(8);
+
+        writer.notify("a line longer than the pipe".to_string()).unwrap();
+        tokio::time::timeout(WRITE_TIMEOUT * 2, join).await.unwrap().unwrap();
+        assert!(token.is_cancelled());
+    }
+}
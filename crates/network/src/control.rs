@@ -0,0 +1,309 @@
+use std::ffi::OsString;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+
+use tracing::{info, warn};
+
+use config::reload::ConfigReloader;
+use control_protocol::{ControlRequest, ControlResponse, PoolRow, Status};
+
+use crate::bans::BanList;
+use crate::registry::{ConnRegistry, Selector};
+use crate::upgrade::{self, UpgradeController};
+use crate::upstream::overrides::PoolOverrides;
+
+// A request is a single short line, anything longer isn't a request
+const MAX_REQUEST_LEN: u64 = 64 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Everything the control commands work with
+#[derive(Clone)]
+pub struct ControlState {
+    pub registry: Arc<ConnRegistry>,
+    pub overrides: Arc<PoolOverrides>,
+    pub bans: Arc<BanList>,
+    pub upgrade: Arc<UpgradeController>,
+    pub reloader: Arc<ConfigReloader>,
+    started: Instant
+}
+
+/// Local control socket of the proxy, it's what `proxy-gatesctl` talks to. Access is limited by
+/// the permissions of the socket file
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+    state: ControlState,
+    shutdown: CancellationToken
+}
+
+impl ControlState {
+    pub fn new(
+        registry: Arc<ConnRegistry>, overrides: Arc<PoolOverrides>, bans: Arc<BanList>,
+        upgrade: Arc<UpgradeController>, reloader: Arc<ConfigReloader>
+    ) -> Self {
+        Self {
+            registry,
+            overrides,
+            bans,
+            upgrade,
+            reloader,
+            started: Instant::now()
+        }
+    }
+}
+
+impl ControlServer {
+    pub fn new(
+        listener: UnixListener, path: impl Into<PathBuf>, state: ControlState, shutdown: CancellationToken
+    ) -> Self {
+        Self { listener, path: path.into(), state, shutdown }
+    }
+
+    /// Binds the socket file at `path`, only the owner may connect. The socket is bound in a directory
+    /// only the owner may enter and moved to `path` once it's 0600, so others can't connect meanwhile
+    pub fn bind(path: &Path) -> anyhow::Result<UnixListener> {
+        // Another proxy listens on the file, and a socket file left by a process which is gone is
+        // removed. Anything else at `path` isn't touched
+        match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => anyhow::bail!("control socket {} is in use", path.display()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {
+                // Linux refuses a connection to a file which isn't a socket as well
+                if !std::fs::symlink_metadata(path)?.file_type().is_socket() {
+                    anyhow::bail!("control socket {} isn't a socket", path.display());
+                }
+                std::fs::remove_file(path)?;
+            }
+            Err(err) => anyhow::bail!("control socket {} can't be checked: {}", path.display(), err)
+        }
+
+        let file_name = path.file_name().ok_or_else(|| anyhow::anyhow!("{} isn't a file path", path.display()))?;
+        let mut private_name = OsString::from(".");
+        private_name.push(file_name);
+        private_name.push(format!(".{}", std::process::id()));
+        let private = path.with_file_name(private_name);
+
+        let _ = std::fs::remove_dir_all(&private);
+        std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+
+        let staged = private.join("socket");
+        let bound = UnixListener::bind(&staged).map_err(anyhow::Error::from).and_then(|listener| {
+            std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+            std::fs::rename(&staged, path)?;
+            Ok(listener)
+        });
+        let _ = std::fs::remove_dir_all(&private);
+
+        bound
+    }
+
+    /// Listening socket, it's handed to the new process on an upgrade
+    pub fn listener_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        info!(path = ?self.path, "Control socket started!");
+
+        loop {
+            select! {
+                _ = self.shutdown.cancelled() => break,
+                conn = self.listener.accept() => {
+                    let stream = match conn {
+                        Ok((stream, _)) => stream,
+                        Err(err) => {
+                            warn!("Control socket accept error: {}", err);
+                            continue;
+                        }
+                    };
+
+                    let state = self.state.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = tokio::time::timeout(REQUEST_TIMEOUT, serve(stream, state)).await {
+                            warn!("Control request timed out: {}", err);
+                        }
+                    });
+                }
+            }
+        }
+
+        // After an upgrade the new process listens on the same file
+        if !upgrade::is_handed_off() {
+            let _ = std::fs::remove_file(&self.path);
+        }
+        Ok(())
+    }
+}
+
+async fn serve(stream: UnixStream, state: ControlState) {
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half).take(MAX_REQUEST_LEN);
+
+    let mut line = String::new();
+    let response = match reader.read_line(&mut line).await {
+        Ok(_) => match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => {
+                info!(?request, "Control request");
+                execute(&state, request).await
+            }
+            Err(err) => ControlResponse::Error(format!("Bad request: {err}"))
+        },
+        Err(err) => ControlResponse::Error(format!("Couldn't read the request: {err}"))
+    };
+
+    let mut response = serde_json::to_string(&response).unwrap_or_else(|err| {
+        json!({ "error": format!("Couldn't encode the response: {err}") }).to_string()
+    });
+    response.push('\n');
+
+    if let Err(err) = write_half.write_all(response.as_bytes()).await {
+        warn!("Couldn't write the control response: {}", err);
+    }
+}
+
+async fn execute(state: &ControlState, request: ControlRequest) -> ControlResponse {
+    match request {
+        ControlRequest::Status => {
+            let conns = state.registry.list(&Selector::All);
+            ok(Status {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                uptime_secs: state.started.elapsed().as_secs(),
+                connections: conns.len(),
+                workers: conns.iter().filter_map(|conn| conn.session.as_ref()).map(|session| session.workers.len()).sum(),
+                pools: state.registry.upstreams().await.len(),
+                bans: state.bans.list().len()
+            })
+        }
+        ControlRequest::ConnsList { filter } => ok(state.registry.list(&Selector::from(filter))),
+        ControlRequest::ConnsShow { conn_id } => match state.registry.inspect(conn_id).await {
+            Some(details) => ok(details),
+            None => ControlResponse::Error(format!("Connection {conn_id} is not found"))
+        },
+        ControlRequest::ConnsKick { conn_id, reason } => {
+            let reason = reason.unwrap_or_else(|| "operator".to_string());
+            match state.registry.kick(&Selector::Conn(conn_id), &reason) {
+                0 => ControlResponse::Error(format!("Connection {conn_id} is not found")),
+                _ => ok(json!({ "kicked": conn_id }))
+            }
+        }
+        ControlRequest::PoolsList => ok(pool_rows(state).await),
+        ControlRequest::PoolsDrain { pool, redirect_to } => {
+            state.overrides.drain(pool.clone(), redirect_to.clone());
+            // The miners authorize again and get to the pool they are sent to now
+            let reconnected = state.registry.reconnect(&Selector::Pool(pool.clone()), None).await;
+            ok(json!({ "pool": pool, "redirect_to": redirect_to, "reconnected": reconnected }))
+        }
+        ControlRequest::BansAdd { ip, reason, ttl_secs } => {
+            let ban = state.bans.add(ip, reason.unwrap_or_else(|| "operator".to_string()), ttl_secs);
+            let kicked = state.registry.kick(&Selector::RemoteIp(ip), "banned");
+            ok(json!({ "ban": ban, "kicked": kicked }))
+        }
+        ControlRequest::BansRemove { ip } => match state.bans.remove(&ip) {
+            Some(ban) => ok(ban),
+            None => ControlResponse::Error(format!("{ip} isn't banned"))
+        },
+        ControlRequest::BansList => ok(state.bans.list()),
+        ControlRequest::ConfigReload => match state.reloader.reload() {
+            Ok(summary) => ok(summary),
+            Err(err) => ControlResponse::Error(err.to_string())
+        },
+        ControlRequest::Upgrade => match state.upgrade.upgrade().await {
+            Ok(pid) => ok(json!({ "upgraded": true, "pid": pid })),
+            Err(err) => ControlResponse::Error(format!("Upgrade failed: {err}"))
+        },
+        ControlRequest::Broadcast { message, filter } => {
+            let notified = state.registry.show_message(&Selector::from(filter), &message).await;
+            ok(json!({ "notified": notified }))
+        }
+    }
+}
+
+/// Pools with sessions and the drained ones, sorted by address
+async fn pool_rows(state: &ControlState) -> Vec<PoolRow> {
+    let mut rows: Vec<PoolRow> = state.registry.upstreams().await
+        .into_iter()
+        .map(|(pool, summary)| PoolRow {
+            pool,
+            sessions: summary.sessions,
+            connected_sessions: summary.connected_sessions,
+            workers: summary.workers,
+            drained: false,
+            redirect_to: None
+        })
+        .collect();
+
+    for (pool, redirect_to) in state.overrides.drained() {
+        match rows.iter_mut().find(|row| row.pool == pool) {
+            Some(row) => {
+                row.drained = true;
+                row.redirect_to = redirect_to;
+            }
+            None => rows.push(PoolRow {
+                pool,
+                sessions: 0,
+                connected_sessions: 0,
+                workers: 0,
+                drained: true,
+                redirect_to
+            })
+        }
+    }
+
+    rows.sort_by(|a, b| a.pool.cmp(&b.pool));
+    rows
+}
+
+fn ok<T: Serialize>(value: T) -> ControlResponse {
+    match serde_json::to_value(value) {
+        Ok(value) => ControlResponse::Ok(value),
+        Err(err) => ControlResponse::Error(format!("Couldn't encode the response: {err}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn bind_refuses_a_socket_in_use() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+
+        let _listener = ControlServer::bind(&path).unwrap();
+        let err = ControlServer::bind(&path).unwrap_err();
+        assert!(err.to_string().contains("is in use"), "{err}");
+        assert!(path.exists());
+    }
+
+    #[tokio::test]
+    async fn bind_replaces_a_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let _listener = ControlServer::bind(&path).unwrap();
+        std::os::unix::net::UnixStream::connect(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn bind_leaves_other_files_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        std::fs::write(&path, "data").unwrap();
+
+        assert!(ControlServer::bind(&path).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+    }
+}
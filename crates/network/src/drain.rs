@@ -0,0 +1,246 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use tracing::{info, warn};
+
+use config::{Config, DrainConfig};
+use score::session::SessionState;
+
+use crate::registry::{ConnRegistry, Selector};
+
+// How often the drain looks whether the pending submits are answered
+const PENDING_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone)]
+pub struct DrainSettings {
+    pub window: Duration,
+    pub pending_timeout: Duration,
+    pub reconnect_to: Option<(String, u16)> // sibling proxy, None means the miners reconnect where they were
+}
+
+/// Lets the miners go before the proxy exits: new connections aren't accepted, every miner is told to
+/// reconnect, spread over the window, the pool's answers on the submits sent already are awaited and
+/// then the proxy shuts down
+pub struct DrainController {
+    registry: Arc<ConnRegistry>,
+    config: watch::Receiver<Arc<Config>>,
+    stop_accepting: CancellationToken,
+    shutdown: CancellationToken,
+    is_draining: AtomicBool
+}
+
+impl DrainSettings {
+    pub fn from_config(config: &DrainConfig, stratum_port: u16) -> Self {
+        Self {
+            window: Duration::from_secs(config.window_secs),
+            pending_timeout: Duration::from_secs(config.pending_timeout_secs),
+            reconnect_to: config.reconnect_host.clone().map(|host| (host, config.reconnect_port.unwrap_or(stratum_port)))
+        }
+    }
+}
+
+impl DrainController {
+    pub fn new(
+        registry: Arc<ConnRegistry>, config: watch::Receiver<Arc<Config>>,
+        stop_accepting: CancellationToken, shutdown: CancellationToken
+    ) -> Self {
+        Self {
+            registry,
+            config,
+            stop_accepting,
+            shutdown,
+            is_draining: AtomicBool::new(false)
+        }
+    }
+
+    /// Starts the drain, false if it has been started already
+    pub fn start(self: &Arc<Self>) -> bool {
+        let settings = self.settings();
+        self.begin(settings)
+    }
+
+    /// Starts the drain after the listeners are handed to a new process, the miners reconnect to
+    /// the same address then
+    pub fn start_handoff(self: &Arc<Self>) -> bool {
+        let settings = DrainSettings { reconnect_to: None, ..self.settings() };
+        self.begin(settings)
+    }
+
+    // The drain runs with the config as it is when the drain starts
+    fn settings(&self) -> DrainSettings {
+        let config = self.config.borrow();
+        DrainSettings::from_config(&config.drain, config.stratum_port)
+    }
+
+    fn begin(self: &Arc<Self>, settings: DrainSettings) -> bool {
+        if self.is_draining.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+
+        let drain = Arc::clone(self);
+        tokio::spawn(async move { drain.run(settings).await });
+        true
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.is_draining.load(Ordering::SeqCst)
+    }
+
+    async fn run(&self, settings: DrainSettings) {
+        self.stop_accepting.cancel();
+
+        let conns = self.registry.select(&Selector::All);
+        info!(connections = conns.len(), window = ?settings.window, "Drain started");
+
+        let interval = settings.window / u32::try_from(conns.len().max(1)).unwrap_or(u32::MAX);
+        let target = settings.reconnect_to.as_ref().map(|(host, port)| (host.as_str(), *port));
+
+        for conn_id in conns {
+            if self.shutdown.is_cancelled() {
+                return;
+            }
+
+            if let Some(session) = self.registry.session(conn_id) {
+                let _ = session.transition(SessionState::Draining).await;
+            }
+            self.registry.reconnect(&Selector::Conn(conn_id), target).await;
+
+            tokio::time::sleep(interval).await;
+        }
+
+        let deadline = Instant::now() + settings.pending_timeout;
+        loop {
+            let pending = self.registry.pending_submits();
+            if pending == 0 {
+                break;
+            }
+            if Instant::now() >= deadline {
+                warn!(pending, "Pool hasn't answered on every submit, shutting down anyway");
+                break;
+            }
+
+            tokio::select! {
+                _ = self.shutdown.cancelled() => return,
+                _ = tokio::time::sleep(PENDING_POLL_INTERVAL) => {}
+            }
+        }
+
+        info!(connections = self.registry.len(), "Drain is done, shutting down");
+        self.shutdown.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    use config::PassthroughConfig;
+    use score::job::{Job, MethodRoutes, PoolMessage, Priority, ProxyMessage};
+    use score::worker::Worker;
+
+    use super::*;
+    use crate::connection::{handle_connection, ConnShared};
+    use crate::events::EventEmitter;
+    use crate::passthrough::PassthroughPolicy;
+    use crate::registry::CloseReason;
+    use crate::stats::ShareStats;
+
+    const POOL: &str = "pool.example:3333";
+    const WAIT: Duration = Duration::from_secs(5);
+
+    #[tokio::test]
+    async fn answer_on_a_submit_in_flight_is_counted() {
+        let shutdown = CancellationToken::new();
+        let registry = Arc::new(ConnRegistry::new(EventEmitter::default()));
+        let stats = Arc::new(ShareStats::new(16));
+        let (tx_queue_high, mut rx_queue_high) = mpsc::channel(16);
+        let (tx_queue_norm, _rx_queue_norm) = mpsc::channel(16);
+        let mut routes = MethodRoutes::default();
+        routes.insert("mining.submit", Priority::High);
+        let shared = ConnShared {
+            registry: Arc::clone(&registry),
+            tx_queue_high,
+            tx_queue_norm,
+            routes: Arc::new(routes),
+            passthrough: Arc::new(PassthroughPolicy::new(&PassthroughConfig::default())),
+            stats: Arc::clone(&stats),
+            handshake_timeout: Duration::from_secs(60),
+            pending_timeout: WAIT,
+            events: EventEmitter::default(),
+            block_candidates: false
+        };
+
+        // The miner's connection, served the way the server does it
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let miner = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, remote) = listener.accept().await.unwrap();
+        let token = shutdown.child_token();
+        registry.register(1, remote, socket.local_addr().unwrap(), token.clone());
+        let conn_registry = Arc::clone(&registry);
+        tokio::spawn(async move {
+            handle_connection(socket, token, 1, shared).await.unwrap();
+            conn_registry.remove(1, CloseReason::Closed);
+        });
+
+        // The worker is authorized on the pool, the test plays the pool and the scheduler
+        let session = timeout(WAIT, async {
+            loop {
+                if let Some(session) = registry.session(1) {
+                    return session;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }).await.unwrap();
+        let (pool_tx, _pool_rx) = mpsc::channel(16);
+        session.transition(SessionState::Subscribed).await.unwrap().unwrap();
+        session.add_pool_session(POOL, pool_tx.clone()).await.unwrap();
+        session.add_worker(Worker::new("acc.rig1", "acc", POOL, pool_tx, 0)).await.unwrap().unwrap();
+        while session.snapshot().workers.is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let (read_half, mut write_half) = miner.into_split();
+        let mut lines = BufReader::new(read_half).lines();
+        let submit = json!({"id": 7, "method": "mining.submit", "params": ["acc.rig1", "j1", "00000001", "5f5e1000", "00000002"]});
+        write_half.write_all(format!("{submit}\n").as_bytes()).await.unwrap();
+
+        // The submit goes to the pool, its answer is waited for
+        let job = timeout(WAIT, rx_queue_high.recv()).await.unwrap().unwrap();
+        let Job::Method((call, _)) = job.job else {
+            panic!("{:?} isn't the submit", job.job);
+        };
+        assert_eq!(call.message, submit);
+        job.respond_to.send(ProxyMessage::Wait).unwrap();
+        assert_eq!(registry.pending_submits(), 1);
+
+        let drain = Arc::new(DrainController::new(
+            Arc::clone(&registry), watch::channel(Arc::new(Config::default())).1, CancellationToken::new(), shutdown.clone()
+        ));
+        drain.begin(DrainSettings { window: Duration::ZERO, pending_timeout: WAIT, reconnect_to: None });
+
+        // The miner reconnects as it's told, before the pool has answered
+        let reconnect = timeout(WAIT, lines.next_line()).await.unwrap().unwrap().unwrap();
+        assert!(reconnect.contains("client.reconnect"));
+        drop(lines);
+        drop(write_half);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!shutdown.is_cancelled(), "the drain waits for the pool's answer");
+        assert_eq!(registry.pending_submits(), 1);
+
+        let pool_messages = session.routing().await.unwrap().pool_messages;
+        let answer = json!({"id": 7, "result": true, "error": null}).to_string();
+        pool_messages.send(PoolMessage { pool: Arc::from(POOL), line: answer }).await.unwrap();
+
+        timeout(WAIT, shutdown.cancelled()).await.expect("the drain is done once the pool has answered");
+        assert_eq!(stats.sub_account("acc").unwrap().accepted, 1);
+    }
+}
@@ -0,0 +1,352 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::select;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::{sleep, timeout, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use config::EventsConfig;
+
+use crate::metrics::METRICS;
+use crate::registry::CloseReason;
+use crate::server::ConnId;
+
+pub mod memory_sink;
+pub mod spool;
+
+/// Version of the event schema, it's raised when a change breaks the consumers
+pub const SCHEMA_VERSION: u16 = 1;
+
+// A failed batch is sent again after this, the wait doubles up to the max
+const RETRY_MIN: Duration = Duration::from_millis(100);
+const RETRY_MAX: Duration = Duration::from_secs(5);
+// On the shutdown the connections are still closing, their events are waited for until it's quiet
+const FLUSH_QUIET: Duration = Duration::from_millis(500);
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Something which has happened to a worker. Events of a subaccount keep their order, a consumer
+/// drops the ones it has seen by `id`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub version: u16,
+    pub id: Uuid,
+    pub at_ms: u64, // unix time
+    pub proxy: Arc<str>, // client id of the proxy which has seen it
+    pub conn_id: ConnId,
+    pub worker: String,
+    pub sub_account: String,
+    #[serde(flatten)]
+    pub kind: EventKind
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    ShareSubmitted {
+        job_id: String,
+        difficulty: f64,
+        pool: String
+    },
+    ShareAccepted {
+        difficulty: f64,
+        latency_ms: u64
+    },
+    ShareRejected {
+        difficulty: f64,
+        latency_ms: u64,
+        reason: String // stale, duplicate, low_difficulty, ...
+    },
+    // The share meets the network's target, the pool is to submit the block
+    BlockCandidate {
+        job_id: String,
+        hash: String,
+        pool: String
+    },
+    WorkerConnected {
+        remote: SocketAddr,
+        pool: String
+    },
+    WorkerDisconnected {
+        remote: SocketAddr,
+        reason: CloseReason,
+        duration_secs: u64
+    },
+    // The worker has authorized again and it's sent to another pool now
+    UpstreamSwitched {
+        from: String,
+        to: String
+    }
+}
+
+/// Where the publisher sends the events. A batch is delivered as a whole or it fails and it's sent
+/// again, so an event may be delivered twice
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn publish(&self, events: &[Event]) -> anyhow::Result<()>;
+}
+
+/// Hands the events to the publisher without waiting. A default one drops them, the events are off
+#[derive(Debug, Clone, Default)]
+pub struct EventEmitter {
+    inner: Option<Arc<Emitter>>
+}
+
+#[derive(Debug)]
+struct Emitter {
+    tx: mpsc::Sender<Event>,
+    proxy: Arc<str>
+}
+
+/// Sends the buffered events to the sink in batches, a failed batch is retried until it goes out
+pub struct EventPublisher {
+    rx: mpsc::Receiver<Event>,
+    sink: Arc<dyn EventSink>,
+    batch_size: usize,
+    linger: Duration,
+    shutdown: CancellationToken
+}
+
+impl Event {
+    /// Events are partitioned by the subaccount, the worker's name if it isn't known yet
+    pub fn key(&self) -> &str {
+        if self.sub_account.is_empty() { &self.worker } else { &self.sub_account }
+    }
+}
+
+impl EventEmitter {
+    pub fn is_enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    pub fn emit(&self, conn_id: ConnId, worker: &str, sub_account: &str, kind: EventKind) {
+        let Some(emitter) = &self.inner else {
+            return;
+        };
+
+        let event = Event {
+            version: SCHEMA_VERSION,
+            id: Uuid::new_v4(),
+            at_ms: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default(),
+            proxy: Arc::clone(&emitter.proxy),
+            conn_id,
+            worker: worker.to_string(),
+            sub_account: sub_account.to_string(),
+            kind
+        };
+        match emitter.tx.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => METRICS.events_dropped("buffer_full", 1),
+            Err(TrySendError::Closed(_)) => METRICS.events_dropped("closed", 1)
+        }
+    }
+}
+
+impl EventPublisher {
+    /// `proxy` is the name of the proxy in the events, e.g. its Kafka client id
+    pub fn new(
+        sink: Arc<dyn EventSink>, config: &EventsConfig, proxy: &str, shutdown: CancellationToken
+    ) -> (Self, EventEmitter) {
+        let (tx, rx) = mpsc::channel(config.buffer_size);
+        let emitter = EventEmitter { inner: Some(Arc::new(Emitter { tx, proxy: Arc::from(proxy) })) };
+
+        let publisher = Self {
+            rx,
+            sink,
+            batch_size: config.batch_size,
+            linger: Duration::from_millis(config.linger_ms),
+            shutdown
+        };
+
+        (publisher, emitter)
+    }
+
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        info!(sink = self.sink.name(), "Event publisher started!");
+        let mut batch = Vec::with_capacity(self.batch_size);
+
+        loop {
+            // The first event of a batch is waited for, the rest of it for `linger` at most
+            select! {
+                _ = self.shutdown.cancelled() => break,
+                event = self.rx.recv() => match event {
+                    Some(event) => batch.push(event),
+                    None => break
+                }
+            }
+
+            let deadline = sleep(self.linger);
+            tokio::pin!(deadline);
+            while batch.len() < self.batch_size {
+                select! {
+                    _ = &mut deadline => break,
+                    event = self.rx.recv() => match event {
+                        Some(event) => batch.push(event),
+                        None => break
+                    }
+                }
+            }
+
+            self.deliver(&mut batch).await;
+        }
+
+        self.flush(batch).await;
+        Ok(())
+    }
+
+    /// Sends what's left after the shutdown, a failed batch isn't retried anymore
+    async fn flush(&mut self, mut batch: Vec<Event>) {
+        let until = Instant::now() + FLUSH_TIMEOUT;
+
+        while Instant::now() < until {
+            match timeout(FLUSH_QUIET, self.rx.recv()).await {
+                Ok(Some(event)) => {
+                    batch.push(event);
+                    if batch.len() >= self.batch_size {
+                        self.deliver(&mut batch).await;
+                    }
+                }
+                Ok(None) | Err(_) => break
+            }
+        }
+        if !batch.is_empty() {
+            self.deliver(&mut batch).await;
+        }
+
+        // The events of the connections closed later are counted as dropped
+        self.rx.close();
+        let left = self.rx.len();
+        if left > 0 {
+            METRICS.events_dropped("shutdown", left);
+            warn!(events = left, "Events are dropped, the proxy is shutting down");
+        }
+    }
+
+    async fn deliver(&self, batch: &mut Vec<Event>) {
+        let mut retry = RETRY_MIN;
+
+        loop {
+            match self.sink.publish(batch).await {
+                Ok(()) => {
+                    METRICS.events_published(self.sink.name(), batch.len());
+                    break;
+                }
+                Err(err) if self.shutdown.is_cancelled() => {
+                    METRICS.events_dropped("sink_error", batch.len());
+                    warn!(sink = self.sink.name(), events = batch.len(), "Couldn't publish the events: {:#}", err);
+                    break;
+                }
+                Err(err) => {
+                    warn!(sink = self.sink.name(), events = batch.len(), "Couldn't publish the events, retrying in {:?}: {:#}", retry, err);
+                    select! {
+                        _ = sleep(retry) => {}
+                        _ = self.shutdown.cancelled() => {}
+                    }
+                    retry = (retry * 2).min(RETRY_MAX);
+                }
+            }
+        }
+
+        batch.clear();
+        METRICS.set_events_buffered(self.rx.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::events::memory_sink::MemorySink;
+
+    // Keeps the size of every batch, the events go on to the memory sink
+    struct BatchSink {
+        sizes: Mutex<Vec<usize>>,
+        memory: MemorySink
+    }
+
+    #[async_trait]
+    impl EventSink for BatchSink {
+        fn name(&self) -> &'static str {
+            "batches"
+        }
+
+        async fn publish(&self, events: &[Event]) -> anyhow::Result<()> {
+            self.sizes.lock().unwrap().push(events.len());
+            self.memory.publish(events).await
+        }
+    }
+
+    fn accepted(latency_ms: u64) -> EventKind {
+        EventKind::ShareAccepted { difficulty: 1024.0, latency_ms }
+    }
+
+    // Publishes what's emitted before the publisher runs, until the emitter is dropped
+    async fn publish(config: &EventsConfig, emit: impl FnOnce(&EventEmitter)) -> Arc<BatchSink> {
+        let sink = Arc::new(BatchSink { sizes: Mutex::new(Vec::new()), memory: MemorySink::new(100) });
+        let (publisher, emitter) = EventPublisher::new(sink.clone(), config, "proxy-1", CancellationToken::new());
+
+        emit(&emitter);
+        drop(emitter);
+        publisher.run().await.unwrap();
+
+        sink
+    }
+
+    #[tokio::test]
+    async fn events_are_published_in_batches() {
+        let config = EventsConfig { batch_size: 3, linger_ms: 20, ..EventsConfig::default() };
+        let sink = publish(&config, |emitter| {
+            for latency_ms in 0..7 {
+                emitter.emit(1, "acc.rig", "acc", accepted(latency_ms));
+            }
+        }).await;
+
+        assert_eq!(*sink.sizes.lock().unwrap(), [3, 3, 1]);
+        let events = sink.memory.events(None);
+        let latencies: Vec<u64> = events.iter()
+            .map(|event| match event.kind {
+                EventKind::ShareAccepted { latency_ms, .. } => latency_ms,
+                _ => panic!("{:?} isn't emitted", event.kind)
+            })
+            .collect();
+        assert_eq!(latencies, [0, 1, 2, 3, 4, 5, 6]);
+        assert!(events.iter().all(|event| &*event.proxy == "proxy-1" && event.version == SCHEMA_VERSION));
+    }
+
+    #[tokio::test]
+    async fn events_beyond_the_buffer_are_dropped() {
+        let config = EventsConfig { buffer_size: 2, batch_size: 10, linger_ms: 20, ..EventsConfig::default() };
+        let sink = publish(&config, |emitter| {
+            for latency_ms in 0..5 {
+                emitter.emit(1, "acc.rig", "acc", accepted(latency_ms));
+            }
+        }).await;
+
+        // The newest events are the ones dropped, the emitter doesn't wait for the publisher
+        let events = sink.memory.events(None);
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[1].kind, EventKind::ShareAccepted { latency_ms: 1, .. }));
+    }
+
+    #[tokio::test]
+    async fn events_are_partitioned_by_the_subaccount() {
+        let config = EventsConfig { linger_ms: 20, ..EventsConfig::default() };
+        let sink = publish(&config, |emitter| {
+            emitter.emit(1, "acc.rig1", "acc", accepted(0));
+            emitter.emit(2, "acc.rig2", "acc", accepted(0));
+            // The subaccount isn't known before the worker is authorized
+            emitter.emit(3, "other.rig1", "", accepted(0));
+        }).await;
+
+        let keys: Vec<String> = sink.memory.events(None).iter().map(|event| event.key().to_string()).collect();
+        assert_eq!(keys, ["acc", "acc", "other.rig1"]);
+    }
+}
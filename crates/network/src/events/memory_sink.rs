@@ -0,0 +1,46 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+
+use crate::events::{Event, EventSink};
+
+/// Keeps the last events instead of sending them anywhere. The pipeline runs the same as with a
+/// broker, the admin API shows what it has published
+pub struct MemorySink {
+    events: Mutex<VecDeque<Event>>,
+    capacity: usize
+}
+
+impl MemorySink {
+    pub fn new(capacity: usize) -> Self {
+        Self { events: Mutex::new(VecDeque::with_capacity(capacity)), capacity }
+    }
+
+    /// The last `limit` events, the oldest first
+    pub fn events(&self, limit: Option<usize>) -> Vec<Event> {
+        let events = self.events.lock().unwrap_or_else(|err| err.into_inner());
+        let skip = limit.map_or(0, |limit| events.len().saturating_sub(limit));
+
+        events.iter().skip(skip).cloned().collect()
+    }
+}
+
+#[async_trait]
+impl EventSink for MemorySink {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn publish(&self, batch: &[Event]) -> anyhow::Result<()> {
+        let mut events = self.events.lock().unwrap_or_else(|err| err.into_inner());
+        for event in batch {
+            if events.len() >= self.capacity {
+                events.pop_front();
+            }
+            events.push_back(event.clone());
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,628 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use tokio::select;
+use tokio::sync::Notify;
+use tokio::time::{interval, sleep};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use config::{EventSpoolConfig, SpoolDropPolicy};
+
+use crate::events::{Event, EventSink, RETRY_MAX, RETRY_MIN};
+use crate::metrics::METRICS;
+
+// Every batch is a record: payload length, events, unix time in ms of the write, crc32 of these and
+// the payload, then the payload, the JSON array of the events
+const HEADER_LEN: u64 = 20;
+const SEGMENT_EXTENSION: &str = "spool";
+// Where the sink has got to: the segment and the offset of the first record it hasn't taken
+const CURSOR_FILE: &str = "cursor";
+// The age of the oldest event is updated this often while the sink is down
+const AGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Write-ahead spool in front of a sink. The publisher's batches are appended to segment files on
+/// disk, and they're sent from there to the sink in order, one batch at a time. The position of the
+/// sink is stored after every batch it takes, so a crash sends the batch it was taking once more
+pub struct EventSpool {
+    spool: Arc<Mutex<Spool>>,
+    sink: Arc<dyn EventSink>,
+    appended: Notify
+}
+
+struct Spool {
+    directory: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    drop_policy: SpoolDropPolicy,
+    segments: VecDeque<Segment>, // the oldest first, the last one is written
+    writer: Option<File>, // of the last segment
+    next_seq: u64,
+    records: VecDeque<Record>, // the sink hasn't taken them, the oldest first
+    bytes: u64, // of every segment
+    events: u64 // of the records
+}
+
+#[derive(Debug)]
+struct Segment {
+    seq: u64,
+    bytes: u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Record {
+    seq: u64,
+    offset: u64,
+    len: u64, // of the payload
+    events: u32,
+    written_ms: u64
+}
+
+impl EventSpool {
+    /// Opens the spool in the config's directory, the batches which are there already are sent first.
+    /// A record which isn't whole, e.g. of a crash during the write, is cut off
+    pub fn open(sink: Arc<dyn EventSink>, config: &EventSpoolConfig) -> anyhow::Result<Self> {
+        let spool = Spool::open(config)?;
+        if !spool.records.is_empty() {
+            info!(events = spool.events, batches = spool.records.len(), "The spool has events to send");
+        }
+
+        Ok(Self { spool: Arc::new(Mutex::new(spool)), sink, appended: Notify::new() })
+    }
+
+    /// Sends the spooled batches to the sink until the shutdown, a failed one is retried until it
+    /// goes out. What's left stays on disk for the next start
+    pub async fn run(self: Arc<Self>, shutdown: CancellationToken) -> anyhow::Result<()> {
+        info!(sink = self.sink.name(), "Event spool started!");
+        let mut retry = RETRY_MIN;
+        let mut age_tick = interval(AGE_INTERVAL);
+
+        loop {
+            let next = self.with_spool(Spool::next).await?;
+            let Some((record, events)) = next else {
+                select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = self.appended.notified() => {}
+                    _ = age_tick.tick() => METRICS.set_spool_age(Duration::ZERO)
+                }
+                continue;
+            };
+
+            match self.sink.publish(&events).await {
+                Ok(()) => {
+                    METRICS.events_published(self.sink.name(), events.len());
+                    self.with_spool(move |spool| spool.ack(record)).await??;
+                    retry = RETRY_MIN;
+                }
+                Err(err) => {
+                    METRICS.set_spool_age(age(record.written_ms));
+                    warn!(sink = self.sink.name(), events = events.len(), "Couldn't publish the spooled events, retrying in {:?}: {:#}", retry, err);
+                    select! {
+                        _ = shutdown.cancelled() => break,
+                        _ = sleep(retry) => {}
+                    }
+                    retry = (retry * 2).min(RETRY_MAX);
+                }
+            }
+        }
+
+        let left = self.with_spool(|spool| spool.events).await?;
+        if left > 0 {
+            info!(events = left, "Events stay in the spool until the next start");
+        }
+        Ok(())
+    }
+
+    // The files are written and synced on the blocking threads
+    async fn with_spool<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&mut Spool) -> T + Send + 'static,
+        T: Send + 'static
+    {
+        let spool = Arc::clone(&self.spool);
+        let result = tokio::task::spawn_blocking(move || f(&mut spool.lock().unwrap_or_else(|err| err.into_inner()))).await?;
+
+        Ok(result)
+    }
+}
+
+/// The publisher's batch is taken once it's synced to disk
+#[async_trait]
+impl EventSink for EventSpool {
+    fn name(&self) -> &'static str {
+        "spool"
+    }
+
+    async fn publish(&self, events: &[Event]) -> anyhow::Result<()> {
+        let payload = serde_json::to_vec(events)?;
+        let count = events.len() as u32;
+
+        self.with_spool(move |spool| spool.append(&payload, count)).await??;
+        self.appended.notify_one();
+
+        Ok(())
+    }
+}
+
+impl Spool {
+    fn open(config: &EventSpoolConfig) -> anyhow::Result<Self> {
+        let directory = PathBuf::from(&config.directory);
+        fs::create_dir_all(&directory).with_context(|| format!("Couldn't create the spool directory {}", directory.display()))?;
+
+        let mut seqs = Vec::new();
+        for entry in fs::read_dir(&directory)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == SEGMENT_EXTENSION)
+                && let Some(seq) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u64>().ok())
+            {
+                seqs.push(seq);
+            }
+        }
+        seqs.sort_unstable();
+
+        let (cursor_seq, cursor_offset) = read_cursor(&directory)?.unwrap_or((0, 0));
+        let mut spool = Self {
+            max_bytes: config.max_bytes,
+            segment_bytes: config.segment_bytes,
+            drop_policy: config.drop_policy,
+            segments: VecDeque::new(),
+            writer: None,
+            // A segment is never numbered again, the cursor may point past the last one
+            next_seq: seqs.last().map_or(cursor_seq, |seq| seq + 1).max(cursor_seq + 1),
+            records: VecDeque::new(),
+            bytes: 0,
+            events: 0,
+            directory
+        };
+
+        for seq in seqs {
+            // The sink has taken everything in it
+            if seq < cursor_seq {
+                spool.remove_segment_file(seq);
+                continue;
+            }
+
+            let from = if seq == cursor_seq { cursor_offset } else { 0 };
+            let bytes = spool.scan_segment(seq, from)?;
+            spool.segments.push_back(Segment { seq, bytes });
+            spool.bytes += bytes;
+        }
+        spool.update_metrics();
+
+        Ok(spool)
+    }
+
+    // Indexes the records of the segment from `from` on, the segment is cut where a record is broken
+    fn scan_segment(&mut self, seq: u64, from: u64) -> anyhow::Result<u64> {
+        let path = self.segment_path(seq);
+        let mut reader = BufReader::new(File::open(&path)?);
+        let mut offset = 0;
+
+        loop {
+            let record = match read_record(&mut reader, seq, offset) {
+                Ok(Some((record, _))) => record,
+                Ok(None) => break,
+                Err(err) => {
+                    warn!(segment = %path.display(), offset, "The spool is cut at a broken record: {:#}", err);
+                    OpenOptions::new().write(true).open(&path)?.set_len(offset)?;
+                    break;
+                }
+            };
+
+            if offset >= from {
+                self.events += record.events as u64;
+                self.records.push_back(record);
+            }
+            offset += HEADER_LEN + record.len;
+        }
+
+        Ok(offset)
+    }
+
+    fn append(&mut self, payload: &[u8], events: u32) -> anyhow::Result<()> {
+        let size = HEADER_LEN + payload.len() as u64;
+        if self.bytes + size > self.max_bytes && !self.make_room(size, events) {
+            return Ok(());
+        }
+
+        let is_full = self.segments.back().is_none_or(|segment| segment.bytes > 0 && segment.bytes + size > self.segment_bytes);
+        if self.writer.is_none() || is_full {
+            self.start_segment()?;
+        }
+        let (Some(writer), Some(segment)) = (self.writer.as_mut(), self.segments.back_mut()) else {
+            anyhow::bail!("The spool has no segment to write");
+        };
+
+        let written_ms = now_ms();
+        let mut record = Vec::with_capacity(size as usize);
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&events.to_le_bytes());
+        record.extend_from_slice(&written_ms.to_le_bytes());
+        record.extend_from_slice(&checksum(&record[0..16], payload).to_le_bytes());
+        record.extend_from_slice(payload);
+
+        if let Err(err) = writer.write_all(&record).and_then(|_| writer.sync_data()) {
+            // A partial record would be cut off on the next start, a new segment starts clean
+            self.writer = None;
+            return Err(err).context("Couldn't write to the spool");
+        }
+
+        self.records.push_back(Record { seq: segment.seq, offset: segment.bytes, len: payload.len() as u64, events, written_ms });
+        segment.bytes += size;
+        self.bytes += size;
+        self.events += events as u64;
+        self.update_metrics();
+
+        Ok(())
+    }
+
+    // Whether the record fits after the drop policy has made room for it
+    fn make_room(&mut self, size: u64, events: u32) -> bool {
+        if self.drop_policy == SpoolDropPolicy::Newest || size > self.max_bytes {
+            METRICS.events_dropped("spool_full", events as usize);
+            warn!(events, "The spool is full, the events are dropped");
+            return false;
+        }
+
+        let mut dropped = 0;
+        while self.bytes + size > self.max_bytes && let Some(segment) = self.segments.pop_front() {
+            // The segment which is written is dropped too, the next record starts a new one
+            if self.segments.is_empty() {
+                self.writer = None;
+            }
+            while self.records.front().is_some_and(|record| record.seq == segment.seq) {
+                if let Some(record) = self.records.pop_front() {
+                    dropped += record.events as u64;
+                }
+            }
+            self.bytes -= segment.bytes;
+            self.remove_segment_file(segment.seq);
+        }
+
+        self.events -= dropped;
+        METRICS.events_dropped("spool_full", dropped as usize);
+        warn!(events = dropped, "The spool is full, its oldest events are dropped");
+        true
+    }
+
+    fn start_segment(&mut self) -> anyhow::Result<()> {
+        let seq = self.next_seq;
+        let path = self.segment_path(seq);
+        let file = OpenOptions::new().create(true).append(true).open(&path)
+            .with_context(|| format!("Couldn't create the spool segment {}", path.display()))?;
+
+        self.writer = Some(file);
+        self.segments.push_back(Segment { seq, bytes: 0 });
+        self.next_seq += 1;
+
+        Ok(())
+    }
+
+    /// The oldest batch the sink hasn't taken. A batch which can't be read anymore is dropped
+    fn next(&mut self) -> Option<(Record, Vec<Event>)> {
+        while let Some(record) = self.records.front().copied() {
+            match self.read(record) {
+                Ok(events) => return Some((record, events)),
+                Err(err) => {
+                    warn!(segment = record.seq, offset = record.offset, events = record.events, "A spooled batch is dropped, it can't be read: {:#}", err);
+                    METRICS.events_dropped("spool_broken", record.events as usize);
+                    if let Err(err) = self.ack(record) {
+                        warn!("Couldn't move the spool past the batch: {:#}", err);
+                        return None;
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    fn read(&self, record: Record) -> anyhow::Result<Vec<Event>> {
+        let mut file = File::open(self.segment_path(record.seq))?;
+        file.seek(SeekFrom::Start(record.offset))?;
+
+        match read_record(&mut file, record.seq, record.offset)? {
+            Some((_, payload)) => Ok(serde_json::from_slice(&payload)?),
+            None => anyhow::bail!("The record isn't there")
+        }
+    }
+
+    /// The sink has taken the batch, its position is stored and the segments before it are deleted
+    fn ack(&mut self, record: Record) -> anyhow::Result<()> {
+        // The drop policy has taken it out meanwhile
+        if self.records.front() != Some(&record) {
+            return Ok(());
+        }
+        self.records.pop_front();
+        self.events -= record.events as u64;
+
+        write_cursor(&self.directory, record.seq, record.offset + HEADER_LEN + record.len)?;
+
+        let first_needed = self.records.front().map_or(record.seq, |next| next.seq);
+        while self.segments.len() > 1 && self.segments.front().is_some_and(|segment| segment.seq < first_needed) {
+            if let Some(segment) = self.segments.pop_front() {
+                self.bytes -= segment.bytes;
+                self.remove_segment_file(segment.seq);
+            }
+        }
+        self.update_metrics();
+
+        Ok(())
+    }
+
+    fn segment_path(&self, seq: u64) -> PathBuf {
+        self.directory.join(format!("{seq:020}.{SEGMENT_EXTENSION}"))
+    }
+
+    fn remove_segment_file(&self, seq: u64) {
+        if let Err(err) = fs::remove_file(self.segment_path(seq)) && err.kind() != ErrorKind::NotFound {
+            warn!(segment = seq, "Couldn't delete the spool segment: {}", err);
+        }
+    }
+
+    fn update_metrics(&self) {
+        METRICS.set_spool(self.events, self.bytes);
+        METRICS.set_spool_age(self.records.front().map_or(Duration::ZERO, |record| age(record.written_ms)));
+    }
+}
+
+// The record at `offset`, none at the end of the segment
+fn read_record(reader: &mut impl Read, seq: u64, offset: u64) -> anyhow::Result<Option<(Record, Vec<u8>)>> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    match read_up_to(reader, &mut header)? {
+        0 => return Ok(None),
+        n if n < header.len() => anyhow::bail!("The header isn't whole"),
+        _ => {}
+    }
+
+    let len = u32::from_le_bytes(header[0..4].try_into()?) as u64;
+    let events = u32::from_le_bytes(header[4..8].try_into()?);
+    let written_ms = u64::from_le_bytes(header[8..16].try_into()?);
+    let crc = u32::from_le_bytes(header[16..20].try_into()?);
+
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).context("The payload isn't whole")?;
+    if checksum(&header[0..16], &payload) != crc {
+        anyhow::bail!("The checksum doesn't match");
+    }
+
+    Ok(Some((Record { seq, offset, len, events, written_ms }, payload)))
+}
+
+// Bytes read into `buf`, fewer than its length only at the end of the file
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err)
+        }
+    }
+
+    Ok(filled)
+}
+
+fn checksum(header: &[u8], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+fn read_cursor(directory: &Path) -> anyhow::Result<Option<(u64, u64)>> {
+    let text = match fs::read_to_string(directory.join(CURSOR_FILE)) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into())
+    };
+
+    let mut parts = text.split_whitespace().map(str::parse::<u64>);
+    match (parts.next(), parts.next()) {
+        (Some(Ok(seq)), Some(Ok(offset))) => Ok(Some((seq, offset))),
+        _ => anyhow::bail!("The spool cursor {:?} isn't `<segment> <offset>`", text.trim())
+    }
+}
+
+// Replaced at once, a crash leaves the old cursor or the new one
+fn write_cursor(directory: &Path, seq: u64, offset: u64) -> anyhow::Result<()> {
+    let tmp = directory.join(format!("{CURSOR_FILE}.tmp"));
+    let mut file = File::create(&tmp)?;
+    file.write_all(format!("{seq} {offset}\n").as_bytes())?;
+    file.sync_data()?;
+    fs::rename(&tmp, directory.join(CURSOR_FILE))?;
+
+    Ok(())
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+fn age(written_ms: u64) -> Duration {
+    Duration::from_millis(now_ms().saturating_sub(written_ms))
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::events::{EventKind, SCHEMA_VERSION};
+
+    // A batch of one event, `n` tells the batches apart and they're all the same size
+    fn batch(n: u64) -> Vec<Event> {
+        vec![Event {
+            version: SCHEMA_VERSION,
+            id: Uuid::nil(),
+            at_ms: 0,
+            proxy: Arc::from("proxy-1"),
+            conn_id: 1,
+            worker: "acc.rig".to_string(),
+            sub_account: "acc".to_string(),
+            kind: EventKind::ShareAccepted { difficulty: 1024.0, latency_ms: n }
+        }]
+    }
+
+    fn record_size() -> u64 {
+        HEADER_LEN + serde_json::to_vec(&batch(0)).unwrap().len() as u64
+    }
+
+    fn config(directory: &Path, max_bytes: u64, segment_bytes: u64, drop_policy: SpoolDropPolicy) -> EventSpoolConfig {
+        EventSpoolConfig { directory: directory.to_str().unwrap().to_string(), max_bytes, segment_bytes, drop_policy }
+    }
+
+    fn append(spool: &mut Spool, n: u64) {
+        spool.append(&serde_json::to_vec(&batch(n)).unwrap(), 1).unwrap();
+    }
+
+    fn latency(events: &[Event]) -> u64 {
+        match events[0].kind {
+            EventKind::ShareAccepted { latency_ms, .. } => latency_ms,
+            _ => panic!("{:?} isn't spooled", events[0].kind)
+        }
+    }
+
+    // The batches the sink takes from now on
+    fn replay(spool: &mut Spool) -> Vec<u64> {
+        let mut taken = Vec::new();
+        while let Some((record, events)) = spool.next() {
+            taken.push(latency(&events));
+            spool.ack(record).unwrap();
+        }
+
+        taken
+    }
+
+    #[test]
+    fn batches_are_replayed_in_order_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        // Two records a segment
+        let config = config(dir.path(), u64::MAX, 2 * record_size(), SpoolDropPolicy::Oldest);
+
+        let mut spool = Spool::open(&config).unwrap();
+        for n in 0..5 {
+            append(&mut spool, n);
+        }
+        assert_eq!(spool.segments.len(), 3);
+        drop(spool);
+
+        let mut spool = Spool::open(&config).unwrap();
+        assert_eq!(spool.events, 5);
+        assert_eq!(replay(&mut spool), [0, 1, 2, 3, 4]);
+
+        // The segments the sink is done with are deleted
+        assert_eq!(spool.segments.len(), 1);
+        assert_eq!(Spool::open(&config).unwrap().events, 0);
+    }
+
+    #[test]
+    fn half_written_record_is_cut_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), u64::MAX, u64::MAX, SpoolDropPolicy::Oldest);
+
+        let mut spool = Spool::open(&config).unwrap();
+        append(&mut spool, 0);
+        append(&mut spool, 1);
+        let path = spool.segment_path(spool.segments[0].seq);
+        drop(spool);
+
+        // A crash in the middle of the second record
+        let size = record_size();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(size + size / 2).unwrap();
+
+        let mut spool = Spool::open(&config).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+        assert_eq!(replay(&mut spool), [0]);
+
+        // The spool goes on after the cut
+        drop(spool);
+        let mut spool = Spool::open(&config).unwrap();
+        append(&mut spool, 2);
+        drop(spool);
+        assert_eq!(replay(&mut Spool::open(&config).unwrap()), [2]);
+    }
+
+    #[test]
+    fn batch_of_a_bad_checksum_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), u64::MAX, u64::MAX, SpoolDropPolicy::Oldest);
+
+        let mut spool = Spool::open(&config).unwrap();
+        for n in 0..3 {
+            append(&mut spool, n);
+        }
+
+        // A bit flips in the event count of the second record, the checksum covers the header too
+        let path = spool.segment_path(spool.segments[0].seq);
+        let mut segment = fs::read(&path).unwrap();
+        segment[record_size() as usize + 4] ^= 1;
+        fs::write(&path, segment).unwrap();
+
+        assert_eq!(replay(&mut spool), [0, 2]);
+    }
+
+    #[test]
+    fn crash_before_the_ack_sends_the_last_batch_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), u64::MAX, 2 * record_size(), SpoolDropPolicy::Oldest);
+
+        let mut spool = Spool::open(&config).unwrap();
+        for n in 0..4 {
+            append(&mut spool, n);
+        }
+        for _ in 0..2 {
+            let (record, _) = spool.next().unwrap();
+            spool.ack(record).unwrap();
+        }
+        // The sink has taken it but the proxy is gone before the ack
+        let (_, events) = spool.next().unwrap();
+        assert_eq!(latency(&events), 2);
+        drop(spool);
+
+        assert_eq!(replay(&mut Spool::open(&config).unwrap()), [2, 3]);
+    }
+
+    #[test]
+    fn full_spool_drops_the_newest_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), 2 * record_size(), 1, SpoolDropPolicy::Newest);
+
+        let mut spool = Spool::open(&config).unwrap();
+        for n in 0..4 {
+            append(&mut spool, n);
+        }
+
+        assert!(spool.bytes <= config.max_bytes);
+        assert_eq!(replay(&mut spool), [0, 1]);
+    }
+
+    #[test]
+    fn full_spool_drops_the_oldest_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        // A segment for every record, the oldest ones go a segment at a time
+        let config = config(dir.path(), 2 * record_size(), 1, SpoolDropPolicy::Oldest);
+
+        let mut spool = Spool::open(&config).unwrap();
+        for n in 0..4 {
+            append(&mut spool, n);
+        }
+
+        assert!(spool.bytes <= config.max_bytes);
+        assert_eq!(spool.events, 2);
+        assert_eq!(replay(&mut spool), [2, 3]);
+
+        // The segments of the dropped batches are deleted
+        let segments = fs::read_dir(dir.path()).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension().is_some_and(|extension| extension == SEGMENT_EXTENSION))
+            .count();
+        assert_eq!(segments, 1);
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::api::client::ApiClient;
+use crate::drain::DrainController;
+use crate::registry::{ConnRegistry, PoolSummary};
+
+// The scheduler beats at least this often while it's idle
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+// The loop only spawns the handlers, so it turns at least every beat. A few missed beats is a stall
+const SCHEDULER_STALL: Duration = HEARTBEAT_INTERVAL.saturating_mul(5);
+// Probes come every few seconds, the API is asked at most this often
+const API_CHECK_TTL: Duration = Duration::from_secs(5);
+
+/// Time of the last turn of a loop, the liveness probe looks whether it's still turning
+#[derive(Debug)]
+pub struct Heartbeat {
+    started: Instant,
+    last_ms: AtomicU64 // since `started`
+}
+
+/// What the probes and `/status` of the admin API look at
+pub struct Health {
+    registry: Arc<ConnRegistry>,
+    drain: Arc<DrainController>,
+    api: Arc<ApiClient>,
+    scheduler: Arc<Heartbeat>,
+    listening: watch::Receiver<bool>,
+    started: Instant,
+    started_at: u64, // unix time, seconds
+    // Result of the last API request and when it was made
+    api_check: Mutex<Option<(Instant, Result<(), String>)>>
+}
+
+/// Answer of a probe, the probe passes if every check is ok
+#[derive(Debug, Clone, Serialize)]
+pub struct Probe {
+    pub ok: bool,
+    pub checks: Vec<Check>
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>
+}
+
+/// Summary of the proxy for the operators
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub pid: u32,
+    pub started_at: u64,
+    pub uptime_secs: u64,
+    pub draining: bool,
+    pub live: Probe,
+    pub ready: Probe,
+    pub connections: usize,
+    pub workers: usize,
+    pub pending_submits: usize,
+    pub upstreams: BTreeMap<String, PoolSummary>
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self { started: Instant::now(), last_ms: AtomicU64::new(0) }
+    }
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn beat(&self) {
+        self.last_ms.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Time since the last beat
+    pub fn age(&self) -> Duration {
+        self.started.elapsed().saturating_sub(Duration::from_millis(self.last_ms.load(Ordering::Relaxed)))
+    }
+}
+
+impl Health {
+    /// `listening` is up while the stratum listeners are bound and accept the miners
+    pub fn new(
+        registry: Arc<ConnRegistry>, drain: Arc<DrainController>, api: Arc<ApiClient>,
+        scheduler: Arc<Heartbeat>, listening: watch::Receiver<bool>
+    ) -> Self {
+        Self {
+            registry,
+            drain,
+            api,
+            scheduler,
+            listening,
+            started: Instant::now(),
+            started_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+            api_check: Mutex::new(None)
+        }
+    }
+
+    /// The process is up and the scheduler loop is turning
+    pub fn liveness(&self) -> Probe {
+        let age = self.scheduler.age();
+        let scheduler = if age < SCHEDULER_STALL {
+            Check::ok("scheduler")
+        } else {
+            Check::failed("scheduler", format!("the loop hasn't turned for {}s", age.as_secs()))
+        };
+
+        Probe::new(vec![scheduler])
+    }
+
+    /// The proxy may take new miners: it isn't draining, the listeners are bound, the API answers
+    /// and the pools are reachable
+    pub async fn readiness(&self) -> Probe {
+        let draining = if self.drain.is_draining() {
+            Check::failed("drain", "the proxy is draining".to_string())
+        } else {
+            Check::ok("drain")
+        };
+        let listeners = if *self.listening.borrow() {
+            Check::ok("listeners")
+        } else {
+            Check::failed("listeners", "the stratum listeners don't accept connections".to_string())
+        };
+        let api = match self.check_api().await {
+            Ok(()) => Check::ok("api"),
+            Err(err) => Check::failed("api", err)
+        };
+        let upstreams = upstreams_check(&self.registry.upstreams().await);
+
+        Probe::new(vec![draining, listeners, api, upstreams])
+    }
+
+    pub async fn status(&self) -> Status {
+        let upstreams = self.registry.upstreams().await;
+        let workers = upstreams.values().map(|pool| pool.workers).sum();
+
+        Status {
+            pid: std::process::id(),
+            started_at: self.started_at,
+            uptime_secs: self.started.elapsed().as_secs(),
+            draining: self.drain.is_draining(),
+            live: self.liveness(),
+            ready: self.readiness().await,
+            connections: self.registry.len(),
+            workers,
+            pending_submits: self.registry.pending_submits(),
+            upstreams
+        }
+    }
+
+    async fn check_api(&self) -> Result<(), String> {
+        if let Some((at, result)) = self.api_check.lock().unwrap_or_else(|err| err.into_inner()).as_ref()
+            && at.elapsed() < API_CHECK_TTL
+        {
+            return result.clone();
+        }
+
+        let result = self.api.ping().await.map_err(|err| format!("{err:#}"));
+        *self.api_check.lock().unwrap_or_else(|err| err.into_inner()) = Some((Instant::now(), result.clone()));
+
+        result
+    }
+}
+
+/// At least one pool session is connected. Without any session there is nothing known to be down,
+/// the pools are connected as the miners authorize
+fn upstreams_check(upstreams: &BTreeMap<String, PoolSummary>) -> Check {
+    let sessions: usize = upstreams.values().map(|pool| pool.sessions).sum();
+    let connected: usize = upstreams.values().map(|pool| pool.connected_sessions).sum();
+
+    if sessions == 0 || connected > 0 {
+        Check::ok("upstreams")
+    } else {
+        Check::failed("upstreams", format!("none of the {sessions} pool sessions is connected"))
+    }
+}
+
+impl Probe {
+    fn new(checks: Vec<Check>) -> Self {
+        Self { ok: checks.iter().all(|check| check.ok), checks }
+    }
+}
+
+impl Check {
+    fn ok(name: &'static str) -> Self {
+        Self { name, ok: true, detail: None }
+    }
+
+    fn failed(name: &'static str, detail: String) -> Self {
+        Self { name, ok: false, detail: Some(detail) }
+    }
+}
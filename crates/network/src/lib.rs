@@ -2,5 +2,33 @@ pub mod connection;
 pub mod server;
 mod message;
 mod utils;
+mod passthrough;
 pub mod api;
 pub mod upstream;
+pub mod registry;
+pub mod admin;
+pub mod bans;
+pub mod control;
+pub mod drain;
+pub mod health;
+pub mod upgrade;
+pub mod metrics;
+pub mod systemd;
+pub mod stats;
+pub mod events;
+pub mod commands;
+
+// The tests which change the environment of the process take turns
+#[cfg(test)]
+static ENV_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+// Safety of the set_var and remove_var calls: the tests which change the environment hold ENV_LOCK
+#[cfg(test)]
+fn set_env(vars: &[(&str, Option<&str>)]) {
+    for (name, value) in vars {
+        match value {
+            Some(value) => unsafe { std::env::set_var(name, value) },
+            None => unsafe { std::env::remove_var(name) }
+        }
+    }
+}
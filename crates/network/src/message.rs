@@ -1,13 +1,13 @@
 pub mod parse_message;
 mod validation;
 
-use score::job::{Submit, Authorize, Subscribe};
+use serde_json::Value;
+use score::job::MethodCall;
 
 #[derive(Debug)]
 pub enum Command {
     Ping,
-    CSubmit(Submit),
-    CAuthorize(Authorize),
-    CSubscribe(Subscribe),
+    Call(MethodCall),
+    Reply(Value), // the miner's answer on a request of the pool (client.get_version etc.)
     Unknown
-}
\ No newline at end of file
+}
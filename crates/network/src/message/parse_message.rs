@@ -1,62 +1,38 @@
 use serde_json::{from_str, Value};
-use tracing::{info, warn};
-use score::job::{Authorize, Submit, Subscribe};
+use tracing::warn;
+use score::job::MethodCall;
 use crate::message::{Command};
-use crate::message::validation::authorize_validation::validation_authorize;
-use crate::message::validation::submit_validation::submit_validation;
-use crate::message::validation::subscribe_validation::validation_subscribe;
+use crate::message::validation::validate;
 
 pub fn parse_message(line: &str) -> anyhow::Result<Command> {
+    if line == "PING" {
+        return Ok(Command::Ping)
+    }
+
     let message_json = from_str::<Value>(line);
 
-    if let Err(_) = message_json {
+    if message_json.is_err() {
         return Ok(Command::Unknown)
     }
     let message_json = message_json?;
 
-    let method = message_json.get("method").unwrap().as_str().unwrap();
-
-    match method {
-        "mining.submit" => {
-            let validation_result = submit_validation(&message_json);
-            if let Err(err) = validation_result {
-                warn!("Validation Error: {:?}", err);
-                return Ok(Command::Unknown);
-            }
-            let submit = Submit::from_value(&message_json)?;
+    if is_reply(&message_json) {
+        return Ok(Command::Reply(message_json))
+    }
 
-            Ok(Command::CSubmit(submit))
-        },
-        "mining.authorize" => {
-            let validation_result = validation_authorize(&message_json);
-            if let Err(err) = validation_result {
-                warn!("Validation Error: {:?}", err);
-                return Ok(Command::Unknown)
-            }
-            let authorize = Authorize::form_value(&message_json)?;
+    let method = match validate(&message_json) {
+        Ok(method) => method.to_string(),
+        Err(err) => {
+            warn!("Validation Error: {}", err);
+            return Ok(Command::Unknown);
+        }
+    };
 
-            Ok(Command::CAuthorize(authorize))
-        },
-        "mining.subscribe" => {
-            let validation_result = validation_subscribe(&message_json);
-            if let Err(err) = validation_result {
-                warn!("Validation Error: {:?}", err);
-                return Ok(Command::Unknown);
-            }
-            let subscribe = Subscribe::from_value(&message_json)?;
+    Ok(Command::Call(MethodCall::new(method, message_json)))
+}
 
-            Ok(Command::CSubscribe(subscribe))
-        }
-        _ => {
-            Ok(Command::Unknown)
-            // let validation_result = validation_subscribe(&message_json);
-            // if let Err(err) = validation_result {
-            //     warn!("Validation Error: {:?}", err);
-            //     return Ok(Command::Unknown)
-            // }
-            // let subscribe = Subscribe::from_value(&message_json)?;
-            //
-            // Ok(Command::CSubscribe(subscribe))
-        }
-    }
+fn is_reply(message: &Value) -> bool {
+    message.get("method").is_none()
+        && message.get("id").is_some()
+        && (message.get("result").is_some() || message.get("error").is_some())
 }
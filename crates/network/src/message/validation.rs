@@ -1,66 +1,44 @@
 use thiserror::Error;
-use std::fmt;
-pub mod submit_validation;
-pub mod authorize_validation;
-pub mod subscribe_validation;
+pub mod schema;
+pub mod methods;
+
+pub use schema::validate;
 
 #[derive(Debug, Error)]
 pub enum ValidationError {
-    #[error("Base fields not found: {0}")]
-    NotFoundBaseFields(String),
+    #[error("Base field `{1}` not found: {0}")]
+    NotFoundBaseFields(String, &'static str),
+    #[error("Method is not a string")]
+    MethodIsNotString,
     #[error("Params in {0} is not array")]
     ParamsIsNotArray(String),
     #[error("Array with params is empty: {0}")]
     ParamsIsEmpty(String),
-    #[error("Incorrect number of parameters: {0}")]
-    IncorrectNumberOfParameters(String)
-}
-
-pub mod validation {
-    use serde_json::Value;
-
-    pub fn check_obj_on_base_fields(message: &Value) -> bool {
-        let is_id = check_id(message);
-        let is_method = check_method(message);
-        let is_params = check_params(message);
-
-        if is_id && is_method && is_params {
-            true
-        } else {
-            false
-        }
-    }
-
-    pub fn check_id(obj: &Value) -> bool {
-        match obj.get("id") {
-            None => {
-                false
-            }
-            Some(_) => {
-                true
-            }
-        }
-    }
-
-    pub fn check_method(obj: &Value) -> bool {
-        match obj.get("method") {
-            None => {
-                false
-            }
-            Some(_) => {
-                true
-            }
-        }
+    #[error("Incorrect number of parameters in {method}: expected {expected}, got {got}")]
+    IncorrectNumberOfParameters {
+        method: String,
+        expected: String,
+        got: usize
+    },
+    #[error("Invalid type of param #{index} `{name}` in {method}: expected {expected}")]
+    InvalidParamType {
+        method: String,
+        index: usize,
+        name: &'static str,
+        expected: &'static str
+    },
+    #[error("Param #{index} `{name}` in {method} is not a hex string")]
+    NotHex {
+        method: String,
+        index: usize,
+        name: &'static str
+    },
+    #[error("Invalid length of param #{index} `{name}` in {method}: expected {expected}, got {got}")]
+    InvalidHexLength {
+        method: String,
+        index: usize,
+        name: &'static str,
+        expected: String,
+        got: usize
     }
-
-    pub fn check_params(obj: &Value) -> bool {
-        match obj.get("params") {
-            None => {
-                false
-            }
-            Some(_) => {
-                true
-            }
-        }
-    }
-}
\ No newline at end of file
+}
@@ -0,0 +1,72 @@
+use crate::message::validation::schema::{MethodSchema, ParamKind, ParamSpec};
+
+// To support a new method it is enough to describe its params here
+pub static METHODS: &[MethodSchema] = &[
+    MethodSchema {
+        method: "mining.subscribe",
+        params: &[
+            ParamSpec::optional("user_agent", ParamKind::String),
+            ParamSpec::optional("extranonce1", ParamKind::Hex { min: 2, max: 16 }),
+        ]
+    },
+    MethodSchema {
+        method: "mining.authorize",
+        params: &[
+            ParamSpec::required("username", ParamKind::String),
+            ParamSpec::optional("password", ParamKind::String),
+        ]
+    },
+    MethodSchema {
+        method: "mining.submit",
+        params: &[
+            ParamSpec::required("worker_name", ParamKind::String),
+            ParamSpec::required("job_id", ParamKind::String),
+            ParamSpec::required("extranonce2", ParamKind::Hex { min: 2, max: 16 }),
+            ParamSpec::required("n_time", ParamKind::hex(8)),
+            ParamSpec::required("nonce", ParamKind::hex(8)),
+            ParamSpec::optional("version_bits", ParamKind::hex(8)),
+        ]
+    },
+    MethodSchema {
+        method: "mining.configure",
+        params: &[
+            ParamSpec::required("extensions", ParamKind::Array),
+            ParamSpec::optional("extension_params", ParamKind::Object),
+        ]
+    },
+    MethodSchema {
+        method: "mining.suggest_difficulty",
+        params: &[
+            ParamSpec::required("difficulty", ParamKind::Number),
+        ]
+    },
+    MethodSchema {
+        method: "mining.extranonce.subscribe",
+        params: &[]
+    },
+];
+
+pub fn find_method(method: &str) -> Option<&'static MethodSchema> {
+    METHODS.iter().find(|schema| schema.method == method)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn optional_params_are_trailing() {
+        for schema in METHODS {
+            let first_optional = schema.params.iter().position(|p| p.optional).unwrap_or(schema.params.len());
+            assert!(schema.params[first_optional..].iter().all(|p| p.optional), "{}", schema.method);
+        }
+    }
+
+    #[test]
+    fn methods_are_unique() {
+        for schema in METHODS {
+            assert!(std::ptr::eq(find_method(schema.method).unwrap(), schema), "{}", schema.method);
+        }
+        assert!(find_method("mining.custom").is_none());
+    }
+}
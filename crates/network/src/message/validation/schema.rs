@@ -0,0 +1,279 @@
+use serde_json::Value;
+
+use crate::message::validation::methods::find_method;
+use crate::message::validation::ValidationError;
+
+/// Expected shape of a single positional param
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamKind {
+    String,
+    Number,
+    Array,
+    Object,
+    // Hex string with a length (in chars) within min..=max
+    Hex { min: usize, max: usize }
+}
+
+#[derive(Debug)]
+pub struct ParamSpec {
+    pub name: &'static str,
+    pub kind: ParamKind,
+    // The param may be omitted. Only trailing params can be optional
+    pub optional: bool,
+    // `null` is accepted in place of the value
+    pub nullable: bool
+}
+
+/// Declarative description of a Stratum method
+#[derive(Debug)]
+pub struct MethodSchema {
+    pub method: &'static str,
+    pub params: &'static [ParamSpec]
+}
+
+impl ParamKind {
+    pub const fn hex(len: usize) -> Self {
+        ParamKind::Hex { min: len, max: len }
+    }
+
+    fn expected(&self) -> &'static str {
+        match self {
+            ParamKind::String => "string",
+            ParamKind::Number => "number",
+            ParamKind::Array => "array",
+            ParamKind::Object => "object",
+            ParamKind::Hex { .. } => "hex string"
+        }
+    }
+}
+
+impl ParamSpec {
+    pub const fn required(name: &'static str, kind: ParamKind) -> Self {
+        Self { name, kind, optional: false, nullable: false }
+    }
+
+    pub const fn optional(name: &'static str, kind: ParamKind) -> Self {
+        Self { name, kind, optional: true, nullable: true }
+    }
+}
+
+impl MethodSchema {
+    pub fn min_params(&self) -> usize {
+        self.params.iter().filter(|p| !p.optional).count()
+    }
+
+    pub fn max_params(&self) -> usize {
+        self.params.len()
+    }
+
+    fn expected_arity(&self) -> String {
+        let (min, max) = (self.min_params(), self.max_params());
+        if min == max {
+            min.to_string()
+        } else {
+            format!("{}..={}", min, max)
+        }
+    }
+
+    pub fn validate_params(&self, params: &[Value]) -> Result<(), ValidationError> {
+        let method = self.method;
+
+        if params.is_empty() && self.min_params() > 0 {
+            return Err(ValidationError::ParamsIsEmpty(method.to_string()))
+        }
+
+        if params.len() < self.min_params() || params.len() > self.max_params() {
+            return Err(ValidationError::IncorrectNumberOfParameters {
+                method: method.to_string(),
+                expected: self.expected_arity(),
+                got: params.len()
+            })
+        }
+
+        for (index, (spec, value)) in self.params.iter().zip(params).enumerate() {
+            if value.is_null() && spec.nullable {
+                continue;
+            }
+
+            let is_valid_type = match spec.kind {
+                ParamKind::String | ParamKind::Hex { .. } => value.is_string(),
+                ParamKind::Number => value.is_number(),
+                ParamKind::Array => value.is_array(),
+                ParamKind::Object => value.is_object()
+            };
+
+            if !is_valid_type {
+                return Err(ValidationError::InvalidParamType {
+                    method: method.to_string(),
+                    index,
+                    name: spec.name,
+                    expected: spec.kind.expected()
+                })
+            }
+
+            if let (ParamKind::Hex { min, max }, Some(hex)) = (spec.kind, value.as_str()) {
+                if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err(ValidationError::NotHex { method: method.to_string(), index, name: spec.name })
+                }
+
+                if !(min..=max).contains(&hex.len()) {
+                    let expected = if min == max { min.to_string() } else { format!("{}..={}", min, max) };
+                    return Err(ValidationError::InvalidHexLength {
+                        method: method.to_string(),
+                        index,
+                        name: spec.name,
+                        expected,
+                        got: hex.len()
+                    })
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks the JSON-RPC envelope of the message and, if the method is in the registry, its params.
+/// Methods without a schema are checked only for the envelope
+pub fn validate(message: &Value) -> Result<&str, ValidationError> {
+    let method = match message.get("method") {
+        None => return Err(ValidationError::NotFoundBaseFields("unknown".to_string(), "method")),
+        Some(method) => method.as_str().ok_or(ValidationError::MethodIsNotString)?
+    };
+
+    if message.get("id").is_none() {
+        return Err(ValidationError::NotFoundBaseFields(method.to_string(), "id"))
+    }
+
+    let params = message.get("params")
+        .ok_or_else(|| ValidationError::NotFoundBaseFields(method.to_string(), "params"))?
+        .as_array()
+        .ok_or_else(|| ValidationError::ParamsIsNotArray(method.to_string()))?;
+
+    if let Some(schema) = find_method(method) {
+        schema.validate_params(params)?;
+    }
+
+    Ok(method)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn check(method: &str, params: Value) -> Result<(), ValidationError> {
+        validate(&json!({"id": 1, "method": method, "params": params})).map(|_| ())
+    }
+
+    fn is_arity(result: Result<(), ValidationError>) -> bool {
+        matches!(result, Err(ValidationError::IncorrectNumberOfParameters { .. } | ValidationError::ParamsIsEmpty(_)))
+    }
+
+    fn is_type(result: Result<(), ValidationError>, index: usize) -> bool {
+        matches!(result, Err(ValidationError::InvalidParamType { index: i, .. }) if i == index)
+    }
+
+    #[test]
+    fn subscribe() {
+        assert!(check("mining.subscribe", json!([])).is_ok());
+        assert!(check("mining.subscribe", json!(["cgminer/4.10"])).is_ok());
+        assert!(check("mining.subscribe", json!(["cgminer/4.10", "08000002"])).is_ok());
+        assert!(check("mining.subscribe", json!([null, null])).is_ok());
+
+        assert!(is_type(check("mining.subscribe", json!([1])), 0));
+        assert!(matches!(check("mining.subscribe", json!(["ua", "xyz"])), Err(ValidationError::NotHex { index: 1, .. })));
+        assert!(matches!(
+            check("mining.subscribe", json!(["ua", "0"])),
+            Err(ValidationError::InvalidHexLength { index: 1, got: 1, .. })
+        ));
+        assert!(is_arity(check("mining.subscribe", json!(["ua", "08000002", "extra"]))));
+    }
+
+    #[test]
+    fn authorize() {
+        assert!(check("mining.authorize", json!(["acc.worker"])).is_ok());
+        assert!(check("mining.authorize", json!(["acc.worker", "x"])).is_ok());
+        assert!(check("mining.authorize", json!(["acc.worker", null])).is_ok());
+
+        assert!(matches!(check("mining.authorize", json!([])), Err(ValidationError::ParamsIsEmpty(_))));
+        assert!(is_type(check("mining.authorize", json!([null])), 0));
+        assert!(is_type(check("mining.authorize", json!([1, "x"])), 0));
+        assert!(is_type(check("mining.authorize", json!(["acc.worker", 1])), 1));
+        assert!(is_arity(check("mining.authorize", json!(["acc.worker", "x", "extra"]))));
+    }
+
+    #[test]
+    fn submit() {
+        let share = || json!(["acc.worker", "1f", "00000001", "5f5e1000", "deadbeef"]);
+
+        assert!(check("mining.submit", share()).is_ok());
+        let mut with_version = share();
+        with_version.as_array_mut().unwrap().push(json!("1fffe000"));
+        assert!(check("mining.submit", with_version.clone()).is_ok());
+
+        assert!(is_arity(check("mining.submit", json!(["acc.worker", "1f", "00000001", "5f5e1000"]))));
+        with_version.as_array_mut().unwrap().push(json!("extra"));
+        assert!(is_arity(check("mining.submit", with_version)));
+
+        assert!(is_type(check("mining.submit", json!(["acc.worker", 1, "00000001", "5f5e1000", "deadbeef"])), 1));
+        assert!(is_type(check("mining.submit", json!(["acc.worker", "1f", "00000001", 1, "deadbeef"])), 3));
+        assert!(matches!(
+            check("mining.submit", json!(["acc.worker", "1f", "00000001", "5f5e1000", "deadbeeg"])),
+            Err(ValidationError::NotHex { index: 4, .. })
+        ));
+        assert!(matches!(
+            check("mining.submit", json!(["acc.worker", "1f", "00000001", "5f5e10", "deadbeef"])),
+            Err(ValidationError::InvalidHexLength { index: 3, got: 6, .. })
+        ));
+        assert!(matches!(
+            check("mining.submit", json!(["acc.worker", "1f", "000000000000000001", "5f5e1000", "deadbeef"])),
+            Err(ValidationError::InvalidHexLength { index: 2, got: 18, .. })
+        ));
+    }
+
+    #[test]
+    fn configure() {
+        assert!(check("mining.configure", json!([["version-rolling"]])).is_ok());
+        assert!(check("mining.configure", json!([["version-rolling"], {"version-rolling.mask": "1fffe000"}])).is_ok());
+
+        assert!(matches!(check("mining.configure", json!([])), Err(ValidationError::ParamsIsEmpty(_))));
+        assert!(is_type(check("mining.configure", json!(["version-rolling"])), 0));
+        assert!(is_type(check("mining.configure", json!([[], []])), 1));
+        assert!(is_arity(check("mining.configure", json!([[], {}, "extra"]))));
+    }
+
+    #[test]
+    fn suggest_difficulty() {
+        assert!(check("mining.suggest_difficulty", json!([1024])).is_ok());
+        assert!(check("mining.suggest_difficulty", json!([0.5])).is_ok());
+
+        assert!(matches!(check("mining.suggest_difficulty", json!([])), Err(ValidationError::ParamsIsEmpty(_))));
+        assert!(is_type(check("mining.suggest_difficulty", json!(["1024"])), 0));
+        assert!(is_arity(check("mining.suggest_difficulty", json!([1024, 1]))));
+    }
+
+    #[test]
+    fn extranonce_subscribe() {
+        assert!(check("mining.extranonce.subscribe", json!([])).is_ok());
+        assert!(is_arity(check("mining.extranonce.subscribe", json!([true]))));
+    }
+
+    #[test]
+    fn method_without_schema_passes_through() {
+        assert_eq!(validate(&json!({"id": 1, "method": "mining.custom", "params": [1, "a", null]})).unwrap(), "mining.custom");
+        assert_eq!(validate(&json!({"id": 1, "method": "mining.custom", "params": []})).unwrap(), "mining.custom");
+
+        // The envelope is checked still
+        assert!(matches!(
+            validate(&json!({"id": 1, "method": "mining.custom", "params": {}})),
+            Err(ValidationError::ParamsIsNotArray(_))
+        ));
+        assert!(matches!(
+            validate(&json!({"method": "mining.custom", "params": []})),
+            Err(ValidationError::NotFoundBaseFields(_, "id"))
+        ));
+        assert!(matches!(validate(&json!({"id": 1, "method": 1, "params": []})), Err(ValidationError::MethodIsNotString)));
+    }
+}
@@ -0,0 +1,211 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use prometheus::core::Collector;
+use prometheus::proto::MetricFamily;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use serde_json::Value;
+
+use crate::registry::ConnRegistry;
+
+pub const QUEUE_HIGH: &str = "high";
+pub const QUEUE_NORMAL: &str = "normal";
+// Subaccount label of the shares whose subaccount isn't known, e.g. of a worker which isn't authorized
+pub const UNKNOWN_SUB_ACCOUNT: &str = "unknown";
+
+// Handlers answer from memory in microseconds, authorize waits for the API and may take seconds
+const HANDLER_BUCKETS: [f64; 13] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Series of the proxy in the Prometheus format, they're served on `/metrics` of the admin API
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    connections: IntGaugeVec,
+    connections_accepted: IntCounterVec,
+    shares_accepted: IntCounterVec,
+    shares_rejected: IntCounterVec,
+    upstream_sessions: IntGaugeVec,
+    queue_depth: IntGaugeVec,
+    handler_duration: HistogramVec,
+    jobs: IntCounterVec,
+    events_published: IntCounterVec,
+    events_dropped: IntCounterVec,
+    events_buffered: IntGauge,
+    events_spooled: IntGauge,
+    spool_bytes: IntGauge,
+    spool_age: IntGauge
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("proxy_gates".to_string()), None)
+            .expect("the prefix of the metrics is valid");
+
+        Self {
+            connections: register(&registry, IntGaugeVec::new(
+                Opts::new("connections", "Open miner connections"), &["listener"]
+            )),
+            connections_accepted: register(&registry, IntCounterVec::new(
+                Opts::new("connections_accepted_total", "Miner connections accepted"), &["listener"]
+            )),
+            shares_accepted: register(&registry, IntCounterVec::new(
+                Opts::new("shares_accepted_total", "Shares the pools have accepted"), &["subaccount"]
+            )),
+            shares_rejected: register(&registry, IntCounterVec::new(
+                Opts::new("shares_rejected_total", "Shares rejected by the pools or the proxy"), &["subaccount", "reason"]
+            )),
+            upstream_sessions: register(&registry, IntGaugeVec::new(
+                Opts::new("upstream_sessions", "Pool sessions of the miners"), &["pool", "state"]
+            )),
+            queue_depth: register(&registry, IntGaugeVec::new(
+                Opts::new("scheduler_queue_depth", "Jobs waiting for the scheduler"), &["queue"]
+            )),
+            handler_duration: register(&registry, HistogramVec::new(
+                HistogramOpts::new("handler_duration_seconds", "Time the method handlers take")
+                    .buckets(HANDLER_BUCKETS.to_vec()),
+                &["method"]
+            )),
+            jobs: register(&registry, IntCounterVec::new(
+                Opts::new("jobs_total", "Jobs answered to the miners by their outcome"), &["outcome"]
+            )),
+            events_published: register(&registry, IntCounterVec::new(
+                Opts::new("events_published_total", "Share and worker events the sink has taken"), &["sink"]
+            )),
+            events_dropped: register(&registry, IntCounterVec::new(
+                Opts::new("events_dropped_total", "Share and worker events which are lost"), &["reason"]
+            )),
+            events_buffered: register(&registry, IntGauge::new(
+                "events_buffered", "Events waiting for the sink"
+            )),
+            events_spooled: register(&registry, IntGauge::new(
+                "events_spooled", "Events in the spool on disk which the sink hasn't taken yet"
+            )),
+            spool_bytes: register(&registry, IntGauge::new(
+                "events_spool_bytes", "Size of the spool's files"
+            )),
+            spool_age: register(&registry, IntGauge::new(
+                "events_spool_oldest_age_seconds", "Age of the oldest event in the spool, 0 when it's empty"
+            )),
+            registry
+        }
+    }
+
+    /// Adds series of another component, they're served along with the proxy's ones
+    pub fn register(&self, collector: Box<dyn Collector>) -> prometheus::Result<()> {
+        self.registry.register(collector)
+    }
+
+    /// Every series in the text format
+    pub async fn render(&self, registry: &ConnRegistry) -> anyhow::Result<String> {
+        self.count_upstreams(registry).await;
+
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.gather(), &mut buf)?;
+
+        Ok(String::from_utf8(buf)?)
+    }
+
+    /// Current values of every series
+    pub fn gather(&self) -> Vec<MetricFamily> {
+        self.registry.gather()
+    }
+
+    /// Counts the upstream sessions now, the sessions know whether their pool is connected
+    pub async fn count_upstreams(&self, registry: &ConnRegistry) {
+        let upstreams = registry.upstreams().await;
+
+        // Pools nobody is connected to anymore are dropped
+        self.upstream_sessions.reset();
+        for (pool, summary) in upstreams {
+            let disconnected = summary.sessions.saturating_sub(summary.connected_sessions);
+            self.upstream_sessions.with_label_values(&[pool.as_str(), "connected"]).set(summary.connected_sessions as i64);
+            self.upstream_sessions.with_label_values(&[pool.as_str(), "disconnected"]).set(disconnected as i64);
+        }
+    }
+
+    pub(crate) fn connection_opened(&self, listener: &str) {
+        self.connections.with_label_values(&[listener]).inc();
+        self.connections_accepted.with_label_values(&[listener]).inc();
+    }
+
+    pub(crate) fn connection_closed(&self, listener: &str) {
+        self.connections.with_label_values(&[listener]).dec();
+    }
+
+    /// Counts the pool's answer on a submit, `reason` is why the pool has rejected the share
+    pub(crate) fn share_answered(&self, sub_account: &str, reason: Option<&str>) {
+        let sub_account = sub_account_label(sub_account);
+        match reason {
+            None => self.shares_accepted.with_label_values(&[sub_account]).inc(),
+            Some(reason) => self.shares_rejected.with_label_values(&[sub_account, reason]).inc()
+        }
+    }
+
+    /// A share the proxy has rejected itself, e.g. of a worker which isn't authorized
+    pub fn share_rejected(&self, sub_account: &str, reason: &str) {
+        self.shares_rejected.with_label_values(&[sub_account_label(sub_account), reason]).inc();
+    }
+
+    pub fn set_queue_depth(&self, queue: &str, depth: usize) {
+        self.queue_depth.with_label_values(&[queue]).set(depth as i64);
+    }
+
+    pub fn observe_handler(&self, method: &str, elapsed: Duration) {
+        self.handler_duration.with_label_values(&[method]).observe(elapsed.as_secs_f64());
+    }
+
+    pub(crate) fn job_done(&self, outcome: &str) {
+        self.jobs.with_label_values(&[outcome]).inc();
+    }
+
+    pub(crate) fn events_published(&self, sink: &str, count: usize) {
+        self.events_published.with_label_values(&[sink]).inc_by(count as u64);
+    }
+
+    pub fn events_dropped(&self, reason: &str, count: usize) {
+        self.events_dropped.with_label_values(&[reason]).inc_by(count as u64);
+    }
+
+    pub(crate) fn set_events_buffered(&self, count: usize) {
+        self.events_buffered.set(count as i64);
+    }
+
+    pub(crate) fn set_spool(&self, events: u64, bytes: u64) {
+        self.events_spooled.set(events as i64);
+        self.spool_bytes.set(bytes as i64);
+    }
+
+    pub(crate) fn set_spool_age(&self, age: Duration) {
+        self.spool_age.set(age.as_secs() as i64);
+    }
+}
+
+fn register<M: Collector + Clone + 'static>(registry: &Registry, metric: prometheus::Result<M>) -> M {
+    // Names and labels are constants, an error here is a bug
+    let metric = metric.expect("the metric is valid");
+    registry.register(Box::new(metric.clone())).expect("the metric is registered once");
+    metric
+}
+
+fn sub_account_label(sub_account: &str) -> &str {
+    if sub_account.is_empty() { UNKNOWN_SUB_ACCOUNT } else { sub_account }
+}
+
+/// Why the pool has rejected the share, none if it's accepted. The error is `[code, message, data]`
+/// or `{"code": .., "message": ..}`, the codes are the usual stratum ones
+pub(crate) fn reject_reason(answer: &Value) -> Option<&'static str> {
+    let error = answer.get("error").filter(|error| !error.is_null());
+    let code = error.and_then(|error| error.get(0).or_else(|| error.get("code"))).and_then(Value::as_i64);
+
+    match (answer.get("result"), error, code) {
+        (Some(Value::Bool(true)), None, _) => None,
+        (_, _, Some(21)) => Some("stale"),
+        (_, _, Some(22)) => Some("duplicate"),
+        (_, _, Some(23)) => Some("low_difficulty"),
+        (_, _, Some(24)) => Some("unauthorized"),
+        (_, _, Some(25)) => Some("not_subscribed"),
+        (_, None, _) => Some("rejected"),
+        _ => Some("other")
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use serde_json::Value;
+
+use config::PassthroughConfig;
+
+// Ids of forwarded requests start from here, so they don't clash with ids of the miner's own requests
+const PASSTHROUGH_ID_BASE: u64 = 1 << 40;
+// How many forwarded requests may wait for the pool's answer at once
+const MAX_PENDING: usize = 256;
+
+/// Which of the methods without a handler are forwarded to the pool
+#[derive(Debug, Default)]
+pub struct PassthroughPolicy {
+    enabled: bool,
+    allow: Vec<String>,
+    deny: Vec<String>
+}
+
+/// Maps ids of the forwarded requests to the ids the miner has sent them with, and ids of the pools'
+/// requests to the pools which wait for the miner's answer
+#[derive(Debug)]
+pub struct Correlator {
+    inner: Mutex<CorrelatorInner>
+}
+
+#[derive(Debug)]
+struct CorrelatorInner {
+    next_id: u64,
+    pending: HashMap<u64, Value>,
+    order: VecDeque<u64>,
+    // Requests of the pools by their ids, the miner's reply goes back to the pool which has asked
+    pool_requests: HashMap<String, Arc<str>>,
+    pool_order: VecDeque<String>
+}
+
+impl PassthroughPolicy {
+    pub fn new(config: &PassthroughConfig) -> Self {
+        Self {
+            enabled: config.enabled,
+            allow: config.allow.clone(),
+            deny: config.deny.clone()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn allows(&self, method: &str) -> bool {
+        if !self.enabled || self.deny.iter().any(|pattern| matches(pattern, method)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|pattern| matches(pattern, method))
+    }
+}
+
+fn matches(pattern: &str, method: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => method.starts_with(prefix),
+        None => pattern == method
+    }
+}
+
+impl Default for Correlator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Correlator {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(CorrelatorInner {
+                next_id: PASSTHROUGH_ID_BASE,
+                pending: HashMap::new(),
+                order: VecDeque::new(),
+                pool_requests: HashMap::new(),
+                pool_order: VecDeque::new()
+            })
+        }
+    }
+
+    /// Replaces the id of the miner's request with an upstream one and returns the request to forward
+    pub fn outgoing(&self, mut message: Value) -> String {
+        let mut inner = self.inner.lock().unwrap_or_else(|err| err.into_inner());
+
+        let upstream_id = inner.next_id;
+        inner.next_id += 1;
+
+        let miner_id = message.get("id").cloned().unwrap_or(Value::Null);
+        message["id"] = Value::from(upstream_id);
+
+        // The pool never answered the oldest ones, forget them
+        if inner.order.len() >= MAX_PENDING && let Some(oldest) = inner.order.pop_front() {
+            inner.pending.remove(&oldest);
+        }
+        inner.pending.insert(upstream_id, miner_id);
+        inner.order.push_back(upstream_id);
+
+        message.to_string()
+    }
+
+    /// If the message from the pool is an answer on a forwarded request, restores the miner's id and
+    /// tells it's such an answer. If it's a request of the pool, remembers which pool waits for the answer
+    pub fn incoming(&self, pool: &Arc<str>, message: String) -> (String, bool) {
+        let Ok(mut json) = serde_json::from_str::<Value>(&message) else {
+            return (message, false);
+        };
+
+        if json.get("method").is_some() {
+            if let Some(id) = json.get("id").filter(|id| !id.is_null()) {
+                let mut inner = self.inner.lock().unwrap_or_else(|err| err.into_inner());
+                if inner.pool_order.len() >= MAX_PENDING && let Some(oldest) = inner.pool_order.pop_front() {
+                    inner.pool_requests.remove(&oldest);
+                }
+                inner.pool_requests.insert(id.to_string(), Arc::clone(pool));
+                inner.pool_order.push_back(id.to_string());
+            }
+            return (message, false);
+        }
+
+        let upstream_id = match json.get("id").and_then(|id| id.as_u64()) {
+            Some(id) if id >= PASSTHROUGH_ID_BASE => id,
+            _ => return (message, false)
+        };
+
+        let mut inner = self.inner.lock().unwrap_or_else(|err| err.into_inner());
+        let Some(miner_id) = inner.pending.remove(&upstream_id) else {
+            return (message, false);
+        };
+        inner.order.retain(|id| *id != upstream_id);
+
+        json["id"] = miner_id;
+        (json.to_string(), true)
+    }
+
+    /// The pool whose request the miner answers with the reply
+    pub fn reply_pool(&self, reply: &Value) -> Option<Arc<str>> {
+        let id = reply.get("id")?.to_string();
+
+        let mut inner = self.inner.lock().unwrap_or_else(|err| err.into_inner());
+        let pool = inner.pool_requests.remove(&id)?;
+        inner.pool_order.retain(|pending| *pending != id);
+
+        Some(pool)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn answer_gets_the_miner_id_back() {
+        let correlator = Correlator::new();
+        let pool: Arc<str> = Arc::from("pool:3333");
+
+        let request: Value = serde_json::from_str(&correlator.outgoing(json!({"id": 1, "method": "mining.get_transactions", "params": []}))).unwrap();
+        let upstream_id = request["id"].as_u64().unwrap();
+        assert!(upstream_id >= PASSTHROUGH_ID_BASE);
+
+        let (answer, is_answer) = correlator.incoming(&pool, json!({"id": upstream_id, "result": [], "error": null}).to_string());
+        assert!(is_answer);
+        assert_eq!(serde_json::from_str::<Value>(&answer).unwrap()["id"], json!(1));
+
+        // It's answered once
+        let (_, is_answer) = correlator.incoming(&pool, json!({"id": upstream_id, "result": [], "error": null}).to_string());
+        assert!(!is_answer);
+    }
+
+    #[test]
+    fn other_messages_pass_as_they_are() {
+        let correlator = Correlator::new();
+        let pool: Arc<str> = Arc::from("pool:3333");
+
+        let answer = json!({"id": 1, "result": true, "error": null}).to_string();
+        assert_eq!(correlator.incoming(&pool, answer.clone()), (answer, false));
+        let notify = json!({"id": null, "method": "mining.notify", "params": []}).to_string();
+        assert_eq!(correlator.incoming(&pool, notify.clone()), (notify, false));
+    }
+}
@@ -0,0 +1,514 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::json;
+use tokio_util::sync::CancellationToken;
+
+use tracing::info;
+
+use control_protocol::ConnFilter;
+use score::miner::{MinerSnapshot, UpstreamInfo};
+use score::session::SessionHandle;
+
+use crate::connection::pending::PendingSubmits;
+use crate::events::{EventEmitter, EventKind};
+use crate::metrics::METRICS;
+use crate::server::ConnId;
+
+// How many closed connections are kept for the queries
+const MAX_CLOSED_CONNS: usize = 1024;
+
+/// Why a connection has been closed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "detail")]
+pub enum CloseReason {
+    ClientClosed, // the miner closed the socket
+    HandshakeTimeout, // the miner hasn't authorized in time
+    Kicked(String), // closed on purpose, the string tells why
+    Shutdown, // the proxy is going down
+    Error(String), // read or protocol error
+    Closed // the connection's tasks stopped it (slow reader, writer error)
+}
+
+/// Which connections an operation targets
+#[derive(Debug, Clone)]
+pub enum Selector {
+    All,
+    Conn(ConnId),
+    Worker(String),
+    SubAccount(String),
+    RemoteIp(IpAddr),
+    Pool(String),
+    // Connections accepted on the listener with this address
+    Listener(SocketAddr),
+    // Connections every one of the selectors matches
+    Every(Vec<Selector>)
+}
+
+/// Pool sessions of all the miners to one pool
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PoolSummary {
+    pub sessions: usize,
+    pub connected_sessions: usize,
+    pub workers: usize
+}
+
+/// A connection which is open now
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnInfo {
+    pub conn_id: ConnId,
+    pub remote: SocketAddr,
+    pub local: SocketAddr,
+    pub connected_at: u64, // unix time, seconds
+    #[serde(rename = "duration_secs", serialize_with = "serialize_secs")]
+    pub duration: Duration,
+    pub session: Option<Arc<MinerSnapshot>>
+}
+
+/// A connection with the live state of its pool sessions
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionDetails {
+    #[serde(flatten)]
+    pub conn: ConnInfo,
+    pub upstreams: Vec<UpstreamInfo>
+}
+
+/// A connection which has been closed
+#[derive(Debug, Clone, Serialize)]
+pub struct ClosedConn {
+    pub conn_id: ConnId,
+    pub remote: SocketAddr,
+    pub connected_at: u64,
+    #[serde(rename = "duration_secs", serialize_with = "serialize_secs")]
+    pub duration: Duration,
+    pub reason: CloseReason,
+    pub workers: Vec<String>
+}
+
+struct ConnEntry {
+    remote: SocketAddr,
+    local: SocketAddr,
+    connected_at: u64,
+    opened: Instant,
+    token: CancellationToken,
+    session: Option<SessionHandle>,
+    pending: Option<Arc<PendingSubmits>>,
+    close_reason: Option<CloseReason>,
+    // Keys of the connection in the indexes, so it can be taken out of them
+    workers: HashSet<String>,
+    sub_accounts: HashSet<String>,
+    pools: HashSet<String>,
+    // Subaccount and pool of every worker, for the events
+    upstreams: HashMap<String, (String, String)>
+}
+
+/// Every open connection of the server. Connections are found by id, by the workers authorized over them,
+/// by subaccount, by remote IP and by upstream pool
+#[derive(Default)]
+pub struct ConnRegistry {
+    conns: DashMap<ConnId, ConnEntry>,
+    by_worker: DashMap<String, HashSet<ConnId>>,
+    by_sub_account: DashMap<String, HashSet<ConnId>>,
+    by_ip: DashMap<IpAddr, HashSet<ConnId>>,
+    by_pool: DashMap<String, HashSet<ConnId>>,
+    closed: Mutex<VecDeque<ClosedConn>>,
+    events: EventEmitter
+}
+
+impl ConnRegistry {
+    /// The workers coming and going are published to `events`
+    pub fn new(events: EventEmitter) -> Self {
+        Self { events, ..Self::default() }
+    }
+
+    pub(crate) fn register(&self, conn_id: ConnId, remote: SocketAddr, local: SocketAddr, token: CancellationToken) {
+        let connected_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+
+        self.conns.insert(conn_id, ConnEntry {
+            remote,
+            local,
+            connected_at,
+            opened: Instant::now(),
+            token,
+            session: None,
+            pending: None,
+            close_reason: None,
+            workers: HashSet::new(),
+            sub_accounts: HashSet::new(),
+            pools: HashSet::new(),
+            upstreams: HashMap::new()
+        });
+        index_insert(&self.by_ip, remote.ip(), conn_id);
+        METRICS.connection_opened(&local.to_string());
+    }
+
+    pub(crate) fn attach_session(&self, conn_id: ConnId, session: SessionHandle, pending: Arc<PendingSubmits>) {
+        if let Some(mut entry) = self.conns.get_mut(&conn_id) {
+            entry.session = Some(session);
+            entry.pending = Some(pending);
+        }
+    }
+
+    /// Brings the worker, subaccount and pool indexes in line with the session's workers
+    pub(crate) fn update_session(&self, conn_id: ConnId, snapshot: &MinerSnapshot) {
+        let workers: HashSet<String> = snapshot.workers.iter().map(|w| w.worker_name.clone()).collect();
+        let sub_accounts: HashSet<String> = snapshot.workers.iter().map(|w| w.sub_account_name.clone()).collect();
+        let pools: HashSet<String> = snapshot.workers.iter().map(|w| w.pool_addr.clone()).collect();
+
+        let Some(mut entry) = self.conns.get_mut(&conn_id) else {
+            return;
+        };
+
+        let old_workers = std::mem::replace(&mut entry.workers, workers.clone());
+        let old_sub_accounts = std::mem::replace(&mut entry.sub_accounts, sub_accounts.clone());
+        let old_pools = std::mem::replace(&mut entry.pools, pools.clone());
+        let remote = entry.remote;
+        let upstreams: HashMap<String, (String, String)> = snapshot.workers.iter()
+            .map(|w| (w.worker_name.clone(), (w.sub_account_name.clone(), w.pool_addr.clone())))
+            .collect();
+        let old_upstreams = std::mem::replace(&mut entry.upstreams, upstreams.clone());
+        drop(entry);
+
+        // Workers leave with the connection, they're never taken out of a session
+        for (worker, (sub_account, pool)) in upstreams {
+            match old_upstreams.get(&worker) {
+                None => self.events.emit(conn_id, &worker, &sub_account, EventKind::WorkerConnected { remote, pool }),
+                Some((_, old_pool)) if *old_pool != pool => {
+                    self.events.emit(conn_id, &worker, &sub_account, EventKind::UpstreamSwitched { from: old_pool.clone(), to: pool });
+                }
+                Some(_) => {}
+            }
+        }
+
+        reindex(&self.by_worker, conn_id, old_workers, workers);
+        reindex(&self.by_sub_account, conn_id, old_sub_accounts, sub_accounts);
+        reindex(&self.by_pool, conn_id, old_pools, pools);
+    }
+
+    /// Closes the connection. The first reason given is the one recorded
+    pub fn close(&self, conn_id: ConnId, reason: CloseReason) -> bool {
+        let Some(mut entry) = self.conns.get_mut(&conn_id) else {
+            return false;
+        };
+
+        if entry.close_reason.is_none() {
+            entry.close_reason = Some(reason);
+        }
+        entry.token.cancel();
+        true
+    }
+
+    /// Closes every connection the selector matches, returns how many of them
+    pub fn kick(&self, selector: &Selector, reason: &str) -> usize {
+        self.select(selector)
+            .into_iter()
+            .filter(|conn_id| self.close(*conn_id, CloseReason::Kicked(reason.to_string())))
+            .count()
+    }
+
+    /// Takes the connection out of the registry once its tasks are stopped. `fallback` is recorded
+    /// if nobody has given a reason to close it
+    pub(crate) fn remove(&self, conn_id: ConnId, fallback: CloseReason) -> Option<ClosedConn> {
+        let (_, entry) = self.conns.remove(&conn_id)?;
+        METRICS.connection_closed(&entry.local.to_string());
+
+        index_remove(&self.by_ip, &entry.remote.ip(), conn_id);
+        for worker in &entry.workers {
+            index_remove(&self.by_worker, worker, conn_id);
+        }
+        for sub_account in &entry.sub_accounts {
+            index_remove(&self.by_sub_account, sub_account, conn_id);
+        }
+        for pool in &entry.pools {
+            index_remove(&self.by_pool, pool, conn_id);
+        }
+
+        let closed = ClosedConn {
+            conn_id,
+            remote: entry.remote,
+            connected_at: entry.connected_at,
+            duration: entry.opened.elapsed(),
+            reason: entry.close_reason.unwrap_or(fallback),
+            workers: entry.workers.into_iter().collect()
+        };
+        for (worker, (sub_account, _)) in entry.upstreams {
+            self.events.emit(conn_id, &worker, &sub_account, EventKind::WorkerDisconnected {
+                remote: closed.remote,
+                reason: closed.reason.clone(),
+                duration_secs: closed.duration.as_secs()
+            });
+        }
+        info!(
+            conn_id, remote = %closed.remote, reason = ?closed.reason, workers = ?closed.workers,
+            duration_secs = closed.duration.as_secs(), "Connection is closed"
+        );
+
+        let mut history = self.closed.lock().unwrap_or_else(|err| err.into_inner());
+        if history.len() >= MAX_CLOSED_CONNS {
+            history.pop_front();
+        }
+        history.push_back(closed.clone());
+
+        Some(closed)
+    }
+
+    /// Sends the message to the miners of every connection the selector matches, returns how many got it
+    pub async fn notify(&self, selector: &Selector, message: &str) -> usize {
+        // Handles are taken out first, the map isn't locked while the sessions are awaited
+        let sessions: Vec<SessionHandle> = self.select(selector)
+            .into_iter()
+            .filter_map(|conn_id| self.session(conn_id))
+            .collect();
+
+        let mut notified = 0;
+        for session in sessions {
+            if session.notify_miner(message.to_string()).await.is_ok() {
+                notified += 1;
+            }
+        }
+
+        notified
+    }
+
+    /// Tells the miners to connect again, to `host:port` if it's given. Their workers are authorized
+    /// from scratch then
+    pub async fn reconnect(&self, selector: &Selector, target: Option<(&str, u16)>) -> usize {
+        let params = match target {
+            Some((host, port)) => json!([host, port, 0]),
+            None => json!([])
+        };
+        let message = json!({ "id": null, "method": "client.reconnect", "params": params });
+        self.notify(selector, &message.to_string()).await
+    }
+
+    /// Shows the text to the operators of the miners
+    pub async fn show_message(&self, selector: &Selector, text: &str) -> usize {
+        let message = json!({ "id": null, "method": "client.show_message", "params": [text] });
+        self.notify(selector, &message.to_string()).await
+    }
+
+    /// Asks the pools of the miners for the difficulty with mining.suggest_difficulty, the pools
+    /// set it with mining.set_difficulty. Returns how many pool sessions were asked
+    pub async fn suggest_difficulty(&self, selector: &Selector, difficulty: f64) -> usize {
+        let sessions: Vec<SessionHandle> = self.select(selector)
+            .into_iter()
+            .filter_map(|conn_id| self.session(conn_id))
+            .collect();
+        let message = json!({ "id": null, "method": "mining.suggest_difficulty", "params": [difficulty] }).to_string();
+
+        let mut asked = 0;
+        for session in sessions {
+            let Ok(upstreams) = session.upstreams().await else {
+                continue;
+            };
+            for upstream in upstreams {
+                if let Ok(Some(pool_tx)) = session.pool_tx_for(&upstream.pool_addr).await
+                    && pool_tx.send(message.clone()).await.is_ok()
+                {
+                    asked += 1;
+                }
+            }
+        }
+
+        asked
+    }
+
+    /// Pool sessions of all the connections by pool
+    pub async fn upstreams(&self) -> BTreeMap<String, PoolSummary> {
+        let sessions: Vec<SessionHandle> = self.conns.iter().filter_map(|entry| entry.session.clone()).collect();
+
+        let mut pools: BTreeMap<String, PoolSummary> = BTreeMap::new();
+        for session in sessions {
+            let Ok(upstreams) = session.upstreams().await else {
+                continue;
+            };
+
+            for upstream in upstreams {
+                let pool = pools.entry(upstream.pool_addr).or_default();
+                pool.sessions += 1;
+                pool.connected_sessions += usize::from(upstream.is_connected);
+                pool.workers += upstream.workers.len();
+            }
+        }
+
+        pools
+    }
+
+    /// Ids of the open connections the selector matches
+    pub fn select(&self, selector: &Selector) -> Vec<ConnId> {
+        match selector {
+            Selector::All => self.conns.iter().map(|entry| *entry.key()).collect(),
+            Selector::Conn(conn_id) => self.conns.contains_key(conn_id).then_some(*conn_id).into_iter().collect(),
+            Selector::Worker(worker) => index_get(&self.by_worker, worker),
+            Selector::SubAccount(sub_account) => index_get(&self.by_sub_account, sub_account),
+            Selector::RemoteIp(ip) => index_get(&self.by_ip, ip),
+            Selector::Pool(pool) => index_get(&self.by_pool, pool),
+            // Listeners are removed rarely, it isn't worth an index
+            Selector::Listener(local) => self.conns.iter()
+                .filter(|entry| entry.local == *local)
+                .map(|entry| *entry.key())
+                .collect(),
+            Selector::Every(selectors) => {
+                let Some((first, rest)) = selectors.split_first() else {
+                    return self.select(&Selector::All);
+                };
+
+                let mut conns: HashSet<ConnId> = self.select(first).into_iter().collect();
+                for selector in rest {
+                    let matched: HashSet<ConnId> = self.select(selector).into_iter().collect();
+                    conns.retain(|conn_id| matched.contains(conn_id));
+                }
+                conns.into_iter().collect()
+            }
+        }
+    }
+
+    pub fn get(&self, conn_id: ConnId) -> Option<ConnInfo> {
+        self.conns.get(&conn_id).map(|entry| conn_info(conn_id, &entry))
+    }
+
+    /// The connection and its pool sessions as they are at the moment
+    pub async fn inspect(&self, conn_id: ConnId) -> Option<SessionDetails> {
+        let conn = self.get(conn_id)?;
+        let upstreams = match self.session(conn_id) {
+            Some(session) => session.upstreams().await.ok()?,
+            None => Vec::new()
+        };
+
+        Some(SessionDetails { conn, upstreams })
+    }
+
+    pub fn list(&self, selector: &Selector) -> Vec<ConnInfo> {
+        self.select(selector).into_iter().filter_map(|conn_id| self.get(conn_id)).collect()
+    }
+
+    pub fn session(&self, conn_id: ConnId) -> Option<SessionHandle> {
+        self.conns.get(&conn_id).and_then(|entry| entry.session.clone())
+    }
+
+    /// Connections closed lately, the oldest first
+    pub fn closed(&self) -> Vec<ClosedConn> {
+        self.closed.lock().unwrap_or_else(|err| err.into_inner()).iter().cloned().collect()
+    }
+
+    /// Submits of all the connections which wait for the pool's answer
+    pub fn pending_submits(&self) -> usize {
+        self.conns.iter().filter_map(|entry| entry.pending.as_ref().map(|pending| pending.len())).sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.conns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conns.is_empty()
+    }
+}
+
+/// A connection has to match every field of the filter which is set
+impl From<ConnFilter> for Selector {
+    fn from(filter: ConnFilter) -> Self {
+        let mut selectors: Vec<Selector> = [
+            filter.worker.map(Selector::Worker),
+            filter.subaccount.map(Selector::SubAccount),
+            filter.ip.map(Selector::RemoteIp),
+            filter.pool.map(Selector::Pool)
+        ].into_iter().flatten().collect();
+
+        match selectors.len() {
+            0 => Selector::All,
+            1 => selectors.remove(0),
+            _ => Selector::Every(selectors)
+        }
+    }
+}
+
+fn conn_info(conn_id: ConnId, entry: &ConnEntry) -> ConnInfo {
+    ConnInfo {
+        conn_id,
+        remote: entry.remote,
+        local: entry.local,
+        connected_at: entry.connected_at,
+        duration: entry.opened.elapsed(),
+        session: entry.session.as_ref().map(|session| session.snapshot())
+    }
+}
+
+fn serialize_secs<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_secs())
+}
+
+fn reindex(index: &DashMap<String, HashSet<ConnId>>, conn_id: ConnId, old: HashSet<String>, new: HashSet<String>) {
+    for key in old.difference(&new) {
+        index_remove(index, key, conn_id);
+    }
+    for key in new.difference(&old) {
+        index_insert(index, key.clone(), conn_id);
+    }
+}
+
+fn index_insert<K: Eq + Hash>(index: &DashMap<K, HashSet<ConnId>>, key: K, conn_id: ConnId) {
+    index.entry(key).or_default().insert(conn_id);
+}
+
+fn index_remove<K: Eq + Hash>(index: &DashMap<K, HashSet<ConnId>>, key: &K, conn_id: ConnId) {
+    index.remove_if_mut(key, |_, conns| {
+        conns.remove(&conn_id);
+        conns.is_empty()
+    });
+}
+
+fn index_get<K: Eq + Hash>(index: &DashMap<K, HashSet<ConnId>>, key: &K) -> Vec<ConnId> {
+    index.get(key).map(|conns| conns.iter().copied().collect()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(mut conns: Vec<ConnId>) -> Vec<ConnId> {
+        conns.sort();
+        conns
+    }
+
+    fn registry() -> ConnRegistry {
+        let registry = ConnRegistry::new(EventEmitter::default());
+        let (main, other) = ("0.0.0.0:3333".parse().unwrap(), "0.0.0.0:4444".parse().unwrap());
+
+        registry.register(1, "10.0.0.1:5000".parse().unwrap(), main, CancellationToken::new());
+        registry.register(2, "10.0.0.1:5001".parse().unwrap(), other, CancellationToken::new());
+        registry.register(3, "10.0.0.2:5000".parse().unwrap(), other, CancellationToken::new());
+        registry
+    }
+
+    #[test]
+    fn every_selector_has_to_match() {
+        let registry = registry();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let selector = Selector::Every(vec![Selector::RemoteIp(ip), Selector::Listener("0.0.0.0:4444".parse().unwrap())]);
+        assert_eq!(registry.select(&selector), [2]);
+        assert_eq!(sorted(registry.select(&Selector::Every(vec![Selector::RemoteIp(ip)]))), [1, 2]);
+        assert_eq!(sorted(registry.select(&Selector::Every(Vec::new()))), [1, 2, 3]);
+    }
+
+    #[test]
+    fn filter_matches_every_field() {
+        let registry = registry();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let filter = ConnFilter { ip: Some(ip), ..ConnFilter::default() };
+        assert_eq!(sorted(registry.select(&Selector::from(filter))), [1, 2]);
+
+        // None of the connections has a pool yet
+        let filter = ConnFilter { ip: Some(ip), pool: Some("pool:3333".to_string()), ..ConnFilter::default() };
+        assert!(registry.select(&Selector::from(filter)).is_empty());
+
+        assert_eq!(sorted(registry.select(&Selector::from(ConnFilter::default()))), [1, 2, 3]);
+    }
+}
@@ -1,110 +1,310 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::{Arc, atomic::{AtomicU64}};
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, atomic::{AtomicU64}};
 use std::sync::atomic::Ordering;
+use std::time::Duration;
 
-use dashmap::DashMap;
-
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
 use tokio::select;
-use tokio::sync::mpsc::{Sender};
-use tokio::task::JoinHandle;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::watch;
 use tokio_util::sync::CancellationToken;
 
-use tracing::{error, info, warn};
+use tracing::{error, field, info, info_span, warn, Instrument};
 use config::Config;
-use score::job::JobRequest;
+use score::job::{JobRequest, MethodRoutes};
 
-use crate::connection::handle_connection;
+use crate::bans::BanList;
+use crate::connection::{handle_connection, ConnShared};
+use crate::events::EventEmitter;
+use crate::passthrough::PassthroughPolicy;
+use crate::registry::{CloseReason, ConnRegistry, Selector};
+use crate::stats::ShareStats;
 
 static TOTAL_CONN: AtomicU64 = AtomicU64::new(0);
 
-pub type ConnId = u64;
+pub use control_protocol::ConnId;
 
-struct ConnHandle {
-    token: CancellationToken,
-    join: JoinHandle<()>
-}
+// Accepted connection of an extra listener: the stream, the miner's address and the listener's address
+type Accepted = (TcpStream, SocketAddr, SocketAddr);
+
+// Backlog of the extra listeners
+const LISTEN_BACKLOG: u32 = 1024;
 
 #[derive(Clone)]
 pub struct Server {
     listener: Arc<TcpListener>,
     shutdown: CancellationToken,
-    tx_queue_high: Sender<JobRequest>,
-    tx_queue_norm: Sender<JobRequest>,
-    conns: Arc<DashMap<ConnId, ConnHandle>>,
-    config: Arc<Config>
+    stop_accepting: CancellationToken,
+    registry: Arc<ConnRegistry>,
+    bans: Arc<BanList>,
+    stats: Arc<ShareStats>,
+    listening: Arc<watch::Sender<bool>>,
+    // Sockets passed by systemd or the previous process for the extra listeners, by their address.
+    // They're kept open, a later upgrade hands them over again
+    activated: Arc<Mutex<HashMap<SocketAddr, std::net::TcpListener>>>,
+    shared: ConnShared,
+    config: watch::Receiver<Arc<Config>>
 }
 
 impl Server {
     pub async fn new(
-        addr: SocketAddr, tx_queue_high: Sender<JobRequest>,
+        listener: TcpListener, tx_queue_high: Sender<JobRequest>,
         tx_queue_norm: Sender<JobRequest>, token: CancellationToken,
-        config: Arc<Config>
+        routes: Arc<MethodRoutes>, config: watch::Receiver<Arc<Config>>, events: EventEmitter
     ) -> anyhow::Result<Server> {
-        let listener = Arc::new(TcpListener::bind(addr).await?);
-        let conns = Arc::new(DashMap::new());
+        let listener = Arc::new(listener);
+        let registry = Arc::new(ConnRegistry::new(events.clone()));
+        let current = config.borrow().clone();
+        let stats = Arc::new(ShareStats::new(current.stats.max_workers));
+        let shared = ConnShared {
+            registry: Arc::clone(&registry),
+            tx_queue_high,
+            tx_queue_norm,
+            routes,
+            passthrough: Arc::new(PassthroughPolicy::new(&current.passthrough)),
+            stats: Arc::clone(&stats),
+            handshake_timeout: Duration::from_secs(current.handshake_timeout_secs),
+            pending_timeout: Duration::from_secs(current.drain.pending_timeout_secs),
+            block_candidates: current.events.block_candidates,
+            events
+        };
 
         Ok(Server {
             listener,
             shutdown: token,
-            tx_queue_high,
-            tx_queue_norm,
-            conns,
+            stop_accepting: CancellationToken::new(),
+            registry,
+            bans: Arc::new(BanList::new()),
+            stats,
+            listening: Arc::new(watch::Sender::new(false)),
+            activated: Arc::new(Mutex::new(HashMap::new())),
+            shared,
             config
         })
     }
-    pub async fn server_run(self) -> anyhow::Result<()> {
+
+    /// Open connections of the server
+    pub fn registry(&self) -> Arc<ConnRegistry> {
+        Arc::clone(&self.registry)
+    }
+
+    /// Cancelling it makes the server close the listener, the open connections are kept
+    pub fn stop_accepting_token(&self) -> CancellationToken {
+        self.stop_accepting.clone()
+    }
+
+    /// Listening socket, it's handed to the new process on an upgrade
+    pub fn listener_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    /// IPs the server doesn't accept connections from
+    pub fn bans(&self) -> Arc<BanList> {
+        Arc::clone(&self.bans)
+    }
+
+    /// Hashrate and shares of the workers
+    pub fn stats(&self) -> Arc<ShareStats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Up while every listener is bound and the server accepts the miners
+    pub fn listening(&self) -> watch::Receiver<bool> {
+        self.listening.subscribe()
+    }
+
+    /// Sockets of the socket activation or of the previous process, the extra listeners of their
+    /// addresses take them instead of binding
+    pub fn set_activated(self, listeners: HashMap<SocketAddr, std::net::TcpListener>) -> Self {
+        *self.activated.lock().unwrap_or_else(|err| err.into_inner()) = listeners;
+        self
+    }
+
+    pub async fn server_run(mut self) -> anyhow::Result<()> {
         let mut next_id: ConnId = 0;
+        let main_addr = self.listener.local_addr()?;
+
+        // Extra listeners hand their connections to this loop, so ids and bans are in one place
+        let (accepted_tx, mut accepted_rx) = mpsc::channel::<Accepted>(LISTEN_BACKLOG as usize);
+        let mut extra: HashMap<SocketAddr, CancellationToken> = HashMap::new();
+
+        let config = self.config.borrow_and_update().clone();
+        for addr in config.listener_addrs() {
+            let listener = self.extra_listener(addr)?;
+            extra.insert(addr, self.spawn_listener(listener, addr, accepted_tx.clone()));
+        }
+        self.sync_bans(&config);
+        self.listening.send_replace(true);
 
         loop {
             select! {
                 _ = self.shutdown.cancelled() => break,
+                _ = self.stop_accepting.cancelled() => {
+                    info!("The server doesn't accept new connections anymore");
+                    break;
+                }
 
-                sock = self.listener.accept() => {
-                    let (socket, addr) = match sock {
-                        Ok((socket, addr)) => (socket, addr),
-                        Err(err) => {
-                            error!("Socket connection error: {}", err.to_string());
-                            continue;
-                        }
-                    };
-                    next_id += 1;
-                    let id = next_id;
+                sock = self.listener.accept() => match sock {
+                    Ok((socket, addr)) => self.accept(socket, addr, main_addr, &mut next_id).await,
+                    Err(err) => error!("Socket connection error: {}", err.to_string())
+                },
 
-                    self.spawn_conn(id, addr, socket).await;
-                }
+                Some((socket, addr, local)) = accepted_rx.recv() => self.accept(socket, addr, local, &mut next_id).await,
 
+                Ok(()) = self.config.changed() => self.apply_config(&mut extra, &accepted_tx)
             }
         }
 
+        self.listening.send_replace(false);
+
+        // The connections already accepted live until the shutdown
+        for stop in extra.values() {
+            stop.cancel();
+        }
+        drop(self.listener);
+        self.shutdown.cancelled().await;
+
         Ok(())
     }
 
-    async fn spawn_conn(&self, conn_id: ConnId, addr: SocketAddr, socket: TcpStream) {
-        let token = self.shutdown.child_token();
-        let token_clone = token.clone();
-        let token_handle_connection = token.clone();
+    async fn accept(&self, socket: TcpStream, addr: SocketAddr, local: SocketAddr, next_id: &mut ConnId) {
+        if self.bans.is_banned(&addr.ip()) {
+            info!(%addr, "The connection from a banned IP is refused");
+            return;
+        }
+        *next_id += 1;
+
+        self.spawn_conn(*next_id, addr, local, socket).await;
+    }
+
+    /// Takes the reloaded config: new connections get the new settings, listeners are bound or
+    /// removed and the bans of the config are applied
+    fn apply_config(&mut self, extra: &mut HashMap<SocketAddr, CancellationToken>, accepted_tx: &mpsc::Sender<Accepted>) {
+        let config = self.config.borrow_and_update().clone();
 
-        let tx_high = self.tx_queue_high.clone();
-        let tx_norm = self.tx_queue_norm.clone();
-        let conn = self.conns.clone();
+        self.shared.passthrough = Arc::new(PassthroughPolicy::new(&config.passthrough));
+        self.shared.handshake_timeout = Duration::from_secs(config.handshake_timeout_secs);
+        self.shared.pending_timeout = Duration::from_secs(config.drain.pending_timeout_secs);
 
-        let api_url = self.config.api_url.clone();
-        let join = tokio::spawn(async move {
-            if let Err(e) = handle_connection(socket, token_handle_connection, conn_id, tx_high, tx_norm, api_url).await {
-                warn!(%addr, %conn_id, error=?e, "conn error")
+        let wanted = config.listener_addrs();
+        for addr in &wanted {
+            if extra.contains_key(addr) {
+                continue;
             }
+
+            match self.extra_listener(*addr) {
+                Ok(listener) => {
+                    extra.insert(*addr, self.spawn_listener(listener, *addr, accepted_tx.clone()));
+                }
+                Err(err) => warn!(%addr, "Couldn't listen on the new address: {}", err)
+            }
+        }
+
+        let removed: Vec<SocketAddr> = extra.keys().filter(|addr| !wanted.contains(addr)).copied().collect();
+        for addr in removed {
+            if let Some(stop) = extra.remove(&addr) {
+                stop.cancel();
+            }
+
+            // The miners of the listener come back to the main address, the accept loop doesn't wait for them
+            let registry = Arc::clone(&self.registry);
+            let config = Arc::clone(&config);
+            tokio::spawn(async move {
+                let target = Some((config.stratum_host.as_str(), config.stratum_port));
+                let reconnected = registry.reconnect(&Selector::Listener(addr), target).await;
+                info!(%addr, reconnected, "The listener is removed");
+            }.in_current_span());
+        }
+
+        self.sync_bans(&config);
+    }
+
+    fn sync_bans(&self, config: &Config) {
+        for ip in self.bans.sync_config(&config.bans) {
+            let kicked = self.registry.kick(&Selector::RemoteIp(ip), "banned");
+            info!(%ip, kicked, "The IP is banned by the config");
+        }
+    }
+
+    fn extra_listener(&self, addr: SocketAddr) -> std::io::Result<TcpListener> {
+        let activated = self.activated.lock().unwrap_or_else(|err| err.into_inner())
+            .get(&addr)
+            .map(|listener| listener.try_clone())
+            .transpose()?;
+
+        match activated {
+            Some(listener) => {
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)
+            }
+            None => bind_listener(addr)
+        }
+    }
+
+    fn spawn_listener(&self, listener: TcpListener, local: SocketAddr, accepted_tx: mpsc::Sender<Accepted>) -> CancellationToken {
+        let stop = self.stop_accepting.child_token();
+        let stop_listener = stop.clone();
+
+        info!(%local, "Listening on an extra address");
+        tokio::spawn(async move {
+            loop {
+                select! {
+                    _ = stop_listener.cancelled() => break,
+                    sock = listener.accept() => match sock {
+                        Ok((socket, addr)) => {
+                            if accepted_tx.send((socket, addr, local)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(err) => error!(%local, "Socket connection error: {}", err.to_string())
+                    }
+                }
+            }
+
+            info!(%local, "The extra listener is closed");
         });
 
-        info!(%addr, %conn_id, "A new connection");
-        TOTAL_CONN.fetch_add(1, Ordering::Relaxed);
+        stop
+    }
 
-        let conn_handle = ConnHandle {
-            token: token_clone,
-            join,
-        };
+    async fn spawn_conn(&self, conn_id: ConnId, addr: SocketAddr, local: SocketAddr, socket: TcpStream) {
+        let token = self.shutdown.child_token();
+        let token_handle_connection = token.clone();
+
+        // The connection is registered before its task starts, so the task always finds its entry
+        self.registry.register(conn_id, addr, local, token);
+
+        let registry = Arc::clone(&self.registry);
+        let shutdown = self.shutdown.clone();
+        let shared = self.shared.clone();
+        // The workers are recorded once they authorize
+        let span = info_span!("conn", conn_id, peer = %addr, worker = field::Empty, subaccount = field::Empty);
+        tokio::spawn(async move {
+            let fallback = match handle_connection(socket, token_handle_connection, conn_id, shared).await {
+                Ok(()) if shutdown.is_cancelled() => CloseReason::Shutdown,
+                Ok(()) => CloseReason::Closed,
+                Err(e) => {
+                    warn!(%addr, %conn_id, error=?e, "conn error");
+                    CloseReason::Error(e.to_string())
+                }
+            };
+
+            registry.remove(conn_id, fallback);
+        }.instrument(span));
 
-        conn.insert(conn_id, conn_handle);
+        info!(%addr, %conn_id, "A new connection");
+        TOTAL_CONN.fetch_add(1, Ordering::Relaxed);
     }
 }
 
+fn bind_listener(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    socket.set_reuseaddr(true)?;
+    // On an upgrade the new process binds the extra addresses while this one drains
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+
+    socket.listen(LISTEN_BACKLOG)
+}
@@ -0,0 +1,265 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::stats::window::{Hashrate, HashrateWindows};
+
+pub mod window;
+
+// A worker without shares for this long may be dropped to make room for a new one
+const IDLE_SECS: u64 = 86_400;
+// A full table is cleaned up at most this often, a flood of new workers doesn't scan it every time
+const PRUNE_INTERVAL_SECS: u64 = 60;
+
+/// Hashrate and share counts of every worker and subaccount which has submitted, from the pools'
+/// answers. The hashrate is weighted by the difficulty the pool has set for the connection
+#[derive(Debug)]
+pub struct ShareStats {
+    workers: Table,
+    sub_accounts: Table,
+    max_entries: usize
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    entries: DashMap<String, Counters>,
+    last_prune: AtomicU64 // unix time, seconds
+}
+
+/// How the pool has answered on a share
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accepted,
+    Stale,
+    Rejected
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    sub_account: String, // of a worker, empty for a subaccount
+    windows: HashrateWindows,
+    accepted: u64,
+    stale: u64,
+    rejected: u64,
+    last_share_at: Option<u64>, // unix time, seconds
+    latency_ms_total: u64 // from the submit to the pool's answer, of every answered share
+}
+
+/// Stats of a worker or a subaccount as the admin API shows them
+#[derive(Debug, Clone, Serialize)]
+pub struct ShareSummary {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_account: Option<String>,
+    pub hashrate: Hashrate,
+    pub accepted: u64,
+    pub stale: u64,
+    pub rejected: u64,
+    pub last_share_at: Option<u64>,
+    pub avg_latency_ms: Option<u64>
+}
+
+/// Which workers a list has, the fastest ones over 5 minutes come first
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StatsFilter {
+    #[serde(default)]
+    pub subaccount: Option<String>,
+    #[serde(default)]
+    pub limit: Option<usize>
+}
+
+impl ShareStats {
+    /// Keeps at most `max_entries` workers and as many subaccounts
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            workers: Table::default(),
+            sub_accounts: Table::default(),
+            max_entries
+        }
+    }
+
+    /// Counts the pool's answer on a share of `difficulty`, `latency` is the time the pool took
+    pub fn record(&self, worker: &str, sub_account: &str, difficulty: f64, verdict: Verdict, latency: Duration) {
+        let now = now_secs();
+        let count = |counters: &mut Counters| counters.count(now, difficulty, verdict, latency);
+
+        self.update(&self.workers, worker, now, |counters| {
+            if counters.sub_account != sub_account {
+                counters.sub_account = sub_account.to_string();
+            }
+            count(counters);
+        });
+        if !sub_account.is_empty() {
+            self.update(&self.sub_accounts, sub_account, now, count);
+        }
+    }
+
+    pub fn worker(&self, name: &str) -> Option<ShareSummary> {
+        let now = now_secs();
+        self.workers.entries.get(name).map(|counters| counters.summary(name, now))
+    }
+
+    pub fn sub_account(&self, name: &str) -> Option<ShareSummary> {
+        let now = now_secs();
+        self.sub_accounts.entries.get(name).map(|counters| counters.summary(name, now))
+    }
+
+    pub fn workers(&self, filter: &StatsFilter) -> Vec<ShareSummary> {
+        let now = now_secs();
+        let workers = self.workers.entries.iter()
+            .filter(|entry| filter.subaccount.as_ref().is_none_or(|sub_account| entry.sub_account == *sub_account))
+            .map(|entry| entry.summary(entry.key(), now))
+            .collect();
+
+        fastest(workers, filter.limit)
+    }
+
+    pub fn sub_accounts(&self, limit: Option<usize>) -> Vec<ShareSummary> {
+        let now = now_secs();
+        let sub_accounts = self.sub_accounts.entries.iter().map(|entry| entry.summary(entry.key(), now)).collect();
+
+        fastest(sub_accounts, limit)
+    }
+
+    fn update(&self, table: &Table, name: &str, now: u64, update: impl FnOnce(&mut Counters)) {
+        if let Some(mut counters) = table.entries.get_mut(name) {
+            update(&mut counters);
+            return;
+        }
+
+        if table.entries.len() >= self.max_entries && !table.prune(now, self.max_entries) {
+            return;
+        }
+        update(&mut table.entries.entry(name.to_string()).or_default());
+    }
+}
+
+impl Table {
+    /// Drops the entries idle for a day, whether there is room now
+    fn prune(&self, now: u64, max_entries: usize) -> bool {
+        let last = self.last_prune.load(Ordering::Relaxed);
+        if now.saturating_sub(last) >= PRUNE_INTERVAL_SECS
+            && self.last_prune.compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+        {
+            self.entries.retain(|_, counters| counters.last_share_at.is_some_and(|at| now.saturating_sub(at) < IDLE_SECS));
+            if self.entries.len() >= max_entries {
+                warn!(max = max_entries, "The share stats are full, new ones aren't counted");
+            }
+        }
+
+        self.entries.len() < max_entries
+    }
+}
+
+impl Verdict {
+    /// From the reason the metrics give a rejected share
+    pub fn from_reject_reason(reason: Option<&str>) -> Self {
+        match reason {
+            None => Verdict::Accepted,
+            Some("stale") => Verdict::Stale,
+            Some(_) => Verdict::Rejected
+        }
+    }
+}
+
+impl Counters {
+    fn count(&mut self, now: u64, difficulty: f64, verdict: Verdict, latency: Duration) {
+        match verdict {
+            Verdict::Accepted => {
+                self.accepted += 1;
+                self.windows.add(now, difficulty);
+            }
+            Verdict::Stale => self.stale += 1,
+            Verdict::Rejected => self.rejected += 1
+        }
+        self.last_share_at = Some(now);
+        self.latency_ms_total = self.latency_ms_total.saturating_add(latency.as_millis() as u64);
+    }
+
+    fn summary(&self, name: &str, now: u64) -> ShareSummary {
+        let answered = self.accepted + self.stale + self.rejected;
+
+        ShareSummary {
+            name: name.to_string(),
+            sub_account: (!self.sub_account.is_empty()).then(|| self.sub_account.clone()),
+            hashrate: self.windows.hashrate(now),
+            accepted: self.accepted,
+            stale: self.stale,
+            rejected: self.rejected,
+            last_share_at: self.last_share_at,
+            avg_latency_ms: (answered > 0).then(|| self.latency_ms_total / answered)
+        }
+    }
+}
+
+fn fastest(mut summaries: Vec<ShareSummary>, limit: Option<usize>) -> Vec<ShareSummary> {
+    summaries.sort_by(|a, b| b.hashrate.m5.total_cmp(&a.hashrate.m5).then_with(|| a.name.cmp(&b.name)));
+    if let Some(limit) = limit {
+        summaries.truncate(limit);
+    }
+
+    summaries
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T: u64 = 1_700_000_000;
+
+    fn share(stats: &ShareStats, worker: &str, now: u64) {
+        stats.update(&stats.workers, worker, now, |counters| counters.count(now, 1.0, Verdict::Accepted, Duration::ZERO));
+    }
+
+    #[test]
+    fn full_table_keeps_active_entries() {
+        let stats = ShareStats::new(2);
+        share(&stats, "acc.a", T);
+        share(&stats, "acc.b", T);
+
+        share(&stats, "acc.c", T + 1);
+        assert!(stats.worker("acc.c").is_none());
+
+        // Known entries are still counted
+        share(&stats, "acc.a", T + 1);
+        assert_eq!(stats.workers.entries.get("acc.a").unwrap().accepted, 2);
+    }
+
+    #[test]
+    fn idle_entries_are_pruned_for_new_ones() {
+        let stats = ShareStats::new(2);
+        share(&stats, "acc.a", T);
+        share(&stats, "acc.b", T + IDLE_SECS - 10);
+
+        share(&stats, "acc.c", T + IDLE_SECS);
+        assert!(stats.workers.entries.get("acc.a").is_none());
+        assert!(stats.workers.entries.get("acc.b").is_some());
+        assert!(stats.workers.entries.get("acc.c").is_some());
+    }
+
+    #[test]
+    fn full_table_is_pruned_once_an_interval() {
+        let stats = ShareStats::new(2);
+        share(&stats, "acc.a", T);
+        share(&stats, "acc.b", T);
+
+        // Nothing is idle yet, the scan finds no room
+        share(&stats, "acc.c", T + IDLE_SECS - 1);
+        assert!(stats.workers.entries.get("acc.c").is_none());
+
+        // Idle now, but the table was scanned a moment ago
+        share(&stats, "acc.c", T + IDLE_SECS + 1);
+        assert!(stats.workers.entries.get("acc.c").is_none());
+
+        share(&stats, "acc.c", T + IDLE_SECS - 1 + PRUNE_INTERVAL_SECS);
+        assert!(stats.workers.entries.get("acc.c").is_some());
+        assert_eq!(stats.workers.entries.len(), 1);
+    }
+}
@@ -0,0 +1,167 @@
+use serde::Serialize;
+
+// Hashes of a share of difficulty 1
+const HASHES_PER_DIFFICULTY: f64 = 4_294_967_296.0;
+
+/// Hashrate over the last minute, 5 minutes, etc., hashes per second
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Hashrate {
+    #[serde(rename = "1m")]
+    pub m1: f64,
+    #[serde(rename = "5m")]
+    pub m5: f64,
+    #[serde(rename = "15m")]
+    pub m15: f64,
+    #[serde(rename = "1h")]
+    pub h1: f64,
+    #[serde(rename = "24h")]
+    pub h24: f64
+}
+
+/// Difficulty of the accepted shares in buckets of time. Short windows are summed from minute
+/// buckets, long ones from coarser buckets, so a worker costs the same whatever its share rate is
+#[derive(Debug)]
+pub struct HashrateWindows {
+    minutes: Buckets<16>,
+    five_minutes: Buckets<13>,
+    hours: Buckets<25>
+}
+
+/// Ring of `N` buckets `width` seconds each. The oldest bucket is there so a window which starts in
+/// the middle of it takes its part
+#[derive(Debug)]
+struct Buckets<const N: usize> {
+    width: u64,
+    head: u64, // number of the newest bucket, unix time / width
+    sums: [f64; N]
+}
+
+impl HashrateWindows {
+    /// Adds the difficulty of a share accepted at `now`, unix time in seconds
+    pub fn add(&mut self, now: u64, difficulty: f64) {
+        self.minutes.add(now, difficulty);
+        self.five_minutes.add(now, difficulty);
+        self.hours.add(now, difficulty);
+    }
+
+    pub fn hashrate(&self, now: u64) -> Hashrate {
+        let rate = |sum: f64, window: u64| sum * HASHES_PER_DIFFICULTY / window as f64;
+
+        Hashrate {
+            m1: rate(self.minutes.sum(now, 60), 60),
+            m5: rate(self.minutes.sum(now, 300), 300),
+            m15: rate(self.minutes.sum(now, 900), 900),
+            h1: rate(self.five_minutes.sum(now, 3600), 3600),
+            h24: rate(self.hours.sum(now, 86_400), 86_400)
+        }
+    }
+}
+
+impl Default for HashrateWindows {
+    fn default() -> Self {
+        Self {
+            minutes: Buckets::new(60),
+            five_minutes: Buckets::new(300),
+            hours: Buckets::new(3600)
+        }
+    }
+}
+
+impl<const N: usize> Buckets<N> {
+    fn new(width: u64) -> Self {
+        Self { width, head: 0, sums: [0.0; N] }
+    }
+
+    fn add(&mut self, now: u64, value: f64) {
+        let bucket = now / self.width;
+
+        if bucket > self.head {
+            // Buckets nothing has come in since they were last used are emptied
+            for skipped in (self.head + 1)..=bucket.min(self.head + N as u64) {
+                self.sums[skipped as usize % N] = 0.0;
+            }
+            self.head = bucket;
+        }
+
+        // A clock gone back counts the share in the newest bucket
+        self.sums[self.head as usize % N] += value;
+    }
+
+    /// Sum over the last `window` seconds, a multiple of the width. The oldest bucket the window
+    /// touches is taken in proportion to the part of it inside the window
+    fn sum(&self, now: u64, window: u64) -> f64 {
+        let bucket = now / self.width;
+        let full = (window / self.width).min(N as u64 - 1);
+        let outside = (now % self.width) as f64 / self.width as f64;
+
+        let value = |number: u64| {
+            if number > self.head || self.head - number >= N as u64 {
+                0.0
+            } else {
+                self.sums[number as usize % N]
+            }
+        };
+
+        let mut sum: f64 = (0..full).filter_map(|back| bucket.checked_sub(back)).map(value).sum();
+        if let Some(oldest) = bucket.checked_sub(full) {
+            sum += value(oldest) * (1.0 - outside);
+        }
+
+        sum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_bucket_is_taken_in_part() {
+        let mut buckets = Buckets::<16>::new(60);
+        buckets.add(600, 10.0);
+        buckets.add(660, 20.0);
+
+        // Half of the first minute is inside the window
+        assert_eq!(buckets.sum(690, 60), 25.0);
+        assert_eq!(buckets.sum(660, 60), 30.0);
+        assert_eq!(buckets.sum(719, 120), 30.0);
+    }
+
+    #[test]
+    fn skipped_buckets_are_emptied() {
+        let mut buckets = Buckets::<4>::new(10);
+        buckets.add(0, 1.0);
+        buckets.add(10, 2.0);
+
+        // The bucket of t=40 is the slot of t=0 again, the share of t=0 is gone
+        buckets.add(40, 4.0);
+        assert_eq!(buckets.sum(40, 30), 6.0);
+        assert_eq!(buckets.sum(40, 20), 4.0);
+
+        // Nothing is left after a gap longer than the ring
+        buckets.add(1000, 8.0);
+        assert_eq!(buckets.sum(1000, 30), 8.0);
+        assert_eq!(buckets.sum(1040, 30), 0.0);
+    }
+
+    #[test]
+    fn clock_gone_back_counts_in_the_newest_bucket() {
+        let mut buckets = Buckets::<16>::new(60);
+        buckets.add(6000, 1.0);
+        buckets.add(5400, 2.0);
+
+        assert_eq!(buckets.sum(6000, 60), 3.0);
+        // Buckets after the newest one are empty
+        assert_eq!(buckets.sum(6120, 60), 0.0);
+    }
+
+    #[test]
+    fn hashrate_of_a_minute() {
+        let mut windows = HashrateWindows::default();
+        windows.add(6000, 60.0);
+
+        let hashrate = windows.hashrate(6000);
+        assert_eq!(hashrate.m1, HASHES_PER_DIFFICULTY);
+        assert_eq!(hashrate.m5, HASHES_PER_DIFFICULTY / 5.0);
+    }
+}
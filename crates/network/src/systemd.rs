@@ -0,0 +1,115 @@
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+// Set by systemd for a Type=notify service
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+// Set with WatchdogSec=, the pings are expected at least this often
+const WATCHDOG_USEC_ENV: &str = "WATCHDOG_USEC";
+const WATCHDOG_PID_ENV: &str = "WATCHDOG_PID";
+
+/// Whether the proxy runs as a Type=notify service of systemd
+pub fn is_notify_enabled() -> bool {
+    std::env::var_os(NOTIFY_SOCKET_ENV).is_some_and(|path| !path.is_empty())
+}
+
+/// Sends `state` to systemd, e.g. `READY=1` or `STATUS=...`, several of them are separated by new
+/// lines. Does nothing if there is no notify socket
+pub fn notify(state: &str) -> anyhow::Result<()> {
+    let Some(path) = std::env::var_os(NOTIFY_SOCKET_ENV).filter(|path| !path.is_empty()) else {
+        return Ok(());
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    // A socket in the abstract namespace starts with @
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&path)?
+    };
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+
+    Ok(())
+}
+
+/// How often systemd expects the watchdog pings, none if the watchdog is off or it's meant for
+/// another process
+pub fn watchdog_timeout() -> Option<Duration> {
+    if let Ok(pid) = std::env::var(WATCHDOG_PID_ENV)
+        && pid.parse::<u32>().ok() != Some(std::process::id())
+    {
+        return None;
+    }
+
+    std::env::var(WATCHDOG_USEC_ENV).ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::set_env;
+
+    fn received(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 256];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    #[test]
+    fn states_are_sent_to_the_notify_socket() {
+        let _env = crate::ENV_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+
+        set_env(&[(NOTIFY_SOCKET_ENV, Some(path.to_str().unwrap()))]);
+        assert!(is_notify_enabled());
+        notify("READY=1\nSTATUS=Accepting").unwrap();
+        notify("WATCHDOG=1").unwrap();
+
+        // The abstract socket of the name after @
+        let name = format!("proxy-gates-test-{}", std::process::id());
+        let abstract_systemd = UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        set_env(&[(NOTIFY_SOCKET_ENV, Some(&format!("@{name}")))]);
+        notify("STOPPING=1").unwrap();
+        set_env(&[(NOTIFY_SOCKET_ENV, None)]);
+
+        assert_eq!(received(&systemd), "READY=1\nSTATUS=Accepting");
+        assert_eq!(received(&systemd), "WATCHDOG=1");
+        assert_eq!(received(&abstract_systemd), "STOPPING=1");
+    }
+
+    #[test]
+    fn nothing_is_sent_without_a_notify_socket() {
+        let _env = crate::ENV_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+
+        set_env(&[(NOTIFY_SOCKET_ENV, Some(""))]);
+        assert!(!is_notify_enabled());
+        notify("READY=1").unwrap();
+        set_env(&[(NOTIFY_SOCKET_ENV, None)]);
+        assert!(!is_notify_enabled());
+        notify("READY=1").unwrap();
+    }
+
+    #[test]
+    fn watchdog_is_meant_for_this_process() {
+        let _env = crate::ENV_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let pid = std::process::id().to_string();
+
+        for (usec, watchdog_pid, timeout) in [
+            (Some("2000000"), None, Some(Duration::from_secs(2))),
+            (Some("500000"), Some(pid.as_str()), Some(Duration::from_millis(500))),
+            (Some("2000000"), Some("1"), None),
+            (Some("0"), None, None),
+            (Some("soon"), None, None),
+            (None, None, None)
+        ] {
+            set_env(&[(WATCHDOG_USEC_ENV, usec), (WATCHDOG_PID_ENV, watchdog_pid)]);
+            assert_eq!(watchdog_timeout(), timeout, "WATCHDOG_USEC={usec:?} WATCHDOG_PID={watchdog_pid:?}");
+        }
+        set_env(&[(WATCHDOG_USEC_ENV, None), (WATCHDOG_PID_ENV, None)]);
+    }
+}
@@ -0,0 +1,486 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use tokio::net::{TcpListener, UnixListener};
+
+use tracing::{info, warn};
+
+use crate::drain::DrainController;
+
+/// Listening sockets a process hands to its successor, as `name=fd` pairs separated by commas
+pub const LISTEN_FDS_ENV: &str = "PROXY_GATES_LISTEN_FDS";
+/// Write end of the pipe the successor reports on that it accepts connections
+pub const READY_FD_ENV: &str = "PROXY_GATES_READY_FD";
+
+// Socket activation of systemd: the sockets start at fd 3, the names are from FileDescriptorName=
+const SD_LISTEN_PID_ENV: &str = "LISTEN_PID";
+const SD_LISTEN_FDS_ENV: &str = "LISTEN_FDS";
+const SD_LISTEN_FDNAMES_ENV: &str = "LISTEN_FDNAMES";
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+pub const STRATUM_LISTENER: &str = "stratum";
+pub const ADMIN_LISTENER: &str = "admin";
+pub const CONTROL_LISTENER: &str = "control";
+// Extra listeners are named by their address, e.g. `listener@0.0.0.0:3334`
+const EXTRA_LISTENER_PREFIX: &str = "listener@";
+
+// The successor reads the config and binds nothing, it's ready in a moment
+const SUCCESSOR_READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Set once the listeners are handed over, the socket files belong to the successor since then
+static HANDED_OFF: AtomicBool = AtomicBool::new(false);
+static READY_SENT: AtomicBool = AtomicBool::new(false);
+
+/// Listening sockets inherited from the process being upgraded or passed by systemd
+#[derive(Debug, Default)]
+pub struct InheritedListeners {
+    fds: HashMap<String, RawFd>,
+    // Sockets of the extra listeners, passed by systemd without a name the proxy knows or by the
+    // previous process, by their address
+    activated: HashMap<SocketAddr, std::net::TcpListener>
+}
+
+/// Zero-downtime upgrade: a new process of the binary gets the listening sockets, starts accepting and
+/// this one drains its miners, which reconnect to the same address and land on the new process
+pub struct UpgradeController {
+    listeners: Vec<(String, RawFd)>,
+    drain: Arc<DrainController>,
+    in_progress: AtomicBool
+}
+
+impl InheritedListeners {
+    /// Listeners named in `PROXY_GATES_LISTEN_FDS` and the ones of the socket activation, none if the
+    /// process isn't started by an upgrade or by systemd
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut listeners = Self::default();
+        for (name, fd) in upgrade_fds()? {
+            match name.strip_prefix(EXTRA_LISTENER_PREFIX).and_then(|addr| addr.parse::<SocketAddr>().ok()) {
+                Some(addr) => {
+                    // Safety: the fd is open (checked in upgrade_fds) and nothing else in this process owns it
+                    listeners.activated.insert(addr, unsafe { std::net::TcpListener::from_raw_fd(fd) });
+                }
+                None => {
+                    listeners.fds.insert(name, fd);
+                }
+            }
+        }
+        listeners.add_activated(SD_LISTEN_FDS_START)?;
+
+        Ok(listeners)
+    }
+
+    /// Sockets of `LISTEN_FDS`, if they're meant for this process. A socket named `stratum`, `admin` or
+    /// `control` takes the place of that listener, others are used for the listeners of their address.
+    /// systemd passes them from `first_fd` on
+    fn add_activated(&mut self, first_fd: RawFd) -> anyhow::Result<()> {
+        let is_ours = std::env::var(SD_LISTEN_PID_ENV).ok().and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id());
+        let Some(count) = std::env::var(SD_LISTEN_FDS_ENV).ok().and_then(|count| count.parse::<RawFd>().ok()) else {
+            return Ok(());
+        };
+        // The successor of an upgrade sees the variables of its predecessor
+        if !is_ours {
+            return Ok(());
+        }
+
+        let names = std::env::var(SD_LISTEN_FDNAMES_ENV).unwrap_or_default();
+        let mut names = names.split(':');
+        for fd in first_fd..first_fd + count {
+            set_cloexec(fd, true)?;
+
+            let name = names.next().unwrap_or_default();
+            if [STRATUM_LISTENER, ADMIN_LISTENER, CONTROL_LISTENER].contains(&name) && !self.fds.contains_key(name) {
+                self.fds.insert(name.to_string(), fd);
+                continue;
+            }
+
+            // Safety: systemd has passed the fd to this process, nothing else owns it
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            match listener.local_addr() {
+                Ok(addr) => {
+                    self.activated.insert(addr, listener);
+                }
+                Err(err) => warn!(fd, name, "The activated socket isn't a TCP listener, it's closed: {}", err)
+            }
+        }
+
+        info!(listeners = ?self.fds, activated = ?self.activated.keys(), "Listeners are passed by systemd");
+        Ok(())
+    }
+
+    /// Sockets none of the named listeners has taken, the extra listeners of the config use them
+    pub fn take_activated(&mut self) -> HashMap<SocketAddr, std::net::TcpListener> {
+        std::mem::take(&mut self.activated)
+    }
+
+    /// The inherited TCP listener, the activated one of `addr` or a new one bound to `addr`
+    pub async fn tcp_or_bind(&mut self, name: &str, addr: SocketAddr) -> anyhow::Result<TcpListener> {
+        let Some(fd) = self.fds.remove(name) else {
+            if let Some(listener) = self.activated.remove(&addr) {
+                listener.set_nonblocking(true)?;
+                return Ok(TcpListener::from_std(listener)?);
+            }
+            return Ok(TcpListener::bind(addr).await?);
+        };
+
+        // Safety: the fd is open (checked in from_env) and nothing else in this process owns it
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+
+        let local_addr = listener.local_addr()?;
+        if local_addr != addr {
+            warn!(name, %local_addr, configured = %addr, "The inherited listener is bound elsewhere than configured, it's kept");
+        }
+
+        Ok(listener)
+    }
+
+    /// The inherited Unix listener, the caller binds a new one without it
+    pub fn take_unix(&mut self, name: &str) -> anyhow::Result<Option<UnixListener>> {
+        let Some(fd) = self.fds.remove(name) else {
+            return Ok(None);
+        };
+
+        // Safety: the fd is open (checked in from_env) and nothing else in this process owns it
+        let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
+
+        Ok(Some(UnixListener::from_std(listener)?))
+    }
+}
+
+/// Listeners named in `PROXY_GATES_LISTEN_FDS` by the process being upgraded
+fn upgrade_fds() -> anyhow::Result<HashMap<String, RawFd>> {
+    let Ok(value) = std::env::var(LISTEN_FDS_ENV) else {
+        return Ok(HashMap::new());
+    };
+
+    let mut fds = HashMap::new();
+    for pair in value.split(',').filter(|pair| !pair.is_empty()) {
+        let Some((name, fd)) = pair.split_once('=') else {
+            anyhow::bail!("Bad {LISTEN_FDS_ENV} entry {pair:?}, name=fd is expected");
+        };
+        let fd: RawFd = fd.parse().map_err(|_| anyhow::anyhow!("Bad fd in the {LISTEN_FDS_ENV} entry {pair:?}"))?;
+        // Not passed further unless the next upgrade asks for it
+        set_cloexec(fd, true)?;
+        fds.insert(name.to_string(), fd);
+    }
+
+    info!(listeners = ?fds, "Listeners are inherited from the previous process");
+    Ok(fds)
+}
+
+/// Tells the process which started this one that the listeners are taken over, once. Does nothing
+/// if the process isn't started by an upgrade
+pub fn notify_ready() -> anyhow::Result<()> {
+    let Ok(fd) = std::env::var(READY_FD_ENV) else {
+        return Ok(());
+    };
+    if READY_SENT.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let fd: RawFd = fd.parse().map_err(|_| anyhow::anyhow!("Bad fd in {READY_FD_ENV}: {fd:?}"))?;
+    set_cloexec(fd, true)?;
+
+    // Safety: the fd is open (checked by set_cloexec) and it's used only here
+    let mut pipe = unsafe { File::from_raw_fd(fd) };
+    pipe.write_all(b"1")?;
+
+    Ok(())
+}
+
+/// Name an extra listener is handed to the successor by
+pub fn extra_listener_name(addr: &SocketAddr) -> String {
+    format!("{EXTRA_LISTENER_PREFIX}{addr}")
+}
+
+/// Whether the listeners have been handed to a successor
+pub fn is_handed_off() -> bool {
+    HANDED_OFF.load(Ordering::SeqCst)
+}
+
+impl UpgradeController {
+    pub fn new(listeners: Vec<(String, RawFd)>, drain: Arc<DrainController>) -> Self {
+        Self {
+            listeners,
+            drain,
+            in_progress: AtomicBool::new(false)
+        }
+    }
+
+    /// Starts the successor and drains this process once it accepts connections, returns its pid
+    pub async fn upgrade(&self) -> anyhow::Result<u32> {
+        if self.drain.is_draining() {
+            anyhow::bail!("The proxy is draining already");
+        }
+        if self.in_progress.swap(true, Ordering::SeqCst) {
+            anyhow::bail!("An upgrade is in progress already");
+        }
+
+        match spawn_successor(&self.listeners).await {
+            Ok(pid) => {
+                HANDED_OFF.store(true, Ordering::SeqCst);
+                info!(pid, "The new process accepts connections, draining this one");
+                self.drain.start_handoff();
+                Ok(pid)
+            }
+            Err(err) => {
+                self.in_progress.store(false, Ordering::SeqCst);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Runs the binary again with the same arguments and the listeners, and waits for it to report
+/// it's ready. The successor is killed if it doesn't
+async fn spawn_successor(listeners: &[(String, RawFd)]) -> anyhow::Result<u32> {
+    let (ready_rx, ready_tx) = pipe()?;
+    let ready_fd = ready_tx.as_raw_fd();
+
+    let listen_fds = listen_fds(listeners);
+    let mut inherited: Vec<RawFd> = listeners.iter().map(|(_, fd)| *fd).collect();
+    inherited.push(ready_fd);
+
+    let exe = current_exe()?;
+    info!(exe = ?exe, listeners = %listen_fds, "Starting the new process");
+
+    let mut command = Command::new(&exe);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(LISTEN_FDS_ENV, &listen_fds)
+        .env(READY_FD_ENV, ready_fd.to_string());
+    // Safety: only fcntl runs between fork and exec, it's async-signal-safe
+    unsafe {
+        command.pre_exec(move || {
+            for fd in &inherited {
+                if libc::fcntl(*fd, libc::F_SETFD, 0) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    let pid = child.id();
+
+    // The read end gets EOF if the successor exits before it's ready
+    drop(ready_tx);
+    let ready_rx = File::from(ready_rx);
+    let ready = tokio::task::spawn_blocking(move || is_ready(ready_rx));
+
+    match tokio::time::timeout(SUCCESSOR_READY_TIMEOUT, ready).await {
+        Ok(Ok(Ok(true))) => Ok(pid),
+        Ok(Ok(Ok(false))) => {
+            let status = child.wait()?;
+            anyhow::bail!("The new process exited before it was ready: {status}")
+        }
+        Ok(Ok(Err(err))) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            anyhow::bail!("Couldn't read the readiness of the new process: {err}")
+        }
+        Ok(Err(err)) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(err.into())
+        }
+        Err(_) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            anyhow::bail!("The new process isn't ready in {SUCCESSOR_READY_TIMEOUT:?}, it's killed")
+        }
+    }
+}
+
+/// The value of `PROXY_GATES_LISTEN_FDS` for the listeners
+fn listen_fds(listeners: &[(String, RawFd)]) -> String {
+    listeners.iter()
+        .map(|(name, fd)| format!("{name}={fd}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Waits for the successor to write to the ready pipe, false if it closes the pipe without it
+fn is_ready(mut ready_rx: File) -> std::io::Result<bool> {
+    let mut buf = [0u8; 1];
+    ready_rx.read(&mut buf).map(|n| n == 1)
+}
+
+/// Path of the running binary. The binary is usually replaced before the upgrade, then the kernel
+/// reports the old file as deleted, and the new file is at the same path
+fn current_exe() -> anyhow::Result<PathBuf> {
+    let exe = std::env::current_exe()?;
+    match exe.to_str().and_then(|path| path.strip_suffix(" (deleted)")) {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Ok(exe)
+    }
+}
+
+fn pipe() -> anyhow::Result<(OwnedFd, OwnedFd)> {
+    let mut fds: [RawFd; 2] = [-1; 2];
+    // Safety: fds is a valid array of two fds
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    // Safety: both fds are just created and owned by nothing else
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) -> anyhow::Result<()> {
+    let flags = if cloexec { libc::FD_CLOEXEC } else { 0 };
+    // Safety: fcntl on a bad fd fails with EBADF and changes nothing
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } == -1 {
+        anyhow::bail!("fd {fd} can't be used: {}", std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::fd::IntoRawFd;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    use super::*;
+    use crate::set_env;
+
+    #[tokio::test]
+    async fn listeners_are_handed_over_by_their_fds() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let stratum = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stratum_addr = stratum.local_addr().unwrap();
+        let extra = TcpListener::bind("127.0.0.1:0").unwrap();
+        let extra_addr = extra.local_addr().unwrap();
+        let control_path = dir.path().join("control.sock");
+        let control = UnixListener::bind(&control_path).unwrap();
+
+        // The fds are owned by the listeners the successor makes of them now
+        let listeners = vec![
+            (STRATUM_LISTENER.to_string(), stratum.into_raw_fd()),
+            (extra_listener_name(&extra_addr), extra.into_raw_fd()),
+            (CONTROL_LISTENER.to_string(), control.into_raw_fd())
+        ];
+        let mut inherited = {
+            let _env = crate::ENV_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+            set_env(&[
+                (LISTEN_FDS_ENV, Some(&listen_fds(&listeners))),
+                (SD_LISTEN_FDS_ENV, None),
+                (SD_LISTEN_PID_ENV, None)
+            ]);
+            let inherited = InheritedListeners::from_env().unwrap();
+            set_env(&[(LISTEN_FDS_ENV, None)]);
+            inherited
+        };
+
+        let stratum = inherited.tcp_or_bind(STRATUM_LISTENER, stratum_addr).await.unwrap();
+        assert_eq!(stratum.local_addr().unwrap(), stratum_addr);
+        let _client = TcpStream::connect(stratum_addr).unwrap();
+        stratum.accept().await.unwrap();
+
+        let mut activated = inherited.take_activated();
+        let extra = activated.remove(&extra_addr).expect("the extra listener is passed by its address");
+        assert!(activated.is_empty());
+        let _client = TcpStream::connect(extra_addr).unwrap();
+        extra.accept().unwrap();
+
+        let control = inherited.take_unix(CONTROL_LISTENER).unwrap().expect("the control listener is passed");
+        let _client = UnixStream::connect(&control_path).unwrap();
+        control.accept().await.unwrap();
+
+        // Nothing is inherited for a listener which isn't passed
+        assert!(inherited.take_unix(ADMIN_LISTENER).unwrap().is_none());
+    }
+
+    #[test]
+    fn activated_sockets_are_taken_by_their_names() {
+        let _env = crate::ENV_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+
+        let stratum = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stratum_addr = stratum.local_addr().unwrap();
+        let extra = TcpListener::bind("127.0.0.1:0").unwrap();
+        let extra_addr = extra.local_addr().unwrap();
+        // systemd passes the sockets one after the other, far from the fds the tests have open
+        let first_fd = 900;
+        for (fd, listener) in [(first_fd, stratum), (first_fd + 1, extra)] {
+            // Safety: dup2 onto an fd nothing in the tests uses, the copy is owned by the listeners made of it
+            assert_eq!(unsafe { libc::dup2(OwnedFd::from(listener).as_raw_fd(), fd) }, fd);
+        }
+
+        // Sockets meant for another process are left alone
+        set_env(&[
+            (SD_LISTEN_PID_ENV, Some("1")),
+            (SD_LISTEN_FDS_ENV, Some("2")),
+            (SD_LISTEN_FDNAMES_ENV, Some("stratum:extra"))
+        ]);
+        let mut inherited = InheritedListeners::default();
+        inherited.add_activated(first_fd).unwrap();
+        assert!(inherited.fds.is_empty() && inherited.activated.is_empty());
+
+        set_env(&[(SD_LISTEN_PID_ENV, Some(&std::process::id().to_string()))]);
+        inherited.add_activated(first_fd).unwrap();
+        set_env(&[(SD_LISTEN_PID_ENV, None), (SD_LISTEN_FDS_ENV, None), (SD_LISTEN_FDNAMES_ENV, None)]);
+
+        assert_eq!(inherited.fds.get(STRATUM_LISTENER), Some(&first_fd));
+        let mut activated = inherited.take_activated();
+        assert!(activated.remove(&extra_addr).is_some(), "the socket of another name is used by its address");
+        assert!(activated.is_empty());
+
+        // Safety: the fd is the stratum socket, nothing else has taken it
+        let stratum = unsafe { TcpListener::from_raw_fd(first_fd) };
+        assert_eq!(stratum.local_addr().unwrap(), stratum_addr);
+    }
+
+    #[test]
+    fn bad_listen_fds_are_rejected() {
+        let _env = crate::ENV_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+
+        for value in ["stratum", "stratum=x", "stratum=100000"] {
+            set_env(&[(LISTEN_FDS_ENV, Some(value))]);
+            assert!(InheritedListeners::from_env().is_err(), "{value} is accepted");
+        }
+        set_env(&[(LISTEN_FDS_ENV, None)]);
+    }
+
+    #[test]
+    fn successor_reports_ready_once() {
+        let _env = crate::ENV_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+
+        let (ready_rx, ready_tx) = pipe().unwrap();
+        set_env(&[(READY_FD_ENV, Some(&ready_tx.into_raw_fd().to_string()))]);
+        notify_ready().unwrap();
+        // The pipe is closed after the first report, the second one writes nothing
+        notify_ready().unwrap();
+        set_env(&[(READY_FD_ENV, None)]);
+
+        let mut ready_rx = File::from(ready_rx);
+        let mut report = Vec::new();
+        ready_rx.read_to_end(&mut report).unwrap();
+        assert_eq!(report, b"1");
+    }
+
+    #[test]
+    fn successor_which_exits_is_not_ready() {
+        let (ready_rx, ready_tx) = pipe().unwrap();
+        drop(ready_tx);
+        assert!(!is_ready(File::from(ready_rx)).unwrap());
+
+        let (ready_rx, ready_tx) = pipe().unwrap();
+        File::from(ready_tx).write_all(b"1").unwrap();
+        assert!(is_ready(File::from(ready_rx)).unwrap());
+    }
+}
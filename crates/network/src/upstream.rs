@@ -1 +1,2 @@
-pub mod pool_client;
\ No newline at end of file
+pub mod pool_client;
+pub mod overrides;
\ No newline at end of file
@@ -0,0 +1,67 @@
+use dashmap::DashMap;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("pool {0} is drained")]
+pub struct PoolDrained(pub String);
+
+/// Pools workers are sent to instead of the pool target the API gives for them. An override is
+/// applied the next time the worker authorizes, the worker's own one before its subaccount's
+#[derive(Debug, Default)]
+pub struct PoolOverrides {
+    by_worker: DashMap<String, String>,
+    by_sub_account: DashMap<String, String>,
+    // Drained pools and the pools their workers are redirected to. Workers of a drained pool without
+    // a redirect aren't authorized
+    drained: DashMap<String, Option<String>>
+}
+
+impl PoolOverrides {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&self, worker_name: impl Into<String>, pool_addr: impl Into<String>) {
+        self.by_worker.insert(worker_name.into(), pool_addr.into());
+    }
+
+    pub fn remove(&self, worker_name: &str) -> Option<String> {
+        self.by_worker.remove(worker_name).map(|(_, pool_addr)| pool_addr)
+    }
+
+    /// Sends every worker of the subaccount to the pool
+    pub fn set_sub_account(&self, sub_account: impl Into<String>, pool_addr: impl Into<String>) {
+        self.by_sub_account.insert(sub_account.into(), pool_addr.into());
+    }
+
+    pub fn remove_sub_account(&self, sub_account: &str) -> Option<String> {
+        self.by_sub_account.remove(sub_account).map(|(_, pool_addr)| pool_addr)
+    }
+
+    /// Stops sending workers to the pool, they go to `redirect_to` if it's given
+    pub fn drain(&self, pool_addr: impl Into<String>, redirect_to: Option<String>) {
+        self.drained.insert(pool_addr.into(), redirect_to);
+    }
+
+    pub fn undrain(&self, pool_addr: &str) -> bool {
+        self.drained.remove(pool_addr).is_some()
+    }
+
+    /// Drained pools with the pools they are redirected to
+    pub fn drained(&self) -> Vec<(String, Option<String>)> {
+        self.drained.iter().map(|entry| (entry.key().clone(), entry.value().clone())).collect()
+    }
+
+    /// The pool the worker has to be sent to, `pool_target` unless it's overridden or drained
+    pub fn resolve(&self, worker_name: &str, sub_account: &str, pool_target: String) -> Result<String, PoolDrained> {
+        let pool_addr = self.by_worker.get(worker_name)
+            .or_else(|| self.by_sub_account.get(sub_account))
+            .map(|pool_addr| pool_addr.clone())
+            .unwrap_or(pool_target);
+
+        match self.drained.get(&pool_addr) {
+            None => Ok(pool_addr),
+            Some(redirect) => redirect.clone().ok_or(PoolDrained(pool_addr))
+        }
+    }
+}
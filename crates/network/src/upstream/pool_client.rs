@@ -8,7 +8,10 @@ use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
 use tokio::sync::{Mutex, mpsc};
 use tokio::task::JoinHandle;
-use tracing::{error, info, warn};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, trace, warn};
+
+use score::job::PoolMessage;
 
 pub struct PoolClient {
     miner_channel_writer: mpsc::Sender<String>,
@@ -16,7 +19,7 @@ pub struct PoolClient {
 }
 
 impl PoolClient {
-    pub async fn new(pool_address: &String, up_to_miner: mpsc::Sender<String>) -> anyhow::Result<Self> {
+    pub async fn new(pool_address: &String, up_to_miner: mpsc::Sender<PoolMessage>) -> anyhow::Result<Self> {
         // let (host, port) = match pool_address.split_once(":") {
         //     Some((host, port)) => {
         //         match port.parse::<u16>() {
@@ -33,22 +36,34 @@ impl PoolClient {
         //
         // let socket_address = SocketAddr::new(host, port);
 
-        info!("PRE CONN");
+        debug!("PRE CONN");
         let stream = tokio::time::timeout(Duration::from_secs(3), TcpStream::connect(pool_address))
             .await
             .context("connect timeout")??;
-        info!("CONN");
+        debug!("CONN");
 
         let (read_half, mut write_half) = tokio::io::split(stream);
         let mut reader = BufReader::new(read_half);
 
         let (miner_tx, mut miner_rx) = mpsc::channel(32);
+        // The pool has closed the socket, the miner is gone or the pool can't be written to. Both halves
+        // of the socket are closed then and the writer's channel with them, so a closed `pool_tx` is a
+        // session which is gone
+        let closed = CancellationToken::new();
 
+        let writer_closed = closed.clone();
         let writer_handle = tokio::spawn(async move {
-            info!("Writer handle to pool started!");
-            while let Some(msg) = miner_rx.recv().await {
+            debug!("Writer handle to pool started!");
+            loop {
+                let msg = tokio::select! {
+                    _ = writer_closed.cancelled() => break,
+                    msg = miner_rx.recv() => match msg {
+                        Some(msg) => msg,
+                        None => break
+                    }
+                };
                 let mut to_write: String = msg;
-                info!("PoolClient msg -> {}", to_write); // workFlow2.asc6
+                trace!("PoolClient msg -> {}", to_write); // workFlow2.asc6
                 if !to_write.ends_with('\n') {
                     to_write.push_str("\n");
                 }
@@ -56,22 +71,32 @@ impl PoolClient {
                     error!("Error writing to upstream: {:?}", e);
                     break;
                 }
-                info!("miner->pool writer exiting");
+                trace!("miner->pool writer exiting");
+            }
+
+            writer_closed.cancel();
+            if let Err(e) = write_half.shutdown().await {
+                debug!("Error shutting down the upstream writer: {:?}", e);
             }
         });
 
+        let pool: Arc<str> = Arc::from(pool_address.as_str());
         let reader_handle = tokio::spawn(async move {
-            info!("Reader handle from pool to started!");
+            debug!("Reader handle from pool to started!");
             let mut line = String::new();
             loop {
                 line.clear();
-                let n = match reader.read_line(&mut line).await {
+                let read = tokio::select! {
+                    _ = closed.cancelled() => break,
+                    read = reader.read_line(&mut line) => read
+                };
+                let n = match read {
                     Ok(0) => {
-                        info!("upstream closed connection");
+                        debug!("upstream closed connection");
                         break;
                     }
                     Ok(n) => {
-                        info!("{} bytes were received", n);
+                        trace!("{} bytes were received", n);
                         n
                     },
                     Err(e) => {
@@ -86,12 +111,15 @@ impl PoolClient {
                     line.clone()
                 };
 
-                info!("Response from pool -> {}", s);
+                trace!("Response from pool -> {}", s);
 
-                if let Err(_e) = up_to_miner.send(s).await {
+                if let Err(_e) = up_to_miner.send(PoolMessage { pool: Arc::clone(&pool), line: s }).await {
                     warn!("miner receiver dropped, stopping reading from upstream");
+                    break;
                 }
             }
+
+            closed.cancel();
         });
 
         Ok(Self {
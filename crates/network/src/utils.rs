@@ -1,26 +1,22 @@
-use std::sync::Arc;
-use std::sync::atomic::Ordering::Relaxed;
-use serde_json::Value;
-use tokio::sync::{oneshot, Mutex};
-use tokio::io::{AsyncWriteExt, BufWriter};
-use tokio::net::tcp::OwnedWriteHalf;
-use tokio::net::TcpStream;
+use tokio::sync::oneshot;
 use tokio_util::sync::CancellationToken;
 
-use tracing::{error, info, warn};
+use tracing::error;
 use score::job::ProxyMessage;
-use crate::connection::{TOTAL_JOBS, TOTAL_JOBS_FAILED, TOTAL_JOBS_SUCCEEDED};
+use crate::metrics::METRICS;
+use crate::connection::writer::{ResponseSlot, WriterError};
 
 #[derive(Debug)]
 pub enum Outcome {
     Replied,
     NoReply,
     Cancelled,
-    IoError(tokio::io::Error)
+    WriterError(WriterError)
 }
 
+/// Waits for the job's answer and puts it to the request's place in the order of responses
 pub async fn await_and_replay(
-    writer: Arc<Mutex<BufWriter<OwnedWriteHalf>>>,
+    slot: ResponseSlot,
     rx: oneshot::Receiver<ProxyMessage<'static>>,
     cancel: CancellationToken
 ) -> Outcome {
@@ -30,28 +26,15 @@ pub async fn await_and_replay(
         }
         res = rx => {
             match res {
-                Ok(msg) => {
-                    match msg {
-                        ProxyMessage::Wait => {
-                            Outcome::NoReply
-                        },
-                        ProxyMessage::Response(response) => {
-                            if let Err(err) = writer.lock().await.write_all(response.as_bytes()).await {
-                                Outcome::IoError(err)
-                            } else {
-                                Outcome::Replied
-                            }
-                        },
-                        ProxyMessage::Err(_) => {
-                            Outcome::NoReply
-                        }
-                        _ => {
-                            Outcome::NoReply
-                        }
+                Ok(ProxyMessage::Response(response)) => {
+                    match slot.send(response.into_owned()) {
+                        Ok(()) => Outcome::Replied,
+                        Err(err) => Outcome::WriterError(err)
                     }
-
                 }
-                Err(_err) => {Outcome::NoReply}
+                // Nothing to write now, the slot is released on drop
+                Ok(_) => Outcome::NoReply,
+                Err(_err) => Outcome::NoReply
             }
         }
     }
@@ -59,16 +42,12 @@ pub async fn await_and_replay(
 
 pub fn metrics_record_job_outcome(outcome: Outcome) {
     match outcome {
-        Outcome::Replied => {
-            TOTAL_JOBS_SUCCEEDED.fetch_add(1, Relaxed);
-        }
-        Outcome::IoError(err) => {
-            error!("Outcome IoError: {:?}", err);
-            TOTAL_JOBS_FAILED.fetch_add(1, Relaxed);
-        }
-        Outcome::NoReply | Outcome::Cancelled => {
-            TOTAL_JOBS_FAILED.fetch_add(1, Relaxed);
+        Outcome::Replied => METRICS.job_done("replied"),
+        Outcome::WriterError(err) => {
+            error!("Outcome WriterError: {}", err);
+            METRICS.job_done("writer_error");
         }
+        Outcome::NoReply => METRICS.job_done("no_reply"),
+        Outcome::Cancelled => METRICS.job_done("cancelled")
     }
-    TOTAL_JOBS.fetch_add(1, Relaxed);
-}
\ No newline at end of file
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, oneshot};
+
+use score::job::{MethodCall, MethodRoutes, PoolMessage, Priority, ProxyMessage};
+use score::session::{SessionHandle, SessionState};
+
+/// Everything a handler may need to serve a single request of a miner
+pub struct HandlerContext {
+    pub session: SessionHandle, // miner session, its state is changed by messages to the session actor
+    pub upstream: Option<mpsc::Sender<String>>, // pool session of the miner, it's None until the miner is authorized
+    pub primary_pool: Option<String>, // pool of the connection, the workers authorized later have to be on it
+    pub outbound: mpsc::Sender<String>, // messages which have to be written to the miner besides the response
+    pub pool_messages: mpsc::Sender<PoolMessage>, // a new pool session of the miner relays the pool's messages here
+    pub respond_to: oneshot::Sender<ProxyMessage<'static>> // the response on this request
+}
+
+/// Handler of a single stratum method.
+///
+/// Handlers are registered in the [`HandlerRegistry`] by the method name, so a method can be added
+/// (or a builtin one replaced) without touching the scheduler.
+#[async_trait]
+pub trait MethodHandler: Send + Sync {
+    /// Name of the method, for example `mining.submit`
+    fn method(&self) -> &str;
+
+    /// Queue in which the requests of the method are scheduled
+    fn priority(&self) -> Priority {
+        Priority::High
+    }
+
+    /// The least handshake state the session has to reach before a request can be handled.
+    /// Requests which come earlier are deferred until the session gets there
+    fn required_state(&self) -> SessionState {
+        SessionState::Connected
+    }
+
+    async fn handle(&self, ctx: HandlerContext, call: MethodCall) -> anyhow::Result<()>;
+}
+
+#[derive(Clone, Default)]
+pub struct HandlerRegistry {
+    handlers: HashMap<String, Arc<dyn MethodHandler>>
+}
+
+impl HandlerContext {
+    pub fn respond(self, message: ProxyMessage<'static>) -> anyhow::Result<()> {
+        self.respond_to.send(message).map_err(|_| anyhow::anyhow!("Channel has been closed"))
+    }
+}
+
+impl HandlerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the handler. A handler registered earlier for the same method is replaced
+    pub fn register<H: MethodHandler + 'static>(&mut self, handler: H) -> &mut Self {
+        self.handlers.insert(handler.method().to_string(), Arc::new(handler));
+        self
+    }
+
+    /// Moves every handler of `other` into this registry, replacing the handlers of the same methods
+    pub fn extend(&mut self, other: HandlerRegistry) -> &mut Self {
+        self.handlers.extend(other.handlers);
+        self
+    }
+
+    pub fn get(&self, method: &str) -> Option<Arc<dyn MethodHandler>> {
+        self.handlers.get(method).cloned()
+    }
+
+    /// Queue priorities of the registered methods, they are used by connections to pick a queue
+    pub fn routes(&self) -> MethodRoutes {
+        let mut routes = MethodRoutes::default();
+        for (method, handler) in &self.handlers {
+            routes.insert(method.clone(), handler.priority());
+        }
+
+        routes
+    }
+}
+
+impl fmt::Debug for HandlerRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandlerRegistry")
+            .field("methods", &self.handlers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::Arc;
+
+use network::api::client::ApiClient;
+use network::upstream::overrides::PoolOverrides;
+
+use crate::handler::HandlerRegistry;
+use crate::handlers::authorize::AuthorizeHandler;
+use crate::handlers::configure::ConfigureHandler;
+use crate::handlers::submit::SubmitHandler;
+use crate::handlers::subscribe::SubscribeHandler;
+
+pub mod authorize;
+pub mod configure;
+pub mod submit;
+pub mod subscribe;
+
+/// Registry with handlers of the stratum methods supported out of the box
+pub fn builtin_handlers(api_client: Arc<ApiClient>, overrides: Arc<PoolOverrides>) -> HandlerRegistry {
+    let mut registry = HandlerRegistry::new();
+    registry
+        .register(SubmitHandler)
+        .register(ConfigureHandler)
+        .register(SubscribeHandler)
+        .register(AuthorizeHandler::new(api_client, overrides));
+
+    registry
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::sync::{mpsc, oneshot};
+    use tokio::time::timeout;
+
+    use score::job::{MethodCall, ProxyMessage};
+    use score::miner::Miner;
+    use score::session::{SessionHandle, SessionState};
+
+    use super::*;
+    use crate::handler::{HandlerContext, MethodHandler};
+
+    // A pool which passes every line it gets to the test
+    async fn pool() -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (lines_tx, lines_rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let lines_tx = lines_tx.clone();
+                tokio::spawn(async move {
+                    let mut lines = BufReader::new(socket).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let _ = lines_tx.send(line);
+                    }
+                });
+            }
+        });
+
+        (addr, lines_rx)
+    }
+
+    // The API, it sends the workers of a subaccount to its pool
+    async fn api(pools: HashMap<&'static str, String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = vec![0; 4096];
+                let len = socket.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..len]);
+                let worker = request.split("workerName=").nth(1).and_then(|rest| rest.split(' ').next()).unwrap();
+                let sub_account = worker.split('.').next().unwrap();
+
+                let body = json!({
+                    "id": "1", "minerId": "m", "poolTarget": pools[sub_account], "subAccountName": sub_account,
+                    "active": true, "metadata": {}, "createdAt": "now"
+                }).to_string();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        format!("http://{addr}")
+    }
+
+    async fn handle(handler: &dyn MethodHandler, session: &SessionHandle, message: Value) -> Option<String> {
+        let routing = session.routing().await.unwrap();
+        let (respond_to, response) = oneshot::channel();
+        let ctx = HandlerContext {
+            session: session.clone(),
+            upstream: routing.pool_tx,
+            primary_pool: routing.primary_pool,
+            outbound: routing.miner_tx,
+            pool_messages: routing.pool_messages,
+            respond_to
+        };
+
+        let method = message["method"].as_str().unwrap().to_string();
+        handler.handle(ctx, MethodCall::new(method, message)).await.unwrap();
+        match response.await {
+            Ok(ProxyMessage::Response(response)) => Some(response.into_owned()),
+            Ok(_) | Err(_) => None
+        }
+    }
+
+    async fn next_line(lines: &mut mpsc::UnboundedReceiver<String>) -> Value {
+        let line = timeout(Duration::from_secs(5), lines.recv()).await.expect("the pool gets a line").unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn workers_of_a_connection_are_on_its_pool() {
+        let (pool_a, mut lines_a) = pool().await;
+        let (pool_b, mut lines_b) = pool().await;
+        let api = api(HashMap::from([("acc_a", pool_a.clone()), ("acc_b", pool_b.clone())])).await;
+        let authorize = AuthorizeHandler::new(Arc::new(ApiClient::new(api, Duration::from_secs(5), 0)), Arc::new(PoolOverrides::new()));
+
+        let (miner_tx, _miner_rx) = mpsc::channel(16);
+        let (pool_messages, _pool_messages_rx) = mpsc::channel(16);
+        let mut miner = Miner::new("10.0.0.1:4000".parse().unwrap(), miner_tx, pool_messages);
+        miner.set_subscribe_request(json!({"id": 1, "method": "mining.subscribe", "params": []}).to_string());
+        miner.transition(SessionState::Subscribed).unwrap();
+        let session = SessionHandle::spawn(miner);
+
+        // The first worker opens the connection's pool session, the handshake is replayed on it
+        let response = handle(&authorize, &session, json!({"id": 2, "method": "mining.authorize", "params": ["acc_a.rig1", "x"]})).await;
+        assert_eq!(response, None, "the pool answers the authorize");
+        assert_eq!(next_line(&mut lines_a).await["method"], "mining.subscribe");
+        assert_eq!(next_line(&mut lines_a).await["params"][0], "acc_a.rig1");
+
+        // The miner has the jobs and the extranonce of pool A, a worker of pool B couldn't submit them
+        let response = handle(&authorize, &session, json!({"id": 3, "method": "mining.authorize", "params": ["acc_b.rig1", "x"]})).await;
+        assert!(response.unwrap().contains(r#""error":[20,"#));
+        let upstreams = session.upstreams().await.unwrap();
+        assert_eq!(upstreams.len(), 1);
+        assert_eq!(upstreams[0].pool_addr, pool_a);
+        assert_eq!(upstreams[0].workers, ["acc_a.rig1"]);
+
+        // The share of pool A's job goes to pool A
+        let submit = json!({"id": 4, "method": "mining.submit", "params": ["acc_a.rig1", "job-of-a", "00000001", "5f5e1000", "00000002"]});
+        assert_eq!(handle(&SubmitHandler, &session, submit.clone()).await, None, "the pool answers the submit");
+        assert_eq!(next_line(&mut lines_a).await, submit);
+
+        let submit = json!({"id": 5, "method": "mining.submit", "params": ["acc_b.rig1", "job-of-a", "00000001", "5f5e1000", "00000002"]});
+        assert!(handle(&SubmitHandler, &session, submit).await.unwrap().contains(r#""error":[24,"#));
+
+        // Pool B has never been connected
+        assert!(timeout(Duration::from_millis(100), lines_b.recv()).await.is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use tracing::{error, info};
+
+use score::job::{AuthorizeParams, MethodCall, ProxyMessage};
+use score::session::SessionState;
+use score::worker::Worker;
+
+use network::api::client::{ApiClient, ApiResponse};
+use network::upstream::overrides::PoolOverrides;
+use network::upstream::pool_client::PoolClient;
+
+use crate::handler::{HandlerContext, MethodHandler};
+
+/// mining.authorize
+pub struct AuthorizeHandler {
+    api_client: Arc<ApiClient>,
+    overrides: Arc<PoolOverrides>
+}
+
+impl AuthorizeHandler {
+    pub fn new(api_client: Arc<ApiClient>, overrides: Arc<PoolOverrides>) -> Self {
+        Self { api_client, overrides }
+    }
+}
+
+#[async_trait]
+impl MethodHandler for AuthorizeHandler {
+    fn method(&self) -> &str {
+        "mining.authorize"
+    }
+
+    fn required_state(&self) -> SessionState {
+        SessionState::Subscribed
+    }
+
+    async fn handle(&self, ctx: HandlerContext, call: MethodCall) -> anyhow::Result<()> {
+        let authorize = AuthorizeParams::form_value(&call.message)?;
+        let worker_full_name = authorize.username().to_string();
+
+        let subaccount_info = self.api_client.get_subaccount_info(worker_full_name.clone()).await?;
+
+        match subaccount_info {
+            ApiResponse::Successfully(mut subaccount_info) => {
+                subaccount_info.pool_target = match self.overrides.resolve(
+                    &worker_full_name, &subaccount_info.sub_account_name, subaccount_info.pool_target
+                ) {
+                    Ok(pool_target) => pool_target,
+                    Err(err) => {
+                        info!(worker = %worker_full_name, "{}", err);
+                        return ctx.respond(ProxyMessage::error_response(&call.id, 20, "Pool is unavailable"));
+                    }
+                };
+
+                // The miner mines the jobs of the connection's pool, the shares of a worker of another pool
+                // would be rejected by it. The worker has to connect on its own
+                if let Some(primary_pool) = &ctx.primary_pool
+                    && *primary_pool != subaccount_info.pool_target
+                {
+                    info!(
+                        worker = %worker_full_name, pool = %subaccount_info.pool_target, %primary_pool,
+                        "The worker's pool isn't the pool of the connection"
+                    );
+                    return ctx.respond(ProxyMessage::error_response(&call.id, 20, "Worker is on another pool than the connection"));
+                }
+
+                // Workers routed to the same pool share the pool session
+                let existing_pool_tx = ctx.session.pool_tx_for(&subaccount_info.pool_target).await?;
+
+                let pool_tx = match existing_pool_tx {
+                    Some(pool_tx) => pool_tx,
+                    None => {
+                        let pool_client = PoolClient::new(&subaccount_info.pool_target, ctx.pool_messages.clone()).await?;
+                        let pool_tx = pool_client.miner_channel_writer();
+
+                        match ctx.session.add_pool_session(&subaccount_info.pool_target, pool_tx.clone()).await? {
+                            // Another worker of the pool has opened a session with it meanwhile
+                            Some(existing) => {
+                                pool_client.shutdown().await;
+                                existing
+                            }
+                            None => {
+                                // A new pool session has to be configured and subscribed before a worker is
+                                // authorized on it. The miner gets only the first pool's answers on them
+                                for request in ctx.session.handshake_requests().await? {
+                                    pool_tx.send(request).await?;
+                                }
+                                pool_tx
+                            }
+                        }
+                    }
+                };
+
+                let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                let worker = Worker::new(
+                    worker_full_name,
+                    subaccount_info.sub_account_name,
+                    subaccount_info.pool_target,
+                    pool_tx.clone(),
+                    since_epoch
+                );
+                ctx.session.add_worker(worker).await??;
+
+                if let Err(e) = pool_tx.send(call.message.to_string()).await {
+                    error!("Channel was closed with error: {:?}", e);
+                }
+            }
+            ApiResponse::NotFoundSubAccount(error) => {
+                info!(worker = %worker_full_name, "error -> {:?}", error);
+                ctx.respond(ProxyMessage::error_response(&call.id, 24, "Unauthorized worker"))?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,29 @@
+use async_trait::async_trait;
+use tracing::warn;
+
+use score::job::{MethodCall, ProxyMessage};
+use score::session::SessionState;
+
+use crate::handler::{HandlerContext, MethodHandler};
+
+/// mining.configure
+pub struct ConfigureHandler;
+
+#[async_trait]
+impl MethodHandler for ConfigureHandler {
+    fn method(&self) -> &str {
+        "mining.configure"
+    }
+
+    async fn handle(&self, ctx: HandlerContext, call: MethodCall) -> anyhow::Result<()> {
+        if let Err(err) = ctx.session.transition(SessionState::Configured).await? {
+            warn!("{}", err);
+            return ctx.respond(ProxyMessage::error_response(&call.id, 20, "Configure is allowed only before subscribe"));
+        }
+
+        // Like the subscribe it is sent to every pool session before the workers are authorized
+        ctx.session.set_configure_request(call.message.to_string()).await?;
+
+        ctx.respond(ProxyMessage::Wait)
+    }
+}
@@ -0,0 +1,51 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use tracing::{debug, error, warn};
+
+use score::job::{MethodCall, ProxyMessage, SubmitParams};
+use score::session::SessionState;
+
+use network::metrics::{METRICS, UNKNOWN_SUB_ACCOUNT};
+
+use crate::handler::{HandlerContext, MethodHandler};
+
+/// mining.submit
+pub struct SubmitHandler;
+
+#[async_trait]
+impl MethodHandler for SubmitHandler {
+    fn method(&self) -> &str {
+        "mining.submit"
+    }
+
+    fn required_state(&self) -> SessionState {
+        SessionState::Authorized
+    }
+
+    async fn handle(&self, ctx: HandlerContext, call: MethodCall) -> anyhow::Result<()> {
+        let submit = SubmitParams::from_value(&call.message)?;
+
+        // The share goes to the pool session of the worker it was found by
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let Some(pool_tx) = ctx.session.record_share(&submit.workername, since_epoch).await? else {
+            warn!(worker = %submit.workername, "Submit from a worker which isn't authorized");
+            METRICS.share_rejected(UNKNOWN_SUB_ACCOUNT, "unauthorized");
+            return ctx.respond(ProxyMessage::error_response(&call.id, 24, "Unauthorized worker"));
+        };
+
+        // The shares go to the pool in the order the miner has sent them
+        debug!("Submit -> {:?}", submit);
+        if let Err(err) = pool_tx.send(call.message.to_string()).await {
+            error!("Couldn't forward the submit to the pool: {:?}", err);
+        }
+
+        // The pool answers on the submit through the connection's pool messages
+        if let Err(err) = ctx.respond(ProxyMessage::Wait) {
+            warn!("Couldn't to send respond_to!");
+            error!("respond_to send error: {:?}", err);
+        }
+
+        Ok(())
+    }
+}
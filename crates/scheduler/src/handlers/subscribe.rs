@@ -0,0 +1,30 @@
+use async_trait::async_trait;
+use tracing::warn;
+
+use score::job::{MethodCall, ProxyMessage};
+use score::session::SessionState;
+
+use crate::handler::{HandlerContext, MethodHandler};
+
+/// mining.subscribe
+pub struct SubscribeHandler;
+
+#[async_trait]
+impl MethodHandler for SubscribeHandler {
+    fn method(&self) -> &str {
+        "mining.subscribe"
+    }
+
+    async fn handle(&self, ctx: HandlerContext, call: MethodCall) -> anyhow::Result<()> {
+        if let Err(err) = ctx.session.transition(SessionState::Subscribed).await? {
+            warn!("{}", err);
+            return ctx.respond(ProxyMessage::error_response(&call.id, 20, "Already subscribed"));
+        }
+
+        // The pool session is opened once the first worker is authorized, the subscribe is sent there
+        // and the pool answers on it
+        ctx.session.set_subscribe_request(call.message.to_string()).await?;
+
+        ctx.respond(ProxyMessage::Wait)
+    }
+}
@@ -1 +1,3 @@
-pub mod scheduler;
\ No newline at end of file
+pub mod scheduler;
+pub mod handler;
+pub mod handlers;
@@ -1,31 +1,27 @@
 use std::borrow::Cow;
-use std::cmp::min;
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use anyhow::anyhow;
-use serde_json::Value;
+use std::time::Instant;
 
 use tokio::select;
-use tokio::sync::{oneshot, Mutex};
-use tokio::sync::{mpsc, AcquireError, OwnedSemaphorePermit, Semaphore};
+use tokio::sync::{mpsc, oneshot, Semaphore};
 use tokio::sync::mpsc::error::TryRecvError;
 use tokio_util::sync::CancellationToken;
-use tokio::task::{JoinHandle, JoinSet};
 
-use tracing::{error, info, warn};
+use tracing::{error, info, warn, Instrument, Span};
 
 use config::Config;
+use network::health::{Heartbeat, HEARTBEAT_INTERVAL};
+use network::metrics::{METRICS, QUEUE_HIGH, QUEUE_NORMAL};
 
-use score::job::{AuthorizeParams, Job, JobRequest, ProxyMessage, SubmitParams, SubscribeParams};
-use score::miner::Miner;
+use score::job::{Job, JobRequest, MethodCall, ProxyMessage};
+use score::session::{DeferredCall, Deferral, Routing, SessionHandle, SessionState};
 
-use network::api::client::{ApiClient, ApiResponse};
-use network::upstream::pool_client::PoolClient;
+use crate::handler::{HandlerContext, HandlerRegistry};
 
-const HIGH_BUDGET: u16 = 32;
-
-static IN_FLIGHT_CPU: AtomicU64 = AtomicU64::new(0);
+/// Requests whose handlers are running, each of them holds a CPU permit
+pub static IN_FLIGHT_CPU: AtomicU64 = AtomicU64::new(0);
 
 struct InFlightCpuGuard;
 
@@ -49,7 +45,8 @@ pub struct Scheduler {
     shutdown: CancellationToken,
     cpu_limit: Arc<Semaphore>,
     config: Arc<Config>,
-    api_client: Arc<ApiClient>
+    registry: Arc<HandlerRegistry>,
+    heartbeat: Arc<Heartbeat>
 }
 
 impl Scheduler {
@@ -59,7 +56,7 @@ impl Scheduler {
         shutdown: CancellationToken,
         cpu_limit: Arc<Semaphore>,
         config: Arc<Config>,
-        api_client: Arc<ApiClient>
+        registry: Arc<HandlerRegistry>
     ) -> Self {
         Self {
             rx_high,
@@ -67,18 +64,29 @@ impl Scheduler {
             shutdown,
             cpu_limit,
             config,
-            api_client
+            registry,
+            heartbeat: Arc::new(Heartbeat::new())
         }
     }
 
+    /// Turns of the loop, the liveness probe looks at them
+    pub fn heartbeat(&self) -> Arc<Heartbeat> {
+        Arc::clone(&self.heartbeat)
+    }
+
     pub async fn run(mut self) -> anyhow::Result<()> {
         info!("Scheduler started!");
-        let mut remaining_high = HIGH_BUDGET;
+        let high_budget = self.config.scheduler.high_budget;
+        let mut remaining_high = high_budget;
 
         // let mut tasks_controller = JoinSet::new();
 
+        // An idle loop wakes up to beat
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+
         'outer: loop {
             if self.shutdown.is_cancelled() { break 'outer; }
+            self.heartbeat.beat();
 
             while remaining_high > 0 {
                 match self.rx_high.try_recv() {
@@ -86,7 +94,7 @@ impl Scheduler {
                         self.process_high_queue(job).await;
                         remaining_high -= 1;
                     }
-                    Err(TryRecvError::Empty) => {},
+                    Err(TryRecvError::Empty) => break,
                     Err(TryRecvError::Disconnected) => {
                         // Here we will call token.cancel() and destroy all task
                         warn!("The high queue has been closed");
@@ -108,6 +116,14 @@ impl Scheduler {
                             remaining_high = remaining_high.saturating_sub(1);
                         }
                     }
+                    // The high queue is empty, the normal one doesn't have to wait for the budget
+                    norm_job = self.rx_norm.recv() => {
+                        if let Some(job) = norm_job {
+                            self.process_norm_queue(job).await;
+                            remaining_high = high_budget;
+                        }
+                    }
+                    _ = heartbeat.tick() => {}
                 }
             } else {
                 select! {
@@ -117,11 +133,11 @@ impl Scheduler {
                         if let Some(job) = norm_job {
                             info!(
                                 old_budget = remaining_high,
-                                reset_to = HIGH_BUDGET,
+                                reset_to = high_budget,
                                 "norm fired; budget reset"
                             );
                             self.process_norm_queue(job).await;
-                            remaining_high = HIGH_BUDGET;
+                            remaining_high = high_budget;
                         }
                     }
                     high_job = self.rx_high.recv() => {
@@ -129,6 +145,7 @@ impl Scheduler {
                             self.process_high_queue(job).await;
                         }
                     }
+                    _ = heartbeat.tick() => {}
                 }
             }
         }
@@ -137,15 +154,11 @@ impl Scheduler {
     }
 
     pub async fn process_high_queue(&self, job: JobRequest) {
+        METRICS.set_queue_depth(QUEUE_HIGH, self.rx_high.len());
+
         match job.job {
-            Job::MiningSubmit((submit, miner)) => {
-                let _ = self.handle_submit(submit, job.respond_to).await;
-            },
-            Job::MiningSubscribe((subscribe, miner)) => {
-                let _ = self.handle_subscribe(subscribe, job.respond_to, miner).await;
-            }
-            Job::MiningAuthorize((authorize, miner)) => {
-                let _ = self.handle_authorize(authorize, job.respond_to, miner).await;
+            Job::Method((call, session)) => {
+                self.spawn_dispatch(call, session, job.respond_to, job.span);
             }
             _ => {
                 warn!("It isn't a high priority job!");
@@ -154,6 +167,8 @@ impl Scheduler {
     }
 
     pub async fn process_norm_queue(&self, job: JobRequest) {
+        METRICS.set_queue_depth(QUEUE_NORMAL, self.rx_norm.len());
+
         match job.job {
             Job::Ping => {
                 if let Err(err) = job.respond_to.send(ProxyMessage::Response(Cow::from("OK\n"))) {
@@ -161,100 +176,206 @@ impl Scheduler {
                     error!("respond_to send error: {:?}", err);
                 };
             }
-            _ => {
-                warn!("It isn't a norm priority job!");
+            Job::Method((call, session)) => {
+                self.spawn_dispatch(call, session, job.respond_to, job.span);
             }
         }
     }
 
-    pub async fn handle_submit(&self, submit: SubmitParams, respond_to: oneshot::Sender<ProxyMessage<'static>>) -> anyhow::Result<()> {
-        let permit = self.cpu_limit.clone().acquire_owned().await?;
+    // The handler runs in its own task so a slow one doesn't hold up the other miners. A miner's requests
+    // are still handled one by one in the order of the queues, and no more than `cpu_permits` handlers
+    // run at once. The handler logs in the span of the miner's connection
+    fn spawn_dispatch(
+        &self, call: MethodCall, session: SessionHandle, respond_to: oneshot::Sender<ProxyMessage<'static>>, span: Span
+    ) {
+        let mut turn = session.take_turn();
+        let cpu_limit = Arc::clone(&self.cpu_limit);
+        let registry = Arc::clone(&self.registry);
+
+        tokio::spawn(async move {
+            turn.wait().await;
+            let Ok(_permit) = cpu_limit.acquire_owned().await else {
+                warn!("The CPU permits are closed");
+                return;
+            };
 
-        let _join_submit = tokio::task::spawn_blocking(move ||  {
-            let _permit = permit;
             let _guard = InFlightCpuGuard::new();
+            dispatch(registry, call, session, respond_to).await;
+        }.instrument(span));
+    }
+}
 
-            std::thread::sleep(Duration::from_millis(100));
-
-            info!("Submit -> {:?}", submit);
-            if let Err(err) = respond_to.send(ProxyMessage::Response(Cow::from("OK\n"))) {
-                warn!("Couldn't to send respond_to!");
-                error!("respond_to send error: {:?}", err);
+async fn dispatch(registry: Arc<HandlerRegistry>, call: MethodCall, session: SessionHandle, respond_to: oneshot::Sender<ProxyMessage<'static>>) {
+    let mut calls = VecDeque::from([DeferredCall { call, respond_to }]);
+
+    while let Some(DeferredCall { call, respond_to }) = calls.pop_front() {
+        let Some(handler) = registry.get(&call.method) else {
+            warn!(method = %call.method, "There is no handler for the method");
+            let _ = respond_to.send(ProxyMessage::error_response(&call.id, 20, "Unsupported method"));
+            continue;
+        };
+
+        // The connection is gone, nobody waits for the answer
+        let Ok(Routing { state, pool_tx, primary_pool, miner_tx, pool_messages }) = session.routing().await else {
+            continue;
+        };
+
+        // The request came out of order, it waits until the handshake gets far enough
+        if state < handler.required_state() {
+            info!(method = %call.method, ?state, "Request is deferred");
+            match session.defer(DeferredCall { call, respond_to }, handler.required_state()).await {
+                Ok(Deferral::Ready(ready)) => calls.push_front(ready),
+                Ok(Deferral::Full(deferred)) => {
+                    let response = if handler.required_state() <= SessionState::Subscribed {
+                        ProxyMessage::error_response(&deferred.call.id, 25, "Not subscribed")
+                    } else {
+                        ProxyMessage::error_response(&deferred.call.id, 24, "Unauthorized worker")
+                    };
+                    let _ = deferred.respond_to.send(response);
+                }
+                Ok(Deferral::Deferred) | Err(_) => {}
             }
-        });
+            continue;
+        }
 
-        Ok(())
+        let ctx = HandlerContext {
+            session: session.clone(),
+            upstream: pool_tx,
+            primary_pool,
+            outbound: miner_tx,
+            pool_messages,
+            respond_to
+        };
+
+        let method = call.method.clone();
+        let started = Instant::now();
+        if let Err(err) = handler.handle(ctx, call).await {
+            error!(%method, "Handler error: {:?}", err);
+        }
+        METRICS.observe_handler(&method, started.elapsed());
+
+        // The handler moved the session forward, the deferred requests may be ready now
+        if let Ok(deferred) = session.take_deferred_if_changed(state).await {
+            calls.extend(deferred);
+        }
     }
+}
 
-    pub async fn handle_subscribe(&self, subscribe: SubscribeParams, respond_to: oneshot::Sender<ProxyMessage<'static>>, miner: Arc<Mutex<Miner>>) -> anyhow::Result<()> {
-        let mut miner_guard = miner.lock().await;
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use serde_json::json;
 
-        let subscribe_json = serde_json::to_string(&subscribe)?;
+    use score::miner::Miner;
 
-        if !miner_guard.is_authorize() {
-            miner_guard.set_pending_subscribe(subscribe_json);
-            info!("miner_guard pending subscribe: {:?}", miner_guard.take_pending_subscribe());
-            info!("Miner not authorized yet. Subscribe saved for later.");
-            let result = respond_to.send(ProxyMessage::Wait);
-            if let Err(_) = result {
-                return Err(anyhow!("Channel has been closed"));
-            }
+    use super::*;
+    use crate::handler::MethodHandler;
+
+    struct Subscribe;
+    struct Authorize;
 
-            return Ok(());
+    #[async_trait]
+    impl MethodHandler for Subscribe {
+        fn method(&self) -> &str {
+            "mining.subscribe"
         }
 
-        if let Some(pool_tx) = miner_guard.pool_tx() {
-            pool_tx.send(subscribe_json).await?;
-            miner.lock().await.set_is_subscribe(true);
-        } else {
-            warn!("Pool tx not available even though miner is authorized");
+        async fn handle(&self, ctx: HandlerContext, call: MethodCall) -> anyhow::Result<()> {
+            ctx.session.transition(SessionState::Subscribed).await??;
+            ctx.respond(ProxyMessage::Response(Cow::from(format!("subscribed {}", call.id))))
         }
+    }
 
-        Ok(())
+    #[async_trait]
+    impl MethodHandler for Authorize {
+        fn method(&self) -> &str {
+            "mining.authorize"
+        }
+
+        fn required_state(&self) -> SessionState {
+            SessionState::Subscribed
+        }
+
+        async fn handle(&self, ctx: HandlerContext, call: MethodCall) -> anyhow::Result<()> {
+            ctx.respond(ProxyMessage::Response(Cow::from(format!("authorized {}", call.id))))
+        }
     }
 
-    pub async fn handle_authorize(&self, authorize: AuthorizeParams, respond_to: oneshot::Sender<ProxyMessage<'static>>, miner: Arc<Mutex<Miner>>) -> anyhow::Result<()> {
-        let worker_full_name = authorize.username();
+    fn registry() -> Arc<HandlerRegistry> {
+        let mut registry = HandlerRegistry::new();
+        registry.register(Subscribe).register(Authorize);
+        Arc::new(registry)
+    }
 
-        let subaccount_info = self.api_client.get_subaccount_info(worker_full_name.to_string()).await?;
+    fn session() -> SessionHandle {
+        let (miner_tx, _) = mpsc::channel(8);
+        let (pool_messages, _) = mpsc::channel(8);
+        SessionHandle::spawn(Miner::new("127.0.0.1:3333".parse().unwrap(), miner_tx, pool_messages))
+    }
 
-        match subaccount_info {
-            ApiResponse::Successfully(subaccount_info) => {
-                let pool_target = subaccount_info.pool_target.clone();
+    async fn send(
+        registry: &Arc<HandlerRegistry>, session: &SessionHandle, id: u64, method: &str
+    ) -> oneshot::Receiver<ProxyMessage<'static>> {
+        let (respond_to, rx) = oneshot::channel();
+        let call = MethodCall::new(method, json!({"id": id, "method": method, "params": []}));
+        dispatch(Arc::clone(registry), call, session.clone(), respond_to).await;
+        rx
+    }
 
-                let authorize_json = serde_json::to_string(&authorize)?;
-                let miner_tx = miner.lock().await.miner_tx();
-                let pool_client = PoolClient::new(&pool_target, miner_tx).await?;
+    fn text(message: ProxyMessage<'static>) -> String {
+        match message {
+            ProxyMessage::Response(text) => text.into_owned(),
+            other => panic!("unexpected message {other:?}")
+        }
+    }
 
-                {
-                    let mut miner_guard = miner.lock().await;
-                    let current_time = SystemTime::now();
-                    let since_epoch = current_time.duration_since(UNIX_EPOCH)?.as_secs();
+    #[tokio::test]
+    async fn early_authorize_is_handled_after_subscribe() {
+        let (registry, session) = (registry(), session());
 
-                    miner_guard.set_time_authorize(since_epoch);
-                    miner_guard.set_pool_addr(subaccount_info.pool_target);
-                    miner_guard.set_worker_name(subaccount_info.sub_account_name);
-                    miner_guard.set_is_authorize(true);
-                    miner_guard.set_pool_tx(pool_client.miner_channel_writer());
+        let mut authorize = send(&registry, &session, 1, "mining.authorize").await;
+        assert!(authorize.try_recv().is_err());
 
-                    if let Some(pending_subscribe) = miner_guard.take_pending_subscribe() {
-                        if let Some(pool_tx) = miner_guard.pool_tx() {
-                            pool_tx.send(pending_subscribe.clone()).await?;
-                        }
-                    }
-                }
+        let subscribe = send(&registry, &session, 2, "mining.subscribe").await;
+        assert_eq!(text(subscribe.await.unwrap()), "subscribed 2");
+        assert_eq!(text(authorize.await.unwrap()), "authorized 1");
+    }
 
-                let sender = pool_client.miner_channel_writer();
+    #[tokio::test]
+    async fn authorize_after_subscribe_is_not_deferred() {
+        let (registry, session) = (registry(), session());
 
-                if let Err(e) = sender.send(authorize_json).await {
-                    error!("Channel was closed with error: {:?}", e);
-                }
-            }
-            ApiResponse::NotFoundSubAccount(error) => {
-                info!("error -> {:?}", error);
+        send(&registry, &session, 1, "mining.subscribe").await.await.unwrap();
+        assert_eq!(text(send(&registry, &session, 2, "mining.authorize").await.await.unwrap()), "authorized 2");
+    }
+
+    #[tokio::test]
+    async fn full_deferral_queue_is_not_subscribed() {
+        let (registry, session) = (registry(), session());
+
+        let mut waiting = Vec::new();
+        let rejected = loop {
+            let mut rx = send(&registry, &session, waiting.len() as u64, "mining.authorize").await;
+            match rx.try_recv() {
+                Ok(message) => break text(message),
+                Err(_) => waiting.push(rx)
             }
+        };
+        assert!(rejected.contains(r#""error":[25,"Not subscribed",null]"#), "{rejected}");
+        assert!(!waiting.is_empty());
+
+        // The ones which are waiting are handled once the miner subscribes
+        send(&registry, &session, 100, "mining.subscribe").await.await.unwrap();
+        for (id, rx) in waiting.into_iter().enumerate() {
+            assert_eq!(text(rx.await.unwrap()), format!("authorized {id}"));
         }
+    }
 
-        Ok(())
+    #[tokio::test]
+    async fn method_without_handler_is_unsupported() {
+        let (registry, session) = (registry(), session());
+
+        let response = text(send(&registry, &session, 1, "mining.custom").await.await.unwrap());
+        assert!(response.contains("Unsupported method"), "{response}");
     }
-}
\ No newline at end of file
+}
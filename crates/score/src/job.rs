@@ -1,19 +1,40 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::sync::Arc;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use tokio::sync::{Mutex, oneshot};
-use crate::miner::Miner;
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+use tracing::Span;
+use crate::session::SessionHandle;
 use crate::traits::{extract_params_array, FromParams, ParseError};
+use crate::utils::{get_param_as_string, opt_param_as_string};
 
 #[derive(Debug)]
 pub enum Job {
-    MiningSubmit((SubmitParams, Arc<Mutex<Miner>>)), // mining.submit
-    MiningAuthorize((AuthorizeParams, Arc<Mutex<Miner>>)), // mining.authorize
-    MiningSubscribe((SubscribeParams, Arc<Mutex<Miner>>)), // mining.subscribe
+    Method((MethodCall, SessionHandle)), // any stratum method which has a handler
     Ping,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Priority {
+    High,
+    Norm
+}
+
+/// A validated JSON-RPC request from the miner
+#[derive(Debug, Clone)]
+pub struct MethodCall {
+    pub id: Value, // request id, it has to be returned in the response
+    pub method: String, // mining method
+    pub message: Value // the whole request as it came from the miner
+}
+
+/// Queue priority of every method the scheduler is able to handle
+#[derive(Debug, Clone, Default)]
+pub struct MethodRoutes {
+    routes: HashMap<String, Priority>
+}
+
 #[derive(Debug)]
 pub enum ProxyMessage<'a> {
     Wait,
@@ -22,10 +43,18 @@ pub enum ProxyMessage<'a> {
     Err(Cow<'a, str>)
 }
 
+/// A line which an upstream pool has sent to the miner, with the address of the pool
+#[derive(Debug, Clone)]
+pub struct PoolMessage {
+    pub pool: Arc<str>,
+    pub line: String
+}
+
 #[derive(Debug)]
 pub struct JobRequest {
     pub job: Job, // mining method
     pub respond_to: oneshot::Sender<ProxyMessage<'static>>, // a channel for responding to the user
+    pub span: Span, // span of the miner's connection, the handler logs in it
     // pub miner_notify_rx: Option<tokio::sync::mpsc::Receiver<String>> // If the JobRequest is the mining.notify we will need to open a stream for the message flow
 }
 
@@ -56,12 +85,12 @@ impl FromParams for SubmitParams {
     fn from_params(params: &[Value]) -> Result<Self, ParseError> {
         Ok(
             SubmitParams {
-                workername: params.get(0).and_then(|worker| Some(worker.as_str().unwrap().to_string())).unwrap(),
-                job_id: params.get(1).and_then(|job_id| Some(job_id.as_str().unwrap().to_string())).unwrap(),
-                extranonce2: params.get(2).and_then(|extranonce2| Some(extranonce2.as_str().unwrap().to_string())).unwrap(),
-                n_time: params.get(3).and_then(|n_time| Some(n_time.as_str().unwrap().to_string())).unwrap(),
-                nonce: params.get(4).and_then(|nonce| Some(nonce.as_str().unwrap().to_string())).unwrap(),
-                n_bits: Some(params.get(5).and_then(|n_bits| Some(n_bits.as_str().unwrap().to_string())).or(Some("000000".to_string())).unwrap()),
+                workername: get_param_as_string(params, 0)?,
+                job_id: get_param_as_string(params, 1)?,
+                extranonce2: get_param_as_string(params, 2)?,
+                n_time: get_param_as_string(params, 3)?,
+                nonce: get_param_as_string(params, 4)?,
+                n_bits: Some(opt_param_as_string(params, 5)?.unwrap_or("000000".to_string())),
             }
         )
     }
@@ -72,8 +101,8 @@ impl FromParams for AuthorizeParams {
     fn from_params(params: &[Value]) -> Result<Self, ParseError> {
         Ok(
             AuthorizeParams {
-                username: params.get(0).and_then(|username| Some(username.as_str().unwrap().to_string())).unwrap(),
-                password: params.get(1).and_then(|pass| Some(pass.as_str().unwrap().to_string())),
+                username: get_param_as_string(params, 0)?,
+                password: opt_param_as_string(params, 1)?,
             }
         )
     }
@@ -84,8 +113,8 @@ impl FromParams for SubscribeParams {
     fn from_params(params: &[Value]) -> Result<Self, ParseError> {
         Ok(
             SubscribeParams {
-                agent_version: params.get(0).and_then(|text| Some(text.as_str().unwrap().to_string())).unwrap_or("Unknown".to_string()),
-                extranonce1: params.get(1).and_then(|extranonce1| Some(extranonce1.as_str().unwrap().to_string())),
+                agent_version: opt_param_as_string(params, 0)?.unwrap_or("Unknown".to_string()),
+                extranonce1: opt_param_as_string(params, 1)?,
             }
         )
     }
@@ -122,6 +151,43 @@ impl SubscribeParams {
     }
 }
 
+impl ProxyMessage<'static> {
+    /// Stratum error response on the request with `id`, for example `[24, "Unauthorized worker", null]`
+    pub fn error_response(id: &Value, code: i64, message: &str) -> Self {
+        let response = json!({"id": id, "result": null, "error": [code, message, null]});
+        ProxyMessage::Response(Cow::from(format!("{}\n", response)))
+    }
+}
+
+impl MethodCall {
+    pub fn new(method: impl Into<String>, message: Value) -> Self {
+        let id = message.get("id").cloned().unwrap_or(Value::Null);
+
+        Self {
+            id,
+            method: method.into(),
+            message
+        }
+    }
+
+    pub fn params(&self) -> &[Value] {
+        self.message.get("params")
+            .and_then(|params| params.as_array())
+            .map(|params| params.as_slice())
+            .unwrap_or(&[])
+    }
+}
+
+impl MethodRoutes {
+    pub fn insert(&mut self, method: impl Into<String>, priority: Priority) {
+        self.routes.insert(method.into(), priority);
+    }
+
+    pub fn priority(&self, method: &str) -> Option<Priority> {
+        self.routes.get(method).copied()
+    }
+}
+
 impl AuthorizeParams {
     pub fn username(&self) -> &str {
         &self.username
@@ -2,3 +2,5 @@ pub mod job;
 mod traits;
 pub mod utils;
 pub mod miner;
+pub mod worker;
+pub mod session;
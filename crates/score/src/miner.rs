@@ -1,26 +1,65 @@
+use std::collections::{HashMap, VecDeque};
 use std::net::{IpAddr, SocketAddr};
+use serde::Serialize;
 use tokio::sync::mpsc;
 use uuid::Uuid;
 
+use crate::job::PoolMessage;
+use crate::session::{DeferredCall, SessionState, TransitionError};
+use crate::worker::{Worker, WorkerSnapshot};
+
+// How many requests may wait for the handshake to move forward
+const MAX_DEFERRED_CALLS: usize = 32;
+
 #[derive(Debug)]
 pub struct Miner {
     miner_id: Uuid,
     miner_host: IpAddr,
     miner_port: u16,
-    time_authorize: Option<u64>,
-    pool_addr: String,
-    share_count: u64,
     miner_diff: u64,
-    worker_name: String,
     miner_tx: mpsc::Sender<String>,
-    pool_tx: Option<mpsc::Sender<String>>,
-    pending_subscribe: Option<String>,
-    is_subscribe: bool,
-    is_authorize: bool
+    // The pool sessions of the miner send what they get from the pools here
+    pool_messages: mpsc::Sender<PoolMessage>,
+    // Workers authorized over this connection by their full names
+    workers: HashMap<String, Worker>,
+    // Pool sessions of the workers by the pool's address
+    pool_sessions: HashMap<String, mpsc::Sender<String>>,
+    // The pool of the first pool session. The miner works on its jobs, extranonce and difficulty, so every
+    // worker of the connection is authorized on it, a share wouldn't be valid at another pool
+    primary_pool: Option<String>,
+    // The first authorized worker of the primary pool, its pool session serves the requests which aren't
+    // bound to a worker
+    primary_worker: Option<String>,
+    state: SessionState,
+    // Handshake requests of the miner, they are replayed on every new pool session
+    configure_request: Option<String>,
+    subscribe_request: Option<String>,
+    deferred: VecDeque<DeferredCall>
+}
+
+/// A copy of the session's state for the readers outside of the session (admin queries, stats)
+#[derive(Debug, Clone, Serialize)]
+pub struct MinerSnapshot {
+    pub miner_id: Uuid,
+    pub miner_host: IpAddr,
+    pub miner_port: u16,
+    pub miner_diff: u64,
+    pub state: SessionState,
+    pub primary_pool: Option<String>,
+    pub primary_worker: Option<String>,
+    pub workers: Vec<WorkerSnapshot>
+}
+
+/// A pool session of the miner and the workers which use it
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamInfo {
+    pub pool_addr: String,
+    pub workers: Vec<String>,
+    pub is_connected: bool
 }
 
 impl Miner {
-    pub fn new(socket_address: SocketAddr, miner_tx: mpsc::Sender<String>) -> Self {
+    pub fn new(socket_address: SocketAddr, miner_tx: mpsc::Sender<String>, pool_messages: mpsc::Sender<PoolMessage>) -> Self {
         let host = socket_address.ip();
         let port = socket_address.port();
 
@@ -28,59 +67,83 @@ impl Miner {
             miner_id: Uuid::new_v4(),
             miner_host: host,
             miner_port: port,
-            time_authorize: None,
-            // Need do default pool address for example via.btc.com:3333
-            pool_addr: "".to_string(),
-            share_count: 0,
             miner_diff: 0,
-            worker_name: "".to_string(),
             miner_tx,
-            pool_tx: None,
-            pending_subscribe: None,
-            is_subscribe: false,
-            is_authorize: false,
+            pool_messages,
+            workers: HashMap::new(),
+            pool_sessions: HashMap::new(),
+            primary_pool: None,
+            primary_worker: None,
+            state: SessionState::Connected,
+            configure_request: None,
+            subscribe_request: None,
+            deferred: VecDeque::new()
         }
     }
 
     // --- SETTERS ---
-    pub fn set_time_authorize(&mut self, time: u64) {
-        self.time_authorize = Some(time);
+    pub fn set_miner_diff(&mut self, diff: u64) {
+        self.miner_diff = diff;
     }
 
-    pub fn set_pool_addr(&mut self, pool_addr: String) {
-        self.pool_addr = pool_addr;
-    }
+    /// Keeps the new pool session, unless there is a live one with the pool already. That one is given
+    /// back then and the new one isn't used
+    pub fn add_pool_session(&mut self, pool_addr: &str, pool_tx: mpsc::Sender<String>) -> Option<mpsc::Sender<String>> {
+        if let Some(existing) = self.pool_tx_for(pool_addr) {
+            return Some(existing);
+        }
 
-    pub fn set_share_count(&mut self, count: u64) {
-        self.share_count = count;
+        if self.primary_pool.is_none() {
+            self.primary_pool = Some(pool_addr.to_string());
+        }
+        self.pool_sessions.insert(pool_addr.to_string(), pool_tx);
+        None
     }
 
-    pub fn increment_share_count(&mut self) {
-        self.share_count += 1;
+    /// Adds an authorized worker. A worker authorized again with the same name is replaced
+    pub fn add_worker(&mut self, worker: Worker) {
+        let is_primary_pool = self.primary_pool.as_deref().is_none_or(|pool| pool == worker.pool_addr());
+        if self.primary_worker.is_none() && is_primary_pool {
+            self.primary_worker = Some(worker.worker_name().to_string());
+        }
+        self.workers.insert(worker.worker_name().to_string(), worker);
     }
 
-    pub fn set_miner_diff(&mut self, diff: u64) {
-        self.miner_diff = diff;
+    pub fn worker_mut(&mut self, worker_name: &str) -> Option<&mut Worker> {
+        self.workers.get_mut(worker_name)
     }
 
-    pub fn set_worker_name<S: Into<String>>(&mut self, name: S) {
-        self.worker_name = name.into();
+    /// Moves the session to the `to` state if the transition is legal
+    pub fn transition(&mut self, to: SessionState) -> Result<(), TransitionError> {
+        if !self.state.can_transition(to) {
+            return Err(TransitionError { from: self.state, to });
+        }
+
+        self.state = to;
+        Ok(())
     }
 
-    pub fn set_pool_tx(&mut self, pool_tx: mpsc::Sender<String>) {
-        self.pool_tx = Some(pool_tx);
+    pub fn set_configure_request(&mut self, json: String) {
+        self.configure_request = Some(json);
     }
 
-    pub fn set_pending_subscribe(&mut self, json: String) {
-        self.pending_subscribe = Some(json);
+    pub fn set_subscribe_request(&mut self, json: String) {
+        self.subscribe_request = Some(json);
     }
 
-    pub fn set_is_subscribe(&mut self, value: bool) {
-        self.is_subscribe = value;
+    /// Puts the request aside until the session is ready for it. If too many requests are waiting
+    /// already the request is given back
+    pub fn defer(&mut self, deferred: DeferredCall) -> Result<(), DeferredCall> {
+        if self.deferred.len() >= MAX_DEFERRED_CALLS {
+            return Err(deferred);
+        }
+
+        self.deferred.push_back(deferred);
+        Ok(())
     }
 
-    pub fn set_is_authorize(&mut self, value: bool) {
-        self.is_authorize = value;
+    pub fn take_deferred(&mut self) -> VecDeque<DeferredCall> {
+        std::mem::take(&mut self.deferred)
     }
 
     // --- GETTERS ---
@@ -97,43 +160,86 @@ impl Miner {
         self.miner_port
     }
 
-    pub fn time_authorize(&self) -> Option<u64> {
-        self.time_authorize
+    pub fn miner_diff(&self) -> u64 {
+        self.miner_diff
     }
 
-    pub fn pool_addr(&self) -> &str {
-        &self.pool_addr
+    pub fn miner_tx(&self) -> mpsc::Sender<String> {
+        self.miner_tx.clone()
     }
 
-    pub fn share_count(&self) -> u64 {
-        self.share_count
+    pub fn pool_messages(&self) -> mpsc::Sender<PoolMessage> {
+        self.pool_messages.clone()
     }
 
-    pub fn miner_diff(&self) -> u64 {
-        self.miner_diff
+    pub fn worker(&self, worker_name: &str) -> Option<&Worker> {
+        self.workers.get(worker_name)
     }
 
-    pub fn worker_name(&self) -> &str {
-        &self.worker_name
+    pub fn workers(&self) -> impl Iterator<Item = &Worker> {
+        self.workers.values()
     }
 
-    pub fn miner_tx(&self) -> mpsc::Sender<String> {
-        self.miner_tx.clone()
+    pub fn primary_worker(&self) -> Option<&Worker> {
+        self.primary_worker.as_ref().and_then(|name| self.workers.get(name))
     }
 
+    /// Pool session of the primary worker
     pub fn pool_tx(&self) -> Option<mpsc::Sender<String>> {
-        self.pool_tx.clone()
+        self.primary_worker().map(|worker| worker.pool_tx())
+    }
+
+    /// Pool session which the miner already has with `pool_addr`, unless the pool has closed it
+    pub fn pool_tx_for(&self, pool_addr: &str) -> Option<mpsc::Sender<String>> {
+        self.pool_sessions.get(pool_addr).filter(|pool_tx| !pool_tx.is_closed()).cloned()
+    }
+
+    pub fn primary_pool(&self) -> Option<&str> {
+        self.primary_pool.as_deref()
     }
 
-    pub fn take_pending_subscribe(&self) -> &Option<String> {
-        &self.pending_subscribe
+    /// Shares submitted by all the workers of the connection
+    pub fn share_count(&self) -> u64 {
+        self.workers.values().map(|worker| worker.share_count()).sum()
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    /// Requests which have to be sent to a new pool session before any worker is authorized on it
+    pub fn handshake_requests(&self) -> impl Iterator<Item = &String> {
+        self.configure_request.iter().chain(self.subscribe_request.iter())
     }
 
-    pub fn is_subscribe(&self) -> bool {
-        self.is_subscribe
+    /// Pool sessions of the workers, one per pool. A session is closed once the pool closes the socket
+    pub fn upstreams(&self) -> Vec<UpstreamInfo> {
+        let mut upstreams: Vec<UpstreamInfo> = Vec::new();
+
+        for worker in self.workers.values() {
+            match upstreams.iter_mut().find(|upstream| upstream.pool_addr == worker.pool_addr()) {
+                Some(upstream) => upstream.workers.push(worker.worker_name().to_string()),
+                None => upstreams.push(UpstreamInfo {
+                    pool_addr: worker.pool_addr().to_string(),
+                    workers: vec![worker.worker_name().to_string()],
+                    is_connected: self.pool_tx_for(worker.pool_addr()).is_some()
+                })
+            }
+        }
+
+        upstreams
     }
 
-    pub fn is_authorize(&self) -> bool {
-        self.is_authorize
+    pub fn snapshot(&self) -> MinerSnapshot {
+        MinerSnapshot {
+            miner_id: self.miner_id,
+            miner_host: self.miner_host,
+            miner_port: self.miner_port,
+            miner_diff: self.miner_diff,
+            state: self.state,
+            primary_pool: self.primary_pool.clone(),
+            primary_worker: self.primary_worker.clone(),
+            workers: self.workers.values().map(|worker| worker.snapshot()).collect()
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,176 @@
+use serde::Serialize;
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+use crate::job::{MethodCall, ProxyMessage};
+
+mod actor;
+
+pub use actor::{Routing, SessionClosed, SessionHandle, Turn};
+
+/// Stage of the stratum handshake a miner's connection is in.
+///
+/// States are ordered, so `state >= SessionState::Subscribed` means the miner has subscribed already
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum SessionState {
+    Connected, // nothing has been received yet
+    Configured, // mining.configure, it's optional
+    Subscribed, // mining.subscribe
+    Authorized, // at least one worker is authorized
+    Mining, // the first share has been submitted
+    Draining // the connection is going to be closed, no new work is accepted
+}
+
+#[derive(Debug, Error)]
+#[error("Illegal session transition: {from:?} -> {to:?}")]
+pub struct TransitionError {
+    pub from: SessionState,
+    pub to: SessionState
+}
+
+/// A request which came before the session was ready for it
+#[derive(Debug)]
+pub struct DeferredCall {
+    pub call: MethodCall,
+    pub respond_to: oneshot::Sender<ProxyMessage<'static>>
+}
+
+/// What became of a request which was put aside
+#[derive(Debug)]
+pub enum Deferral {
+    Deferred,
+    // The session got to the state while the request was on its way, it can be handled now
+    Ready(DeferredCall),
+    // Too many requests are waiting already
+    Full(DeferredCall)
+}
+
+impl SessionState {
+    pub fn can_transition(self, to: SessionState) -> bool {
+        use SessionState::*;
+
+        matches!(
+            (self, to),
+            (Connected, Configured)
+                | (Connected | Configured, Subscribed)
+                | (Subscribed, Authorized)
+                | (Authorized, Mining)
+                | (Connected | Configured | Subscribed | Authorized | Mining, Draining)
+        )
+    }
+
+    pub fn is_handshake_complete(self) -> bool {
+        self >= SessionState::Authorized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::miner::Miner;
+
+    fn session() -> SessionHandle {
+        let (miner_tx, _) = mpsc::channel(8);
+        let (pool_messages, _) = mpsc::channel(8);
+        SessionHandle::spawn(Miner::new("127.0.0.1:3333".parse().unwrap(), miner_tx, pool_messages))
+    }
+
+    fn deferred(id: u64) -> (DeferredCall, oneshot::Receiver<ProxyMessage<'static>>) {
+        let (respond_to, rx) = oneshot::channel();
+        let message = json!({"id": id, "method": "mining.authorize", "params": ["acc.worker"]});
+        (DeferredCall { call: MethodCall::new("mining.authorize", message), respond_to }, rx)
+    }
+
+    #[test]
+    fn legal_transitions() {
+        use SessionState::*;
+
+        assert!(Connected.can_transition(Configured));
+        assert!(Connected.can_transition(Subscribed));
+        assert!(Configured.can_transition(Subscribed));
+        assert!(Subscribed.can_transition(Authorized));
+        assert!(Authorized.can_transition(Mining));
+        for from in [Connected, Configured, Subscribed, Authorized, Mining] {
+            assert!(from.can_transition(Draining), "{from:?}");
+        }
+    }
+
+    #[test]
+    fn illegal_transitions() {
+        use SessionState::*;
+
+        assert!(!Connected.can_transition(Authorized));
+        assert!(!Connected.can_transition(Mining));
+        assert!(!Configured.can_transition(Configured));
+        assert!(!Subscribed.can_transition(Subscribed));
+        assert!(!Subscribed.can_transition(Configured));
+        assert!(!Authorized.can_transition(Subscribed));
+        assert!(!Mining.can_transition(Authorized));
+        for to in [Connected, Configured, Subscribed, Authorized, Mining, Draining] {
+            assert!(!Draining.can_transition(to), "{to:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn illegal_transition_keeps_the_state() {
+        let session = session();
+
+        let err = session.transition(SessionState::Authorized).await.unwrap().unwrap_err();
+        assert_eq!((err.from, err.to), (SessionState::Connected, SessionState::Authorized));
+        assert_eq!(session.state(), SessionState::Connected);
+
+        session.transition(SessionState::Subscribed).await.unwrap().unwrap();
+        assert_eq!(session.state(), SessionState::Subscribed);
+    }
+
+    #[tokio::test]
+    async fn deferred_call_is_taken_once_the_state_changes() {
+        let session = session();
+
+        let (call, _rx) = deferred(1);
+        assert!(matches!(session.defer(call, SessionState::Subscribed).await.unwrap(), Deferral::Deferred));
+
+        // Nothing has changed since the call was deferred
+        assert!(session.take_deferred_if_changed(SessionState::Connected).await.unwrap().is_empty());
+
+        session.transition(SessionState::Subscribed).await.unwrap().unwrap();
+        let taken = session.take_deferred_if_changed(SessionState::Connected).await.unwrap();
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].call.id, json!(1));
+
+        // The calls are taken only once
+        assert!(session.take_deferred_if_changed(SessionState::Connected).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn call_is_ready_if_the_state_is_reached() {
+        let session = session();
+        session.transition(SessionState::Subscribed).await.unwrap().unwrap();
+
+        let (call, _rx) = deferred(1);
+        assert!(matches!(session.defer(call, SessionState::Subscribed).await.unwrap(), Deferral::Ready(_)));
+    }
+
+    #[tokio::test]
+    async fn full_deferral_queue_gives_the_call_back() {
+        let session = session();
+
+        let mut receivers = Vec::new();
+        loop {
+            let (call, rx) = deferred(receivers.len() as u64);
+            receivers.push(rx);
+            match session.defer(call, SessionState::Subscribed).await.unwrap() {
+                Deferral::Deferred => continue,
+                Deferral::Full(call) => {
+                    assert_eq!(call.call.id, json!(receivers.len() - 1));
+                    break;
+                }
+                Deferral::Ready(_) => panic!("the session isn't subscribed")
+            }
+        }
+        assert!(receivers.len() > 1);
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::job::PoolMessage;
+use crate::miner::{Miner, MinerSnapshot, UpstreamInfo};
+use crate::session::{DeferredCall, Deferral, SessionState, TransitionError};
+use crate::worker::Worker;
+
+const MAILBOX_SIZE: usize = 64;
+
+#[derive(Debug, Error)]
+#[error("miner session is closed")]
+pub struct SessionClosed;
+
+/// What a request needs to know about the session to be handled
+#[derive(Debug)]
+pub struct Routing {
+    pub state: SessionState,
+    pub pool_tx: Option<mpsc::Sender<String>>, // pool session of the primary worker
+    pub primary_pool: Option<String>, // pool of the connection, every worker is authorized on it
+    pub miner_tx: mpsc::Sender<String>, // outbound channel of the miner
+    pub pool_messages: mpsc::Sender<PoolMessage> // a new pool session of the miner sends what the pool says here
+}
+
+/// Messages the session actor handles, one at a time and in the order they are sent
+#[derive(Debug)]
+enum SessionCommand {
+    Routing(oneshot::Sender<Routing>),
+    Transition(SessionState, oneshot::Sender<Result<(), TransitionError>>),
+    SetConfigureRequest(String),
+    SetSubscribeRequest(String),
+    HandshakeRequests(oneshot::Sender<Vec<String>>),
+    PoolTxFor(String, oneshot::Sender<Option<mpsc::Sender<String>>>),
+    AddPoolSession(String, mpsc::Sender<String>, oneshot::Sender<Option<mpsc::Sender<String>>>),
+    AddWorker(Worker, oneshot::Sender<Result<(), TransitionError>>),
+    RecordShare(String, u64, oneshot::Sender<Option<mpsc::Sender<String>>>),
+    Defer(DeferredCall, SessionState, oneshot::Sender<Deferral>),
+    TakeDeferredIfChanged(SessionState, oneshot::Sender<VecDeque<DeferredCall>>),
+    Upstreams(oneshot::Sender<Vec<UpstreamInfo>>)
+}
+
+/// Handle of a miner session. The session's `Miner` is owned by an actor task, the handle only sends
+/// messages to it, so there is nothing to lock
+#[derive(Debug, Clone)]
+pub struct SessionHandle {
+    mailbox: mpsc::Sender<SessionCommand>,
+    snapshot: watch::Receiver<Arc<MinerSnapshot>>,
+    // The turn of the miner's last request, the next request waits for it
+    last_turn: Arc<Mutex<Option<oneshot::Receiver<()>>>>
+}
+
+/// Place of a miner's request in the line. The requests of a miner are handled one at a time, in the
+/// order their turns are taken. The turn is over when it's dropped
+#[derive(Debug)]
+pub struct Turn {
+    previous: Option<oneshot::Receiver<()>>,
+    _done: oneshot::Sender<()>
+}
+
+impl Turn {
+    /// Waits until the miner's previous request is handled
+    pub async fn wait(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            let _ = previous.await;
+        }
+    }
+}
+
+impl SessionHandle {
+    /// Spawns the actor of the miner's session. It lives until every handle is dropped
+    pub fn spawn(miner: Miner) -> Self {
+        let (mailbox, rx) = mpsc::channel(MAILBOX_SIZE);
+        let (tx_snapshot, snapshot) = watch::channel(Arc::new(miner.snapshot()));
+
+        tokio::spawn(run_session(miner, rx, tx_snapshot));
+
+        Self { mailbox, snapshot, last_turn: Arc::new(Mutex::new(None)) }
+    }
+
+    /// Puts the miner's next request in the line
+    pub fn take_turn(&self) -> Turn {
+        let (done, rx) = oneshot::channel();
+        let previous = self.last_turn.lock().unwrap_or_else(|err| err.into_inner()).replace(rx);
+
+        Turn { previous, _done: done }
+    }
+
+    /// The latest state of the session, it doesn't wait for the actor
+    pub fn snapshot(&self) -> Arc<MinerSnapshot> {
+        self.snapshot.borrow().clone()
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.snapshot.borrow().state
+    }
+
+    /// Waits until the session's snapshot changes since it was seen by this handle the last time
+    pub async fn changed(&mut self) -> Result<Arc<MinerSnapshot>, SessionClosed> {
+        self.snapshot.changed().await.map_err(|_| SessionClosed)?;
+        Ok(self.snapshot.borrow_and_update().clone())
+    }
+
+    pub async fn routing(&self) -> Result<Routing, SessionClosed> {
+        self.request(SessionCommand::Routing).await
+    }
+
+    /// Moves the session to the `to` state if the transition is legal
+    pub async fn transition(&self, to: SessionState) -> Result<Result<(), TransitionError>, SessionClosed> {
+        self.request(|reply| SessionCommand::Transition(to, reply)).await
+    }
+
+    pub async fn set_configure_request(&self, json: String) -> Result<(), SessionClosed> {
+        self.send(SessionCommand::SetConfigureRequest(json)).await
+    }
+
+    pub async fn set_subscribe_request(&self, json: String) -> Result<(), SessionClosed> {
+        self.send(SessionCommand::SetSubscribeRequest(json)).await
+    }
+
+    /// Requests which have to be sent to a new pool session before any worker is authorized on it
+    pub async fn handshake_requests(&self) -> Result<Vec<String>, SessionClosed> {
+        self.request(SessionCommand::HandshakeRequests).await
+    }
+
+    /// Pool session which some of the workers already have with `pool_addr`
+    pub async fn pool_tx_for(&self, pool_addr: &str) -> Result<Option<mpsc::Sender<String>>, SessionClosed> {
+        self.request(|reply| SessionCommand::PoolTxFor(pool_addr.to_string(), reply)).await
+    }
+
+    /// Keeps a new pool session of the miner. If a session with the pool has been opened meanwhile, it's
+    /// given back and the new one has to be closed
+    pub async fn add_pool_session(
+        &self, pool_addr: &str, pool_tx: mpsc::Sender<String>
+    ) -> Result<Option<mpsc::Sender<String>>, SessionClosed> {
+        self.request(|reply| SessionCommand::AddPoolSession(pool_addr.to_string(), pool_tx, reply)).await
+    }
+
+    /// Adds an authorized worker. The first one completes the handshake
+    pub async fn add_worker(&self, worker: Worker) -> Result<Result<(), TransitionError>, SessionClosed> {
+        self.request(|reply| SessionCommand::AddWorker(worker, reply)).await
+    }
+
+    /// Counts the share of the worker and gives the worker's pool session, None if the worker isn't authorized
+    pub async fn record_share(&self, worker_name: &str, time: u64) -> Result<Option<mpsc::Sender<String>>, SessionClosed> {
+        self.request(|reply| SessionCommand::RecordShare(worker_name.to_string(), time, reply)).await
+    }
+
+    /// Puts the request aside until the session gets to the `until` state. The request is given back
+    /// if the session is there already or too many requests are waiting
+    pub async fn defer(&self, deferred: DeferredCall, until: SessionState) -> Result<Deferral, SessionClosed> {
+        self.request(|reply| SessionCommand::Defer(deferred, until, reply)).await
+    }
+
+    /// Deferred requests, if the session has moved from the `since` state
+    pub async fn take_deferred_if_changed(&self, since: SessionState) -> Result<VecDeque<DeferredCall>, SessionClosed> {
+        self.request(|reply| SessionCommand::TakeDeferredIfChanged(since, reply)).await
+    }
+
+    /// Pool sessions of the miner with their state at the moment
+    pub async fn upstreams(&self) -> Result<Vec<UpstreamInfo>, SessionClosed> {
+        self.request(SessionCommand::Upstreams).await
+    }
+
+    /// Queues a message to the miner besides the responses (client.reconnect, client.show_message, etc.)
+    pub async fn notify_miner(&self, message: String) -> Result<(), SessionClosed> {
+        let routing = self.routing().await?;
+        routing.miner_tx.send(message).await.map_err(|_| SessionClosed)
+    }
+
+    async fn send(&self, command: SessionCommand) -> Result<(), SessionClosed> {
+        self.mailbox.send(command).await.map_err(|_| SessionClosed)
+    }
+
+    async fn request<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> SessionCommand) -> Result<T, SessionClosed> {
+        let (reply, rx) = oneshot::channel();
+        self.send(command(reply)).await?;
+        rx.await.map_err(|_| SessionClosed)
+    }
+}
+
+async fn run_session(mut miner: Miner, mut rx: mpsc::Receiver<SessionCommand>, tx_snapshot: watch::Sender<Arc<MinerSnapshot>>) {
+    while let Some(command) = rx.recv().await {
+        let is_changed = match command {
+            SessionCommand::Routing(reply) => {
+                let _ = reply.send(Routing {
+                    state: miner.state(),
+                    pool_tx: miner.pool_tx(),
+                    primary_pool: miner.primary_pool().map(str::to_string),
+                    miner_tx: miner.miner_tx(),
+                    pool_messages: miner.pool_messages()
+                });
+                false
+            }
+            SessionCommand::Transition(to, reply) => {
+                let _ = reply.send(miner.transition(to));
+                true
+            }
+            SessionCommand::SetConfigureRequest(json) => {
+                miner.set_configure_request(json);
+                false
+            }
+            SessionCommand::SetSubscribeRequest(json) => {
+                miner.set_subscribe_request(json);
+                false
+            }
+            SessionCommand::HandshakeRequests(reply) => {
+                let _ = reply.send(miner.handshake_requests().cloned().collect());
+                false
+            }
+            SessionCommand::PoolTxFor(pool_addr, reply) => {
+                let _ = reply.send(miner.pool_tx_for(&pool_addr));
+                false
+            }
+            SessionCommand::AddPoolSession(pool_addr, pool_tx, reply) => {
+                let _ = reply.send(miner.add_pool_session(&pool_addr, pool_tx));
+                true
+            }
+            SessionCommand::AddWorker(worker, reply) => {
+                miner.add_worker(worker);
+
+                // The next workers join the session which is authorized already
+                let result = if miner.state() == SessionState::Subscribed {
+                    miner.transition(SessionState::Authorized)
+                } else {
+                    Ok(())
+                };
+                let _ = reply.send(result);
+                true
+            }
+            SessionCommand::RecordShare(worker_name, time, reply) => {
+                let pool_tx = miner.worker_mut(&worker_name).map(|worker| {
+                    worker.record_share(time);
+                    worker.pool_tx()
+                });
+
+                if pool_tx.is_some() && miner.state() == SessionState::Authorized {
+                    let _ = miner.transition(SessionState::Mining);
+                }
+                let _ = reply.send(pool_tx);
+                true
+            }
+            SessionCommand::Defer(deferred, until, reply) => {
+                // Handlers run at once, the state may have moved since the request was routed
+                let deferral = if miner.state() >= until {
+                    Deferral::Ready(deferred)
+                } else {
+                    match miner.defer(deferred) {
+                        Ok(()) => Deferral::Deferred,
+                        Err(deferred) => Deferral::Full(deferred)
+                    }
+                };
+                let _ = reply.send(deferral);
+                false
+            }
+            SessionCommand::TakeDeferredIfChanged(since, reply) => {
+                let deferred = if miner.state() != since {
+                    miner.take_deferred()
+                } else {
+                    VecDeque::new()
+                };
+                let _ = reply.send(deferred);
+                false
+            }
+            SessionCommand::Upstreams(reply) => {
+                let _ = reply.send(miner.upstreams());
+                false
+            }
+        };
+
+        if is_changed {
+            tx_snapshot.send_replace(Arc::new(miner.snapshot()));
+        }
+    }
+}
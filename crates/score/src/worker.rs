@@ -0,0 +1,88 @@
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+/// A worker authorized over a miner's connection. One connection may hold several of them
+#[derive(Debug)]
+pub struct Worker {
+    worker_name: String, // full name from mining.authorize, for example account.worker1
+    sub_account_name: String,
+    pool_addr: String,
+    pool_tx: mpsc::Sender<String>,
+    time_authorize: u64,
+    share_count: u64,
+    last_share_time: Option<u64>
+}
+
+/// A copy of the worker's state, it's handed out of the session without the pool channel
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkerSnapshot {
+    pub worker_name: String,
+    pub sub_account_name: String,
+    pub pool_addr: String,
+    pub time_authorize: u64,
+    pub share_count: u64,
+    pub last_share_time: Option<u64>
+}
+
+impl Worker {
+    pub fn new(
+        worker_name: impl Into<String>, sub_account_name: impl Into<String>,
+        pool_addr: impl Into<String>, pool_tx: mpsc::Sender<String>, time_authorize: u64
+    ) -> Self {
+        Self {
+            worker_name: worker_name.into(),
+            sub_account_name: sub_account_name.into(),
+            pool_addr: pool_addr.into(),
+            pool_tx,
+            time_authorize,
+            share_count: 0,
+            last_share_time: None
+        }
+    }
+
+    // --- SETTERS ---
+    pub fn record_share(&mut self, time: u64) {
+        self.share_count += 1;
+        self.last_share_time = Some(time);
+    }
+
+    // --- GETTERS ---
+    pub fn worker_name(&self) -> &str {
+        &self.worker_name
+    }
+
+    pub fn sub_account_name(&self) -> &str {
+        &self.sub_account_name
+    }
+
+    pub fn pool_addr(&self) -> &str {
+        &self.pool_addr
+    }
+
+    pub fn pool_tx(&self) -> mpsc::Sender<String> {
+        self.pool_tx.clone()
+    }
+
+    pub fn time_authorize(&self) -> u64 {
+        self.time_authorize
+    }
+
+    pub fn share_count(&self) -> u64 {
+        self.share_count
+    }
+
+    pub fn last_share_time(&self) -> Option<u64> {
+        self.last_share_time
+    }
+
+    pub fn snapshot(&self) -> WorkerSnapshot {
+        WorkerSnapshot {
+            worker_name: self.worker_name.clone(),
+            sub_account_name: self.sub_account_name.clone(),
+            pool_addr: self.pool_addr.clone(),
+            time_authorize: self.time_authorize,
+            share_count: self.share_count,
+            last_share_time: self.last_share_time
+        }
+    }
+}
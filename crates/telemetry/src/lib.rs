@@ -1,48 +1,98 @@
+use std::sync::Arc;
 use std::time::Duration;
+
+use futures::future::join_all;
+use tokio::sync::{watch, Semaphore};
 use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use config::Config;
+use network::metrics::METRICS;
+use network::registry::ConnRegistry;
 
-use crate::jobs_telemetry::jobs_telemetry;
+use crate::resources_telemetry::ResourcesTelemetry;
+use crate::sink::{Snapshot, TelemetrySink};
 
-pub mod jobs_telemetry;
+pub mod resources_telemetry;
+pub mod sink;
 
 pub struct ActivityTelemetry {
-    is_jobs_telemetry: bool,
     is_cpu_telemetry: bool,
     frequency: Duration,
-    shutdown: CancellationToken
+    shutdown: CancellationToken,
+    resources: ResourcesTelemetry,
+    sinks: Vec<Arc<dyn TelemetrySink>>,
+    // Upstream sessions are counted from the connections before every snapshot
+    registry: Arc<ConnRegistry>,
+    // The thresholds are taken from the running config on every tick
+    config: watch::Receiver<Arc<Config>>
 }
 
 impl ActivityTelemetry {
-    pub fn new(telemetry_token: CancellationToken, frequency: Duration) -> Self {
+    pub fn new(
+        telemetry_token: CancellationToken, config: watch::Receiver<Arc<Config>>, cpu_limit: Arc<Semaphore>,
+        registry: Arc<ConnRegistry>
+    ) -> Self {
+        let current = config.borrow().clone();
+
         ActivityTelemetry {
-            is_jobs_telemetry: false,
             is_cpu_telemetry: false,
-            frequency,
-            shutdown: telemetry_token
+            frequency: Duration::from_secs(current.telemetry.interval_secs),
+            shutdown: telemetry_token,
+            resources: ResourcesTelemetry::new(cpu_limit, current.scheduler.cpu_permits),
+            sinks: Vec::new(),
+            registry,
+            config
         }
     }
 
-    pub async fn run_telemetry(&self) -> anyhow::Result<()> {
-        let mut interval = tokio::time::interval(self.frequency);
+    pub async fn run_telemetry(&mut self) -> anyhow::Result<()> {
+        // The first tick comes after a whole period, the CPU usage is measured over it
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + self.frequency, self.frequency);
 
         loop {
-            if self.shutdown.is_cancelled() { break; }
-            interval.tick().await;
+            tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                _ = interval.tick() => {}
+            }
 
-            if self.is_jobs_telemetry {
-                self.log_jobs_telemetry();
+            if self.is_cpu_telemetry {
+                let config = self.config.borrow().clone();
+                self.resources.sample(&config.telemetry);
             }
+            self.publish().await;
         }
 
         Ok(())
     }
 
-    fn log_jobs_telemetry(&self) {
-        jobs_telemetry();
+    /// Feeds one snapshot of the series to every sink which takes them, at the same time so a slow
+    /// sink doesn't hold the others
+    async fn publish(&self) {
+        let pushed: Vec<&Arc<dyn TelemetrySink>> = self.sinks.iter().filter(|sink| !sink.is_pull()).collect();
+        if pushed.is_empty() {
+            return;
+        }
+
+        METRICS.count_upstreams(&self.registry).await;
+        let snapshot = Snapshot::gather();
+
+        let results = join_all(pushed.iter().map(|sink| sink.publish(&snapshot))).await;
+        for (sink, result) in pushed.iter().zip(results) {
+            if let Err(err) = result {
+                warn!(sink = sink.name(), "Couldn't publish the telemetry: {:?}", err);
+            }
+        }
+    }
+
+    /// Process resources, runtime tasks and CPU permits of the scheduler
+    pub fn set_cpu_telemetry(mut self, is_cpu_telemetry: bool) -> Self {
+        self.is_cpu_telemetry = is_cpu_telemetry;
+        self
     }
 
-    pub fn set_jobs_telemetry(mut self, is_jobs_telemetry: bool) -> Self {
-        self.is_jobs_telemetry = is_jobs_telemetry;
+    pub fn add_sink(mut self, sink: Arc<dyn TelemetrySink>) -> Self {
+        self.sinks.push(sink);
         self
     }
-}
\ No newline at end of file
+}
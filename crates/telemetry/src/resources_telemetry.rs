@@ -0,0 +1,262 @@
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, LazyLock};
+use std::time::Instant;
+
+use anyhow::anyhow;
+use tracing::{info, warn};
+use prometheus::{Counter, Gauge, IntGauge};
+use tokio::runtime::Handle;
+use tokio::sync::Semaphore;
+
+use config::TelemetryConfig;
+use network::metrics::METRICS;
+use scheduler::scheduler::IN_FLIGHT_CPU;
+
+static GAUGES: LazyLock<Gauges> = LazyLock::new(Gauges::new);
+
+/// What the process uses, read from /proc/self
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessSample {
+    pub cpu_seconds: f64, // user and system time since the start
+    pub rss_bytes: u64,
+    pub open_fds: u64,
+    pub fd_limit: u64, // soft limit of the open files
+    pub threads: u64
+}
+
+/// Tasks of the tokio runtime and the CPU work of the scheduler
+#[derive(Debug, Clone, Copy)]
+pub struct RuntimeSample {
+    pub workers: usize,
+    pub alive_tasks: usize,
+    pub global_queue_depth: usize,
+    // The runtime reports its blocking pool only when it's built with `--cfg tokio_unstable`
+    pub blocking_threads: Option<usize>,
+    pub idle_blocking_threads: Option<usize>,
+    pub in_flight_cpu: u64,
+    pub permits_available: usize,
+    pub permits: usize
+}
+
+/// Samples the process and the runtime into the series the sinks get, and warns when the usage
+/// crosses a threshold of the config
+pub struct ResourcesTelemetry {
+    cpu_limit: Arc<Semaphore>,
+    permits: usize,
+    cores: usize,
+    last_process: Option<(Instant, ProcessSample)>,
+    is_process_unavailable: bool,
+    cpu: HighWater,
+    rss: HighWater,
+    fds: HighWater,
+    cpu_permits: HighWater
+}
+
+/// Whether a usage is over its threshold, so crossing it is logged once
+#[derive(Debug, Default)]
+struct HighWater {
+    is_over: bool
+}
+
+struct Gauges {
+    cpu_seconds: Counter,
+    cpu_percent: Gauge,
+    rss_bytes: IntGauge,
+    open_fds: IntGauge,
+    fd_limit: IntGauge,
+    threads: IntGauge,
+    workers: IntGauge,
+    alive_tasks: IntGauge,
+    global_queue_depth: IntGauge,
+    blocking_threads: IntGauge,
+    idle_blocking_threads: IntGauge,
+    in_flight_cpu: IntGauge,
+    permits_available: IntGauge
+}
+
+impl ResourcesTelemetry {
+    pub fn new(cpu_limit: Arc<Semaphore>, permits: usize) -> Self {
+        // The first CPU usage is counted from here
+        let last_process = sample_process().ok().map(|sample| (Instant::now(), sample));
+        if let Some((_, sample)) = last_process {
+            GAUGES.cpu_seconds.inc_by(sample.cpu_seconds);
+        }
+
+        Self {
+            cpu_limit,
+            permits,
+            cores: std::thread::available_parallelism().map(|cores| cores.get()).unwrap_or(1),
+            last_process,
+            is_process_unavailable: false,
+            cpu: HighWater::default(),
+            rss: HighWater::default(),
+            fds: HighWater::default(),
+            cpu_permits: HighWater::default()
+        }
+    }
+
+    pub fn sample(&mut self, thresholds: &TelemetryConfig) {
+        match sample_process() {
+            Ok(sample) => self.process(sample, thresholds),
+            Err(err) if !self.is_process_unavailable => {
+                warn!("The process can't be sampled, only the runtime is reported: {err}");
+                self.is_process_unavailable = true;
+            }
+            Err(_) => {}
+        }
+
+        let sample = self.sample_runtime();
+        self.runtime(sample, thresholds);
+    }
+
+    fn process(&mut self, sample: ProcessSample, thresholds: &TelemetryConfig) {
+        let now = Instant::now();
+        // Of all the cores, since the previous sample
+        let cpu_percent = match self.last_process {
+            Some((at, last)) => {
+                let used = (sample.cpu_seconds - last.cpu_seconds).max(0.0);
+                GAUGES.cpu_seconds.inc_by(used);
+                used / now.duration_since(at).as_secs_f64().max(f64::EPSILON) / self.cores as f64 * 100.0
+            }
+            None => {
+                GAUGES.cpu_seconds.inc_by(sample.cpu_seconds);
+                0.0
+            }
+        };
+        self.last_process = Some((now, sample));
+
+        GAUGES.cpu_percent.set(cpu_percent);
+        GAUGES.rss_bytes.set(sample.rss_bytes as i64);
+        GAUGES.open_fds.set(sample.open_fds as i64);
+        GAUGES.fd_limit.set(sample.fd_limit.min(i64::MAX as u64) as i64);
+        GAUGES.threads.set(sample.threads as i64);
+
+        let rss_mb = sample.rss_bytes / 1024 / 1024;
+        self.cpu.check("CPU usage", cpu_percent.round() as u64, thresholds.cpu_warn_percent, "%");
+        self.rss.check("Resident memory", rss_mb, thresholds.rss_warn_mb, " MiB");
+        let fds_percent = (sample.open_fds * 100).checked_div(sample.fd_limit).unwrap_or(0);
+        self.fds.check("Open files", fds_percent, thresholds.fds_warn_percent, "% of the limit");
+    }
+
+    fn runtime(&mut self, sample: RuntimeSample, thresholds: &TelemetryConfig) {
+        GAUGES.workers.set(sample.workers as i64);
+        GAUGES.alive_tasks.set(sample.alive_tasks as i64);
+        GAUGES.global_queue_depth.set(sample.global_queue_depth as i64);
+        GAUGES.in_flight_cpu.set(sample.in_flight_cpu as i64);
+        GAUGES.permits_available.set(sample.permits_available as i64);
+
+        if let (Some(threads), Some(idle)) = (sample.blocking_threads, sample.idle_blocking_threads) {
+            GAUGES.blocking_threads.set(threads as i64);
+            GAUGES.idle_blocking_threads.set(idle as i64);
+        }
+
+        let used = sample.permits.saturating_sub(sample.permits_available);
+        let permits_percent = (used as u64 * 100).checked_div(sample.permits as u64).unwrap_or(0);
+        self.cpu_permits.check("CPU permits in use", permits_percent, thresholds.permits_warn_percent, "%");
+    }
+
+    fn sample_runtime(&self) -> RuntimeSample {
+        let metrics = Handle::current().metrics();
+
+        #[cfg(tokio_unstable)]
+        let (blocking_threads, idle_blocking_threads) =
+            (Some(metrics.num_blocking_threads()), Some(metrics.num_idle_blocking_threads()));
+        #[cfg(not(tokio_unstable))]
+        let (blocking_threads, idle_blocking_threads) = (None, None);
+
+        RuntimeSample {
+            workers: metrics.num_workers(),
+            alive_tasks: metrics.num_alive_tasks(),
+            global_queue_depth: metrics.global_queue_depth(),
+            blocking_threads,
+            idle_blocking_threads,
+            in_flight_cpu: IN_FLIGHT_CPU.load(Ordering::Relaxed),
+            permits_available: self.cpu_limit.available_permits(),
+            permits: self.permits
+        }
+    }
+}
+
+impl HighWater {
+    /// `threshold` 0 means it's off
+    fn check(&mut self, what: &str, usage: u64, threshold: u64, unit: &str) {
+        if threshold == 0 {
+            self.is_over = false;
+            return;
+        }
+
+        if usage >= threshold && !self.is_over {
+            warn!("{what} is over the high-water mark: {usage}{unit}, the threshold is {threshold}{unit}");
+            self.is_over = true;
+        } else if usage < threshold && self.is_over {
+            info!("{what} is back under the high-water mark: {usage}{unit}");
+            self.is_over = false;
+        }
+    }
+}
+
+impl Gauges {
+    fn new() -> Self {
+        Self {
+            cpu_seconds: register(Counter::new("process_cpu_seconds_total", "User and system CPU time of the process")),
+            cpu_percent: register(Gauge::new("process_cpu_percent", "CPU usage of all the cores since the previous sample")),
+            rss_bytes: register(IntGauge::new("process_resident_memory_bytes", "Resident memory of the process")),
+            open_fds: register(IntGauge::new("process_open_fds", "Open file descriptors")),
+            fd_limit: register(IntGauge::new("process_max_fds", "Limit of the open file descriptors")),
+            threads: register(IntGauge::new("process_threads", "Threads of the process")),
+            workers: register(IntGauge::new("runtime_workers", "Worker threads of the runtime")),
+            alive_tasks: register(IntGauge::new("runtime_alive_tasks", "Tasks alive in the runtime")),
+            global_queue_depth: register(IntGauge::new("runtime_global_queue_depth", "Tasks in the global queue of the runtime")),
+            blocking_threads: register(IntGauge::new("runtime_blocking_threads", "Threads of the blocking pool, tokio_unstable builds only")),
+            idle_blocking_threads: register(IntGauge::new("runtime_idle_blocking_threads", "Idle threads of the blocking pool, tokio_unstable builds only")),
+            in_flight_cpu: register(IntGauge::new("scheduler_in_flight_cpu_jobs", "CPU jobs running on the blocking pool")),
+            permits_available: register(IntGauge::new("scheduler_cpu_permits_available", "CPU permits of the scheduler not in use"))
+        }
+    }
+}
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: prometheus::Result<M>) -> M {
+    // Names are constants, an error here is a bug
+    let metric = metric.expect("the metric is valid");
+    METRICS.register(Box::new(metric.clone())).expect("the metric is registered once");
+    metric
+}
+
+/// CPU time, memory and threads from /proc/self/stat, the open files from /proc/self/fd
+pub fn sample_process() -> anyhow::Result<ProcessSample> {
+    let stat = std::fs::read_to_string("/proc/self/stat")?;
+    // The command name in the parentheses may have spaces, the fields after it don't
+    let fields: Vec<&str> = stat.rsplit_once(')')
+        .map(|(_, rest)| rest.split_whitespace().collect())
+        .ok_or_else(|| anyhow!("/proc/self/stat has no command name"))?;
+    // Fields are numbered from 1 as in proc(5), the state is the 3rd one
+    let field = |number: usize| -> anyhow::Result<u64> {
+        fields.get(number - 3)
+            .and_then(|field| field.parse().ok())
+            .ok_or_else(|| anyhow!("/proc/self/stat has no field {number}"))
+    };
+
+    // Safety: sysconf only reads the system's configuration
+    let (ticks, page_size) = unsafe { (libc::sysconf(libc::_SC_CLK_TCK), libc::sysconf(libc::_SC_PAGESIZE)) };
+    if ticks <= 0 || page_size <= 0 {
+        return Err(anyhow!("The clock ticks or the page size are unknown"));
+    }
+
+    // The directory handle read_dir opens is in the list too
+    let open_fds = std::fs::read_dir("/proc/self/fd")?.count().saturating_sub(1) as u64;
+
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // Safety: limit is a valid rlimit to write to
+    let fd_limit = match unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } {
+        0 => limit.rlim_cur,
+        _ => 0
+    };
+
+    Ok(ProcessSample {
+        cpu_seconds: (field(14)? + field(15)?) as f64 / ticks as f64,
+        rss_bytes: field(24)? * page_size as u64,
+        open_fds,
+        fd_limit,
+        threads: field(20)?
+    })
+}
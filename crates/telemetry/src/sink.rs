@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use prometheus::proto::MetricType;
+use serde::Serialize;
+
+use network::metrics::METRICS;
+
+pub mod log_sink;
+pub mod prometheus_sink;
+pub mod statsd_sink;
+
+/// Where the telemetry goes. Every sink gets the same snapshot of the series on every tick
+#[async_trait]
+pub trait TelemetrySink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// The sink reads the series itself when it needs them, nothing is pushed to it
+    fn is_pull(&self) -> bool {
+        false
+    }
+
+    async fn publish(&self, snapshot: &Snapshot) -> anyhow::Result<()>;
+}
+
+/// Values of every series at one moment
+#[derive(Debug, Clone, Serialize)]
+pub struct Snapshot {
+    pub at: u64, // unix time, milliseconds
+    pub series: Vec<Series>
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Series {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: SeriesValue
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SeriesValue {
+    Counter { value: f64 },
+    Gauge { value: f64 },
+    // Buckets are (upper bound, observations up to it)
+    Histogram { count: u64, sum: f64, buckets: Vec<(f64, u64)> }
+}
+
+impl Snapshot {
+    /// Every series of the proxy's metrics, the ones components have registered included
+    pub fn gather() -> Self {
+        let at = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_millis() as u64).unwrap_or(0);
+
+        let mut series = Vec::new();
+        for family in METRICS.gather() {
+            for metric in family.get_metric() {
+                let value = match family.get_field_type() {
+                    MetricType::COUNTER => SeriesValue::Counter { value: metric.get_counter().get_value() },
+                    MetricType::GAUGE => SeriesValue::Gauge { value: metric.get_gauge().get_value() },
+                    MetricType::HISTOGRAM => {
+                        let histogram = metric.get_histogram();
+                        SeriesValue::Histogram {
+                            count: histogram.get_sample_count(),
+                            sum: histogram.get_sample_sum(),
+                            buckets: histogram.get_bucket().iter()
+                                .map(|bucket| (bucket.upper_bound(), bucket.cumulative_count()))
+                                .collect()
+                        }
+                    }
+                    // The proxy has no summaries or untyped series
+                    _ => continue
+                };
+
+                series.push(Series {
+                    name: family.name().to_string(),
+                    labels: metric.get_label().iter()
+                        .map(|label| (label.name().to_string(), label.value().to_string()))
+                        .collect(),
+                    value
+                });
+            }
+        }
+
+        Snapshot { at, series }
+    }
+}
@@ -0,0 +1,51 @@
+use async_trait::async_trait;
+use tracing::info;
+
+use crate::sink::{Series, SeriesValue, Snapshot, TelemetrySink};
+
+/// Writes the series to the logs, one event per metric with the values summed over the labels, so a
+/// metric labelled by subaccount is still one line
+pub struct LogSink;
+
+#[async_trait]
+impl TelemetrySink for LogSink {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    async fn publish(&self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        // The series of a metric come one after another
+        for metric in snapshot.series.chunk_by(|a, b| a.name == b.name) {
+            log_metric(metric);
+        }
+
+        Ok(())
+    }
+}
+
+fn log_metric(series: &[Series]) {
+    let Some(first) = series.first() else {
+        return;
+    };
+    let name = first.name.as_str();
+
+    match first.value {
+        SeriesValue::Counter { .. } | SeriesValue::Gauge { .. } => {
+            let value: f64 = series.iter()
+                .map(|series| match series.value {
+                    SeriesValue::Counter { value } | SeriesValue::Gauge { value } => value,
+                    SeriesValue::Histogram { .. } => 0.0
+                })
+                .sum();
+            info!(metric = name, value, series = series.len(), "telemetry");
+        }
+        SeriesValue::Histogram { .. } => {
+            let (count, sum) = series.iter().fold((0, 0.0), |(count, sum), series| match series.value {
+                SeriesValue::Histogram { count: c, sum: s, .. } => (count + c, sum + s),
+                _ => (count, sum)
+            });
+            let mean = if count > 0 { sum / count as f64 } else { 0.0 };
+            info!(metric = name, count, mean, series = series.len(), "telemetry");
+        }
+    }
+}
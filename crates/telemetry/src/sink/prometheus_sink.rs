@@ -0,0 +1,22 @@
+use async_trait::async_trait;
+
+use crate::sink::{Snapshot, TelemetrySink};
+
+/// Prometheus scrapes `/metrics` of the admin API, which reads the series when it's asked. The sink
+/// is what turns the endpoint on, nothing is pushed to it
+pub struct PrometheusSink;
+
+#[async_trait]
+impl TelemetrySink for PrometheusSink {
+    fn name(&self) -> &'static str {
+        "prometheus"
+    }
+
+    fn is_pull(&self) -> bool {
+        true
+    }
+
+    async fn publish(&self, _snapshot: &Snapshot) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use tokio::net::UdpSocket;
+
+use crate::sink::{SeriesValue, Snapshot, TelemetrySink};
+
+// Fits into one packet on the usual 1500 bytes MTU
+const MAX_DATAGRAM: usize = 1432;
+
+/// Sends the series to a StatsD server over UDP. Labels go as DogStatsD tags, counters are sent as
+/// the increase since the previous snapshot
+pub struct StatsdSink {
+    socket: UdpSocket,
+    // Last values of the counters by their lines' keys
+    counters: Mutex<HashMap<String, f64>>
+}
+
+impl StatsdSink {
+    pub async fn connect(address: &str) -> anyhow::Result<Self> {
+        let server = tokio::net::lookup_host(address).await?
+            .next()
+            .ok_or_else(|| anyhow!("{address} isn't resolved to any address"))?;
+        let local: SocketAddr = if server.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(server).await?;
+
+        Ok(Self { socket, counters: Mutex::new(HashMap::new()) })
+    }
+
+    fn lines(&self, snapshot: &Snapshot) -> Vec<String> {
+        let mut counters = self.counters.lock().unwrap_or_else(|err| err.into_inner());
+        let mut lines = Vec::with_capacity(snapshot.series.len());
+
+        let mut increase = |key: String, value: f64| -> f64 {
+            let last = counters.insert(key, value).unwrap_or(0.0);
+            // A counter going down means the process has restarted the count
+            if value >= last { value - last } else { value }
+        };
+
+        for series in &snapshot.series {
+            let tags = tags(&series.labels);
+            match series.value {
+                SeriesValue::Gauge { value } => lines.push(format!("{}:{}|g{}", series.name, value, tags)),
+                SeriesValue::Counter { value } => {
+                    let delta = increase(format!("{}{}", series.name, tags), value);
+                    if delta > 0.0 {
+                        lines.push(format!("{}:{}|c{}", series.name, delta, tags));
+                    }
+                }
+                SeriesValue::Histogram { count, sum, .. } => {
+                    let count_delta = increase(format!("{}_count{}", series.name, tags), count as f64);
+                    let sum_delta = increase(format!("{}_sum{}", series.name, tags), sum);
+                    if count_delta > 0.0 {
+                        lines.push(format!("{}_count:{}|c{}", series.name, count_delta, tags));
+                        lines.push(format!("{}_sum:{}|c{}", series.name, sum_delta, tags));
+                    }
+                }
+            }
+        }
+
+        lines
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for StatsdSink {
+    fn name(&self) -> &'static str {
+        "statsd"
+    }
+
+    async fn publish(&self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        let mut datagram = String::with_capacity(MAX_DATAGRAM);
+
+        for line in self.lines(snapshot) {
+            if !datagram.is_empty() && datagram.len() + line.len() + 1 > MAX_DATAGRAM {
+                self.socket.send(datagram.as_bytes()).await?;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            self.socket.send(datagram.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// `|#listener:127.0.0.1:5555,state:connected`, the characters of the protocol are replaced
+fn tags(labels: &BTreeMap<String, String>) -> String {
+    let mut tags = String::new();
+    for (name, value) in labels {
+        tags.push_str(if tags.is_empty() { "|#" } else { "," });
+        let value: String = value.chars().map(|c| if matches!(c, ',' | '|' | '#' | '\n') { '_' } else { c }).collect();
+        let _ = write!(tags, "{name}:{value}");
+    }
+
+    tags
+}
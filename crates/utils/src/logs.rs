@@ -1,8 +1,131 @@
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use tracing::Subscriber;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{self, RollingFileAppender};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+use crate::logs::rate_limit::{RateLimitLayer, RATE_LIMIT};
+
+pub mod rate_limit;
+
+// Swaps the filter of the running subscriber, it's set once the logs are initialized
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// How the log lines are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    // One JSON object per line, for log collectors
+    Json
+}
+
+/// How often a new log file is started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never
+}
+
+/// Where the logs go and how many of them
+#[derive(Debug, Clone)]
+pub struct LogOptions {
+    pub format: LogFormat,
+    // In the RUST_LOG syntax, e.g. `info,network=debug`
+    pub filter: String,
+    pub stdout: bool,
+    pub file: Option<LogFile>,
+    // Lines of one log statement per `rate_limit_interval`, the rest are dropped. 0 doesn't limit them
+    pub rate_limit: u64,
+    pub rate_limit_interval: Duration
+}
+
+/// Files `<directory>/<prefix>.<date>`, the oldest ones beyond `max_files` are deleted
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    pub directory: PathBuf,
+    pub prefix: String,
+    pub rotation: Rotation,
+    pub max_files: usize // 0 keeps every file
+}
+
+/// The file is written by a background thread, the lines it hasn't written yet are flushed when the
+/// guard is dropped. It's kept until the process exits
+pub struct LogGuard {
+    _file: Option<WorkerGuard>
+}
+
 /// This is where the logs are initialized
-pub fn init_logs() {
-    tracing_subscriber::fmt()
-        .with_env_filter("info")
-        .with_line_number(true)
-        .with_file(true)
-        .init();
-}
\ No newline at end of file
+pub fn init_logs(options: &LogOptions) -> anyhow::Result<LogGuard> {
+    let (filter, handle) = reload::Layer::new(EnvFilter::try_new(&options.filter)?);
+    RATE_LIMIT.set(options.rate_limit, options.rate_limit_interval);
+
+    let mut outputs = Vec::new();
+    if options.stdout {
+        outputs.push(output(options.format, std::io::stdout, true));
+    }
+    let mut file_guard = None;
+    if let Some(file) = &options.file {
+        // A full queue drops lines instead of blocking the runtime
+        let (writer, guard) = tracing_appender::non_blocking(file.appender()?);
+        outputs.push(output(options.format, writer, false));
+        file_guard = Some(guard);
+    }
+
+    tracing_subscriber::registry().with(filter).with(RateLimitLayer).with(outputs).try_init()?;
+    let _ = FILTER.set(handle);
+
+    Ok(LogGuard { _file: file_guard })
+}
+
+/// Replaces the filter of the logs, e.g. `info,network=debug`
+pub fn set_filter(filter: &str) -> anyhow::Result<()> {
+    let filter = EnvFilter::try_new(filter)?;
+    match FILTER.get() {
+        Some(handle) => Ok(handle.reload(filter)?),
+        None => anyhow::bail!("The logs aren't initialized")
+    }
+}
+
+/// The filter the logs have now
+pub fn current_filter() -> Option<String> {
+    FILTER.get().and_then(|handle| handle.with_current(|filter| filter.to_string()).ok())
+}
+
+fn output<S, W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static
+{
+    let layer = fmt::layer().with_line_number(true).with_file(true).with_ansi(ansi).with_writer(writer);
+    match format {
+        LogFormat::Pretty => layer.boxed(),
+        LogFormat::Json => layer.json().boxed()
+    }
+}
+
+impl LogFile {
+    fn appender(&self) -> anyhow::Result<RollingFileAppender> {
+        let rotation = match self.rotation {
+            Rotation::Hourly => rolling::Rotation::HOURLY,
+            Rotation::Daily => rolling::Rotation::DAILY,
+            Rotation::Never => rolling::Rotation::NEVER
+        };
+
+        let mut builder = RollingFileAppender::builder().rotation(rotation).filename_prefix(&self.prefix);
+        if self.max_files > 0 {
+            builder = builder.max_log_files(self.max_files);
+        }
+
+        Ok(builder.build(&self.directory)?)
+    }
+}
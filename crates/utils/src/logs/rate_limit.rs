@@ -0,0 +1,99 @@
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tracing::callsite::Identifier;
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// Limit of the lines of every log statement, a statement which logs every read of every miner
+/// doesn't flood the outputs
+pub static RATE_LIMIT: LazyLock<RateLimit> = LazyLock::new(RateLimit::default);
+
+pub struct RateLimit {
+    events: AtomicU64, // of a statement per interval, 0 is unlimited
+    interval_ms: AtomicU64,
+    callsites: DashMap<Identifier, Window>
+}
+
+struct Window {
+    metadata: &'static Metadata<'static>,
+    started: Instant,
+    count: u64,
+    dropped: u64 // since the last report
+}
+
+/// Lines of a log statement the rate limit has dropped
+#[derive(Debug, Clone)]
+pub struct Dropped {
+    pub target: &'static str,
+    pub location: String, // file:line
+    pub count: u64
+}
+
+// Drops the events over the limit before any output sees them
+pub(super) struct RateLimitLayer;
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            events: AtomicU64::new(0),
+            interval_ms: AtomicU64::new(1000),
+            callsites: DashMap::new()
+        }
+    }
+}
+
+impl RateLimit {
+    /// At most `events` lines of every statement per `interval`, the change takes effect at once
+    pub fn set(&self, events: u64, interval: Duration) {
+        self.events.store(events, Ordering::Relaxed);
+        self.interval_ms.store(interval.as_millis().max(1) as u64, Ordering::Relaxed);
+    }
+
+    /// Lines dropped since the last call, by the statement
+    pub fn take_dropped(&self) -> Vec<Dropped> {
+        self.callsites.iter_mut()
+            .filter_map(|mut window| {
+                let count = std::mem::take(&mut window.dropped);
+                (count > 0).then(|| Dropped {
+                    target: window.metadata.target(),
+                    location: format!("{}:{}", window.metadata.file().unwrap_or("?"), window.metadata.line().unwrap_or(0)),
+                    count
+                })
+            })
+            .collect()
+    }
+
+    /// Whether the event is within the limit of its statement. Errors always are
+    fn allows(&self, metadata: &'static Metadata<'static>) -> bool {
+        let events = self.events.load(Ordering::Relaxed);
+        if events == 0 || *metadata.level() == Level::ERROR {
+            return true;
+        }
+
+        let interval = Duration::from_millis(self.interval_ms.load(Ordering::Relaxed));
+        let now = Instant::now();
+        let mut window = self.callsites.entry(metadata.callsite())
+            .or_insert_with(|| Window { metadata, started: now, count: 0, dropped: 0 });
+
+        if now.duration_since(window.started) >= interval {
+            window.started = now;
+            window.count = 0;
+        }
+        if window.count < events {
+            window.count += 1;
+            true
+        } else {
+            window.dropped += 1;
+            false
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for RateLimitLayer {
+    fn event_enabled(&self, event: &Event<'_>, _ctx: Context<'_, S>) -> bool {
+        RATE_LIMIT.allows(event.metadata())
+    }
+}